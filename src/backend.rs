@@ -0,0 +1,133 @@
+//! Built-in display backends, which know the right commands and flags of common wallpaper tools,
+//! as an alternative to writing `command_line` by hand.
+
+use serde::Deserialize;
+
+use crate::template::CommandLines;
+
+/// See `Configuration::backend`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Backend {
+    /// `swww img`, which needs a running `swww-daemon`.
+    Swww {
+        /// E.g. "fade", "wipe" or "grow", see `swww img --help`
+        #[serde(default)]
+        transition: Option<String>,
+        /// Duration of the transition in seconds
+        #[serde(default)]
+        transition_duration: Option<f32>,
+        /// "crop", "fit" or "no"
+        #[serde(default)]
+        resize: Option<String>,
+    },
+    /// Asks a running hyprpaper via `hyprctl hyprpaper`, and unloads the previous images.
+    Hyprpaper {
+        /// Show the whole image instead of covering the monitor
+        #[serde(default)]
+        contain: bool,
+    },
+    /// `feh --bg-fill`, for X11. Can't target single outputs.
+    Feh {
+        /// "fill", "scale", "center", "max" or "tile"
+        #[serde(default = "default_feh_mode")]
+        mode: String,
+    },
+    /// `xwallpaper --zoom`, for X11.
+    Xwallpaper {
+        /// "zoom", "stretch", "center", "maximize" or "tile"
+        #[serde(default = "default_xwallpaper_mode")]
+        mode: String,
+    },
+}
+
+fn default_feh_mode() -> String {
+    "fill".to_owned()
+}
+
+fn default_xwallpaper_mode() -> String {
+    "zoom".to_owned()
+}
+
+impl Backend {
+    /// The commands which show `{image}`, on the `{monitor}` if `per_output` is set.
+    pub fn command_lines(&self, per_output: bool) -> CommandLines {
+        let lines = match self {
+            Self::Swww {
+                transition,
+                transition_duration,
+                resize,
+            } => {
+                let mut line = "swww img".to_owned();
+                if per_output {
+                    line += " -o {monitor}";
+                }
+                if let Some(transition) = transition {
+                    line += &format!(" --transition-type {}", quote(transition));
+                }
+                if let Some(duration) = transition_duration {
+                    line += &format!(" --transition-duration {duration}");
+                }
+                if let Some(resize) = resize {
+                    line += &format!(" --resize {}", quote(resize));
+                }
+                vec![line + " {image}"]
+            }
+            Self::Hyprpaper { contain } => {
+                // An empty monitor shows the image on all of them
+                let monitor = if per_output { "{monitor}" } else { "" };
+                let mode = if *contain { "contain:" } else { "" };
+                vec![
+                    "hyprctl hyprpaper preload {image}".to_owned(),
+                    format!("hyprctl hyprpaper wallpaper {monitor},{mode}{{image}}"),
+                    "hyprctl hyprpaper unload unused".to_owned(),
+                ]
+            }
+            Self::Feh { mode } => {
+                vec![format!("feh --no-fehbg --bg-{} {{image}}", quote(mode))]
+            }
+            Self::Xwallpaper { mode } => {
+                let output = if per_output {
+                    "--output {monitor} "
+                } else {
+                    ""
+                };
+                vec![format!("xwallpaper {output}--{} {{image}}", quote(mode))]
+            }
+        };
+        CommandLines::Many(lines)
+    }
+}
+
+/// `text` as a single word of a command line, without placeholders.
+fn quote(text: &str) -> String {
+    shell_words::quote(&text.replace('{', "{{").replace('}', "}}")).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backend_commands_target_outputs() {
+        let swww = Backend::Swww {
+            transition: Some("fade".to_owned()),
+            transition_duration: None,
+            resize: None,
+        };
+        let lines = swww.command_lines(true);
+        let lines: Vec<_> = lines.iter().collect();
+        assert_eq!(
+            lines,
+            ["swww img -o {monitor} --transition-type fade {image}"]
+        );
+
+        let hyprpaper = Backend::Hyprpaper { contain: true };
+        let lines = hyprpaper.command_lines(false);
+        assert_eq!(
+            lines.iter().nth(1),
+            Some("hyprctl hyprpaper wallpaper ,contain:{image}")
+        );
+        assert!(lines.parse().is_ok());
+    }
+}
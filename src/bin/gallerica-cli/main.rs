@@ -0,0 +1,23 @@
+//! `gallerica-cli`, kept as an alias of `gallerica` for scripts written before the daemon and
+//! its client were merged into one binary.
+
+use std::{os::unix::process::CommandExt, path::PathBuf, process::ExitCode};
+
+fn main() -> ExitCode {
+    let gallerica = std::env::current_exe()
+        .ok()
+        .map(|exe| exe.with_file_name("gallerica"))
+        .filter(|gallerica| gallerica.is_file())
+        .unwrap_or_else(|| PathBuf::from("gallerica"));
+    let mut args: Vec<_> = std::env::args_os().skip(1).collect();
+    // Without a command, `gallerica` would start the daemon
+    if args.is_empty() {
+        args.push("help".into());
+    }
+    let err = std::process::Command::new(&gallerica)
+        .arg0("gallerica-cli")
+        .args(args)
+        .exec();
+    eprintln!("Error: Failed to run '{}': {err}", gallerica.display());
+    ExitCode::FAILURE
+}
@@ -0,0 +1,863 @@
+//! The commands which control a running daemon, see `CliCommand::Client`. They use the
+//! lib crate's request types and transports, like other clients would.
+
+use std::{
+    fmt::Display,
+    io::IsTerminal,
+    path::PathBuf,
+    process::ExitCode,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use clap::Subcommand;
+
+use gallerica::{
+    completions, duration,
+    message_api::{Event, PowerSaving},
+    transport::{Endpoint, Timeouts},
+    Request, Response,
+};
+
+mod tui;
+
+/// Exit status for failures without a more specific one, e.g. `Response::Error`.
+const EXIT_FAILURE: u8 = 1;
+const EXIT_INVALID_GALLERY: u8 = 2;
+const EXIT_BAD_REQUEST: u8 = 3;
+const EXIT_UNREACHABLE: u8 = 4;
+
+/// Help text describing the exit status of the client commands.
+pub const EXIT_STATUS: &str = "EXIT STATUS of the commands controlling a daemon:
+    0    Success
+    1    The request failed, e.g. the gallery has no images
+    2    There is no such gallery, or the arguments are invalid
+    3    The daemon rejected the request
+    4    The daemon couldn't be contacted";
+
+/// Options of all client commands, given before the command.
+#[derive(clap::Args)]
+pub struct Options {
+    #[clap(flatten)]
+    daemon: Daemon,
+
+    /// Send command to all sockets in the runtime directory instead of the default one.
+    /// If this option is set, then the value of <socket> is ignored.
+    #[clap(short, long, conflicts_with_all = &["tcp", "mqtt"])]
+    all: bool,
+
+    /// Only print errors, e.g. in scripts which only check the exit status
+    #[clap(short, long)]
+    quiet: bool,
+}
+
+/// Where the daemon is listening.
+#[derive(clap::Args)]
+struct Daemon {
+    /// Path to the unix socket file on which a gallerica daemon is listening.
+    /// May be an absolute or relative path.
+    /// Relative paths are relative to the system runtime directory (XDG_RUNTIME_DIR).
+    /// On Windows, this is the name of a named pipe instead, e.g. \\.\pipe\gallerica.
+    /// If omitted, the default socket or pipe is used if it exists, otherwise the daemon is
+    /// contacted via TCP on the default address.
+    #[clap(short, long)]
+    socket: Option<PathBuf>,
+
+    /// Address of a daemon with a TCP listener, e.g. on another machine
+    #[clap(long, value_name = "HOST:PORT", conflicts_with_all = &["socket", "mqtt"])]
+    tcp: Option<String>,
+
+    /// Token of the TCP listener given with <tcp>, if it has one
+    #[clap(long, requires = "tcp")]
+    token: Option<String>,
+
+    /// MQTT broker through which to contact a daemon with an MQTT listener, see <topic>.
+    /// The port defaults to 1883.
+    #[clap(
+        long,
+        value_name = "HOST:PORT",
+        requires = "topic",
+        conflicts_with = "socket"
+    )]
+    mqtt: Option<String>,
+
+    /// Topic the daemon is subscribed to, used with <mqtt>
+    #[clap(long, requires = "mqtt")]
+    topic: Option<String>,
+
+    /// Keep retrying for up to this many seconds if the daemon isn't running yet, e.g. in login
+    /// scripts which run while the daemon is starting
+    #[clap(long, value_name = "SECS")]
+    wait: Option<u64>,
+
+    /// Give up if the daemon doesn't respond within this many seconds.
+    /// Without it, requests via MQTT time out after 5 seconds, others wait indefinitely.
+    #[clap(long, value_name = "SECS")]
+    timeout: Option<u64>,
+}
+
+impl Daemon {
+    fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            connect: Duration::from_secs(self.wait.unwrap_or_default()),
+            response: self.timeout.map(Duration::from_secs),
+        }
+    }
+
+    /// The connection to the daemon given on the command line, or the default one.
+    fn connection(self) -> anyhow::Result<Connection> {
+        let mut timeouts = self.timeouts();
+        let endpoint = if let Some(address) = self.tcp {
+            Endpoint::Tcp {
+                address,
+                token: self.token,
+            }
+        } else if let (Some(broker), Some(topic)) = (self.mqtt, self.topic) {
+            Endpoint::Mqtt { broker, topic }
+        } else {
+            match self.socket {
+                #[cfg(unix)]
+                Some(socket) => Endpoint::unix(&socket),
+                #[cfg(windows)]
+                Some(pipe) => Endpoint::Pipe(pipe.to_string_lossy().into_owned()),
+                #[cfg(not(any(unix, windows)))]
+                Some(_) => anyhow::bail!("Unix sockets are not supported on this platform"),
+                None => {
+                    let start = Instant::now();
+                    let endpoint = Endpoint::discover_within(timeouts.connect);
+                    timeouts.connect = timeouts.connect.saturating_sub(start.elapsed());
+                    endpoint
+                }
+            }
+        };
+        Ok(Connection { endpoint, timeouts })
+    }
+}
+
+/// A daemon and how long to wait for it.
+struct Connection {
+    endpoint: Endpoint,
+    timeouts: Timeouts,
+}
+
+impl Connection {
+    fn send(&self, request: &Request) -> anyhow::Result<Response> {
+        self.endpoint
+            .send_with(request, self.timeouts)
+            .context(Unreachable)
+    }
+
+    fn subscribe(&self) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Event>>> {
+        self.endpoint
+            .subscribe_with(self.timeouts)
+            .context(Unreachable)
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Show the current image and gallery, whether the rotation is paused, and the time until
+    /// the next image
+    Status {
+        /// Print the status as JSON, e.g. for scripts
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// List the galleries with their number of images, marking the current one with "*"
+    Galleries {
+        /// Print the galleries as JSON, e.g. for scripts
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Show the status, galleries and history in an interactive dashboard, with keys to change
+    /// the image, pause, rate and select galleries
+    Tui,
+
+    /// Print each change of the image, gallery or pause state as it happens
+    Watch {
+        /// Print the events as lines of JSON, e.g. for status bars
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// Open a recently shown image in the default viewer, see the `history` subcommand
+    Open {
+        /// Number of the image in the history, 1 is the most recent one
+        #[clap(default_value = "1")]
+        number: usize,
+    },
+
+    /// Rate the current image with 5 stars, so the "rated" selection mode shows it most often
+    Favorite,
+
+    /// Never show the current image again, and show the next one. Undo with `unban`.
+    Ban,
+
+    /// Rate the current image, see the "rated" selection mode
+    Rate {
+        /// Number of stars from 0 to 5
+        #[clap(value_parser = clap::value_parser!(u8).range(0..=5))]
+        stars: u8,
+    },
+
+    /// Move the current image to the trash and show the next one, after asking for confirmation
+    Trash {
+        /// Don't ask for confirmation, e.g. when bound to a key
+        #[clap(short, long)]
+        yes: bool,
+    },
+
+    /// Copy the current image into a folder, e.g. to collect favorite images
+    Collect {
+        /// Folder to copy the image to. Defaults to `collect_directory` of the configuration.
+        folder: Option<PathBuf>,
+    },
+
+    /// Print the names of the galleries, used by the completion scripts
+    #[clap(name = completions::GALLERY_NAMES, hide = true)]
+    GalleryNames,
+
+    #[clap(flatten)]
+    Request(Request),
+}
+
+/// How responses are printed.
+#[derive(Clone, Copy)]
+enum Output {
+    Text,
+    Quiet,
+    Status,
+    Galleries,
+    History,
+    GalleryNames,
+    Json,
+}
+
+impl Output {
+    /// Text printed for `response`.
+    fn format(self, response: &Response) -> anyhow::Result<String> {
+        Ok(match self {
+            Self::Text => format_response(response),
+            Self::Quiet => String::new(),
+            Self::Status => format_status(response),
+            Self::Galleries => format_galleries(response),
+            Self::History => format_history(response),
+            Self::GalleryNames => format_gallery_names(response),
+            Self::Json => serde_json::to_string_pretty(response)? + "\n",
+        })
+    }
+
+    /// `Quiet` if `quiet` is set, otherwise this output.
+    fn unless_quiet(self, quiet: bool) -> Self {
+        if quiet {
+            Self::Quiet
+        } else {
+            self
+        }
+    }
+}
+
+/// Marks errors in contacting the daemon, which exit with `EXIT_UNREACHABLE`.
+#[derive(Debug)]
+struct Unreachable;
+
+impl Display for Unreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The daemon couldn't be contacted")
+    }
+}
+
+/// The exit status and a message for `response` if it reports a failure.
+fn failure(response: &Response) -> Option<(u8, String)> {
+    match response {
+        Response::InvalidGallery => Some((EXIT_INVALID_GALLERY, "No such gallery".to_owned())),
+        Response::BadRequest { message } => Some((EXIT_BAD_REQUEST, message.clone())),
+        Response::Error { message } | Response::CommandFailed { message } => {
+            Some((EXIT_FAILURE, message.clone()))
+        }
+        Response::NoImages {
+            gallery,
+            folders_checked,
+        } => {
+            let folders: Vec<_> = folders_checked
+                .iter()
+                .map(|folder| format!("'{}'", folder.display()))
+                .collect();
+            let message = format!(
+                "Gallery '{gallery}' has no images, checked {}",
+                folders.join(", ")
+            );
+            Some((EXIT_FAILURE, message))
+        }
+        Response::Ok
+        | Response::NewImage { .. }
+        | Response::DryRun { .. }
+        | Response::Stats { .. }
+        | Response::Galleries { .. }
+        | Response::History { .. }
+        | Response::Diagnosis { .. }
+        | Response::RecentEvents { .. }
+        | Response::Status { .. } => None,
+    }
+}
+
+/// Run `command`, and exit with a status telling whether it succeeded, see `EXIT_STATUS`.
+pub fn main(options: Options, command: Command) -> ExitCode {
+    match run(options, command) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            ExitCode::from(if err.is::<Unreachable>() {
+                EXIT_UNREACHABLE
+            } else {
+                EXIT_FAILURE
+            })
+        }
+    }
+}
+
+fn run(cli: Options, command: Command) -> anyhow::Result<ExitCode> {
+    let (mut request, output) = match command {
+        Command::Status { json: false } => (Request::GetStatus, Output::Status),
+        Command::Status { json: true } => (Request::GetStatus, Output::Json),
+        Command::Galleries { json: false } => (Request::ListGalleries, Output::Galleries),
+        Command::Galleries { json: true } => (Request::ListGalleries, Output::Json),
+        Command::Open { number } => {
+            return open(&cli.daemon.connection()?, number).map(|()| ExitCode::SUCCESS)
+        }
+        Command::Watch { json } => {
+            return watch(&cli.daemon.connection()?, json).map(|()| ExitCode::SUCCESS)
+        }
+        Command::Tui => return tui::run(&cli.daemon.connection()?).map(|()| ExitCode::SUCCESS),
+        Command::Favorite => (Request::RateCurrent { stars: 5 }, Output::Text),
+        Command::Ban => (Request::BanCurrent, Output::Text),
+        Command::Rate { stars } => (Request::RateCurrent { stars }, Output::Text),
+        Command::Collect { folder } => (
+            Request::CollectCurrent {
+                destination: folder,
+            },
+            Output::Text,
+        ),
+        Command::Trash { yes: true } => (Request::TrashCurrent { image: None }, Output::Text),
+        Command::Trash { yes: false } if cli.all => {
+            bail!("Pass --yes to trash the current images of all daemons")
+        }
+        Command::Trash { yes: false } => {
+            let connection = cli.daemon.connection()?;
+            let Some(image) = confirm_trash(&connection)? else {
+                return Ok(ExitCode::SUCCESS);
+            };
+            let request = Request::TrashCurrent { image: Some(image) };
+            return send(&connection, &request, Output::Text.unless_quiet(cli.quiet));
+        }
+        Command::Request(request @ Request::History { .. }) => (request, Output::History),
+        Command::Request(request) => (request, Output::Text),
+        Command::GalleryNames => (Request::ListGalleries, Output::GalleryNames),
+    };
+    let output = output.unless_quiet(cli.quiet);
+    request.make_paths_absolute(&std::env::current_dir()?);
+
+    if !cli.all {
+        return send(&cli.daemon.connection()?, &request, output);
+    }
+
+    // The most severe status of all daemons
+    let mut code = 0;
+    let timeouts = cli.daemon.timeouts();
+    for endpoint in Endpoint::discover_all()? {
+        let name = endpoint_name(&endpoint);
+        let response = match (Connection { endpoint, timeouts }).send(&request) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("{name}: {e:#}");
+                code = code.max(EXIT_UNREACHABLE);
+                continue;
+            }
+        };
+        let failure = failure(&response);
+        match (&failure, output) {
+            (_, Output::Json) => println!(
+                "{}",
+                serde_json::json!({ "endpoint": name, "response": response })
+            ),
+            (Some((_, message)), _) => eprintln!("{name}: {message}"),
+            (None, Output::Quiet) => {}
+            (None, Output::GalleryNames) => print!("{}", format_gallery_names(&response)),
+            (None, _) => {
+                let text = output.format(&response)?;
+                if text.trim_end().contains('\n') {
+                    println!("{name}:\n{text}");
+                } else {
+                    print!("{name}: {text}");
+                }
+            }
+        }
+        code = code.max(failure.map_or(0, |(code, _)| code));
+    }
+    Ok(ExitCode::from(code))
+}
+
+/// Send `request` to the daemon at `connection` and print the response.
+fn send(connection: &Connection, request: &Request, output: Output) -> anyhow::Result<ExitCode> {
+    let response = connection.send(request)?;
+    let failure = failure(&response);
+    match &failure {
+        Some((_, message)) if !matches!(output, Output::Json) => eprintln!("Error: {message}"),
+        _ => print!("{}", output.format(&response)?),
+    }
+    Ok(ExitCode::from(failure.map_or(0, |(code, _)| code)))
+}
+
+/// Ask whether to trash the current image of the daemon at `connection`.
+/// Returns the image if the user agreed.
+fn confirm_trash(connection: &Connection) -> anyhow::Result<Option<PathBuf>> {
+    let image = match connection.send(&Request::GetStatus)? {
+        Response::Status {
+            current_image: Some(image),
+            ..
+        } => image,
+        Response::Status { .. } => bail!("No image is currently shown"),
+        response => bail!(
+            "Failed to get the current image: {}",
+            format_response(&response).trim_end()
+        ),
+    };
+    if !std::io::stdin().is_terminal() {
+        bail!(
+            "Pass --yes to trash '{}' without confirmation",
+            image.display()
+        );
+    }
+    eprint!("Move '{}' to the trash? [y/N] ", image.display());
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes").then_some(image))
+}
+
+/// Open the `number`th most recently shown image in the default viewer.
+fn open(connection: &Connection, number: usize) -> anyhow::Result<()> {
+    if number == 0 {
+        bail!("The history is numbered from 1");
+    }
+    let request = Request::History {
+        gallery: None,
+        limit: number,
+    };
+    let entries = match connection.send(&request)? {
+        Response::History { entries } => entries,
+        response => bail!(
+            "Failed to read the history: {}",
+            format_response(&response).trim_end()
+        ),
+    };
+    let Some(entry) = entries.get(number - 1) else {
+        bail!(
+            "There is no entry {number}, the history has {} entries",
+            entries.len()
+        );
+    };
+    let status = std::process::Command::new("xdg-open")
+        .arg(&entry.path)
+        .status()
+        .context("Failed to run xdg-open")?;
+    if !status.success() {
+        bail!(
+            "Failed to open '{}': xdg-open {status}",
+            entry.path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Print the events of the daemon at `connection` until it shuts down.
+fn watch(connection: &Connection, json: bool) -> anyhow::Result<()> {
+    for event in connection.subscribe()? {
+        let event = event?;
+        if json {
+            println!("{}", serde_json::to_string(&event)?);
+        } else {
+            let now = chrono::Local::now().format("%H:%M:%S");
+            println!("{now}  {}", format_event(&event));
+        }
+    }
+    Ok(())
+}
+
+fn format_event(event: &Event) -> String {
+    match event {
+        Event::ImageShown {
+            image,
+            gallery,
+            output,
+            trigger,
+        } => {
+            let output = output
+                .as_ref()
+                .map_or_else(String::new, |output| format!(" on {output}"));
+            format!(
+                "{gallery}  {}  ({}{output})",
+                image.display(),
+                format!("{trigger:?}").to_lowercase()
+            )
+        }
+        Event::GallerySelected { gallery } => format!("Selected gallery '{gallery}'"),
+        Event::PauseChanged { paused: true } => "Paused".to_owned(),
+        Event::PauseChanged { paused: false } => "Resumed".to_owned(),
+        Event::Logged {
+            level,
+            target,
+            message,
+        } => {
+            let target = target.strip_prefix("gallerica::").unwrap_or(target);
+            format!("{} {target}: {message}", level.to_uppercase())
+        }
+    }
+}
+
+/// The `Response::RecentEvents` with their time, other responses as described by
+/// `format_response`.
+fn format_recent_events(response: &Response) -> String {
+    let Response::RecentEvents { events } = response else {
+        return format_response(response);
+    };
+    events
+        .iter()
+        .map(|recent| {
+            let time = chrono::DateTime::from_timestamp_millis(recent.time_ms as i64)
+                .map(|time| time.with_timezone(&chrono::Local))
+                .map_or_else(String::new, |time| {
+                    time.format("%Y-%m-%d %H:%M:%S").to_string()
+                });
+            format!("{time}  {}\n", format_event(&recent.event))
+        })
+        .collect()
+}
+
+/// Text describing `response`, using the tables below for responses with data.
+fn format_response(response: &Response) -> String {
+    if let Some((_, message)) = failure(response) {
+        return format!("Error: {message}\n");
+    }
+    match response {
+        Response::Status { .. } => format_status(response),
+        Response::Galleries { .. } => format_galleries(response),
+        Response::History { .. } => format_history(response),
+        Response::Stats { .. } => format_stats(response),
+        Response::RecentEvents { .. } => format_recent_events(response),
+        Response::NewImage { image: Some(image) } => format!("Showing '{}'\n", image.display()),
+        Response::Diagnosis { checks } => checks.iter().map(|check| format!("{check}\n")).collect(),
+        Response::DryRun { commands } => {
+            let mut text = "Dry run, the image would be shown with:\n".to_owned();
+            for command in commands {
+                text += &format!("  {command}\n");
+            }
+            text
+        }
+        _ => "Done\n".to_owned(),
+    }
+}
+
+/// The `Response::Stats` of each gallery, other responses as described by `format_response`.
+fn format_stats(response: &Response) -> String {
+    let Response::Stats { galleries } = response else {
+        return format_response(response);
+    };
+    let mut text = String::new();
+    for gallery in galleries {
+        text += &format!(
+            "{}: {} images, shown {} times\n",
+            gallery.name, gallery.number_images, gallery.times_shown
+        );
+        if !gallery.most_shown.is_empty() {
+            text += "  Most shown:\n";
+        }
+        for image in &gallery.most_shown {
+            text += &format!("  {:>6}  {}\n", image.times_shown, image.path.display());
+        }
+        if !gallery.never_shown.is_empty() {
+            text += "  Never shown:\n";
+        }
+        for path in &gallery.never_shown {
+            text += &format!("          {}\n", path.display());
+        }
+    }
+    text
+}
+
+/// The entries of the `Response::History`, numbered like `open` expects, other responses as
+/// described by `format_response`.
+fn format_history(response: &Response) -> String {
+    let Response::History { entries } = response else {
+        return format_response(response);
+    };
+    let width = entries
+        .iter()
+        .map(|entry| entry.gallery.len())
+        .max()
+        .unwrap_or_default();
+    entries
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let shown = chrono::DateTime::from_timestamp(entry.shown as i64, 0)
+                .map(|time| time.with_timezone(&chrono::Local))
+                .map_or_else(String::new, |time| {
+                    time.format("%Y-%m-%d %H:%M:%S").to_string()
+                });
+            format!(
+                "{:>3}  {shown}  {:<width$}  {}  ({})\n",
+                index + 1,
+                entry.gallery,
+                entry.path.display(),
+                format!("{:?}", entry.trigger).to_lowercase()
+            )
+        })
+        .collect()
+}
+
+/// The names of the galleries in `Response::Galleries`, one per line.
+fn format_gallery_names(response: &Response) -> String {
+    match response {
+        Response::Galleries { galleries } => galleries
+            .iter()
+            .map(|gallery| format!("{}\n", gallery.name))
+            .collect(),
+        _ => String::new(),
+    }
+}
+
+/// A table of the `Response::Galleries`, other responses as described by `format_response`.
+fn format_galleries(response: &Response) -> String {
+    let Response::Galleries { galleries } = response else {
+        return format_response(response);
+    };
+    let width = galleries
+        .iter()
+        .map(|gallery| gallery.name.len())
+        .max()
+        .unwrap_or_default();
+    galleries
+        .iter()
+        .map(|gallery| {
+            let mut notes = vec![];
+            if gallery.pinned {
+                notes.push("pinned");
+            }
+            if !gallery.safe {
+                notes.push("not safe");
+            }
+            let notes = if notes.is_empty() {
+                String::new()
+            } else {
+                format!("  ({})", notes.join(", "))
+            };
+            format!(
+                "{} {:<width$}  {:>6} images{notes}\n",
+                if gallery.active { '*' } else { ' ' },
+                gallery.name,
+                gallery.number_images
+            )
+        })
+        .collect()
+}
+
+/// A table of the `Response::Status`, other responses as described by `format_response`.
+fn format_status(response: &Response) -> String {
+    let Response::Status {
+        gallery,
+        current_image,
+        next_image,
+        paused,
+        safe_only,
+        power_saving,
+        remaining_ms,
+        profile,
+        safe_mode,
+        ..
+    } = response
+    else {
+        return format_response(response);
+    };
+
+    let mut state = vec![if *paused { "paused" } else { "running" }];
+    if *safe_mode {
+        state.push("in safe mode after crashing");
+    }
+    if *safe_only {
+        state.push("safe galleries only");
+    }
+    match power_saving {
+        PowerSaving::Off => {}
+        PowerSaving::Slowed => state.push("slowed down to save power"),
+        PowerSaving::Paused => state.push("paused to save power"),
+    }
+    let next_update = match remaining_ms {
+        Some(ms) => format!("in {}", duration::format(Duration::from_millis(*ms))),
+        None => "on schedule".to_owned(),
+    };
+    let path = |path: &Option<PathBuf>| {
+        path.as_ref()
+            .map_or("none".to_owned(), |path| path.display().to_string())
+    };
+
+    let mut rows = vec![
+        (
+            "Gallery",
+            gallery.clone().unwrap_or_else(|| "none".to_owned()),
+        ),
+        ("Image", path(current_image)),
+        ("Next image", path(next_image)),
+        ("State", state.join(", ")),
+        ("Next update", next_update),
+    ];
+    if let Some(profile) = profile {
+        rows.insert(0, ("Profile", profile.clone()));
+    }
+    rows.iter()
+        .map(|(label, value)| format!("{:<13}{value}\n", format!("{label}:")))
+        .collect()
+}
+
+/// Short name of an endpoint, used to label responses when sending to multiple daemons.
+fn endpoint_name(endpoint: &Endpoint) -> String {
+    match endpoint {
+        #[cfg(unix)]
+        Endpoint::Unix(path) => path
+            .file_name()
+            .unwrap_or(path.as_os_str())
+            .to_string_lossy()
+            .into_owned(),
+        #[cfg(windows)]
+        Endpoint::Pipe(name) => name.rsplit('\\').next().unwrap_or(name).to_owned(),
+        Endpoint::Tcp { address, .. } => address.clone(),
+        Endpoint::Mqtt { topic, .. } => topic.clone(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use gallerica::message_api::{
+        Diagnostic, DiagnosticStatus, GalleryInfo, HistoryEntry, Trigger,
+    };
+
+    #[test]
+    fn test_galleries_are_formatted() {
+        let gallery = |name: &str, active, pinned| GalleryInfo {
+            name: name.to_owned(),
+            number_images: 12,
+            active,
+            safe: true,
+            pinned,
+        };
+        let galleries = Response::Galleries {
+            galleries: vec![
+                gallery("art", false, true),
+                gallery("wallpapers", true, false),
+            ],
+        };
+        assert_eq!(
+            format_galleries(&galleries),
+            "  art             12 images  (pinned)\n\
+             * wallpapers      12 images\n"
+        );
+    }
+
+    #[test]
+    fn test_history_is_numbered() {
+        let entry = |path: &str| HistoryEntry {
+            path: PathBuf::from(path),
+            gallery: "wallpapers".to_owned(),
+            shown: 0,
+            trigger: Trigger::Request,
+        };
+        let history = Response::History {
+            entries: vec![entry("/b.png"), entry("/a.png")],
+        };
+        let lines: Vec<_> = format_history(&history)
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("  1  "));
+        assert!(lines[1].starts_with("  2  "));
+        assert!(lines[1].ends_with("  wallpapers  /a.png  (request)"));
+    }
+
+    #[test]
+    fn test_status_is_formatted() {
+        let status = Response::Status {
+            gallery: Some("wallpapers".to_owned()),
+            current_image: Some(PathBuf::from("/a.png")),
+            next_image: None,
+            paused: true,
+            safe_only: false,
+            power_saving: PowerSaving::Slowed,
+            elapsed_ms: Some(0),
+            remaining_ms: Some(90_000),
+            palette: vec![],
+            profile: None,
+            safe_mode: true,
+        };
+        assert_eq!(
+            format_status(&status),
+            "Gallery:     wallpapers\n\
+             Image:       /a.png\n\
+             Next image:  none\n\
+             State:       paused, in safe mode after crashing, slowed down to save power\n\
+             Next update: in 1m30s\n"
+        );
+    }
+
+    #[test]
+    fn test_responses_are_formatted() {
+        let image = Some(PathBuf::from("/a.png"));
+        assert_eq!(
+            format_response(&Response::NewImage { image }),
+            "Showing '/a.png'\n"
+        );
+        assert_eq!(format_response(&Response::Ok), "Done\n");
+        assert_eq!(
+            format_response(&Response::InvalidGallery),
+            "Error: No such gallery\n"
+        );
+        let commands = vec!["feh --bg-fill /a.png".to_owned()];
+        assert_eq!(
+            format_response(&Response::DryRun { commands }),
+            "Dry run, the image would be shown with:\n  feh --bg-fill /a.png\n"
+        );
+        let checks = vec![
+            Diagnostic {
+                check: "command".to_owned(),
+                status: DiagnosticStatus::Ok,
+                detail: "'feh' found".to_owned(),
+                fix: None,
+            },
+            Diagnostic {
+                check: "gallery 'a'".to_owned(),
+                status: DiagnosticStatus::Error,
+                detail: "contains no images".to_owned(),
+                fix: Some("add images".to_owned()),
+            },
+        ];
+        assert_eq!(
+            format_response(&Response::Diagnosis { checks }),
+            "[  ok  ] command: 'feh' found\n\
+             [ FAIL ] gallery 'a': contains no images\n         fix: add images\n"
+        );
+    }
+
+    #[test]
+    fn test_failures_have_exit_codes() {
+        let code = |response| failure(&response).map(|(code, _)| code);
+        assert_eq!(code(Response::NewImage { image: None }), None);
+        assert_eq!(code(Response::InvalidGallery), Some(EXIT_INVALID_GALLERY));
+        let message = "Unknown method".to_owned();
+        assert_eq!(
+            code(Response::BadRequest { message }),
+            Some(EXIT_BAD_REQUEST)
+        );
+    }
+}
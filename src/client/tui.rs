@@ -0,0 +1,292 @@
+//! Interactive dashboard with the status, the galleries and the history of the daemon, drawn
+//! with ratatui on a crossterm terminal.
+
+use std::{
+    io::Stdout,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute, terminal,
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Layout},
+    widgets::{Block, List, ListState, Paragraph},
+    Frame, Terminal,
+};
+
+use gallerica::{message_api::GalleryInfo, Request, Response};
+
+use super::{format_galleries, format_history, format_response, format_status, Connection};
+
+/// How often the status is refreshed while no key is pressed.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+/// The galleries are scanned for their image counts, so they are refreshed less often.
+const GALLERY_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to wait for input before checking whether a refresh is due.
+const INPUT_TIMEOUT: Duration = Duration::from_millis(100);
+const HISTORY_LENGTH: usize = 10;
+
+const HELP: &str =
+    "n next  p pause/resume  f favorite (5 stars)  b ban  ↑↓ gallery  enter select  q quit";
+
+#[derive(Debug, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Up,
+    Down,
+    Enter,
+}
+
+/// The key pressed in `event`, None for keys without an action.
+fn parse_key(event: KeyEvent) -> Option<Key> {
+    match event.code {
+        KeyCode::Up => Some(Key::Up),
+        KeyCode::Down => Some(Key::Down),
+        KeyCode::Enter => Some(Key::Enter),
+        // Ctrl-C, as the terminal doesn't send signals in raw mode
+        KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+            Some(Key::Char('q'))
+        }
+        KeyCode::Char(char) => Some(Key::Char(char)),
+        _ => None,
+    }
+}
+
+/// Leave raw mode and the alternate screen, if the terminal is still in them.
+fn restore_terminal() {
+    if terminal::is_raw_mode_enabled().unwrap_or(false) {
+        let _ = terminal::disable_raw_mode();
+        let _ = execute!(
+            std::io::stdout(),
+            terminal::LeaveAlternateScreen,
+            cursor::Show
+        );
+    }
+}
+
+/// Puts the terminal into raw mode on the alternate screen, and restores it when dropped. It is
+/// also restored before panics are reported, so the message isn't lost on the alternate screen.
+struct Screen {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+}
+
+impl Screen {
+    fn new() -> anyhow::Result<Self> {
+        terminal::enable_raw_mode().context("The tui needs a terminal")?;
+        let report = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            report(info);
+        }));
+        let terminal = execute!(
+            std::io::stdout(),
+            terminal::EnterAlternateScreen,
+            cursor::Hide
+        )
+        .and_then(|()| Terminal::new(CrosstermBackend::new(std::io::stdout())));
+        match terminal {
+            Ok(terminal) => Ok(Self { terminal }),
+            Err(err) => {
+                restore_terminal();
+                Err(err).context("Failed to set up the terminal")
+            }
+        }
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+struct Dashboard<'a> {
+    connection: &'a Connection,
+    status: String,
+    paused: bool,
+    galleries: Vec<GalleryInfo>,
+    /// Index of the gallery selected with the arrow keys
+    cursor: usize,
+    history: String,
+    /// Result of the last action
+    message: String,
+}
+
+impl Dashboard<'_> {
+    fn send(&self, request: &Request) -> Response {
+        self.connection
+            .send(request)
+            .unwrap_or_else(|err| Response::Error {
+                message: format!("{err:#}"),
+            })
+    }
+
+    fn refresh_status(&mut self) {
+        let status = self.send(&Request::GetStatus);
+        if let Response::Status { paused, .. } = status {
+            self.paused = paused;
+        }
+        self.status = format_status(&status);
+        self.history = format_history(&self.send(&Request::History {
+            gallery: None,
+            limit: HISTORY_LENGTH,
+        }));
+    }
+
+    fn refresh_galleries(&mut self) {
+        match self.send(&Request::ListGalleries) {
+            Response::Galleries { galleries } => {
+                if self.galleries.is_empty() {
+                    self.cursor = galleries.iter().position(|g| g.active).unwrap_or_default();
+                }
+                self.cursor = self.cursor.min(galleries.len().saturating_sub(1));
+                self.galleries = galleries;
+            }
+            response => self.message = format_response(&response).trim_end().to_owned(),
+        }
+    }
+
+    /// Run the action bound to `key`. Returns false to quit.
+    fn handle(&mut self, key: Key) -> bool {
+        let request = match key {
+            Key::Char('q') => return false,
+            Key::Up => {
+                self.cursor = self.cursor.saturating_sub(1);
+                return true;
+            }
+            Key::Down => {
+                self.cursor = (self.cursor + 1).min(self.galleries.len().saturating_sub(1));
+                return true;
+            }
+            Key::Char('n') => Request::NextImage { dry_run: false },
+            Key::Char('p') if self.paused => Request::Resume,
+            Key::Char('p') => Request::Pause,
+            Key::Char('f') => Request::RateCurrent { stars: 5 },
+            Key::Char('b') => Request::BanCurrent,
+            Key::Enter => match self.galleries.get(self.cursor) {
+                Some(gallery) => Request::SelectGallery {
+                    name: gallery.name.clone(),
+                    refresh: true,
+                    resume: false,
+                },
+                None => return true,
+            },
+            Key::Char(_) => return true,
+        };
+        self.message = format_response(&self.send(&request)).trim_end().to_owned();
+        if matches!(request, Request::SelectGallery { .. }) {
+            self.refresh_galleries();
+        }
+        self.refresh_status();
+        true
+    }
+
+    /// Draw the status, galleries and history above each other. The galleries get the space
+    /// left over, and scroll to keep the selected one visible.
+    fn draw(&self, frame: &mut Frame) {
+        let status_lines = self.status.lines().count() as u16;
+        let history_lines = self.history.lines().count() as u16;
+        let [status, galleries, history, help, message] = Layout::vertical([
+            Constraint::Length(status_lines + 2),
+            Constraint::Min(3),
+            Constraint::Length(history_lines + 2),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let title = format!("gallerica  {}", self.connection.endpoint);
+        frame.render_widget(
+            Paragraph::new(self.status.as_str()).block(Block::bordered().title(title)),
+            status,
+        );
+
+        let lines = format_galleries(&Response::Galleries {
+            galleries: self.galleries.clone(),
+        });
+        let list = List::new(lines.lines().map(str::to_owned))
+            .block(Block::bordered().title("Galleries"))
+            .highlight_symbol(">");
+        let mut selection = ListState::default().with_selected(Some(self.cursor));
+        frame.render_stateful_widget(list, galleries, &mut selection);
+
+        frame.render_widget(
+            Paragraph::new(self.history.as_str()).block(Block::bordered().title("History")),
+            history,
+        );
+        frame.render_widget(Paragraph::new(HELP), help);
+        frame.render_widget(Paragraph::new(self.message.as_str()), message);
+    }
+}
+
+/// Show the dashboard of the daemon at `connection` until the user quits.
+pub fn run(connection: &Connection) -> anyhow::Result<()> {
+    let mut screen = Screen::new()?;
+    let mut dashboard = Dashboard {
+        connection,
+        status: String::new(),
+        paused: false,
+        galleries: vec![],
+        cursor: 0,
+        history: String::new(),
+        message: String::new(),
+    };
+    dashboard.refresh_galleries();
+    dashboard.refresh_status();
+
+    let mut last_refresh = Instant::now();
+    let mut last_gallery_refresh = Instant::now();
+    let mut changed = true;
+    loop {
+        if changed {
+            // Also adapts to the size of the terminal
+            screen.terminal.draw(|frame| dashboard.draw(frame))?;
+        }
+        changed = false;
+        if event::poll(INPUT_TIMEOUT).context("Failed to read from the terminal")? {
+            match event::read().context("Failed to read from the terminal")? {
+                Event::Key(event) if event.kind != KeyEventKind::Release => {
+                    if let Some(key) = parse_key(event) {
+                        if !dashboard.handle(key) {
+                            return Ok(());
+                        }
+                        changed = true;
+                    }
+                }
+                Event::Resize(..) => changed = true,
+                _ => {}
+            }
+        }
+        if last_gallery_refresh.elapsed() >= GALLERY_REFRESH_INTERVAL {
+            dashboard.refresh_galleries();
+            last_gallery_refresh = Instant::now();
+            changed = true;
+        }
+        if last_refresh.elapsed() >= REFRESH_INTERVAL {
+            dashboard.refresh_status();
+            last_refresh = Instant::now();
+            changed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_keys_are_parsed() {
+        let key = |code| KeyEvent::new(code, KeyModifiers::NONE);
+        assert_eq!(parse_key(key(KeyCode::Char('n'))), Some(Key::Char('n')));
+        assert_eq!(parse_key(key(KeyCode::Up)), Some(Key::Up));
+        assert_eq!(parse_key(key(KeyCode::Enter)), Some(Key::Enter));
+        let ctrl_c = KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL);
+        assert_eq!(parse_key(ctrl_c), Some(Key::Char('q')));
+        assert_eq!(parse_key(key(KeyCode::F(1))), None);
+    }
+}
@@ -0,0 +1,220 @@
+//! Shell completion scripts, generated by clap_complete from the clap definition of a command
+//! line. Gallery names are completed by asking the running daemon with a hidden subcommand, see
+//! `GALLERY_NAMES`, which the generated scripts are wrapped with.
+
+use clap::Command;
+pub use clap_complete::Shell;
+
+/// Value name of arguments which take the name of a gallery.
+pub const GALLERY: &str = "GALLERY";
+
+/// Subcommand which prints the names of the galleries, one per line.
+pub const GALLERY_NAMES: &str = "gallery-names";
+
+/// Where the name of a gallery is expected, e.g. "select-gallery:0" for the first positional
+/// argument of `select-gallery`, or "stats:--gallery" for the value of its `--gallery` flag.
+/// Matched against the words on the command line by the wrappers of `bash` and `zsh`.
+fn gallery_positions(command: &Command) -> Vec<String> {
+    let mut positions = vec![];
+    for subcommand in command.get_subcommands() {
+        let name = subcommand.get_name();
+        let positionals = subcommand.get_arguments().filter(|arg| arg.is_positional());
+        for (index, arg) in positionals.enumerate() {
+            if takes_gallery(arg) {
+                positions.push(format!("{name}:{index}"));
+            }
+        }
+        for arg in subcommand.get_arguments().filter(|arg| takes_gallery(arg)) {
+            positions.extend(arg.get_long().map(|long| format!("{name}:--{long}")));
+            positions.extend(arg.get_short().map(|short| format!("{name}:-{short}")));
+        }
+    }
+    positions
+}
+
+fn takes_gallery(arg: &clap::Arg) -> bool {
+    arg.get_value_names()
+        .is_some_and(|names| names.contains(&GALLERY))
+}
+
+/// Flags which take a value, so the word after them isn't a subcommand or positional argument.
+fn value_flags(command: &Command) -> Vec<String> {
+    let mut flags: Vec<_> = std::iter::once(command)
+        .chain(command.get_subcommands())
+        .flat_map(Command::get_arguments)
+        .filter(|arg| !arg.is_positional() && arg.is_takes_value_set())
+        .flat_map(|arg| {
+            let long = arg.get_long().map(|long| format!("--{long}"));
+            let short = arg.get_short().map(|short| format!("-{short}"));
+            long.into_iter().chain(short)
+        })
+        .collect();
+    flags.sort();
+    flags.dedup();
+    flags
+}
+
+/// The completion script of `command`, which is installed as `bin`.
+pub fn generate(shell: Shell, mut command: Command, bin: &str) -> String {
+    let mut script = vec![];
+    clap_complete::generate(shell, &mut command, bin, &mut script);
+    let script = String::from_utf8(script).expect("Completion scripts are UTF-8");
+    match shell {
+        Shell::Bash => bash(script, &command, bin),
+        Shell::Zsh => zsh(script, &command, bin),
+        Shell::Fish => fish(script, &command, bin),
+        _ => script,
+    }
+}
+
+/// Name of the completion function generated for `bin`.
+fn function(bin: &str) -> String {
+    format!("_{}", bin.replace('-', "_"))
+}
+
+/// Replace the completion function of the generated bash `script` by one which completes gallery
+/// names, and otherwise calls the generated one.
+fn bash(script: String, command: &Command, bin: &str) -> String {
+    let function = function(bin);
+    let value_flags = value_flags(command).join("|");
+    let positions = gallery_positions(command).join("|");
+    format!(
+        r#"{script}
+{function}_galleries() {{
+    local cur=${{COMP_WORDS[COMP_CWORD]}} prev=${{COMP_WORDS[COMP_CWORD-1]}}
+    local command= position=0 i
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        case ${{COMP_WORDS[i]}} in
+            {value_flags}) ((i++)) ;;
+            -*) ;;
+            *) if [[ -z $command ]]; then command=${{COMP_WORDS[i]}}; else ((position++)); fi ;;
+        esac
+    done
+    local at=$command:$position
+    case $prev in
+        {value_flags}) at=$command:$prev ;;
+        *) if [[ $cur == -* ]]; then at=; fi ;;
+    esac
+    case $at in
+        {positions}) COMPREPLY=($(compgen -W "$({bin} {GALLERY_NAMES} 2>/dev/null)" -- "$cur")) ;;
+        *) {function} "$@" ;;
+    esac
+}}
+
+complete -F {function}_galleries -o bashdefault -o default {bin}
+"#
+    )
+}
+
+/// Like `bash`, the generated zsh `script` ends by calling its completion function, which is
+/// replaced by a call of the wrapper.
+fn zsh(script: String, command: &Command, bin: &str) -> String {
+    let function = function(bin);
+    let call = format!("{function} \"$@\"\n");
+    let script = script.strip_suffix(&call).unwrap_or(&script);
+    let value_flags = value_flags(command).join("|");
+    let positions = gallery_positions(command).join("|");
+    format!(
+        r#"{script}
+{function}_galleries() {{
+    local command= position=0 i
+    for ((i = 2; i < CURRENT; i++)); do
+        case ${{words[i]}} in
+            ({value_flags}) ((i++)) ;;
+            (-*) ;;
+            (*) if [[ -z $command ]]; then command=${{words[i]}}; else ((position++)); fi ;;
+        esac
+    done
+    local at=$command:$position
+    case ${{words[CURRENT-1]}} in
+        ({value_flags}) at=$command:${{words[CURRENT-1]}} ;;
+        (*) if [[ ${{words[CURRENT]}} == -* ]]; then at=; fi ;;
+    esac
+    case $at in
+        ({positions}) compadd -- ${{(f)"$({bin} {GALLERY_NAMES} 2>/dev/null)"}} ;;
+        (*) {function} "$@" ;;
+    esac
+}}
+
+if [ "$funcstack[1]" = "{function}" ]; then
+    {function}_galleries "$@"
+else
+    compdef {function}_galleries {bin}
+fi
+"#
+    )
+}
+
+/// Add the gallery names to the completions of the generated fish `script`. Positional
+/// arguments are located by counting the words before them which aren't flags.
+fn fish(mut script: String, command: &Command, bin: &str) -> String {
+    let galleries = format!("({bin} {GALLERY_NAMES} 2>/dev/null)");
+    for subcommand in command.get_subcommands() {
+        let name = subcommand.get_name();
+        let seen = format!("__fish_seen_subcommand_from {name}");
+        let positionals = subcommand.get_arguments().filter(|arg| arg.is_positional());
+        for (index, arg) in positionals.enumerate() {
+            if takes_gallery(arg) {
+                // The binary and the subcommand come first
+                let words = index + 2;
+                script += &format!(
+                    "complete -c {bin} -n \"{seen}; and test (count (string match -v -- '-*' \
+                     (commandline -opc))) -eq {words}\" -f -a \"{galleries}\"\n"
+                );
+            }
+        }
+        for arg in subcommand.get_arguments().filter(|arg| takes_gallery(arg)) {
+            if let Some(long) = arg.get_long() {
+                script += &format!(
+                    "complete -c {bin} -n \"{seen}\" -l {long} -r -f -a \"{galleries}\"\n"
+                );
+            }
+        }
+    }
+    script
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn command() -> Command<'static> {
+        Command::new("cli")
+            .arg(clap::Arg::new("config").short('c').takes_value(true))
+            .subcommand(
+                Command::new("select")
+                    .arg(clap::Arg::new("name").value_name(GALLERY))
+                    .arg(clap::Arg::new("path")),
+            )
+            .subcommand(
+                Command::new("stats").arg(
+                    clap::Arg::new("gallery")
+                        .long("gallery")
+                        .value_name(GALLERY),
+                ),
+            )
+    }
+
+    #[test]
+    fn test_gallery_names_are_completed() {
+        assert_eq!(
+            gallery_positions(&command()),
+            ["select:0", "stats:--gallery"]
+        );
+        assert_eq!(value_flags(&command()), ["--gallery", "-c"]);
+
+        let bash = generate(Shell::Bash, command(), "cli");
+        assert!(bash
+            .contains("select:0|stats:--gallery) COMPREPLY=($(compgen -W \"$(cli gallery-names"));
+        assert!(bash.ends_with("complete -F _cli_galleries -o bashdefault -o default cli\n"));
+
+        let zsh = generate(Shell::Zsh, command(), "cli");
+        assert!(zsh.contains("_arguments"));
+        assert!(!zsh.contains("\n_cli \"$@\"\n"));
+        assert!(zsh.contains("compdef _cli_galleries cli"));
+
+        let fish = generate(Shell::Fish, command(), "cli");
+        assert!(fish.contains("-n \"__fish_seen_subcommand_from stats\" -l gallery -r -f -a \"(cli gallery-names 2>/dev/null)\""));
+        assert!(fish.contains("-eq 2\" -f -a"));
+    }
+}
@@ -0,0 +1,181 @@
+//! Formats of the config file, detected by its extension: TOML, or JSON or YAML, e.g. for
+//! configurations generated by Nix or Ansible. All become the same TOML document, which is then
+//! migrated, overridden and deserialized alike.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use toml_edit::{Array, DocumentMut, InlineTable, Item, Table, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    /// The format of the config file at `path`, JSON if it ends in ".json", YAML for ".yaml" or
+    /// ".yml", otherwise TOML.
+    pub fn of(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Ok(Self::Json),
+            Some("yaml" | "yml") => Ok(Self::Yaml),
+            _ => Ok(Self::Toml),
+        }
+    }
+
+    /// Parse `text` in this format.
+    pub fn parse(self, text: &str) -> Result<DocumentMut> {
+        match self {
+            Self::Toml => text.parse().context("Failed to parse configuration"),
+            Self::Json => {
+                let value = serde_json::from_str(text).context("Failed to parse configuration")?;
+                document(value, "a JSON object")
+            }
+            Self::Yaml => {
+                let value = yaml(text).context("Failed to parse configuration")?;
+                match value {
+                    // Like an empty TOML file
+                    serde_json::Value::Null => Ok(DocumentMut::new()),
+                    value => document(value, "a YAML mapping"),
+                }
+            }
+        }
+    }
+}
+
+/// The YAML document `text` as JSON, with the merge keys (`<<: *anchor`) applied.
+fn yaml(text: &str) -> Result<serde_json::Value> {
+    let mut value: serde_yaml::Value = serde_yaml::from_str(text)?;
+    value.apply_merge()?;
+    Ok(serde_json::to_value(value)?)
+}
+
+/// The TOML document of `value`, which must be an object, described as `expected` otherwise.
+fn document(value: serde_json::Value, expected: &str) -> Result<DocumentMut> {
+    let serde_json::Value::Object(object) = value else {
+        bail!("Failed to parse configuration: expected {expected}");
+    };
+    let mut table = Table::new();
+    for (key, value) in &object {
+        if let Some(value) = json_to_toml(value) {
+            table.insert(key, Item::Value(value));
+        }
+    }
+    Ok(table.into())
+}
+
+/// `value` as TOML, which has no null, so null values are left out like unset options.
+fn json_to_toml(value: &serde_json::Value) -> Option<Value> {
+    Some(match value {
+        serde_json::Value::Null => return None,
+        serde_json::Value::Bool(value) => (*value).into(),
+        serde_json::Value::Number(number) => match number.as_i64() {
+            Some(integer) => integer.into(),
+            None => number.as_f64()?.into(),
+        },
+        serde_json::Value::String(text) => text.into(),
+        serde_json::Value::Array(values) => {
+            Value::Array(values.iter().filter_map(json_to_toml).collect::<Array>())
+        }
+        serde_json::Value::Object(object) => {
+            let mut table = InlineTable::new();
+            for (key, value) in object {
+                if let Some(value) = json_to_toml(value) {
+                    table.insert(key, value);
+                }
+            }
+            Value::InlineTable(table)
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_json_becomes_toml() {
+        let document = Format::Json
+            .parse(
+                r#"{
+                    "update_interval_ms": 60000,
+                    "aspect_tolerance": 0.5,
+                    "prefetch_command": null,
+                    "galleries": [{ "name": "a", "folders": ["~/a"], "recursive": true }]
+                }"#,
+            )
+            .unwrap();
+        let value: toml::Value = toml::from_str(&document.to_string()).unwrap();
+
+        assert_eq!(value["update_interval_ms"].as_integer(), Some(60000));
+        assert_eq!(value["aspect_tolerance"].as_float(), Some(0.5));
+        assert!(value.get("prefetch_command").is_none());
+        assert_eq!(value["galleries"][0]["folders"][0].as_str(), Some("~/a"));
+        assert_eq!(value["galleries"][0]["recursive"].as_bool(), Some(true));
+        assert_eq!(Format::of(Path::new("a.json")).unwrap(), Format::Json);
+        assert_eq!(Format::of(Path::new("config")).unwrap(), Format::Toml);
+    }
+
+    #[test]
+    fn test_yaml_becomes_toml() {
+        let document = Format::of(Path::new("config.yml"))
+            .unwrap()
+            .parse(
+                "update_interval_ms: 60000\n\
+                 prefetch_command: null\n\
+                 galleries:\n\
+                 - name: a\n\
+                 \x20 folders: [~/a]\n",
+            )
+            .unwrap();
+        let value: toml::Value = toml::from_str(&document.to_string()).unwrap();
+
+        assert_eq!(value["update_interval_ms"].as_integer(), Some(60000));
+        assert!(value.get("prefetch_command").is_none());
+        assert_eq!(value["galleries"][0]["folders"][0].as_str(), Some("~/a"));
+        assert!(Format::Yaml.parse("# nothing set yet\n").is_ok());
+        assert!(Format::Yaml.parse("- a\n").is_err());
+    }
+
+    #[test]
+    fn test_yaml_features_are_supported() {
+        let value = yaml(
+            r#"
+---
+defaults: &defaults
+  recursive: false
+  folders: [~/wallpapers, "~/it's #1"]
+galleries:
+  - name: default
+    <<: *defaults
+  - { name: rainy, folders: [~/rainy] }
+quiet_hours:
+  - start: 22:00
+    end: "07:00"
+script: |
+  first line
+    indented
+summary: >-
+  folded
+  into one line
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            value["galleries"][0],
+            serde_json::json!({
+                "name": "default",
+                "recursive": false,
+                "folders": ["~/wallpapers", "~/it's #1"],
+            })
+        );
+        assert_eq!(value["galleries"][1]["folders"][0], "~/rainy");
+        assert_eq!(value["quiet_hours"][0]["start"], "22:00");
+        assert_eq!(value["script"], "first line\n  indented\n");
+        assert_eq!(value["summary"], "folded into one line");
+        assert!(yaml("a: [1, 2").is_err());
+    }
+}
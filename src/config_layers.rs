@@ -0,0 +1,273 @@
+//! Layers of the configuration, each overriding the ones before it: the defaults of the options,
+//! the config file, the selected one of its `profiles`, environment variables like
+//! `GALLERICA_UPDATE_INTERVAL_MS`, and `--set` options. E.g. a container can be configured by
+//! environment variables alone, without a file.
+
+use std::{io::ErrorKind, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+use toml_edit::{DocumentMut, Item, Table, TableLike, Value};
+
+use crate::{
+    config_format::Format,
+    config_migration::{self, Migrated},
+    default_config,
+};
+
+/// Prefix of the environment variables which set options.
+const PREFIX: &str = "GALLERICA_";
+
+/// An option set outside of the config file.
+#[derive(Debug, Clone)]
+pub struct Override {
+    /// Where it was set, like `GALLERICA_UPDATE_INTERVAL_MS` or `--set update_interval_ms`
+    pub origin: String,
+    /// The keys leading to the option, like `["history", "max_entries"]`
+    pub key: Vec<String>,
+    pub value: Value,
+}
+
+/// `text` as a TOML value like `60000`, `true` or `["a", "b"]`, and otherwise as a string, so
+/// strings don't need quotes.
+fn parse_value(text: &str) -> Value {
+    text.parse().unwrap_or_else(|_| text.into())
+}
+
+impl Override {
+    /// Parse `KEY=VALUE` of a `--set` option. Keys of nested tables are separated by dots, like
+    /// `history.max_entries=100`.
+    pub fn parse(text: &str) -> Result<Self> {
+        let Some((key, value)) = text.split_once('=') else {
+            bail!("Expected KEY=VALUE, like 'update_interval_ms=60000'");
+        };
+        let key: Vec<_> = key.trim().split('.').map(str::to_owned).collect();
+        if key.iter().any(String::is_empty) {
+            bail!("Invalid key '{}'", key.join("."));
+        }
+        Ok(Self {
+            origin: format!("--set {}", key.join(".")),
+            key,
+            value: parse_value(value),
+        })
+    }
+
+    /// The option set by the environment variable `name`, if it starts with `GALLERICA_`. Keys
+    /// of nested tables are separated by `__`, like `GALLERICA_HISTORY__MAX_ENTRIES`.
+    fn from_variable(name: &str, value: &str) -> Option<Self> {
+        let key: Vec<_> = name
+            .strip_prefix(PREFIX)?
+            .split("__")
+            .map(str::to_lowercase)
+            .collect();
+        if key.iter().any(String::is_empty) {
+            return None;
+        }
+        Some(Self {
+            origin: name.to_owned(),
+            key,
+            value: parse_value(value),
+        })
+    }
+
+    /// The options set by environment variables, see `from_variable`.
+    pub fn from_environment() -> Vec<Self> {
+        let mut overrides: Vec<_> = std::env::vars_os()
+            .filter_map(|(name, value)| Self::from_variable(name.to_str()?, value.to_str()?))
+            .collect();
+        // Tables before the options within them
+        overrides.sort_by(|a, b| a.key.cmp(&b.key));
+        overrides
+    }
+
+    /// Set the option in `document`, creating the tables leading to it.
+    fn apply(&self, document: &mut DocumentMut) -> Result<()> {
+        let (last, parents) = self.key.split_last().context("Empty key")?;
+        let mut table: &mut dyn TableLike = document.as_table_mut();
+        for key in parents {
+            table = table
+                .entry(key)
+                .or_insert(Item::Table(Table::new()))
+                .as_table_like_mut()
+                .with_context(|| format!("{}: `{key}` is not a table", self.origin))?;
+        }
+        table.insert(last, Item::Value(self.value.clone()));
+        Ok(())
+    }
+}
+
+/// Replace the options in `document` by the ones of its profile `name`, see
+/// `Configuration::profiles`.
+fn apply_profile(document: &mut DocumentMut, name: &str) -> Result<()> {
+    let profiles = document.get("profiles").and_then(Item::as_table_like);
+    let Some(profile) = profiles.and_then(|profiles| profiles.get(name)) else {
+        let mut names: Vec<_> = profiles
+            .into_iter()
+            .flat_map(|profiles| profiles.iter().map(|(name, _)| format!("'{name}'")))
+            .collect();
+        names.sort();
+        match names.is_empty() {
+            true => bail!("Unknown profile '{name}', the configuration defines no `profiles`"),
+            false => bail!("Unknown profile '{name}', use one of {}", names.join(", ")),
+        }
+    };
+    let Some(profile) = profile.as_table_like() else {
+        bail!("`profiles.{name}` must be a table of options");
+    };
+    let options: Vec<_> = profile
+        .iter()
+        .filter(|(key, _)| !["profile", "profiles"].contains(key))
+        .map(|(key, item)| (key.to_owned(), item.clone()))
+        .collect();
+    for (key, item) in options {
+        document.insert(&key, item);
+    }
+    Ok(())
+}
+
+/// Where the configuration is read from, see the module documentation.
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    pub path: PathBuf,
+    /// The `--set` options
+    options: Vec<Override>,
+    /// Selected with `--profile` or `Request::SelectProfile`, instead of the `profile` option
+    pub profile: Option<String>,
+}
+
+impl ConfigSource {
+    pub fn new(path: PathBuf, options: Vec<Override>) -> Self {
+        Self {
+            path,
+            options,
+            profile: None,
+        }
+    }
+
+    /// The options set outside of the config file, later ones take precedence.
+    pub fn overrides(&self) -> Vec<Override> {
+        let mut overrides = Override::from_environment();
+        overrides.extend(self.options.iter().cloned());
+        overrides
+    }
+
+    /// The text of the config file. A missing file is empty if options are set outside of it.
+    pub fn text(&self) -> Result<String> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(text) => Ok(text),
+            Err(err) if err.kind() == ErrorKind::NotFound && !self.overrides().is_empty() => {
+                Ok(String::new())
+            }
+            Err(err) => Err(err)
+                .with_context(|| format!("Failed to open config file '{}'", self.path.display())),
+        }
+    }
+
+    /// Write the default configuration on the first start, if there is no config file and no
+    /// options are set otherwise.
+    pub fn create_default(&self) -> Result<()> {
+        if self.path.try_exists().unwrap_or(true) || !self.overrides().is_empty() {
+            return Ok(());
+        }
+        default_config::create(&self.path)
+    }
+
+    /// Apply the selected profile, and then the `overrides` to the configuration in `document`.
+    pub fn apply(&self, document: &mut DocumentMut) -> Result<()> {
+        let overrides = self.overrides();
+        let selected = overrides
+            .iter()
+            .rev()
+            .find(|option| option.key == ["profile"])
+            .and_then(|option| option.value.as_str());
+        let profile = self
+            .profile
+            .as_deref()
+            .or(selected)
+            .or_else(|| document.get("profile").and_then(Item::as_str))
+            .map(str::to_owned);
+
+        if let Some(name) = &profile {
+            apply_profile(document, name)?;
+        }
+        for option in overrides {
+            option.apply(document)?;
+        }
+        if let Some(name) = profile {
+            document.insert("profile", toml_edit::value(name));
+        }
+        Ok(())
+    }
+
+    /// The format of the config file, see `Format::of`.
+    pub fn format(&self) -> Result<Format> {
+        Format::of(&self.path)
+    }
+
+    /// Parse and migrate `text`, the content of the config file.
+    pub fn parse(&self, text: &str) -> Result<Migrated> {
+        let document = self.format()?.parse(text)?;
+        Ok(config_migration::migrate_document(document))
+    }
+
+    /// Read and migrate the config file, and apply the `overrides`.
+    pub fn load(&self) -> Result<Migrated> {
+        let mut migrated = self.parse(&self.text()?)?;
+        self.apply(&mut migrated.document)?;
+        Ok(migrated)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_overrides_replace_file_values() {
+        let mut document: DocumentMut = "update_interval_ms = 1000\n[history]\nmax_entries = 5\n"
+            .parse()
+            .unwrap();
+        let overrides = [
+            Override::from_variable("GALLERICA_UPDATE_INTERVAL_MS", "60000").unwrap(),
+            Override::from_variable("GALLERICA_HISTORY__MAX_ENTRIES", "10").unwrap(),
+            Override::parse("command_line=feh --bg-fill {image}").unwrap(),
+            Override::parse("current_link.path=\"/tmp/current\"").unwrap(),
+        ];
+        for option in &overrides {
+            option.apply(&mut document).unwrap();
+        }
+        let value: toml::Value = toml::from_str(&document.to_string()).unwrap();
+
+        assert_eq!(value["update_interval_ms"].as_integer(), Some(60000));
+        assert_eq!(value["history"]["max_entries"].as_integer(), Some(10));
+        assert_eq!(
+            value["command_line"].as_str(),
+            Some("feh --bg-fill {image}")
+        );
+        assert_eq!(value["current_link"]["path"].as_str(), Some("/tmp/current"));
+        assert!(Override::from_variable("HOME", "/home").is_none());
+        assert!(Override::parse("update_interval_ms").is_err());
+        assert!(Override::parse("a..b=1").is_err());
+    }
+
+    #[test]
+    fn test_profile_options_replace_file_values() {
+        let mut document: DocumentMut = r#"
+            default_gallery = "home"
+            update_interval_ms = 1000
+            [profiles.work]
+            default_gallery = "work"
+            "#
+        .parse()
+        .unwrap();
+        let mut source = ConfigSource::new(PathBuf::new(), vec![]);
+        source.profile = Some("work".to_owned());
+        source.apply(&mut document).unwrap();
+
+        assert_eq!(document["default_gallery"].as_str(), Some("work"));
+        assert_eq!(document["update_interval_ms"].as_integer(), Some(1000));
+        assert_eq!(document["profile"].as_str(), Some("work"));
+
+        source.profile = Some("play".to_owned());
+        assert!(source.apply(&mut document).is_err());
+    }
+}
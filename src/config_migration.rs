@@ -0,0 +1,135 @@
+//! Upgrades configuration files using deprecated options to the current format.
+//!
+//! Migrations are applied to the parsed TOML document before it is deserialized, so old config
+//! files keep working. `gallerica migrate-config` applies the same migrations and writes the
+//! result back, preserving comments and formatting.
+
+use toml_edit::{DocumentMut, Item, TableLike, Value};
+
+/// An option that was renamed.
+struct Rename {
+    /// Path of keys to the table(s) containing the option.
+    /// Arrays (e.g. `[[galleries]]`) along the path are applied to each of their elements.
+    parent: &'static [&'static str],
+    old: &'static str,
+    new: &'static str,
+}
+
+/// All known deprecated options, oldest first.
+const RENAMES: &[Rename] = &[Rename {
+    parent: &["galleries"],
+    old: "sources",
+    new: "folders",
+}];
+
+/// Result of migrating a configuration file.
+pub struct Migrated {
+    pub document: DocumentMut,
+
+    /// Human readable description of each applied migration.
+    pub warnings: Vec<String>,
+}
+
+/// Apply all migrations to the parsed configuration in `document`.
+pub fn migrate_document(mut document: DocumentMut) -> Migrated {
+    let mut warnings = vec![];
+
+    for rename in RENAMES {
+        let display_path = |key: &str| {
+            rename
+                .parent
+                .iter()
+                .chain(std::iter::once(&key))
+                .copied()
+                .collect::<Vec<_>>()
+                .join(".")
+        };
+
+        visit_tables(document.as_table_mut(), rename.parent, &mut |table| {
+            let Some(value) = table.remove(rename.old) else {
+                return;
+            };
+
+            if table.contains_key(rename.new) {
+                warnings.push(format!(
+                    "Ignoring deprecated option '{}', as '{}' is set as well",
+                    display_path(rename.old),
+                    display_path(rename.new),
+                ));
+            } else {
+                warnings.push(format!(
+                    "Option '{}' is deprecated, use '{}' instead",
+                    display_path(rename.old),
+                    display_path(rename.new),
+                ));
+                table.insert(rename.new, value);
+            }
+        });
+    }
+
+    Migrated { document, warnings }
+}
+
+/// Call `f` on each table reachable from `table` via `path`.
+fn visit_tables(table: &mut dyn TableLike, path: &[&str], f: &mut dyn FnMut(&mut dyn TableLike)) {
+    let Some((first, rest)) = path.split_first() else {
+        return f(table);
+    };
+
+    match table.get_mut(first) {
+        Some(Item::Table(table)) => visit_tables(table, rest, f),
+        Some(Item::ArrayOfTables(tables)) => {
+            for table in tables.iter_mut() {
+                visit_tables(table, rest, f);
+            }
+        }
+        Some(Item::Value(Value::InlineTable(table))) => visit_tables(table, rest, f),
+        Some(Item::Value(Value::Array(values))) => {
+            for value in values.iter_mut() {
+                if let Value::InlineTable(table) = value {
+                    visit_tables(table, rest, f);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rename_keeps_formatting() {
+        let migrated = migrate_document(
+            r#"
+# my galleries
+[[galleries]]
+name = "a"
+sources = [ "~/a" ] # comment
+
+[[galleries]]
+name = "b"
+folders = [ "~/b" ]
+sources = [ "~/old" ]
+"#
+            .parse()
+            .unwrap(),
+        );
+
+        assert_eq!(migrated.warnings.len(), 2);
+        assert_eq!(
+            migrated.document.to_string(),
+            r#"
+# my galleries
+[[galleries]]
+name = "a"
+folders = [ "~/a" ] # comment
+
+[[galleries]]
+name = "b"
+folders = [ "~/b" ]
+"#
+        );
+    }
+}
@@ -0,0 +1,120 @@
+//! Reloading the configuration while the daemon runs, when its file changes or on SIGHUP,
+//! see `Configuration::reload_on_change`.
+
+use std::{
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use tokio::{
+    select,
+    signal::unix::{signal, Signal, SignalKind},
+    time::{Interval, MissedTickBehavior},
+};
+use tracing::info;
+
+use crate::config_layers::ConfigSource;
+
+/// How often the modification time of the file is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Decides when the configuration of `source` should be reloaded.
+pub struct ConfigWatcher {
+    source: ConfigSource,
+    /// Whether to check the file for changes, or only wait for SIGHUP
+    pub poll: bool,
+    interval: Interval,
+    hangup: Signal,
+    /// Modification time of the file when it was last loaded
+    loaded: Option<SystemTime>,
+    /// Modification time at the last check
+    seen: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    /// Start watching the config file of `source`, which was just loaded.
+    pub fn new(source: ConfigSource, poll: bool) -> Result<Self> {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let loaded = modified(&source.path);
+        Ok(Self {
+            source,
+            poll,
+            interval,
+            hangup: signal(SignalKind::hangup())?,
+            loaded,
+            seen: loaded,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.source.path
+    }
+
+    pub fn source(&self) -> &ConfigSource {
+        &self.source
+    }
+
+    pub fn source_mut(&mut self) -> &mut ConfigSource {
+        &mut self.source
+    }
+
+    /// Wait until the configuration should be reloaded.
+    pub async fn changed(&mut self) {
+        loop {
+            select! {
+                _ = self.hangup.recv() => {
+                    info!("Received SIGHUP, reloading the configuration");
+                    break;
+                }
+                _ = self.interval.tick(), if self.poll => {
+                    let modified = modified(self.path());
+                    // Only once the file stopped changing, and not while an editor replaces it
+                    let settled = modified == self.seen && modified.is_some();
+                    self.seen = modified;
+                    if settled && modified != self.loaded {
+                        info!("'{}' changed, reloading the configuration", self.path().display());
+                        break;
+                    }
+                }
+            }
+        }
+        self.loaded = modified(self.path());
+        self.seen = self.loaded;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_changes_are_reported_once_settled() {
+        let dir = TempDir::new("config-watch");
+        let path = dir.join("config.toml");
+        std::fs::write(&path, "").unwrap();
+        let mut watcher =
+            ConfigWatcher::new(ConfigSource::new(path.clone(), vec![]), true).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+
+        let start = tokio::time::Instant::now();
+        watcher.changed().await;
+        let waited = start.elapsed();
+
+        // Seen by the immediate first check, reported by the next one
+        assert_eq!(waited, CHECK_INTERVAL);
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{instance_lock, state_dir};
+
+#[derive(Serialize, Deserialize, Default)]
+struct MarkerContent {
+    /// Times (seconds since the UNIX epoch) at which an unclean shutdown of a previous run was
+    /// detected. Only crashes since the last clean shutdown are recorded.
+    crashes: Vec<u64>,
+}
+
+/// Marker of the daemon using the config file at `config_path`. Daemons with other config files
+/// have their own, so they don't count each other as crashed.
+pub fn path(config_path: &Path) -> PathBuf {
+    state_dir().join(format!(
+        "gallerica-{}.running",
+        instance_lock::config_key(config_path)
+    ))
+}
+
+/// File which exists while the daemon is running.
+/// If the file still exists on startup, then the previous run did not shut down cleanly.
+pub struct RunningMarker {
+    path: PathBuf,
+}
+
+impl RunningMarker {
+    /// Create the marker at `path`.
+    /// Returns the marker together with the number of consecutive crashes that happened within
+    /// `window` before this start.
+    pub fn create(path: PathBuf, window: Duration) -> anyhow::Result<(Self, usize)> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut content = MarkerContent::default();
+        if path.exists() {
+            // An unreadable marker still means that the last run crashed
+            content = std::fs::read(&path)
+                .ok()
+                .and_then(|text| serde_json::from_slice(&text).ok())
+                .unwrap_or_default();
+            content.crashes.push(now);
+        }
+        content
+            .crashes
+            .retain(|&time| now.saturating_sub(time) <= window.as_secs());
+
+        let make_ctx = || format!("Failed to write running marker '{}'", path.display());
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).with_context(make_ctx)?;
+        }
+        std::fs::write(&path, serde_json::to_vec(&content)?).with_context(make_ctx)?;
+
+        Ok((Self { path }, content.crashes.len()))
+    }
+
+    /// Remove the marker, recording a clean shutdown.
+    pub fn remove(self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(
+                "Failed to remove running marker '{}': {e}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_unclean_shutdowns_are_counted() {
+        let dir = TempDir::new("crash-marker");
+        let path = dir.join("gallerica.running");
+        let window = Duration::from_secs(60);
+
+        let (first, crashes) = RunningMarker::create(path.clone(), window).unwrap();
+        assert_eq!(crashes, 0);
+        // Crashed, so the marker is left behind
+        drop(first);
+        let (_, crashes) = RunningMarker::create(path.clone(), window).unwrap();
+        assert_eq!(crashes, 1);
+        let (second, crashes) = RunningMarker::create(path.clone(), window).unwrap();
+        assert_eq!(crashes, 2);
+
+        second.remove();
+        let (_, crashes) = RunningMarker::create(path, window).unwrap();
+        assert_eq!(crashes, 0);
+    }
+
+    #[test]
+    fn test_each_config_has_its_own_marker() {
+        let first = super::path(Path::new("/etc/gallerica/desktop.toml"));
+        let second = super::path(Path::new("/etc/gallerica/frame.toml"));
+        assert_ne!(first, second);
+        assert_eq!(first, super::path(Path::new("/etc/gallerica/desktop.toml")));
+    }
+}
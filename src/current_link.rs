@@ -0,0 +1,50 @@
+//! A stable path to the current image, `$XDG_STATE_HOME/gallerica/current`, for tools which only
+//! want to read one file, like screenshot frames or stream overlays.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::state_dir;
+
+/// See `Configuration::current_link`.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CurrentLink {
+    Off,
+    #[default]
+    Symlink,
+    /// A copy, for tools which don't follow symlinks or run in a sandbox
+    Copy,
+}
+
+/// Where the link is placed unless the daemon is told otherwise, e.g. by tests.
+pub fn default_path() -> PathBuf {
+    state_dir().join("current")
+}
+
+impl CurrentLink {
+    /// Point the stable path `target` to `image`. The path is replaced atomically, so readers
+    /// never see a missing or partially written file.
+    pub fn update(self, target: &Path, image: &Path) -> io::Result<()> {
+        if self == Self::Off {
+            return Ok(());
+        }
+        if let Some(dir) = target.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temporary = target.with_extension("tmp");
+        let _ = fs::remove_file(&temporary);
+        match self {
+            Self::Off => unreachable!(),
+            Self::Symlink => std::os::unix::fs::symlink(image, &temporary)?,
+            Self::Copy => {
+                fs::copy(image, &temporary)?;
+            }
+        }
+        fs::rename(&temporary, target)
+    }
+}
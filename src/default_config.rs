@@ -0,0 +1,116 @@
+//! The configuration written on the first start, when there is no config file yet, so gallerica
+//! works right away and the file shows the most important options.
+
+use std::{borrow::Cow, path::Path};
+
+use anyhow::{Context, Result};
+use directories::UserDirs;
+use tracing::info;
+
+use crate::{doctor::find_executable, gallery_file::contract_tilde};
+
+const TEMPLATE: &str = r#"# Configuration of gallerica, created on its first start. See the README for all options.
+# Paths may start with `~` and use environment variables like `${HOME}`.
+
+# How the selected image is shown. {image} is replaced by its path on each update, e.g.
+# command_line = "feh --bg-fill {image}"
+# A built-in backend knows the commands of common wallpaper tools, instead of `command_line`:
+# backend = { type = "swww", transition = "fade" }
+{display}
+
+# Time between two images, in milliseconds or as a duration like "15m" or "2h".
+update_interval_ms = "10m"
+
+# The gallery which is shown first, switch to another one with `gallerica select-gallery NAME`.
+default_gallery = "pictures"
+
+# Each gallery shows the images in its folders.
+[[galleries]]
+name = "pictures"
+folders = [ "{folder}" ]
+# Include the images in subfolders as well.
+recursive = true
+"#;
+
+/// Display settings for the first of these programs which is installed, on Wayland.
+const WAYLAND: &[(&str, &str)] = &[
+    ("swww", r#"backend = { type = "swww" }"#),
+    ("hyprpaper", r#"backend = { type = "hyprpaper" }"#),
+    (
+        "swaybg",
+        "command_line = \"swaybg -m fill -i {image}\"\n\
+         # swaybg keeps running while its image is shown\n\
+         long_running_command = true",
+    ),
+];
+
+/// Like `WAYLAND`, on X11.
+const X11: &[(&str, &str)] = &[
+    ("feh", r#"backend = { type = "feh" }"#),
+    ("xwallpaper", r#"backend = { type = "xwallpaper" }"#),
+];
+
+/// Used when no wallpaper tool is installed.
+const FALLBACK: &str = "# No wallpaper tool was found, so the path of the image is only printed.\n\
+     command_line = \"echo {image}\"";
+
+/// The display settings for the wallpaper tool installed in this session.
+fn display_settings() -> &'static str {
+    let candidates = if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        WAYLAND
+    } else {
+        X11
+    };
+    candidates
+        .iter()
+        .find(|(program, _)| find_executable(program.as_ref()).is_some())
+        .map_or(FALLBACK, |(_, settings)| settings)
+}
+
+/// The default configuration, showing the pictures folder of the user.
+pub fn text() -> String {
+    let pictures = UserDirs::new().and_then(|dirs| {
+        dirs.picture_dir()
+            .map(|dir| contract_tilde(dir).into_owned())
+    });
+    let folder = match &pictures {
+        Some(dir) => dir.to_string_lossy(),
+        None => Cow::Borrowed("~/Pictures"),
+    };
+    TEMPLATE.replace("{display}", display_settings()).replace(
+        "{folder}",
+        &folder.replace('\\', "\\\\").replace('"', "\\\""),
+    )
+}
+
+/// Write the default configuration to `path`, which doesn't exist yet.
+pub fn create(path: &Path) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create '{}'", dir.display()))?;
+    }
+    std::fs::write(path, text()).with_context(|| {
+        format!(
+            "Failed to write a default configuration to '{}'",
+            path.display()
+        )
+    })?;
+    info!(
+        "Created a default configuration at '{}', edit it to choose your images and how they are shown",
+        path.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_configuration_is_valid() {
+        let config: crate::Configuration = toml::from_str(&text()).unwrap();
+        assert!(config.command_lines().is_ok());
+        assert_eq!(config.galleries().unwrap().len(), 1);
+        assert_eq!(config.update_interval_ms, 600_000);
+    }
+}
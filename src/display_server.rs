@@ -0,0 +1,73 @@
+//! Watching the desktop session through its display server, e.g. for fullscreen windows.
+
+use anyhow::Result;
+use serde::Deserialize;
+use tokio::sync::watch;
+use tracing::warn;
+
+/// Which display server to ask.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayServer {
+    /// Don't ask the display server.
+    #[default]
+    Off,
+    /// Use Wayland if `WAYLAND_DISPLAY` is set, otherwise X11.
+    Auto,
+    X11,
+    Wayland,
+}
+
+impl DisplayServer {
+    /// Either `X11` or `Wayland`, or None if `Off`.
+    pub fn resolve(self) -> Option<Self> {
+        match self {
+            Self::Off => None,
+            Self::Auto if std::env::var_os("WAYLAND_DISPLAY").is_some() => Some(Self::Wayland),
+            Self::Auto => Some(Self::X11),
+            server => Some(server),
+        }
+    }
+}
+
+/// A condition of the desktop session, like "a fullscreen window is focused", or some other state
+/// like the connected monitors, which is checked on its own thread until the monitor is dropped.
+pub struct Monitor<T = bool> {
+    receiver: watch::Receiver<T>,
+}
+
+impl<T: Clone + Default + Send + Sync + 'static> Monitor<T> {
+    /// Run `watch` on a new thread, which reports the condition through `publish` until the
+    /// sender is closed. `name` describes the condition in error messages.
+    pub fn spawn<W: Send + 'static>(
+        name: &'static str,
+        watcher: W,
+        watch: fn(W, &watch::Sender<T>) -> Result<()>,
+    ) -> Self {
+        let (sender, receiver) = watch::channel(T::default());
+        std::thread::spawn(move || {
+            if let Err(err) = watch(watcher, &sender) {
+                warn!("Stopped detecting {name}: {err:#}");
+            }
+        });
+        Self { receiver }
+    }
+
+    /// Wait until the condition changes, and return whether it's met now.
+    /// Never returns if the detection stopped.
+    pub async fn changed(&mut self) -> T {
+        if self.receiver.changed().await.is_err() {
+            return std::future::pending().await;
+        }
+        self.receiver.borrow_and_update().clone()
+    }
+}
+
+/// Store `value` in `sender`, only notifying the monitor if it changed.
+pub fn publish<T: PartialEq>(sender: &watch::Sender<T>, value: T) {
+    sender.send_if_modified(|state| {
+        let changed = *state != value;
+        *state = value;
+        changed
+    });
+}
@@ -0,0 +1,550 @@
+//! `gallerica doctor`, checking the environment for common problems, and `gallerica
+//! check-config`, which only checks the configuration. A running daemon checks its own
+//! environment the same way for `Request::Diagnose`, e.g. its `PATH` under a service manager.
+
+use std::{
+    ffi::OsStr,
+    fmt::Display,
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use gallerica::{
+    transport::{runtime_dir, Endpoint, Timeouts},
+    Request, Response,
+};
+
+use crate::{
+    config_format::Format,
+    config_layers::{ConfigSource, Override},
+    message_api::{Diagnostic, DiagnosticStatus},
+    state_dir,
+    storage::Storage,
+    template::CommandLine,
+    unknown_keys,
+    validation::{self, Severity},
+    Configuration, Gallery, ListenerConfiguration,
+};
+
+/// Time to wait when checking whether a network service is reachable.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+enum Outcome {
+    Ok(String),
+    Warning { problem: String, fix: String },
+    Error { problem: String, fix: String },
+}
+
+fn warning(problem: impl Display, fix: impl Display) -> Outcome {
+    Outcome::Warning {
+        problem: problem.to_string(),
+        fix: fix.to_string(),
+    }
+}
+
+fn error(problem: impl Display, fix: impl Display) -> Outcome {
+    Outcome::Error {
+        problem: problem.to_string(),
+        fix: fix.to_string(),
+    }
+}
+
+fn diagnostic(check: &str, outcome: Outcome) -> Diagnostic {
+    let (status, detail, fix) = match outcome {
+        Outcome::Ok(detail) => (DiagnosticStatus::Ok, detail, None),
+        Outcome::Warning { problem, fix } => (DiagnosticStatus::Warning, problem, Some(fix)),
+        Outcome::Error { problem, fix } => (DiagnosticStatus::Error, problem, Some(fix)),
+    };
+    Diagnostic {
+        check: check.to_owned(),
+        status,
+        detail,
+        fix,
+    }
+}
+
+/// The outcome of a check reported by a daemon.
+fn outcome(diagnostic: gallerica::message_api::Diagnostic) -> Outcome {
+    use gallerica::message_api::DiagnosticStatus;
+    let fix = diagnostic.fix.unwrap_or_default();
+    match diagnostic.status {
+        DiagnosticStatus::Ok => Outcome::Ok(diagnostic.detail),
+        DiagnosticStatus::Warning => warning(diagnostic.detail, fix),
+        DiagnosticStatus::Error => error(diagnostic.detail, fix),
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    errors: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn print(&mut self, check: &str, outcome: Outcome) {
+        let diagnostic = diagnostic(check, outcome);
+        match diagnostic.status {
+            DiagnosticStatus::Ok => {}
+            DiagnosticStatus::Warning => self.warnings += 1,
+            DiagnosticStatus::Error => self.errors += 1,
+        }
+        println!("{diagnostic}");
+    }
+}
+
+/// Run all checks for the configuration at `config_file` and print the results.
+/// Returns an error if any check failed.
+pub fn run(source: &ConfigSource) -> Result<()> {
+    let mut report = Report::default();
+
+    let config = check_config_file(source, &mut report)?;
+    for listener in &config.listeners {
+        let (name, outcome) = check_listener(listener);
+        report.print(&name, outcome);
+    }
+    report.print("state", check_state_dir(&config));
+    check_running_daemons(&config, &mut report);
+
+    report.finish()
+}
+
+/// Add the `Request::Diagnose` of the daemons listening on the sockets of `config`, as their
+/// environment can differ from the one of this command.
+fn check_running_daemons(config: &Configuration, report: &mut Report) {
+    for listener in &config.listeners {
+        let ListenerConfiguration::UnixSocket(listener) = listener else {
+            continue;
+        };
+        let Ok(path) = listener.socket_path() else {
+            continue;
+        };
+        if std::os::unix::net::UnixStream::connect(&path).is_err() {
+            continue;
+        }
+        let timeouts = Timeouts {
+            connect: Duration::ZERO,
+            response: Some(CONNECT_TIMEOUT),
+        };
+        let response = Endpoint::Unix(path.clone()).send_with(&Request::Diagnose, timeouts);
+        let name = format!("daemon on '{}'", path.display());
+        match response {
+            Ok(Response::Diagnosis { checks }) => {
+                println!("\nRunning {name}:");
+                for check in checks {
+                    report.print(&check.check.clone(), outcome(check));
+                }
+            }
+            Ok(response) => report.print(
+                &name,
+                warning(
+                    format!("can't diagnose itself, responded {response:?}"),
+                    "update and restart the daemon",
+                ),
+            ),
+            Err(err) => report.print(
+                &name,
+                warning(format!("didn't respond: {err:#}"), "restart the daemon"),
+            ),
+        }
+    }
+}
+
+/// The checks of `Request::Diagnose`, for the `galleries`, display `commands` and `storage` of
+/// the daemon. Galleries are scanned, so this should run on a blocking thread.
+pub fn diagnose(
+    galleries: &[Gallery],
+    commands: &[CommandLine],
+    storage: Option<&Storage>,
+) -> Vec<Diagnostic> {
+    let mut checks = vec![];
+    for gallery in galleries {
+        let check = format!("gallery '{}'", gallery.name);
+        for outcome in check_gallery(gallery) {
+            checks.push(diagnostic(&check, outcome));
+        }
+    }
+    for command in commands {
+        checks.push(diagnostic("command", check_program(command)));
+    }
+    checks.push(diagnostic("state", check_storage(storage)));
+    checks
+}
+
+/// Like `run`, but only check the configuration, not whether the daemon could start in this
+/// environment, e.g. to validate a config file before deploying it.
+pub fn check_config(source: &ConfigSource) -> Result<()> {
+    let mut report = Report::default();
+    check_config_file(source, &mut report)?;
+    report.finish()
+}
+
+impl Report {
+    /// Print the summary, returns an error if any check failed.
+    fn finish(self) -> Result<()> {
+        println!("\n{} error(s), {} warning(s)", self.errors, self.warnings);
+        if self.errors > 0 {
+            bail!("Found {} problem(s)", self.errors);
+        }
+        Ok(())
+    }
+}
+
+/// Parse the configuration of `source` and check its galleries and commands. Returns an error if
+/// it can't be parsed, as nothing else can be checked then.
+fn check_config_file(source: &ConfigSource, report: &mut Report) -> Result<Configuration> {
+    let make_error = |report: &mut Report, problem: String| {
+        report.print(
+            "config",
+            error(
+                problem,
+                "create the config file or fix the reported error, see the README for an example",
+            ),
+        );
+        anyhow::anyhow!("Can't check anything else without a valid configuration")
+    };
+
+    let text = source
+        .text()
+        .map_err(|err| make_error(report, format!("{err:#}")))?;
+    let mut migrated = source
+        .parse(&text)
+        .map_err(|err| make_error(report, format!("{err:#}")))?;
+    source
+        .apply(&mut migrated.document)
+        .map_err(|err| make_error(report, format!("{err:#}")))?;
+    for problem in &migrated.warnings {
+        report.print(
+            "config",
+            warning(
+                problem,
+                "run `gallerica migrate-config` to update the config file",
+            ),
+        );
+    }
+    let migrated = migrated.document.to_string();
+    let config: Configuration = toml::from_str(&migrated).map_err(|err| {
+        // The lines of a JSON file don't match the TOML it is converted to
+        let context = err
+            .line_col()
+            .filter(|_| matches!(source.format(), Ok(Format::Toml)))
+            .map(|(line, column)| source_context(&migrated, line, column))
+            .unwrap_or_default();
+        make_error(
+            report,
+            format!("Failed to parse configuration: {err}{context}"),
+        )
+    })?;
+    let overrides = source.overrides();
+    let set = match overrides.len() {
+        0 => String::new(),
+        count => format!(", with {count} option(s) set by the environment or --set"),
+    };
+    report.print(
+        "config",
+        Outcome::Ok(format!("parsed '{}'{set}", source.path.display())),
+    );
+
+    check_unknown_keys(&text, &migrated, &overrides, report);
+    for problem in validation::validate(&config) {
+        let fix = format!("change `{}`", problem.path);
+        let outcome = match problem.severity {
+            Severity::Error => error(&problem, fix),
+            Severity::Warning => warning(&problem, fix),
+        };
+        report.print("config", outcome);
+    }
+    check_galleries(&config, report);
+    if let Ok(command_lines) = config.command_lines() {
+        for command_line in command_lines.iter() {
+            if let Some(outcome) = check_command(command_line) {
+                report.print("command", outcome);
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// Line `line` of `text` with a marker below `column`, both counted from 0, to show where a
+/// problem is.
+fn source_context(text: &str, line: usize, column: usize) -> String {
+    let Some(source) = text.lines().nth(line) else {
+        return String::new();
+    };
+    let number = (line + 1).to_string();
+    let margin = " ".repeat(number.len());
+    let marker = " ".repeat(source.chars().take(column).count());
+    format!("\n{margin} |\n{number} | {source}\n{margin} | {marker}^")
+}
+
+/// Warn about keys which no option reads, which are usually misspelled. `original` is the text of
+/// the config file, `migrated` the one with deprecated options replaced and the `overrides` set.
+fn check_unknown_keys(original: &str, migrated: &str, overrides: &[Override], report: &mut Report) {
+    let Ok(value) = toml::from_str::<toml::Value>(migrated) else {
+        return;
+    };
+    let document = toml_edit::Document::parse(original).ok();
+    let mut unknown: Vec<_> = unknown_keys::find::<Configuration>(&value)
+        .into_iter()
+        .map(|path| {
+            // Look the key up in the original text, as that is what the user edits
+            let position = document
+                .as_ref()
+                .and_then(|document| key_position(document, &path));
+            (position, path)
+        })
+        .collect();
+    // In the order of the file, keys which weren't found last
+    unknown.sort_by_key(|(position, _)| position.unwrap_or(usize::MAX));
+
+    for (position, path) in unknown {
+        let set_by = overrides.iter().rev().find(|option| {
+            path.iter()
+                .zip(&option.key)
+                .all(|(segment, key)| *segment == unknown_keys::Segment::Key(key.clone()))
+        });
+        let context = match set_by {
+            Some(option) => format!(" set by {}", option.origin),
+            None => position
+                .map(|position| {
+                    let before = &original[..position];
+                    let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+                    let line = before.matches('\n').count();
+                    source_context(original, line, before[line_start..].chars().count())
+                })
+                .unwrap_or_default(),
+        };
+        report.print(
+            "config",
+            warning(
+                format!("unknown option `{}`{context}", unknown_keys::display(&path)),
+                "check its spelling and the section it is in, or remove it",
+            ),
+        );
+    }
+}
+
+/// Byte offset of the key at `path` in the text of `document`.
+fn key_position(
+    document: &toml_edit::Document<&str>,
+    path: &[unknown_keys::Segment],
+) -> Option<usize> {
+    let (last, parents) = path.split_last()?;
+    let mut item = document.as_item();
+    for segment in parents {
+        item = match segment {
+            unknown_keys::Segment::Key(key) => item.get(key)?,
+            unknown_keys::Segment::Index(index) => item.get(index)?,
+        };
+    }
+    let unknown_keys::Segment::Key(key) = last else {
+        return None;
+    };
+    Some(item.as_table_like()?.key(key)?.span()?.start)
+}
+
+fn check_galleries(config: &Configuration, report: &mut Report) {
+    let galleries = match config.galleries() {
+        Ok(galleries) => galleries,
+        Err(err) => {
+            return report.print(
+                "galleries",
+                error(format!("{err:#}"), "fix the gallery definitions"),
+            )
+        }
+    };
+
+    for gallery in &galleries {
+        let check = format!("gallery '{}'", gallery.name);
+        for outcome in check_gallery(gallery) {
+            report.print(&check, outcome);
+        }
+    }
+}
+
+/// Whether the folders of `gallery` can be read, and contain any files.
+fn check_gallery(gallery: &Gallery) -> Vec<Outcome> {
+    let mut outcomes: Vec<_> = gallery
+        .sources
+        .iter()
+        .filter_map(|source| {
+            let folder = &source.path;
+            let err = std::fs::read_dir(folder).err()?;
+            Some(warning(
+                format!("folder '{}' can't be read: {err}", folder.display()),
+                "check the folder path and its permissions",
+            ))
+        })
+        .collect();
+
+    outcomes.push(match gallery.scan().len() {
+        0 => error(
+            "contains no images",
+            "add images to its folders or fix the folder paths",
+        ),
+        count => Outcome::Ok(format!("{count} file(s)")),
+    });
+    outcomes
+}
+
+/// Whether the program of `command_line` is installed. Invalid commands are reported by
+/// `validation::validate` already.
+fn check_command(command_line: &str) -> Option<Outcome> {
+    let command = CommandLine::parse(command_line).ok()?;
+    Some(check_program(&command))
+}
+
+/// Whether the program of `command` is installed.
+fn check_program(command: &CommandLine) -> Outcome {
+    let program = command.program().to_string_lossy();
+
+    if find_executable(command.program()).is_none() {
+        return error(
+            format!("'{program}' is not an executable program"),
+            "install it, or use the absolute path in `command_line`",
+        );
+    }
+
+    Outcome::Ok(format!("'{program}' found"))
+}
+
+/// Search `program` in `PATH`, unless it contains a path separator.
+pub fn find_executable(program: &OsStr) -> Option<PathBuf> {
+    let is_executable = |path: &Path| {
+        use std::os::unix::fs::PermissionsExt;
+        path.metadata()
+            .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    };
+
+    let program = Path::new(program);
+    if program.components().count() > 1 {
+        return is_executable(program).then(|| program.to_path_buf());
+    }
+
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| is_executable(path))
+}
+
+fn check_listener(listener: &ListenerConfiguration) -> (String, Outcome) {
+    match listener {
+        ListenerConfiguration::UnixSocket(cfg) => {
+            let path = match cfg.socket_path() {
+                Ok(path) => path,
+                Err(err) => {
+                    let name = format!("socket '{}'", cfg.path_to_socket.display());
+                    return (name, error(format!("{err:#}"), "fix the `path_to_socket`"));
+                }
+            };
+            let name = format!("socket '{}'", path.display());
+
+            let outcome = if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+                warning(
+                    "a daemon is already listening on this socket",
+                    "stop the running daemon, or use a different `path_to_socket`",
+                )
+            } else if path.exists() {
+                Outcome::Ok("stale socket from a previous run, will be replaced".to_owned())
+            } else {
+                let bind = std::fs::create_dir_all(runtime_dir())
+                    .and_then(|_| std::os::unix::net::UnixListener::bind(&path));
+                match bind {
+                    Ok(_) => {
+                        std::fs::remove_file(&path).ok();
+                        Outcome::Ok("can be created".to_owned())
+                    }
+                    Err(err) => error(
+                        format!("can't be created: {err}"),
+                        "check the permissions of the runtime directory",
+                    ),
+                }
+            };
+            (name, outcome)
+        }
+        ListenerConfiguration::Tcp(cfg) => {
+            let name = format!("TCP '{}'", cfg.address);
+            let outcome = match TcpListener::bind(&cfg.address) {
+                Ok(_) => Outcome::Ok("address is available".to_owned()),
+                Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => warning(
+                    "address is already in use, possibly by a running daemon",
+                    "stop the other program, or configure a different `address`",
+                ),
+                Err(err) => error(
+                    format!("can't listen: {err}"),
+                    "configure a local address and a port that is free",
+                ),
+            };
+            (name, outcome)
+        }
+        ListenerConfiguration::Mqtt(cfg) => {
+            let name = format!("MQTT '{}:{}'", cfg.host, cfg.port);
+            let reachable = (cfg.host.as_str(), cfg.port)
+                .to_socket_addrs()
+                .map_err(|e| e.to_string())
+                .and_then(|mut addrs| {
+                    addrs
+                        .find_map(|addr| TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).ok())
+                        .ok_or_else(|| "connection failed".to_owned())
+                });
+            let outcome = match reachable {
+                Ok(_) => Outcome::Ok("broker is reachable".to_owned()),
+                Err(err) => error(
+                    format!("broker is not reachable: {err}"),
+                    "check that the broker is running and `host`/`port` are correct, \
+                     or set `wait_for_network` if the network comes up late",
+                ),
+            };
+            (name, outcome)
+        }
+        ListenerConfiguration::NamedPipe(cfg) => {
+            let name = format!("pipe '{}'", cfg.name);
+            let outcome = if !cfg!(windows) {
+                error(
+                    "named pipes are only available on Windows",
+                    "use a `UnixSocket` or `TCP` listener instead",
+                )
+            } else if Path::new(&cfg.name).exists() {
+                warning(
+                    "pipe is already in use, possibly by a running daemon",
+                    "stop the other program, or configure a different `name`",
+                )
+            } else {
+                Outcome::Ok("can be created".to_owned())
+            };
+            (name, outcome)
+        }
+        ListenerConfiguration::Stdin => ("stdin".to_owned(), Outcome::Ok("no checks".to_owned())),
+    }
+}
+
+fn check_state_dir(config: &Configuration) -> Outcome {
+    match config.storage() {
+        Ok(storage) => check_storage(storage.as_ref()),
+        Err(err) => error(format!("{err:#}"), "fix `storage` or `storage_file`"),
+    }
+}
+
+/// Whether the directory of `storage` is writable.
+fn check_storage(storage: Option<&Storage>) -> Outcome {
+    let Some(storage) = storage else {
+        return Outcome::Ok("persistence disabled".to_owned());
+    };
+
+    let dir = storage
+        .path()
+        .parent()
+        .map_or_else(state_dir, Path::to_owned);
+    let probe = dir.join(".gallerica-doctor");
+    let writable = std::fs::create_dir_all(&dir)
+        .and_then(|_| std::fs::write(&probe, b""))
+        .and_then(|_| std::fs::remove_file(&probe));
+
+    match writable {
+        Ok(()) => Outcome::Ok(format!("state is stored in '{}'", storage.path().display())),
+        Err(err) => error(
+            format!("state directory '{}' is not writable: {err}", dir.display()),
+            "fix the permissions, or remove `storage` to disable persistence",
+        ),
+    }
+}
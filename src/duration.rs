@@ -0,0 +1,154 @@
+//! Durations written for humans, like "90s", "15m", "2h" or "1h30m".
+//! Plain numbers are milliseconds, for compatibility with the `_ms` options.
+
+use std::{fmt, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use serde::{de, Deserializer, Serializer};
+
+/// Parse a duration like "90s", "15m", "2h", "1d", "500ms" or "1h30m", or a number of
+/// milliseconds.
+pub fn parse(text: &str) -> Result<Duration> {
+    let text = text.trim();
+    if let Ok(millis) = text.parse() {
+        return Ok(Duration::from_millis(millis));
+    }
+    if text.is_empty() {
+        bail!("Empty duration");
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(digits);
+        let unit_len = tail
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+        let number: u64 = number
+            .parse()
+            .map_err(|_| anyhow!("Expected a number in duration '{text}'"))?;
+        let unit = match unit.trim() {
+            "ms" => Duration::from_millis(1),
+            "s" => Duration::from_secs(1),
+            "m" | "min" => Duration::from_secs(60),
+            "h" => Duration::from_secs(60 * 60),
+            "d" => Duration::from_secs(24 * 60 * 60),
+            unit => bail!("Unknown unit '{unit}' in duration '{text}', use ms, s, m, h or d"),
+        };
+        total = u32::try_from(number)
+            .ok()
+            .and_then(|number| unit.checked_mul(number))
+            .and_then(|part| total.checked_add(part))
+            .ok_or_else(|| anyhow!("Duration '{text}' is too long"))?;
+        rest = tail.trim_start();
+    }
+    Ok(total)
+}
+
+/// Write `duration` like "1h30m" or "45s", rounded down to whole seconds. The inverse of
+/// `parse`.
+pub fn format(duration: Duration) -> String {
+    let mut seconds = duration.as_secs();
+    if seconds == 0 {
+        return format!("{}ms", duration.as_millis());
+    }
+    let mut text = String::new();
+    for (unit, length) in [("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60), ("s", 1)] {
+        if seconds >= length {
+            text += &format!("{}{unit}", seconds / length);
+            seconds %= length;
+        }
+    }
+    text
+}
+
+/// Like `parse`, but in milliseconds.
+pub fn parse_millis(text: &str) -> Result<u64> {
+    Ok(parse(text)?.as_millis().try_into()?)
+}
+
+/// Milliseconds given as a number or as a duration string, for `#[serde(with)]`.
+pub mod millis {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(millis: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(*millis)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        deserializer.deserialize_any(MillisVisitor)
+    }
+}
+
+/// Like `millis`, for optional values. Needs `#[serde(default)]`.
+pub mod optional_millis {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        millis: &Option<u64>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match millis {
+            Some(millis) => serializer.serialize_some(millis),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u64>, D::Error> {
+        super::millis::deserialize(deserializer).map(Some)
+    }
+}
+
+struct MillisVisitor;
+
+impl de::Visitor<'_> for MillisVisitor {
+    type Value = u64;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("milliseconds or a duration like \"15m\"")
+    }
+
+    fn visit_u64<E: de::Error>(self, millis: u64) -> Result<u64, E> {
+        Ok(millis)
+    }
+
+    fn visit_i64<E: de::Error>(self, millis: i64) -> Result<u64, E> {
+        u64::try_from(millis).map_err(|_| E::custom("Durations must not be negative"))
+    }
+
+    fn visit_str<E: de::Error>(self, text: &str) -> Result<u64, E> {
+        parse_millis(text).map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_durations_are_parsed() {
+        assert_eq!(parse("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse("15m").unwrap(), Duration::from_secs(15 * 60));
+        assert_eq!(parse("1h 30m").unwrap(), Duration::from_secs(90 * 60));
+        assert_eq!(parse("2500").unwrap(), Duration::from_millis(2500));
+        assert_eq!(parse("500ms").unwrap(), Duration::from_millis(500));
+
+        assert!(parse("").is_err());
+        assert!(parse("15 minutes").is_err());
+        assert!(parse("h").is_err());
+    }
+
+    #[test]
+    fn test_durations_are_formatted() {
+        assert_eq!(format(Duration::from_secs(90 * 60)), "1h30m");
+        assert_eq!(format(Duration::from_millis(45_900)), "45s");
+        assert_eq!(format(Duration::from_millis(500)), "500ms");
+        assert_eq!(format(Duration::from_secs(24 * 60 * 60 + 5)), "1d5s");
+    }
+}
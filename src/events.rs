@@ -0,0 +1,218 @@
+//! Events for clients which keep their connection open, see `Request::Subscribe`, and the most
+//! recent ones for `Request::RecentEvents`.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use circular_queue::CircularQueue;
+use tokio::{
+    io::AsyncWriteExt,
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::warn;
+
+use crate::message_api::{Event, EventWriter, RecentEvent, Response, Trigger};
+
+/// Number of events kept for subscribers which are slow to read them. Older events are dropped.
+const CAPACITY: usize = 64;
+
+/// The last events, oldest ones are dropped.
+struct Recent(CircularQueue<RecentEvent>);
+
+impl Recent {
+    fn new(capacity: usize) -> Self {
+        Self(CircularQueue::with_capacity(capacity))
+    }
+
+    fn push(&mut self, event: Event) {
+        if self.0.capacity() == 0 {
+            return;
+        }
+        let time_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.0.push(RecentEvent { time_ms, event });
+    }
+
+    /// Keep up to `capacity` events, the newest ones if there are more.
+    fn resize(&mut self, capacity: usize) {
+        if self.0.capacity() == capacity {
+            return;
+        }
+        let old = std::mem::replace(&mut self.0, CircularQueue::with_capacity(capacity));
+        if capacity > 0 {
+            for event in old.asc_iter() {
+                self.0.push(event.clone());
+            }
+        }
+    }
+
+    /// Up to `limit` events, newest first.
+    fn newest(&self, limit: usize) -> Vec<RecentEvent> {
+        self.0.iter().take(limit).cloned().collect()
+    }
+}
+
+/// Kept globally, so the logger can record messages as well.
+static RECENT: Mutex<Option<Recent>> = Mutex::new(None);
+
+/// Remember `event` for `Request::RecentEvents`.
+pub fn record(event: Event) {
+    if let Ok(mut recent) = RECENT.lock() {
+        recent
+            .get_or_insert_with(|| Recent::new(crate::default_recent_events()))
+            .push(event);
+    }
+}
+
+/// Keep the last `capacity` events, see `Configuration::recent_events`.
+pub fn keep_recent(capacity: usize) {
+    if let Ok(mut recent) = RECENT.lock() {
+        recent
+            .get_or_insert_with(|| Recent::new(capacity))
+            .resize(capacity);
+    }
+}
+
+/// Up to `limit` of the last events, newest first.
+pub fn recent(limit: usize) -> Vec<RecentEvent> {
+    RECENT
+        .lock()
+        .ok()
+        .and_then(|recent| recent.as_ref().map(|recent| recent.newest(limit)))
+        .unwrap_or_default()
+}
+
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+    /// Image last announced for each output or rotation, None for the main image
+    shown: HashMap<Option<String>, PathBuf>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+            shown: HashMap::new(),
+        }
+    }
+}
+
+impl Events {
+    pub fn send(&self, event: Event) {
+        record(event.clone());
+        // Fails if there are no subscribers
+        let _ = self.sender.send(event);
+    }
+
+    /// Announce that `image` of `gallery` is shown on `output`, unless it already was, e.g. when
+    /// an image is applied again after a monitor was connected. Returns whether it changed.
+    pub fn image_changed(
+        &mut self,
+        output: Option<&str>,
+        image: &Path,
+        gallery: &str,
+        trigger: Trigger,
+    ) -> bool {
+        let output = output.map(str::to_owned);
+        if self.shown.get(&output).is_some_and(|shown| shown == image) {
+            return false;
+        }
+        self.shown.insert(output.clone(), image.to_owned());
+        self.send(Event::ImageShown {
+            image: image.to_owned(),
+            gallery: gallery.to_owned(),
+            output,
+            trigger,
+        });
+        true
+    }
+
+    /// Confirm the subscription on `writer`, then write each event to it as a line of JSON,
+    /// until the client disconnects.
+    pub fn subscribe(&self, mut writer: EventWriter) {
+        let mut receiver = self.sender.subscribe();
+        tokio::spawn(async move {
+            let mut line = serde_json::to_vec(&Response::Ok).unwrap_or_default();
+            loop {
+                line.push(b'\n');
+                if writer.write_all(&line).await.is_err() || writer.flush().await.is_err() {
+                    break;
+                }
+                let event = match receiver.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(count)) => {
+                        warn!("Dropped {count} event(s) for a slow subscriber");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let Ok(json) = serde_json::to_vec(&event) else {
+                    continue;
+                };
+                line = json;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_recent_events_are_kept() {
+        let mut recent = Recent::new(3);
+        for paused in [true, false, true, false] {
+            recent.push(Event::PauseChanged { paused });
+        }
+        recent.resize(2);
+        recent.push(Event::GallerySelected {
+            gallery: "a".to_owned(),
+        });
+
+        let events: Vec<_> = recent
+            .newest(10)
+            .into_iter()
+            .map(|recent| serde_json::to_value(recent.event).unwrap())
+            .collect();
+        assert_eq!(
+            events,
+            [
+                serde_json::json!({ "type": "GallerySelected", "gallery": "a" }),
+                serde_json::json!({ "type": "PauseChanged", "paused": false }),
+            ]
+        );
+        assert_eq!(recent.newest(1).len(), 1);
+    }
+
+    #[test]
+    fn test_reapplied_images_are_not_announced() {
+        let mut events = Events::default();
+        let mut receiver = events.sender.subscribe();
+
+        let (a, b) = (Path::new("a.png"), Path::new("b.png"));
+        events.image_changed(None, a, "gallery", Trigger::Timer);
+        events.image_changed(Some("HDMI-1"), a, "gallery", Trigger::Timer);
+        events.image_changed(None, a, "gallery", Trigger::Monitor);
+        events.image_changed(None, b, "gallery", Trigger::Timer);
+
+        let mut announced = vec![];
+        while let Ok(Event::ImageShown { image, output, .. }) = receiver.try_recv() {
+            announced.push((output, image));
+        }
+        assert_eq!(
+            announced,
+            vec![
+                (None, a.to_owned()),
+                (Some("HDMI-1".to_owned()), a.to_owned()),
+                (None, b.to_owned()),
+            ]
+        );
+    }
+}
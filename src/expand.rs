@@ -0,0 +1,110 @@
+//! Expansion of `~` and environment variables like `${HOME}` in configured paths and commands,
+//! so one configuration can be shared by machines with different users and directories.
+
+use std::{
+    borrow::Cow,
+    env::VarError,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use directories::UserDirs;
+
+fn home() -> Result<PathBuf> {
+    Ok(UserDirs::new()
+        .ok_or_else(|| anyhow!("User has no home directory!"))?
+        .home_dir()
+        .to_owned())
+}
+
+/// The value of the variable in `${spec}`. `spec` is the name of an environment variable like
+/// `HOME`, optionally with a default for when it is unset or empty, like `WALLPAPERS:-~/images`.
+pub fn variable(spec: &str) -> Result<String> {
+    let (name, default) = match spec.split_once(":-") {
+        Some((name, default)) => (name, Some(default)),
+        None => (spec, None),
+    };
+    match (std::env::var(name), default) {
+        (Ok(value), None) => Ok(value),
+        (Ok(value), Some(_)) if !value.is_empty() => Ok(value),
+        (_, Some(default)) => Ok(default.to_owned()),
+        (Err(VarError::NotUnicode(_)), None) => {
+            bail!("Environment variable '{name}' is not valid UTF-8")
+        }
+        (Err(VarError::NotPresent), None) => bail!(
+            "Environment variable '{name}' is not set, use '${{{name}:-default}}' for a default"
+        ),
+    }
+}
+
+/// `text` with each `${...}` replaced by its `variable`. A literal `${` is written as `$${`.
+pub fn variables(text: &str) -> Result<Cow<'_, str>> {
+    if !text.contains("${") {
+        return Ok(Cow::Borrowed(text));
+    }
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        let after = &rest[start + 2..];
+        if rest[..start].ends_with('$') {
+            expanded += &rest[..start];
+            expanded.push('{');
+            rest = after;
+            continue;
+        }
+        let Some(end) = after.find('}') else {
+            bail!("Unclosed '${{' in '{text}'");
+        };
+        expanded += &rest[..start];
+        expanded += &variable(&after[..end])?;
+        rest = &after[end + 1..];
+    }
+    expanded += rest;
+    Ok(Cow::Owned(expanded))
+}
+
+/// `path` with its `variables` expanded, and a leading `~` replaced by the home directory.
+/// Returns Err if a variable is unset, or the user does not have a home.
+pub fn path(path: &Path) -> Result<Cow<'_, Path>> {
+    let path = match path.to_str().map(variables).transpose()? {
+        Some(Cow::Owned(expanded)) => Cow::Owned(PathBuf::from(expanded)),
+        _ => Cow::Borrowed(path),
+    };
+
+    let mut components = path.components();
+    if let Some(Component::Normal(start)) = components.next() {
+        if let Some("~") = start.to_str() {
+            return Ok(Cow::Owned(home()?.join(components)));
+        }
+    }
+
+    Ok(path)
+}
+
+/// Like `path`, for a word of a command line. Only words starting with `~/` are expanded.
+pub fn tilde(word: &str) -> Result<Cow<'_, str>> {
+    match word.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            Ok(Cow::Owned(format!("{}{rest}", home()?.display())))
+        }
+        _ => Ok(Cow::Borrowed(word)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_variables_are_expanded() {
+        let home = home().unwrap();
+        assert_eq!(
+            path(Path::new("${GALLERICA_TEST_UNSET:-~/images}/a")).unwrap(),
+            home.join("images/a")
+        );
+        assert_eq!(variables("a$${b}").unwrap(), "a${b}");
+        assert_eq!(variables("plain").unwrap(), "plain");
+        assert!(variables("${GALLERICA_TEST_UNSET}").is_err());
+        assert!(variables("${unclosed").is_err());
+    }
+}
@@ -0,0 +1,221 @@
+//! Detecting focused fullscreen windows, e.g. to pause the rotation while a movie or game runs.
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use tokio::sync::watch;
+use tracing::warn;
+use wayland_client::{
+    backend::ObjectId,
+    event_created_child,
+    globals::{registry_queue_init, GlobalListContents},
+    protocol::wl_registry::WlRegistry,
+    Connection, Dispatch, Proxy, QueueHandle, WEnum,
+};
+use wayland_protocols_wlr::foreign_toplevel::v1::client::{
+    zwlr_foreign_toplevel_handle_v1::{self, ZwlrForeignToplevelHandleV1},
+    zwlr_foreign_toplevel_manager_v1::{self, ZwlrForeignToplevelManagerV1},
+};
+use x11rb::{
+    connection::Connection as _,
+    protocol::xproto::{AtomEnum, ConnectionExt, Window},
+    rust_connection::RustConnection,
+};
+
+use crate::display_server::{publish, DisplayServer, Monitor};
+
+/// How often the focused window is checked on X11, which has no event for this that works with
+/// all window managers.
+const X11_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Watch for focused fullscreen windows, or None if `server` is `Off`.
+/// On X11 the window manager is asked using EWMH properties, on Wayland the foreign toplevel
+/// protocol is used, which is supported by wlroots based compositors like Sway.
+pub fn watch(server: DisplayServer) -> Result<Option<Monitor>> {
+    const NAME: &str = "fullscreen windows";
+    Ok(match server.resolve() {
+        None => None,
+        Some(DisplayServer::Wayland) => Some(Monitor::spawn(
+            NAME,
+            WaylandWatcher::connect()?,
+            WaylandWatcher::run,
+        )),
+        Some(_) => Some(Monitor::spawn(
+            NAME,
+            X11Watcher::connect()?,
+            X11Watcher::run,
+        )),
+    })
+}
+
+struct X11Watcher {
+    connection: RustConnection,
+    root: Window,
+    active_window: u32,
+    state: u32,
+    fullscreen: u32,
+}
+
+impl X11Watcher {
+    fn connect() -> Result<Self> {
+        let (connection, screen) =
+            x11rb::connect(None).context("Failed to connect to the X server")?;
+        let root = connection.setup().roots[screen].root;
+        let atom = |name: &str| -> Result<u32> {
+            Ok(connection
+                .intern_atom(false, name.as_bytes())?
+                .reply()?
+                .atom)
+        };
+        Ok(Self {
+            active_window: atom("_NET_ACTIVE_WINDOW")?,
+            state: atom("_NET_WM_STATE")?,
+            fullscreen: atom("_NET_WM_STATE_FULLSCREEN")?,
+            root,
+            connection,
+        })
+    }
+
+    fn run(self, sender: &watch::Sender<bool>) -> Result<()> {
+        while !sender.is_closed() {
+            publish(sender, self.is_fullscreen()?);
+            std::thread::sleep(X11_POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    fn is_fullscreen(&self) -> Result<bool> {
+        let active = self
+            .connection
+            .get_property(false, self.root, self.active_window, AtomEnum::WINDOW, 0, 1)?
+            .reply()?
+            .value32()
+            .and_then(|mut windows| windows.next())
+            .filter(|&window| window != 0);
+        let Some(window) = active else {
+            return Ok(false);
+        };
+
+        // The window may be closed in the meantime, which is an error but not fullscreen
+        let state = self
+            .connection
+            .get_property(false, window, self.state, AtomEnum::ATOM, 0, 64)?
+            .reply();
+        Ok(state.is_ok_and(|state| {
+            state
+                .value32()
+                .is_some_and(|mut atoms| atoms.any(|atom| atom == self.fullscreen))
+        }))
+    }
+}
+
+struct WaylandWatcher {
+    queue: wayland_client::EventQueue<ToplevelState>,
+    state: ToplevelState,
+}
+
+/// States of all toplevel windows, as last reported by the compositor.
+#[derive(Default)]
+struct ToplevelState {
+    /// Whether each window is activated and fullscreen, applied on the `done` event
+    windows: HashMap<ObjectId, bool>,
+    pending: HashMap<ObjectId, bool>,
+}
+
+impl WaylandWatcher {
+    fn connect() -> Result<Self> {
+        let connection =
+            Connection::connect_to_env().context("Failed to connect to the Wayland compositor")?;
+        let (globals, queue) = registry_queue_init::<ToplevelState>(&connection)?;
+        globals
+            .bind::<ZwlrForeignToplevelManagerV1, _, _>(&queue.handle(), 1..=3, ())
+            .map_err(|_| {
+                anyhow!("The compositor doesn't support the wlr foreign toplevel protocol")
+            })?;
+        Ok(Self {
+            queue,
+            state: ToplevelState::default(),
+        })
+    }
+
+    fn run(mut self, sender: &watch::Sender<bool>) -> Result<()> {
+        while !sender.is_closed() {
+            self.queue.blocking_dispatch(&mut self.state)?;
+            publish(
+                sender,
+                self.state.windows.values().any(|&fullscreen| fullscreen),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for ToplevelState {
+    fn event(
+        _: &mut Self,
+        _: &WlRegistry,
+        _: <WlRegistry as Proxy>::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for ToplevelState {
+    fn event(
+        _: &mut Self,
+        _: &ZwlrForeignToplevelManagerV1,
+        event: zwlr_foreign_toplevel_manager_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let zwlr_foreign_toplevel_manager_v1::Event::Finished = event {
+            warn!("The compositor stopped reporting windows");
+        }
+    }
+
+    event_created_child!(ToplevelState, ZwlrForeignToplevelManagerV1, [
+        zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (ZwlrForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for ToplevelState {
+    fn event(
+        state: &mut Self,
+        handle: &ZwlrForeignToplevelHandleV1,
+        event: zwlr_foreign_toplevel_handle_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        use zwlr_foreign_toplevel_handle_v1::{Event, State};
+
+        let id = handle.id();
+        match event {
+            Event::State { state: states } => {
+                let states: Vec<_> = states
+                    .chunks_exact(4)
+                    .map(|value| u32::from_ne_bytes([value[0], value[1], value[2], value[3]]))
+                    .map(WEnum::<State>::from)
+                    .collect();
+                let has = |wanted| states.contains(&WEnum::Value(wanted));
+                state
+                    .pending
+                    .insert(id, has(State::Activated) && has(State::Fullscreen));
+            }
+            Event::Done => {
+                if let Some(fullscreen) = state.pending.remove(&id) {
+                    state.windows.insert(id, fullscreen);
+                }
+            }
+            Event::Closed => {
+                state.pending.remove(&id);
+                state.windows.remove(&id);
+                handle.destroy();
+            }
+            _ => {}
+        }
+    }
+}
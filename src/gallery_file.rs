@@ -0,0 +1,106 @@
+//! Standalone gallery definition files, see `Request::ExportGallery` and `Request::ImportGallery`.
+
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use directories::UserDirs;
+use toml_edit::{Array, DocumentMut, InlineTable, Item, Value};
+
+use crate::{expand, Gallery};
+
+fn is_json(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "json")
+}
+
+/// Write `gallery` to the file at `path`.
+/// Folders inside the home directory are written relative to `~`, so the file can be shared
+/// between users.
+pub fn export(gallery: &Gallery, path: &Path) -> Result<()> {
+    let mut gallery = gallery.clone();
+    for source in gallery.sources.iter_mut() {
+        if let Cow::Owned(contracted) = contract_tilde(&source.path) {
+            source.path = contracted;
+        }
+    }
+
+    let text = if is_json(path) {
+        serde_json::to_string_pretty(&gallery)?
+    } else {
+        to_toml(serde_json::to_value(&gallery)?)
+    };
+
+    std::fs::write(path, text)
+        .with_context(|| format!("Failed to write gallery file '{}'", path.display()))
+}
+
+/// Format a JSON object as TOML.
+/// Unlike `toml::to_string`, this supports tables inside arrays, e.g. weighted folders.
+fn to_toml(value: serde_json::Value) -> String {
+    let mut document = DocumentMut::new();
+    if let serde_json::Value::Object(fields) = value {
+        for (key, value) in fields {
+            if let Some(value) = to_toml_value(value) {
+                document[&key] = Item::Value(value);
+            }
+        }
+    }
+    document.to_string()
+}
+
+fn to_toml_value(value: serde_json::Value) -> Option<Value> {
+    use serde_json::Value as Json;
+    Some(match value {
+        Json::Null => return None,
+        Json::Bool(b) => b.into(),
+        Json::Number(n) => match n.as_i64() {
+            Some(i) => i.into(),
+            None => n.as_f64()?.into(),
+        },
+        Json::String(s) => s.into(),
+        Json::Array(values) => values
+            .into_iter()
+            .filter_map(to_toml_value)
+            .collect::<Array>()
+            .into(),
+        Json::Object(fields) => fields
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, to_toml_value(value)?)))
+            .collect::<InlineTable>()
+            .into(),
+    })
+}
+
+/// Read a gallery from the file at `path`.
+pub fn import(path: &Path) -> Result<Gallery> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read gallery file '{}'", path.display()))?;
+
+    let mut gallery: Gallery = if is_json(path) {
+        serde_json::from_str(&text)?
+    } else {
+        toml::from_str(&text)?
+    };
+
+    for source in gallery.sources.iter_mut() {
+        if let Cow::Owned(expanded) = expand::path(&source.path)? {
+            source.path = expanded;
+        }
+    }
+
+    Ok(gallery)
+}
+
+/// Inverse of `expand::path` for `~`, replace the home directory at the start of `path` by `~`.
+pub fn contract_tilde(path: &Path) -> Cow<'_, Path> {
+    let Some(dirs) = UserDirs::new() else {
+        return Cow::Borrowed(path);
+    };
+
+    match path.strip_prefix(dirs.home_dir()) {
+        Ok(relative) => Cow::Owned(PathBuf::from("~").join(relative)),
+        Err(_) => Cow::Borrowed(path),
+    }
+}
@@ -0,0 +1,280 @@
+//! An append-only log of the shown images, see `Request::History`. Unlike `recently_selected`,
+//! which only avoids repetitions, it records when and why each image was shown.
+//! The log is kept in the state directory, or in the database with the SQLite `storage`.
+
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::{
+    message_api::{HistoryEntry, Trigger},
+    storage::Database,
+};
+
+const FILE_NAME: &str = "history.jsonl";
+/// The log before the last rotation, still used for queries.
+const ROTATED_FILE_NAME: &str = "history.1.jsonl";
+
+/// See `Configuration::history`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct HistoryConfiguration {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+
+    /// Number of entries after which the log is rotated. The previous log is kept until the next
+    /// rotation, so up to twice as many entries can be queried.
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+
+    /// Age of the oldest entry after which the log is rotated, e.g. "30d".
+    #[serde(default = "default_max_age_ms", with = "crate::duration::millis")]
+    pub max_age_ms: u64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_max_entries() -> usize {
+    10_000
+}
+
+fn default_max_age_ms() -> u64 {
+    90 * 24 * 60 * 60 * 1000
+}
+
+impl Default for HistoryConfiguration {
+    fn default() -> Self {
+        Self {
+            enabled: default_enabled(),
+            max_entries: default_max_entries(),
+            max_age_ms: default_max_age_ms(),
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The history in `directory`, or the SQLite `database`, appended to by a background task so a
+/// slow disk doesn't stall updates. Disabled by default.
+#[derive(Default)]
+pub struct History {
+    directory: PathBuf,
+    database: Option<PathBuf>,
+    sender: Option<mpsc::UnboundedSender<HistoryEntry>>,
+}
+
+impl History {
+    /// Start the background task, must be called inside the runtime.
+    pub fn new(
+        config: &HistoryConfiguration,
+        directory: PathBuf,
+        database: Option<PathBuf>,
+    ) -> Self {
+        if !config.enabled {
+            return Self::default();
+        }
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let mut log = Log {
+            directory: directory.clone(),
+            max_entries: config.max_entries,
+            max_age: config.max_age_ms / 1000,
+            entries: None,
+        };
+        let path = database.clone();
+        // Runs until the sender is dropped, the runtime waits for it to write all entries
+        tokio::task::spawn_blocking(move || {
+            let mut database = None;
+            while let Some(entry) = receiver.blocking_recv() {
+                let result = match &path {
+                    Some(path) => log.append_to_database(&mut database, path, &entry),
+                    None => log.append(&entry),
+                };
+                if let Err(err) = result {
+                    warn!("Failed to record history: {err:#}");
+                }
+            }
+        });
+        Self {
+            directory,
+            database,
+            sender: Some(sender),
+        }
+    }
+
+    /// Record that `image` of `gallery` was shown now.
+    pub fn record(&self, image: &Path, gallery: &str, trigger: Trigger) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(HistoryEntry {
+                path: image.to_owned(),
+                gallery: gallery.to_owned(),
+                shown: now(),
+                trigger,
+            });
+        }
+    }
+
+    /// The `limit` most recent entries, optionally only of `gallery`, newest first.
+    pub async fn query(&self, gallery: Option<&str>, limit: usize) -> Result<Vec<HistoryEntry>> {
+        if self.sender.is_none() {
+            return Ok(vec![]);
+        }
+        let directory = self.directory.clone();
+        let database = self.database.clone();
+        let gallery = gallery.map(str::to_owned);
+        tokio::task::spawn_blocking(move || {
+            if let Some(database) = database {
+                return Database::open(&database)?.query_history(gallery.as_deref(), limit);
+            }
+            let mut entries = read(&directory.join(ROTATED_FILE_NAME))?;
+            entries.extend(read(&directory.join(FILE_NAME))?);
+            // Imported entries are appended after newer ones
+            entries.reverse();
+            entries.sort_by_key(|entry| Reverse(entry.shown));
+            Ok(entries
+                .into_iter()
+                .filter(|entry| gallery.as_ref().is_none_or(|name| &entry.gallery == name))
+                .take(limit)
+                .collect())
+        })
+        .await?
+    }
+
+    /// Add `entries`, e.g. from another machine, skipping those which are already recorded.
+    pub async fn import(&self, entries: Vec<HistoryEntry>) -> Result<()> {
+        let Some(sender) = &self.sender else {
+            return Ok(());
+        };
+        let recorded: HashSet<_> = self
+            .query(None, usize::MAX)
+            .await?
+            .into_iter()
+            .map(|entry| (entry.path, entry.shown))
+            .collect();
+        for entry in entries {
+            if !recorded.contains(&(entry.path.clone(), entry.shown)) {
+                let _ = sender.send(entry);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// All entries of the log at `path`, oldest first. Lines which can't be parsed, e.g. a partially
+/// written last line after a crash, are skipped.
+fn read(path: &Path) -> Result<Vec<HistoryEntry>> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect())
+}
+
+struct Log {
+    directory: PathBuf,
+    max_entries: usize,
+    /// In seconds
+    max_age: u64,
+    /// Number of entries in the current log and the time of its first entry, read on the first
+    /// append.
+    entries: Option<(usize, Option<u64>)>,
+}
+
+impl Log {
+    /// Like `append`, but to the database at `path`, which is kept open in `database`.
+    fn append_to_database(
+        &self,
+        database: &mut Option<Database>,
+        path: &Path,
+        entry: &HistoryEntry,
+    ) -> Result<()> {
+        let database = match database {
+            Some(database) => database,
+            None => database.insert(Database::open(path)?),
+        };
+        database.append_history(entry, self.max_entries, self.max_age)
+    }
+
+    fn append(&mut self, entry: &HistoryEntry) -> Result<()> {
+        let path = self.directory.join(FILE_NAME);
+        let (count, oldest) = match self.entries {
+            Some(entries) => entries,
+            None => {
+                let entries = read(&path)?;
+                (entries.len(), entries.first().map(|entry| entry.shown))
+            }
+        };
+        let expired =
+            oldest.is_some_and(|oldest| entry.shown.saturating_sub(oldest) > self.max_age);
+        let (count, oldest) = if count >= self.max_entries || expired {
+            fs::rename(&path, self.directory.join(ROTATED_FILE_NAME))?;
+            (0, None)
+        } else {
+            (count, oldest)
+        };
+
+        fs::create_dir_all(&self.directory)?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        self.entries = Some((count + 1, oldest.or(Some(entry.shown))));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_log_is_rotated() {
+        let dir = TempDir::new("history");
+        let mut log = Log {
+            directory: dir.to_path_buf(),
+            max_entries: 2,
+            max_age: 60,
+            entries: None,
+        };
+        let entry = |shown| HistoryEntry {
+            path: PathBuf::from("image.png"),
+            gallery: "gallery".to_owned(),
+            shown,
+            trigger: Trigger::Timer,
+        };
+        for shown in [0, 1, 2, 100] {
+            log.append(&entry(shown)).unwrap();
+        }
+        let current = read(&dir.join(FILE_NAME)).unwrap();
+        let rotated = read(&dir.join(ROTATED_FILE_NAME)).unwrap();
+
+        // Rotated after 2 entries, then once the entry shown at 2 was too old
+        let shown = |entries: Vec<HistoryEntry>| -> Vec<u64> {
+            entries.iter().map(|entry| entry.shown).collect()
+        };
+        assert_eq!(shown(rotated), vec![2]);
+        assert_eq!(shown(current), vec![100]);
+    }
+}
@@ -0,0 +1,197 @@
+//! Detecting connected monitors, e.g. to show an image as soon as a monitor is plugged in.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tokio::sync::watch;
+use wayland_client::{
+    globals::{registry_queue_init, GlobalListContents},
+    protocol::{
+        wl_output::{self, WlOutput},
+        wl_registry::{self, WlRegistry},
+    },
+    Connection, Dispatch, EventQueue, Proxy, QueueHandle,
+};
+use x11rb::{
+    connection::Connection as _,
+    protocol::{
+        randr::{self, ConnectionExt},
+        xproto::Window,
+    },
+    rust_connection::RustConnection,
+};
+
+use crate::display_server::{publish, DisplayServer, Monitor};
+
+/// Names of the connected monitors, e.g. "DP-1".
+pub type Outputs = BTreeSet<String>;
+
+/// How often the outputs are checked on X11.
+const X11_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// wl_output version which announces the name of the output.
+const WL_OUTPUT_NAME_VERSION: u32 = 4;
+
+/// Watch the connected monitors, or None if `server` is `Off`.
+/// On X11 the RandR extension is asked, on Wayland the names of the `wl_output` globals are used.
+pub fn watch(server: DisplayServer) -> Result<Option<Monitor<Outputs>>> {
+    const NAME: &str = "connected monitors";
+    Ok(match server.resolve() {
+        None => None,
+        Some(DisplayServer::Wayland) => Some(Monitor::spawn(
+            NAME,
+            WaylandWatcher::connect()?,
+            WaylandWatcher::run,
+        )),
+        Some(_) => Some(Monitor::spawn(
+            NAME,
+            X11Watcher::connect()?,
+            X11Watcher::run,
+        )),
+    })
+}
+
+struct X11Watcher {
+    connection: RustConnection,
+    root: Window,
+}
+
+impl X11Watcher {
+    fn connect() -> Result<Self> {
+        let (connection, screen) =
+            x11rb::connect(None).context("Failed to connect to the X server")?;
+        let root = connection.setup().roots[screen].root;
+        connection
+            .randr_query_version(1, 3)?
+            .reply()
+            .context("The X server doesn't support the RandR extension")?;
+        Ok(Self { connection, root })
+    }
+
+    fn run(self, sender: &watch::Sender<Outputs>) -> Result<()> {
+        while !sender.is_closed() {
+            publish(sender, self.connected()?);
+            std::thread::sleep(X11_POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    fn connected(&self) -> Result<Outputs> {
+        let resources = self
+            .connection
+            .randr_get_screen_resources_current(self.root)?
+            .reply()?;
+        let mut outputs = Outputs::new();
+        for output in resources.outputs {
+            let info = self
+                .connection
+                .randr_get_output_info(output, resources.config_timestamp)?
+                .reply()?;
+            if info.connection == randr::Connection::CONNECTED {
+                outputs.insert(String::from_utf8_lossy(&info.name).into_owned());
+            }
+        }
+        Ok(outputs)
+    }
+}
+
+struct WaylandWatcher {
+    queue: EventQueue<OutputState>,
+    state: OutputState,
+}
+
+/// Outputs by the name of their global, with their name once announced by the compositor.
+#[derive(Default)]
+struct OutputState {
+    outputs: HashMap<u32, (WlOutput, Option<String>)>,
+}
+
+impl OutputState {
+    fn bind(&mut self, registry: &WlRegistry, global: u32, version: u32, qh: &QueueHandle<Self>) {
+        let version = version.min(WL_OUTPUT_NAME_VERSION);
+        let output = registry.bind::<WlOutput, _, _>(global, version, qh, global);
+        self.outputs.insert(global, (output, None));
+    }
+
+    fn connected(&self) -> Outputs {
+        self.outputs
+            .values()
+            .filter_map(|(_, name)| name.clone())
+            .collect()
+    }
+}
+
+impl WaylandWatcher {
+    fn connect() -> Result<Self> {
+        let connection =
+            Connection::connect_to_env().context("Failed to connect to the Wayland compositor")?;
+        let (globals, queue) = registry_queue_init::<OutputState>(&connection)?;
+        let mut state = OutputState::default();
+        for global in globals.contents().clone_list() {
+            if global.interface == WlOutput::interface().name {
+                state.bind(
+                    globals.registry(),
+                    global.name,
+                    global.version,
+                    &queue.handle(),
+                );
+            }
+        }
+        Ok(Self { queue, state })
+    }
+
+    fn run(mut self, sender: &watch::Sender<Outputs>) -> Result<()> {
+        while !sender.is_closed() {
+            self.queue.blocking_dispatch(&mut self.state)?;
+            publish(sender, self.state.connected());
+        }
+        Ok(())
+    }
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for OutputState {
+    fn event(
+        state: &mut Self,
+        registry: &WlRegistry,
+        event: wl_registry::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        match event {
+            wl_registry::Event::Global {
+                name,
+                interface,
+                version,
+            } if interface == WlOutput::interface().name => state.bind(registry, name, version, qh),
+            wl_registry::Event::GlobalRemove { name } => {
+                if let Some((output, _)) = state.outputs.remove(&name) {
+                    if output.version() >= 3 {
+                        output.release();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Dispatch<WlOutput, u32> for OutputState {
+    fn event(
+        state: &mut Self,
+        _: &WlOutput,
+        event: wl_output::Event,
+        global: &u32,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        if let wl_output::Event::Name { name } = event {
+            if let Some((_, output_name)) = state.outputs.get_mut(global) {
+                *output_name = Some(name);
+            }
+        }
+    }
+}
@@ -0,0 +1,194 @@
+//! Detecting an idle session, e.g. to pause the rotation while nobody is watching.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use tokio::sync::watch;
+use wayland_client::{
+    globals::{registry_queue_init, GlobalListContents},
+    protocol::{wl_registry::WlRegistry, wl_seat::WlSeat},
+    Connection, Dispatch, EventQueue, Proxy, QueueHandle,
+};
+use wayland_protocols::ext::idle_notify::v1::client::{
+    ext_idle_notification_v1::{self, ExtIdleNotificationV1},
+    ext_idle_notifier_v1::ExtIdleNotifierV1,
+};
+use x11rb::{
+    connection::Connection as _,
+    protocol::{screensaver::ConnectionExt, xproto::Window},
+    rust_connection::RustConnection,
+};
+
+use crate::display_server::{publish, DisplayServer, Monitor};
+
+/// Longest time between two checks of the user activity on X11.
+const X11_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// See `Configuration::idle`.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct IdleConfiguration {
+    /// Time without user input after which the session is idle.
+    pub timeout_ms: u64,
+
+    /// How user input is detected. On X11 the screen saver extension is used, on Wayland the idle
+    /// notify protocol.
+    #[serde(default = "default_detection")]
+    pub detection: DisplayServer,
+}
+
+fn default_detection() -> DisplayServer {
+    DisplayServer::Auto
+}
+
+/// Watch whether the session is idle, or None if detection is `Off`.
+pub fn watch(config: IdleConfiguration) -> Result<Option<Monitor>> {
+    const NAME: &str = "idle sessions";
+    let timeout = Duration::from_millis(config.timeout_ms);
+    Ok(match config.detection.resolve() {
+        None => None,
+        Some(DisplayServer::Wayland) => Some(Monitor::spawn(
+            NAME,
+            WaylandWatcher::connect(timeout)?,
+            WaylandWatcher::run,
+        )),
+        Some(_) => Some(Monitor::spawn(
+            NAME,
+            X11Watcher::connect(timeout)?,
+            X11Watcher::run,
+        )),
+    })
+}
+
+struct X11Watcher {
+    connection: RustConnection,
+    root: Window,
+    timeout: Duration,
+}
+
+impl X11Watcher {
+    fn connect(timeout: Duration) -> Result<Self> {
+        let (connection, screen) =
+            x11rb::connect(None).context("Failed to connect to the X server")?;
+        let root = connection.setup().roots[screen].root;
+        Ok(Self {
+            connection,
+            root,
+            timeout,
+        })
+    }
+
+    fn run(self, sender: &watch::Sender<bool>) -> Result<()> {
+        while !sender.is_closed() {
+            let info = self
+                .connection
+                .screensaver_query_info(self.root)?
+                .reply()
+                .context("The X server doesn't support the screen saver extension")?;
+            let since_input = Duration::from_millis(info.ms_since_user_input.into());
+            let idle = since_input >= self.timeout;
+            publish(sender, idle);
+
+            // Check again around the time the session would become idle
+            let next_check = if idle {
+                X11_POLL_INTERVAL
+            } else {
+                (self.timeout - since_input).min(X11_POLL_INTERVAL)
+            };
+            std::thread::sleep(next_check);
+        }
+        Ok(())
+    }
+}
+
+struct WaylandWatcher {
+    queue: EventQueue<IdleState>,
+    state: IdleState,
+}
+
+#[derive(Default)]
+struct IdleState {
+    idle: bool,
+}
+
+impl WaylandWatcher {
+    fn connect(timeout: Duration) -> Result<Self> {
+        let connection =
+            Connection::connect_to_env().context("Failed to connect to the Wayland compositor")?;
+        let (globals, queue) = registry_queue_init::<IdleState>(&connection)?;
+        let handle = queue.handle();
+        let seat: WlSeat = globals
+            .bind(&handle, 1..=1, ())
+            .map_err(|_| anyhow!("The compositor has no seat"))?;
+        let notifier: ExtIdleNotifierV1 = globals
+            .bind(&handle, 1..=1, ())
+            .map_err(|_| anyhow!("The compositor doesn't support the idle notify protocol"))?;
+        let timeout = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX);
+        notifier.get_idle_notification(timeout, &seat, &handle, ());
+        Ok(Self {
+            queue,
+            state: IdleState::default(),
+        })
+    }
+
+    fn run(mut self, sender: &watch::Sender<bool>) -> Result<()> {
+        while !sender.is_closed() {
+            self.queue.blocking_dispatch(&mut self.state)?;
+            publish(sender, self.state.idle);
+        }
+        Ok(())
+    }
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for IdleState {
+    fn event(
+        _: &mut Self,
+        _: &WlRegistry,
+        _: <WlRegistry as Proxy>::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlSeat, ()> for IdleState {
+    fn event(
+        _: &mut Self,
+        _: &WlSeat,
+        _: <WlSeat as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtIdleNotifierV1, ()> for IdleState {
+    fn event(
+        _: &mut Self,
+        _: &ExtIdleNotifierV1,
+        _: <ExtIdleNotifierV1 as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<ExtIdleNotificationV1, ()> for IdleState {
+    fn event(
+        state: &mut Self,
+        _: &ExtIdleNotificationV1,
+        event: ext_idle_notification_v1::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        match event {
+            ext_idle_notification_v1::Event::Idled => state.idle = true,
+            ext_idle_notification_v1::Event::Resumed => state.idle = false,
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,504 @@
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs::{File, Metadata},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use rusqlite::{params, Connection, OptionalExtension, ToSql};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::image_metadata::{self, ImageMetadata};
+
+/// How files showing the same image are detected.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateDetection {
+    /// Every file is its own image.
+    #[default]
+    Off,
+    /// Files with identical content are the same image.
+    Content,
+    /// Files that look the same are the same image, even if they were e.g. re-encoded or scaled.
+    /// This needs to decode every image once, which is much slower than `Content`.
+    Perceptual,
+}
+
+/// Schema of the index database, see `PRAGMA user_version`.
+/// Databases with a different version are recreated, the index only caches data.
+const SCHEMA_VERSION: i64 = 3;
+
+const SCHEMA: &str = "
+    CREATE TABLE files (
+        path BLOB PRIMARY KEY,
+        len INTEGER NOT NULL,
+        modified INTEGER,
+        content_hash TEXT,
+        perceptual_hash INTEGER,
+        width INTEGER,
+        height INTEGER,
+        metadata TEXT
+    );
+    CREATE TABLE folders (
+        path BLOB PRIMARY KEY,
+        modified INTEGER NOT NULL
+    );
+    CREATE TABLE folder_entries (
+        folder BLOB NOT NULL,
+        name BLOB NOT NULL,
+        kind INTEGER NOT NULL,
+        is_symlink INTEGER NOT NULL,
+        PRIMARY KEY (folder, name)
+    );
+";
+
+/// Folders modified more recently than this are not cached, as file systems with a coarse
+/// modification time could hide changes made right after the folder was listed.
+const FOLDER_SETTLE_TIME: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Folder,
+    Other,
+}
+
+/// A single entry of a folder, as returned by `read_folder`.
+#[derive(Debug, Clone)]
+pub struct FolderEntry {
+    pub name: OsString,
+    /// Kind of the entry, after following symlinks
+    pub kind: EntryKind,
+    pub is_symlink: bool,
+}
+
+/// List the entries of each of `folders` in parallel, see `read_folder`.
+pub fn read_folders(folders: &[PathBuf]) -> Vec<Option<Vec<FolderEntry>>> {
+    folders
+        .par_iter()
+        .map(|folder| read_folder(folder))
+        .collect()
+}
+
+/// List the entries of `folder`, or None if it can't be read.
+pub fn read_folder(folder: &Path) -> Option<Vec<FolderEntry>> {
+    let entries = std::fs::read_dir(folder).ok()?;
+    Some(
+        entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| {
+                let path = entry.path();
+                let kind = if path.is_file() {
+                    EntryKind::File
+                } else if path.is_dir() {
+                    EntryKind::Folder
+                } else {
+                    EntryKind::Other
+                };
+                FolderEntry {
+                    name: entry.file_name(),
+                    kind,
+                    is_symlink: entry.file_type().is_ok_and(|t| t.is_symlink()),
+                }
+            })
+            .collect(),
+    )
+}
+
+#[derive(Default)]
+struct IndexEntry {
+    content_hash: Option<String>,
+    perceptual_hash: Option<u64>,
+    dimensions: Option<(u32, u32)>,
+    /// Content of the metadata file, if this is a valid one, see `ImageIndex::metadata`
+    metadata: Option<String>,
+}
+
+/// Cache of folder listings and file fingerprints, used to detect duplicate images across folders.
+/// Fingerprints are recomputed if the size or modification time of a file changes, listings if
+/// the modification time of the folder changes.
+/// The index is kept in an SQLite database, which may be stored on disk to survive restarts.
+pub struct ImageIndex {
+    db: Connection,
+}
+
+impl Default for ImageIndex {
+    /// An index that is only kept in memory.
+    fn default() -> Self {
+        let db = Connection::open_in_memory().expect("Failed to create in-memory database");
+        Self::with_connection(db).expect("Failed to initialize in-memory database")
+    }
+}
+
+fn path_key(path: &Path) -> &[u8] {
+    path.as_os_str().as_bytes()
+}
+
+/// Modification time in nanoseconds since the UNIX epoch, in the range of an SQLite integer.
+fn timestamp(time: SystemTime) -> Option<i64> {
+    let nanos = time.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+    i64::try_from(nanos).ok()
+}
+
+fn modified(metadata: &Metadata) -> Option<i64> {
+    metadata.modified().ok().and_then(timestamp)
+}
+
+impl ImageIndex {
+    /// Open the index stored in the database at `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = Connection::open(path)
+            .with_context(|| format!("Failed to open image index '{}'", path.display()))?;
+        Self::with_connection(db)
+            .with_context(|| format!("Failed to initialize image index '{}'", path.display()))
+    }
+
+    fn with_connection(db: Connection) -> rusqlite::Result<Self> {
+        db.pragma_update(None, "journal_mode", "WAL")?;
+        db.pragma_update(None, "synchronous", "NORMAL")?;
+
+        let version: i64 = db.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version != SCHEMA_VERSION {
+            db.execute_batch(
+                "DROP TABLE IF EXISTS files;
+                 DROP TABLE IF EXISTS folders;
+                 DROP TABLE IF EXISTS folder_entries;",
+            )?;
+            db.execute_batch(SCHEMA)?;
+            db.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+        Ok(Self { db })
+    }
+
+    /// Reduce `files` to a single file per logical image, according to `mode`.
+    /// Of each set of duplicates, the smallest path is kept, so the result is stable across calls.
+    /// Files which can't be read are kept, as it's impossible to tell whether they are duplicates.
+    pub fn deduplicate(
+        &mut self,
+        mut files: Vec<PathBuf>,
+        mode: DuplicateDetection,
+    ) -> Vec<PathBuf> {
+        if mode == DuplicateDetection::Off {
+            return files;
+        }
+
+        files.sort();
+        files.dedup();
+
+        let mut seen_content = HashSet::new();
+        let mut seen_perceptual = HashSet::new();
+
+        self.batch(|index| {
+            files.retain(|path| match mode {
+                DuplicateDetection::Off => true,
+                DuplicateDetection::Content => match index.content_hash(path) {
+                    Some(hash) => seen_content.insert(hash),
+                    None => true,
+                },
+                DuplicateDetection::Perceptual => match index.perceptual_hash(path) {
+                    Some(hash) => seen_perceptual.insert(hash),
+                    None => true,
+                },
+            })
+        });
+
+        files
+    }
+
+    /// Hash of the content of the file at `path`, or None if it can't be read.
+    pub fn content_hash(&self, path: &Path) -> Option<String> {
+        if let Some(hash) = self.entry(path)?.content_hash {
+            return Some(hash);
+        }
+
+        let mut hasher = blake3::Hasher::new();
+        std::io::copy(&mut File::open(path).ok()?, &mut hasher).ok()?;
+        let hash = hasher.finalize().to_hex().to_string();
+
+        self.store(path, "content_hash", &hash);
+        Some(hash)
+    }
+
+    /// Difference hash of the image, comparing the brightness of neighbouring pixels in a
+    /// downscaled version of the image.
+    fn perceptual_hash(&self, path: &Path) -> Option<u64> {
+        if let Some(hash) = self.entry(path)?.perceptual_hash {
+            return Some(hash);
+        }
+
+        let small = image::open(path)
+            .ok()?
+            .resize_exact(9, 8, image::imageops::FilterType::Triangle)
+            .to_luma8();
+
+        let mut hash = 0;
+        for y in 0..8 {
+            for x in 0..8 {
+                let brighter = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+                hash = (hash << 1) | u64::from(brighter);
+            }
+        }
+
+        // SQLite integers are signed, store the bit pattern
+        self.store(path, "perceptual_hash", hash as i64);
+        Some(hash)
+    }
+
+    /// Width and height of the image at `path`, or None if it's not a readable image.
+    pub fn dimensions(&self, path: &Path) -> Option<(u32, u32)> {
+        if let Some(dimensions) = self.entry(path)?.dimensions {
+            return Some(dimensions);
+        }
+
+        let (width, height) = image::image_dimensions(path).ok()?;
+        self.store(path, "width", width);
+        self.store(path, "height", height);
+        Some((width, height))
+    }
+
+    /// Settings of `image` from its metadata file, or None if it has none.
+    /// Invalid metadata files are reported once and then ignored until they change.
+    pub fn metadata(&self, image: &Path) -> Option<ImageMetadata> {
+        let path = image_metadata::path_for(image);
+        if let Some(text) = self.entry(&path)?.metadata {
+            return ImageMetadata::parse(&text).ok();
+        }
+
+        let mut text = std::fs::read_to_string(&path).ok()?;
+        let metadata = ImageMetadata::parse(&text);
+        if let Err(err) = &metadata {
+            warn!(
+                "Ignoring invalid image metadata '{}': {err:#}",
+                path.display()
+            );
+            text.clear();
+        }
+        self.store(&path, "metadata", &text);
+        metadata.ok()
+    }
+
+    /// Entries of each of `folders`, like `read_folders`, but cached while a folder is unmodified.
+    pub fn list_folders(&mut self, folders: &[PathBuf]) -> Vec<Option<Vec<FolderEntry>>> {
+        let cacheable: Vec<Option<i64>> = folders
+            .par_iter()
+            .map(|folder| {
+                std::fs::metadata(folder)
+                    .ok()?
+                    .modified()
+                    .ok()
+                    .filter(|time| time.elapsed().is_ok_and(|age| age > FOLDER_SETTLE_TIME))
+                    .and_then(timestamp)
+            })
+            .collect();
+
+        let cached: Vec<_> = folders
+            .iter()
+            .zip(&cacheable)
+            .map(|(folder, modified)| self.cached_folder(folder, (*modified)?))
+            .collect();
+
+        let listings: Vec<_> = folders
+            .par_iter()
+            .zip(cached)
+            .map(|(folder, cached)| cached.or_else(|| read_folder(folder)))
+            .collect();
+
+        let result = self.cache_folders(folders.iter().zip(&cacheable).zip(&listings).filter_map(
+            |((folder, modified), entries)| {
+                Some((folder.as_path(), (*modified)?, entries.as_deref()?))
+            },
+        ));
+        if let Err(err) = result {
+            warn!("Failed to update image index: {err}");
+        }
+
+        listings
+    }
+
+    fn cached_folder(&self, folder: &Path, modified: i64) -> Option<Vec<FolderEntry>> {
+        let key = path_key(folder);
+        let cached: i64 = self
+            .db
+            .query_row(
+                "SELECT modified FROM folders WHERE path = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .ok()?;
+        if cached != modified {
+            return None;
+        }
+
+        let mut query = self
+            .db
+            .prepare_cached("SELECT name, kind, is_symlink FROM folder_entries WHERE folder = ?1")
+            .ok()?;
+        let rows = query
+            .query_map(params![key], |row| {
+                let name: Vec<u8> = row.get(0)?;
+                let kind = match row.get(1)? {
+                    0 => EntryKind::File,
+                    1 => EntryKind::Folder,
+                    _ => EntryKind::Other,
+                };
+                Ok(FolderEntry {
+                    name: OsString::from_vec(name),
+                    kind,
+                    is_symlink: row.get(2)?,
+                })
+            })
+            .ok()?;
+        rows.collect::<rusqlite::Result<_>>().ok()
+    }
+
+    /// Store the listings of folders with the given modification time.
+    fn cache_folders<'a>(
+        &mut self,
+        listings: impl Iterator<Item = (&'a Path, i64, &'a [FolderEntry])>,
+    ) -> rusqlite::Result<()> {
+        let transaction = self.db.transaction()?;
+        {
+            let mut remove =
+                transaction.prepare_cached("DELETE FROM folder_entries WHERE folder = ?1")?;
+            let mut update = transaction.prepare_cached(
+                "INSERT OR REPLACE INTO folders (path, modified) VALUES (?1, ?2)",
+            )?;
+            let mut insert = transaction.prepare_cached(
+                "INSERT INTO folder_entries (folder, name, kind, is_symlink) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+
+            for (folder, modified, entries) in listings {
+                let key = path_key(folder);
+                remove.execute(params![key])?;
+                update.execute(params![key, modified])?;
+                for entry in entries {
+                    let kind = match entry.kind {
+                        EntryKind::File => 0,
+                        EntryKind::Folder => 1,
+                        EntryKind::Other => 2,
+                    };
+                    insert.execute(params![key, entry.name.as_bytes(), kind, entry.is_symlink])?;
+                }
+            }
+        }
+        transaction.commit()
+    }
+
+    /// Run `task` in a single transaction, which is much faster than committing every change.
+    pub fn batch<R>(&mut self, task: impl FnOnce(&Self) -> R) -> R {
+        let transaction = self.db.unchecked_transaction().ok();
+        let result = task(self);
+        if let Some(Err(err)) = transaction.map(|t| t.commit()) {
+            warn!("Failed to update image index: {err}");
+        }
+        result
+    }
+
+    /// Get the cached fingerprints for `path`, or None if the file can't be accessed.
+    /// Outdated fingerprints are discarded.
+    fn entry(&self, path: &Path) -> Option<IndexEntry> {
+        let metadata = std::fs::metadata(path).ok()?;
+        let len = i64::try_from(metadata.len()).ok()?;
+        let modified = modified(&metadata);
+        let key = path_key(path);
+
+        let cached = self
+            .db
+            .query_row(
+                "SELECT len, modified, content_hash, perceptual_hash, width, height, metadata
+                 FROM files WHERE path = ?1",
+                params![key],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, Option<i64>>(1)?,
+                        IndexEntry {
+                            content_hash: row.get(2)?,
+                            perceptual_hash: row.get::<_, Option<i64>>(3)?.map(|h| h as u64),
+                            dimensions: row
+                                .get::<_, Option<u32>>(4)?
+                                .zip(row.get::<_, Option<u32>>(5)?),
+                            metadata: row.get(6)?,
+                        },
+                    ))
+                },
+            )
+            .optional()
+            .ok()?;
+
+        match cached {
+            Some((cached_len, cached_modified, entry))
+                if cached_len == len && cached_modified == modified =>
+            {
+                Some(entry)
+            }
+            _ => {
+                let result = self.db.execute(
+                    "INSERT OR REPLACE INTO files (path, len, modified) VALUES (?1, ?2, ?3)",
+                    params![key, len, modified],
+                );
+                if let Err(err) = result {
+                    warn!("Failed to update image index: {err}");
+                }
+                Some(IndexEntry::default())
+            }
+        }
+    }
+
+    /// Store a fingerprint of `path` in `column`, the entry must already exist.
+    fn store(&self, path: &Path, column: &str, value: impl ToSql) {
+        let result = self.db.execute(
+            &format!("UPDATE files SET {column} = ?2 WHERE path = ?1"),
+            params![path_key(path), value],
+        );
+        if let Err(err) = result {
+            warn!("Failed to update image index: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_content_duplicates_are_removed() {
+        let dir = TempDir::new("index");
+
+        let files: Vec<_> = [("b", "same"), ("a", "same"), ("c", "different")]
+            .iter()
+            .map(|(name, content)| {
+                let path = dir.join(name);
+                std::fs::write(&path, content).unwrap();
+                path
+            })
+            .collect();
+
+        let mut index = ImageIndex::default();
+        let unique = index.deduplicate(files.clone(), DuplicateDetection::Content);
+        let off = index.deduplicate(files.clone(), DuplicateDetection::Off);
+
+        assert_eq!(unique, vec![dir.join("a"), dir.join("c")]);
+        assert_eq!(off, files);
+    }
+
+    #[test]
+    fn test_index_is_persisted() {
+        let dir = TempDir::new("index-db");
+        let image = dir.join("image");
+        std::fs::write(&image, "content").unwrap();
+        let database = dir.join("index.sqlite");
+
+        let hash = ImageIndex::open(&database).unwrap().content_hash(&image);
+        let cached = ImageIndex::open(&database).unwrap().entry(&image).unwrap();
+
+        assert!(hash.is_some());
+        assert_eq!(cached.content_hash, hash);
+    }
+}
@@ -0,0 +1,101 @@
+//! Settings for single images, read from a file next to the image, e.g.
+//! `image.jpg.gallerica.toml`.
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, TimeZone};
+use serde::Deserialize;
+
+use crate::schedule::{self, Location, TimeOfDay};
+
+/// Appended to the path of an image to get the path of its metadata file.
+pub const EXTENSION: &str = ".gallerica.toml";
+
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ImageMetadata {
+    /// Factor for the weight of the image in the "random" and "rated" selection modes.
+    #[serde(default)]
+    pub weight: Option<f64>,
+
+    /// Don't show the image again within this time, replacing the `repeat_window_ms` of the
+    /// configuration and gallery for this image.
+    #[serde(default)]
+    pub cooldown_ms: Option<u64>,
+
+    /// Only show the image within this time range of each day, like the time range of a schedule
+    /// rule. Both or neither need to be given.
+    #[serde(default)]
+    pub from: Option<TimeOfDay>,
+    #[serde(default)]
+    pub to: Option<TimeOfDay>,
+}
+
+impl ImageMetadata {
+    pub fn parse(text: &str) -> Result<Self> {
+        let metadata: Self = toml::from_str(text)?;
+        if metadata.from.is_some() != metadata.to.is_some() {
+            bail!("Needs both `from` and `to`");
+        }
+        if metadata.weight.is_some_and(|weight| weight < 0.0) {
+            bail!("`weight` must not be negative");
+        }
+        Ok(metadata)
+    }
+
+    /// Whether the image may be shown at `now`.
+    pub fn is_allowed_at<Tz: TimeZone>(
+        &self,
+        now: &DateTime<Tz>,
+        location: Option<Location>,
+    ) -> bool {
+        match (self.from, self.to) {
+            (Some(from), Some(to)) => schedule::in_time_range(from, to, now, location),
+            _ => true,
+        }
+    }
+}
+
+/// Path of the metadata file of `image`.
+pub fn path_for(image: &Path) -> PathBuf {
+    let mut path = OsString::from(image);
+    path.push(EXTENSION);
+    path.into()
+}
+
+/// The image described by the metadata file at `path`, or None if it's not a metadata file.
+pub fn image_of(path: &Path) -> Option<PathBuf> {
+    let name = path.file_name()?.to_str()?;
+    let image = name
+        .strip_suffix(EXTENSION)
+        .filter(|image| !image.is_empty())?;
+    Some(path.with_file_name(image))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_metadata_is_validated() {
+        let metadata =
+            ImageMetadata::parse("weight = 2.5\nfrom = \"08:00\"\nto = \"12:00\"").unwrap();
+        assert_eq!(metadata.weight, Some(2.5));
+
+        assert!(ImageMetadata::parse("from = \"08:00\"").is_err());
+        assert!(ImageMetadata::parse("weight = -1.0").is_err());
+        assert!(ImageMetadata::parse("wieght = 2.0").is_err());
+    }
+
+    #[test]
+    fn test_metadata_file_names() {
+        let image = Path::new("/images/cat.jpg");
+        assert_eq!(path_for(image), Path::new("/images/cat.jpg.gallerica.toml"));
+        assert_eq!(image_of(&path_for(image)).as_deref(), Some(image));
+        assert_eq!(image_of(image), None);
+    }
+}
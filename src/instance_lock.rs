@@ -0,0 +1,134 @@
+//! Guard against two daemons with the same config file, which would fight over the socket and
+//! the state file. The lock is an `flock` on a file in the state directory, which the kernel
+//! releases when the daemon exits, so a crash never leaves a stale lock behind.
+
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use tracing::info;
+
+use crate::state_dir;
+
+/// How long `--replace` waits for the other daemon to shut down.
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Held while the daemon runs, the lock is released when this is dropped.
+pub struct InstanceLock {
+    _file: File,
+}
+
+/// Identifies the daemon using the config file at `config_path` in the names of its files in the
+/// state directory, like the lock and the crash marker.
+pub fn config_key(config_path: &Path) -> String {
+    let config_path = config_path
+        .canonicalize()
+        .unwrap_or_else(|_| config_path.to_owned());
+    let hash = blake3::hash(config_path.as_os_str().as_bytes()).to_hex();
+    hash[..16].to_owned()
+}
+
+/// Lock file of the daemon using the config file at `config_path`.
+fn path(config_path: &Path) -> PathBuf {
+    state_dir().join(format!("gallerica-{}.lock", config_key(config_path)))
+}
+
+impl InstanceLock {
+    /// Take the lock for `config_path`. If another daemon holds it, fail, or with `replace` ask
+    /// it to shut down and wait until it did.
+    pub async fn acquire(config_path: &Path, replace: bool) -> Result<Self> {
+        Self::acquire_file(&path(config_path), config_path, replace).await
+    }
+
+    async fn acquire_file(path: &Path, config_path: &Path, replace: bool) -> Result<Self> {
+        let make_ctx = || format!("Failed to open lock file '{}'", path.display());
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).with_context(make_ctx)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(make_ctx)?;
+
+        if !try_lock(&file)? {
+            let owner = owner(&mut file);
+            let Some(pid) = owner.filter(|_| replace) else {
+                let owner = owner.map_or_else(String::new, |pid| format!(" (pid {pid})"));
+                bail!(
+                    "Gallerica is already running with '{}'{owner}, stop it or start with --replace",
+                    config_path.display()
+                );
+            };
+            info!("Asking the running daemon (pid {pid}) to shut down");
+            // SAFETY: kill has no memory safety requirements
+            if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+                bail!(
+                    "Failed to stop the running daemon (pid {pid}): {}",
+                    std::io::Error::last_os_error()
+                );
+            }
+            let deadline = tokio::time::Instant::now() + REPLACE_TIMEOUT;
+            while !try_lock(&file)? {
+                if tokio::time::Instant::now() >= deadline {
+                    bail!("The running daemon (pid {pid}) did not shut down");
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(Self { _file: file })
+    }
+}
+
+fn try_lock(file: &File) -> Result<bool> {
+    match file.try_lock() {
+        Ok(()) => Ok(true),
+        Err(TryLockError::WouldBlock) => Ok(false),
+        Err(TryLockError::Error(err)) => Err(err).context("Failed to lock the lock file"),
+    }
+}
+
+/// Pid of the daemon holding the lock, None if it didn't write it yet.
+fn owner(file: &mut File) -> Option<libc::pid_t> {
+    let mut content = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[tokio::test]
+    async fn test_second_instance_is_rejected() {
+        let dir = TempDir::new("lock");
+        let path = dir.join("gallerica.lock");
+        let config = Path::new("config.toml");
+
+        let lock = InstanceLock::acquire_file(&path, config, false)
+            .await
+            .unwrap();
+        let second = InstanceLock::acquire_file(&path, config, false).await;
+        drop(lock);
+        let third = InstanceLock::acquire_file(&path, config, false).await;
+        let owner = owner(&mut File::open(&path).unwrap());
+        drop(third);
+
+        let message = format!("{:#}", second.err().unwrap());
+        assert!(message.contains("already running"), "{message}");
+        assert_eq!(owner, Some(std::process::id() as libc::pid_t));
+    }
+}
@@ -1,4 +1,9 @@
+pub mod completions;
+pub mod duration;
 pub mod message_api;
+pub mod transport;
+
+use std::path::PathBuf;
 
 use directories::ProjectDirs;
 pub use message_api::{Request, Response};
@@ -6,3 +11,12 @@ pub use message_api::{Request, Response};
 pub fn project_dirs() -> ProjectDirs {
     ProjectDirs::from("at", "texel", "gallerica").expect("Failed to grab base directory paths!")
 }
+
+/// Directory where persistent state is stored.
+/// Falls back to the cache directory on systems without a dedicated state directory.
+pub fn state_dir() -> PathBuf {
+    let dirs = project_dirs();
+    dirs.state_dir()
+        .unwrap_or_else(|| dirs.cache_dir())
+        .to_path_buf()
+}
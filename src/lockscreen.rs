@@ -0,0 +1,84 @@
+//! The lock screen image, which follows the wallpaper, or changes on its own timer like a
+//! rotation. Usually blurred, e.g. for the background of swaylock.
+
+use anyhow::{Context, Result};
+use croner::Cron;
+use serde::Deserialize;
+
+use crate::{
+    processing::Processing,
+    rotation::RotationConfiguration,
+    template::{CommandLine, CommandLines},
+};
+
+/// Name of the rotation used for a lock screen with its own timer.
+pub const ROTATION_NAME: &str = "lockscreen";
+
+/// See `Configuration::lockscreen`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LockscreenConfiguration {
+    /// Commands which set the lock screen image, like the global `command_line`, e.g.
+    /// `ln -sf {image} ~/.cache/lockscreen.jpg`.
+    pub command_line: CommandLines,
+
+    #[serde(default)]
+    pub parallel_commands: bool,
+
+    /// How images are prepared for the lock screen, e.g. `{ blur = 10, dim = 0.3 }`.
+    /// Unlike outputs, the global `processing` isn't used.
+    #[serde(default)]
+    pub processing: Option<Processing>,
+
+    /// Change the lock screen image on its own instead of with each wallpaper. Also see
+    /// `update_schedule`.
+    #[serde(default, with = "crate::duration::optional_millis")]
+    pub update_interval_ms: Option<u64>,
+
+    #[serde(default)]
+    pub update_schedule: Option<Cron>,
+
+    /// Gallery of a lock screen with its own timer. Defaults to the `default_gallery`.
+    #[serde(default)]
+    pub gallery: Option<String>,
+}
+
+impl LockscreenConfiguration {
+    /// The lock screen as a rotation with its own timer, or None if it follows the wallpaper.
+    pub fn rotation(&self, default_gallery: &str) -> Option<RotationConfiguration> {
+        if self.update_interval_ms.is_none() && self.update_schedule.is_none() {
+            return None;
+        }
+        Some(RotationConfiguration {
+            name: ROTATION_NAME.to_owned(),
+            command_line: self.command_line.clone(),
+            parallel_commands: self.parallel_commands,
+            gallery: self
+                .gallery
+                .clone()
+                .unwrap_or_else(|| default_gallery.to_owned()),
+            update_interval_ms: self.update_interval_ms,
+            update_schedule: self.update_schedule.clone(),
+            processing: self.processing.clone(),
+        })
+    }
+}
+
+/// A lock screen which shows the same image as the wallpaper.
+pub struct Lockscreen {
+    pub commands: Vec<CommandLine>,
+    pub parallel_commands: bool,
+    pub processing: Option<Processing>,
+}
+
+impl Lockscreen {
+    pub fn new(config: &LockscreenConfiguration) -> Result<Self> {
+        Ok(Self {
+            commands: config
+                .command_line
+                .parse()
+                .context("Invalid command of the lock screen")?,
+            parallel_commands: config.parallel_commands,
+            processing: config.processing.clone(),
+        })
+    }
+}
@@ -0,0 +1,482 @@
+//! Messages of the daemon, logged with `tracing` to stderr, a rotated log file and the systemd
+//! journal. Their level, format and outputs are set by `Configuration::log` and the `RUST_LOG`
+//! environment variable, and change when the configuration is reloaded.
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, IsTerminal},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Mutex, OnceLock},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    warn, Event, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    filter::{filter_fn, EnvFilter},
+    fmt::{time::ChronoLocal, MakeWriter},
+    layer::{self, SubscriberExt},
+    reload, Layer, Registry,
+};
+
+use crate::{events, expand, message_api, state_dir};
+
+/// Prefix of the targets of this crate, which may be left out in filters.
+const CRATE: &str = "gallerica";
+
+/// Format of the timestamps in the log file.
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+/// Identifies the messages of gallerica in the journal, e.g. for `journalctl -t gallerica`.
+const JOURNAL_IDENTIFIER: &str = "gallerica";
+
+/// Which messages are logged, like `warn,gallerica=info,mqtt_listener=debug`: directives of an
+/// `EnvFilter` with a default level, and levels of single modules, whose path may leave out the
+/// `gallerica::` prefix.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Filter {
+    /// The directives, with the ones of relative paths repeated with the prefix
+    directives: String,
+}
+
+impl Default for Filter {
+    /// Warnings of all crates, and informational messages of gallerica itself.
+    fn default() -> Self {
+        Self {
+            directives: format!("warn,{CRATE}=info"),
+        }
+    }
+}
+
+impl Filter {
+    fn env_filter(&self) -> EnvFilter {
+        // The directives were checked when parsing
+        EnvFilter::new(&self.directives)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    /// Parse directives like `RUST_LOG`, only errors are logged for targets without a level.
+    fn from_str(text: &str) -> Result<Self, String> {
+        let level = |level: &str| {
+            level.trim().parse::<LevelFilter>().map_err(|_| {
+                format!("Invalid log level '{level}', use one of off, error, warn, info, debug or trace")
+            })
+        };
+        let mut default = None;
+        let mut directives = vec![];
+        for directive in text
+            .split(',')
+            .map(str::trim)
+            .filter(|directive| !directive.is_empty())
+        {
+            match directive.split_once('=') {
+                Some((target, value)) => {
+                    level(value)?;
+                    directives.push(directive.to_owned());
+                    if !target.starts_with(CRATE) {
+                        directives.push(format!("{CRATE}::{directive}"));
+                    }
+                }
+                None => default = Some(level(directive)?),
+            }
+        }
+        directives.insert(0, default.unwrap_or(LevelFilter::ERROR).to_string());
+        let directives = directives.join(",");
+        EnvFilter::builder()
+            .parse(&directives)
+            .map_err(|err| format!("Invalid log level '{text}': {err}"))?;
+        Ok(Self { directives })
+    }
+}
+
+impl TryFrom<String> for Filter {
+    type Error = String;
+
+    fn try_from(text: String) -> Result<Self, String> {
+        text.parse()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One readable line for each message, like
+    /// `WARN gallerica::mqtt_listener: Lost the connection`
+    #[default]
+    Pretty,
+    /// One JSON object for each message, with its `timestamp`, `level`, `target` and `fields`,
+    /// including the `message`, e.g. for log collectors
+    Json,
+}
+
+/// See `Configuration::log`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogConfiguration {
+    /// The messages which are logged, e.g. "debug" for everything down to debug messages, or
+    /// "info,mqtt_listener=debug" for debug messages of one module. The `RUST_LOG` environment
+    /// variable takes precedence. Defaults to warnings, and informational messages of gallerica.
+    #[serde(default)]
+    pub level: Option<Filter>,
+
+    /// Format of the messages on stderr and in the `file`.
+    #[serde(default)]
+    pub format: LogFormat,
+
+    /// Whether messages are written to stderr.
+    #[serde(default = "default_stderr")]
+    pub stderr: bool,
+
+    /// Also write the messages to a file, which is rotated when it grows too large.
+    #[serde(default)]
+    pub file: Option<LogFileConfiguration>,
+
+    /// Also send the messages to the systemd journal, with their priority and fields. Set
+    /// `stderr` to false to not log them twice when running as a systemd service.
+    #[serde(default)]
+    pub journald: bool,
+}
+
+fn default_stderr() -> bool {
+    true
+}
+
+impl Default for LogConfiguration {
+    fn default() -> Self {
+        Self {
+            level: None,
+            format: LogFormat::default(),
+            stderr: default_stderr(),
+            file: None,
+            journald: false,
+        }
+    }
+}
+
+/// See `LogConfiguration::file`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogFileConfiguration {
+    /// Path of the log file, relative to the state directory.
+    #[serde(default = "default_file_path")]
+    pub path: PathBuf,
+
+    /// Size in bytes after which the file is rotated, to `gallerica.log.1` and so on.
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+
+    /// Number of rotated files which are kept.
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+}
+
+fn default_file_path() -> PathBuf {
+    PathBuf::from("gallerica.log")
+}
+
+fn default_max_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_keep() -> usize {
+    3
+}
+
+/// An open log file, see `LogFileConfiguration`.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    /// Bytes written to `file`, including the ones before it was opened
+    size: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+/// `path` with the suffix of the rotated file `index`, like `gallerica.log.1`.
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{index}"));
+    name.into()
+}
+
+impl LogFile {
+    fn open(config: &LogFileConfiguration) -> Result<Self> {
+        let path = expand::path(&config.path)?;
+        let path = state_dir().join(path);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create '{}'", dir.display()))?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open log file '{}'", path.display()))?;
+        Ok(Self {
+            size: file.metadata().map_or(0, |metadata| metadata.len()),
+            path,
+            file,
+            max_bytes: config.max_bytes,
+            keep: config.keep,
+        })
+    }
+
+    /// Move the file to `gallerica.log.1`, the one before to `gallerica.log.2` and so on, and
+    /// start a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..self.keep).rev() {
+            let _ = fs::rename(rotated(&self.path, index), rotated(&self.path, index + 1));
+        }
+        match self.keep {
+            0 => fs::remove_file(&self.path)?,
+            _ => fs::rename(&self.path, rotated(&self.path, 1))?,
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl io::Write for LogFile {
+    /// The formatter writes each message at once, so it is rotated between messages.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = buf.len() as u64;
+        if self.size > 0 && self.size + length > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(buf)?;
+        self.size += length;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// The outputs of the messages, each of which formats them by itself.
+type Outputs = Vec<Box<dyn Layer<Registry> + Send + Sync>>;
+
+/// Replace the filter and the outputs of the subscriber installed by `init`.
+struct Handles {
+    filter: reload::Handle<EnvFilter, Registry>,
+    outputs: reload::Handle<Outputs, Registry>,
+}
+
+/// Set by `init`, nothing is logged before.
+static HANDLES: OnceLock<Handles> = OnceLock::new();
+
+/// An output which writes the messages in `format` to `writer`, the log file if `file` is set
+/// and stderr otherwise. Colors are only used on terminals.
+fn output<W>(format: LogFormat, writer: W, file: bool) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(!file && io::stderr().is_terminal());
+    match (format, file) {
+        (LogFormat::Pretty, false) => layer.without_time().boxed(),
+        (LogFormat::Pretty, true) => layer
+            .with_timer(ChronoLocal::new(TIME_FORMAT.to_owned()))
+            .boxed(),
+        (LogFormat::Json, _) => layer
+            .json()
+            .with_current_span(false)
+            .with_span_list(false)
+            .boxed(),
+    }
+}
+
+/// An output to the systemd journal, which keeps the priority and the fields of the messages, like
+/// `folder` as `FOLDER=`.
+fn journal() -> Result<tracing_journald::Layer> {
+    let journal = tracing_journald::layer().context("Failed to connect to the systemd journal")?;
+    Ok(journal
+        .with_syslog_identifier(JOURNAL_IDENTIFIER.to_owned())
+        .with_field_prefix(None))
+}
+
+/// The filter of the `RUST_LOG` environment variable, if it is set.
+fn environment_filter() -> Option<Filter> {
+    let text = std::env::var("RUST_LOG").ok()?;
+    match text.parse() {
+        Ok(filter) => Some(filter),
+        Err(err) => {
+            eprintln!("Ignoring RUST_LOG: {err}");
+            None
+        }
+    }
+}
+
+/// Start logging to stderr with the default settings, or the ones of `RUST_LOG`.
+pub fn init() {
+    let filter = environment_filter().unwrap_or_default();
+    let (filter, filter_handle) = reload::Layer::new(filter.env_filter());
+    let outputs: Outputs = vec![output(LogFormat::default(), io::stderr, false)];
+    let (outputs, outputs_handle) = reload::Layer::new(outputs);
+    let subscriber = Registry::default()
+        .with(outputs.with_filter(filter))
+        .with(Recorder.with_filter(filter_fn(recorded)));
+    // Only fails if a logger was installed already
+    if tracing::subscriber::set_global_default(subscriber).is_ok() {
+        let _ = HANDLES.set(Handles {
+            filter: filter_handle,
+            outputs: outputs_handle,
+        });
+    }
+}
+
+/// Apply the settings of the configuration. A log file or the journal which can't be opened is
+/// left out, with a warning on the remaining outputs.
+pub fn configure(config: &LogConfiguration) {
+    let Some(handles) = HANDLES.get() else {
+        return;
+    };
+    let mut problems = vec![];
+    let file = config
+        .file
+        .as_ref()
+        .and_then(|file| LogFile::open(file).map_err(|err| problems.push(err)).ok());
+    let journal = config
+        .journald
+        .then(|| journal().map_err(|err| problems.push(err)).ok())
+        .flatten();
+
+    let mut outputs: Outputs = vec![];
+    // Never lose the warnings below
+    if config.stderr || (file.is_none() && journal.is_none()) {
+        outputs.push(output(config.format, io::stderr, false));
+    }
+    if let Some(file) = file {
+        outputs.push(output(config.format, Mutex::new(file), true));
+    }
+    if let Some(journal) = journal {
+        outputs.push(journal.boxed());
+    }
+    let filter = environment_filter()
+        .or_else(|| config.level.clone())
+        .unwrap_or_default();
+    // Only fail if the subscriber is gone
+    let _ = handles.filter.reload(filter.env_filter());
+    let _ = handles.outputs.reload(outputs);
+
+    for problem in problems {
+        warn!("{problem:#}");
+    }
+}
+
+/// Whether messages with `metadata` are kept for `Request::RecentEvents`, no matter whether they
+/// are logged: the ones of the default filter, warnings and informational messages of gallerica.
+fn recorded(metadata: &Metadata) -> bool {
+    let target = metadata.target();
+    *metadata.level() <= Level::WARN
+        || (*metadata.level() == Level::INFO
+            && target
+                .strip_prefix(CRATE)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::")))
+}
+
+/// Keeps the messages for `Request::RecentEvents`.
+struct Recorder;
+
+/// Collects the message of an event.
+#[derive(Default)]
+struct Message(String);
+
+impl Visit for Message {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0 = value.to_owned();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for Recorder {
+    fn on_event(&self, event: &Event, _context: layer::Context<S>) {
+        let mut message = Message::default();
+        event.record(&mut message);
+        let metadata = event.metadata();
+        events::record(message_api::Event::Logged {
+            level: metadata.level().as_str().to_lowercase(),
+            target: metadata.target().to_owned(),
+            message: message.0,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_filters_apply_to_modules() {
+        let filter: Filter = "warn,mqtt_listener=debug,gallerica::mqtt_listener::tls=error"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            filter.directives,
+            "warn,mqtt_listener=debug,gallerica::mqtt_listener=debug,gallerica::mqtt_listener::tls=error"
+        );
+        let subscriber = Registry::default().with(filter.env_filter());
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(!tracing::enabled!(target: "gallerica", Level::INFO));
+            assert!(tracing::enabled!(target: "gallerica::mqtt_listener", Level::DEBUG));
+            assert!(!tracing::enabled!(target: "gallerica::mqtt_listener::tls", Level::WARN));
+        });
+
+        let subscriber = Registry::default().with(Filter::default().env_filter());
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(tracing::enabled!(target: "gallerica::timer", Level::INFO));
+            assert!(!tracing::enabled!(target: "zbus", Level::INFO));
+        });
+
+        let filter: Filter = "mqtt_listener=debug".parse().unwrap();
+        let subscriber = Registry::default().with(filter.env_filter());
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(tracing::enabled!(target: "zbus", Level::ERROR));
+            assert!(!tracing::enabled!(target: "zbus", Level::WARN));
+        });
+        assert!("verbose".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn test_log_file_is_rotated() {
+        let dir = TempDir::new("log");
+        let path = dir.join("gallerica.log");
+        let mut file = LogFile::open(&LogFileConfiguration {
+            path: path.clone(),
+            max_bytes: 10,
+            keep: 2,
+        })
+        .unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(rotated(&path, 1)).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(rotated(&path, 2)).unwrap(), "second\n");
+        assert!(!rotated(&path, 3).exists());
+    }
+}
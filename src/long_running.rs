@@ -0,0 +1,117 @@
+//! Display commands which keep running while their image is shown, like `swaybg -i {image}`,
+//! see `Configuration::long_running_command`.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use tokio::process::Child;
+use tracing::warn;
+
+use crate::pipeline::Pipeline;
+
+/// Processes exiting sooner after their start aren't restarted, to avoid restarting a broken
+/// command in a loop. They start again with the next image.
+const MIN_RUNTIME: Duration = Duration::from_secs(10);
+
+struct Processes {
+    children: Vec<(String, Child)>,
+    started: Instant,
+}
+
+impl Processes {
+    /// Kill the processes, and reap them in the background so they don't linger as zombies.
+    fn stop(self) {
+        for (_, mut child) in self.children {
+            tokio::spawn(async move {
+                let _ = child.kill().await;
+            });
+        }
+    }
+}
+
+/// The running display processes by the name of their monitor, empty without outputs.
+#[derive(Default)]
+pub struct LongRunning {
+    processes: HashMap<String, Processes>,
+}
+
+impl LongRunning {
+    /// Start the commands of `pipeline`, and stop the processes they replace once all of them are
+    /// running. If a command can't be started, the previous processes keep running.
+    pub fn replace(&mut self, pipeline: Pipeline) -> Result<()> {
+        let (monitor, children) = pipeline.start_detached()?;
+        let previous = self.processes.insert(
+            monitor,
+            Processes {
+                children,
+                started: Instant::now(),
+            },
+        );
+        if let Some(previous) = previous {
+            previous.stop();
+        }
+        Ok(())
+    }
+
+    /// `replace` each of the `pipelines`, e.g. one per output.
+    pub fn replace_all(&mut self, pipelines: Vec<Pipeline>) -> Result<()> {
+        let mut result = Ok(());
+        for pipeline in pipelines {
+            if let Err(err) = self.replace(pipeline) {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    /// Stop all processes.
+    pub fn clear(&mut self) {
+        for (_, processes) in self.processes.drain() {
+            processes.stop();
+        }
+    }
+
+    /// Wait until a process exits, and return the name of its monitor if it should be restarted.
+    /// The other processes of the same monitor are stopped. Never returns without processes.
+    pub async fn exited(&mut self) -> Option<String> {
+        type Wait<'a> = Pin<Box<dyn Future<Output = (String, String)> + Send + 'a>>;
+        let mut waits: Vec<Wait> = vec![];
+        for (monitor, processes) in &mut self.processes {
+            for (program, child) in &mut processes.children {
+                waits.push(Box::pin(async move {
+                    let status = match child.wait().await {
+                        Ok(status) => status.to_string(),
+                        Err(err) => err.to_string(),
+                    };
+                    (monitor.clone(), format!("'{program}' exited: {status}"))
+                }));
+            }
+        }
+        let (monitor, message) = std::future::poll_fn(|context| {
+            for wait in &mut waits {
+                if let Poll::Ready(exited) = wait.as_mut().poll(context) {
+                    return Poll::Ready(exited);
+                }
+            }
+            Poll::Pending
+        })
+        .await;
+        drop(waits);
+
+        let processes = self.processes.remove(&monitor)?;
+        let started = processes.started;
+        processes.stop();
+        if started.elapsed() < MIN_RUNTIME {
+            warn!("{message}, not restarting it until the next image");
+            return None;
+        }
+        warn!("{message}, restarting it");
+        Some(monitor)
+    }
+}
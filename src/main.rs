@@ -3,35 +3,39 @@
 
 use std::{
     borrow::Cow,
-    collections::HashMap,
-    ffi::{OsStr, OsString},
+    collections::{BTreeMap, HashMap, HashSet},
     fs::read_dir,
-    io::{self, Read},
-    path::{Component, Path, PathBuf},
-    process::ExitStatus,
-    sync::Mutex,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{Arc, Mutex, PoisonError},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
 
 use circular_queue::CircularQueue;
-use clap::Parser;
-use directories::UserDirs;
-use rand::prelude::*;
+use clap::{CommandFactory, Parser, Subcommand};
+use croner::Cron;
+use rand::{distributions::WeightedIndex, prelude::*};
 use serde::{Deserialize, Serialize};
 
 use tokio::{
-    pin,
-    process::Command,
-    select, signal,
-    sync::mpsc::{self, Receiver, Sender},
+    pin, select, signal,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Semaphore,
+    },
     task::JoinHandle,
     time::Duration,
 };
+use tracing::{error, info, warn};
 
 mod message_api;
-pub use gallerica::project_dirs;
-use message_api::{InflightRequest, MessageReceiver, MessageSource};
+use gallerica::{completions, duration};
+pub use gallerica::{project_dirs, state_dir};
+use message_api::{
+    Event, GalleryInfo, InflightRequest, MessageReceiver, MessageSource, PowerSaving, Trigger,
+};
 pub use message_api::{Request, Response};
 
 mod unix_socket_listener;
@@ -40,121 +44,823 @@ use unix_socket_listener::{UnixListenerConfig, UnixSocketReceiver};
 mod mqtt_listener;
 use mqtt_listener::{MqttListenerConfig, MqttReceiver};
 
+mod named_pipe_listener;
+use named_pipe_listener::NamedPipeListenerConfig;
+
+mod stdin_listener;
+use stdin_listener::StdinReceiver;
+
+mod tcp_listener;
+use tcp_listener::{TcpListenerConfig, TcpReceiver};
+
 mod timer;
-use timer::{PausableInterval, TickResult};
+use timer::{CronTimer, IntervalRamp, PausableInterval, TickResult, UpdateTimer};
+
+mod image_index;
+use image_index::{DuplicateDetection, EntryKind, FolderEntry, ImageIndex};
+
+mod config_migration;
+
+mod config_format;
+use config_format::Format;
+
+mod config_layers;
+use config_layers::{ConfigSource, Override};
+
+mod config_watch;
+use config_watch::ConfigWatcher;
+
+mod default_config;
+
+mod expand;
+
+mod logging;
+use logging::LogConfiguration;
+
+mod systemd;
+use systemd::Notifier;
+
+mod statistics;
+use statistics::Statistics;
+
+mod startup;
+use startup::StartupConditions;
+
+mod gallery_file;
+
+mod sidecar;
+use sidecar::SidecarFormat;
+
+mod doctor;
+
+mod unknown_keys;
+
+mod validation;
+
+mod trash;
+
+mod quarantine;
+use quarantine::Quarantine;
+
+mod image_metadata;
+use image_metadata::ImageMetadata;
+
+mod schedule;
+use schedule::{Location, QuietHours, Schedule, ScheduleRule, TimeRange};
+
+mod selection;
+use selection::{
+    prefer_aspect_ratio, recency_weight, AspectRatio, Cursor, LeastRecentlyShown, Random,
+    SelectionMode, SelectionStrategy, Sequential, Shuffle, ShuffleState, MAX_STARS, UNRATED_STARS,
+};
+
+mod display_server;
+use display_server::{DisplayServer, Monitor};
+
+mod fullscreen;
+
+mod idle;
+use idle::IdleConfiguration;
+
+mod suspend;
+use suspend::{ResumeBehavior, SuspendMonitor};
+
+mod rotation;
+use rotation::{Rotation, RotationConfiguration};
+
+mod output;
+use output::{Output, OutputConfiguration};
+
+mod hotplug;
+use hotplug::Outputs;
+
+mod power;
+use power::{BatteryConfiguration, PowerMonitor};
+
+mod crash_marker;
+use crash_marker::RunningMarker;
+
+mod template;
+use template::{CommandLine, CommandLines, Values};
+
+mod pipeline;
+use pipeline::{CommandSettings, Pipeline};
+
+mod long_running;
+use long_running::LongRunning;
+
+mod backend;
+use backend::Backend;
+
+mod processing;
+use processing::Processing;
+
+mod palette;
+use palette::{Color, PaletteConfiguration};
+
+mod lockscreen;
+use lockscreen::{Lockscreen, LockscreenConfiguration};
+
+mod current_link;
+use current_link::CurrentLink;
+
+mod notification;
+
+mod state_file;
+
+mod state_migration;
+
+mod history;
+use history::{History, HistoryConfiguration};
+
+mod storage;
+use storage::Storage;
+
+mod state_export;
+use state_export::Snapshot;
+
+mod instance_lock;
+use instance_lock::InstanceLock;
+
+mod events;
+use events::Events;
+
+mod client;
+
+#[cfg(test)]
+mod test_util;
+
+/// Name of the pseudo gallery containing the images of all configured galleries.
+const ALL_GALLERIES: &str = "*";
+
+/// Longest time to wait for the running update when shutting down.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Display command used before a configuration is loaded, and in safe mode.
+const BUILTIN_COMMAND_LINE: &str = "echo {image}";
 
 #[derive(Parser)]
+#[clap(author, version)]
+#[clap(about = "Show random images of galleries, and control the running daemon")]
+#[clap(after_help = client::EXIT_STATUS)]
 struct Cli {
     /// Config file to use. If this argument is not given, then it will read
     /// $XDG_DATA_HOME/gallerica/config.toml (or equivalent) by default
-    #[clap(short)]
+    #[clap(short, global = true)]
     config_file: Option<PathBuf>,
+
+    /// Use the options of this profile of the configuration, see `profiles` in the README
+    #[clap(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Set an option, overriding the config file and environment variables like
+    /// GALLERICA_UPDATE_INTERVAL_MS, e.g. `--set update_interval_ms=60000`
+    #[clap(long = "set", global = true, value_name = "KEY=VALUE", value_parser = Override::parse)]
+    set: Vec<Override>,
+
+    /// Shut down a daemon which is already running with the same config file, instead of exiting
+    #[clap(long)]
+    replace: bool,
+
+    #[clap(flatten)]
+    client: client::Options,
+
+    /// Without a command, the daemon is started
+    #[clap(subcommand)]
+    command: Option<CliCommand>,
 }
 
-enum CmdLinePart {
-    Literal(OsString),
-    Placeholder,
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Start the daemon, like running without a command
+    Daemon,
+
+    /// Rewrite the config file, replacing deprecated options with their current equivalent.
+    /// The original file is kept with an additional `.bak` extension.
+    MigrateConfig,
+
+    /// Check the configuration and environment for problems, and suggest how to fix them.
+    Doctor,
+
+    /// Check only the configuration for problems, without starting the daemon: syntax errors,
+    /// misspelled options, missing folders and invalid commands.
+    CheckConfig,
+
+    /// Show one image and exit, without listening for requests or changing the image later,
+    /// e.g. in login scripts. Honors the history and statistics like the daemon.
+    Once {
+        /// Gallery to show an image of, or "*" for all galleries. Defaults to `default_gallery`.
+        #[clap(value_name = "GALLERY")]
+        gallery: Option<String>,
+    },
+
+    /// Print the completion script for a shell
+    ///
+    /// For bash, e.g.
+    /// `gallerica completions bash > ~/.local/share/bash-completion/completions/gallerica`
+    Completions {
+        #[clap(value_enum)]
+        shell: completions::Shell,
+    },
+
+    #[clap(flatten)]
+    Client(client::Command),
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Gallery {
     name: String,
     #[serde(rename = "folders")]
-    sources: Vec<PathBuf>,
+    sources: Vec<Source>,
+
+    /// Whether to include files in subfolders of `sources`.
+    #[serde(default)]
+    recursive: bool,
+
+    /// Whether to follow symbolic links to files (and folders if `recursive` is set).
+    /// Folders are scanned at most once, so links pointing back up the tree are harmless.
+    #[serde(default = "default_follow_symlinks")]
+    follow_symlinks: bool,
+
+    /// Whether to include hidden files and folders, i.e. those whose name starts with a dot.
+    #[serde(default = "default_include_hidden")]
+    include_hidden: bool,
+
+    /// Size of the recently selected buffer of this gallery.
+    /// Defaults to the global `recent_image_buffer_size`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recent_image_buffer_size: Option<usize>,
+
+    /// Time between two images while this gallery is selected.
+    /// Defaults to the global `update_interval_ms`.
+    #[serde(
+        default,
+        with = "duration::optional_millis",
+        skip_serializing_if = "Option::is_none"
+    )]
+    update_interval_ms: Option<u64>,
+
+    /// Cron expression for the times at which images change while this gallery is selected,
+    /// replacing `update_interval_ms`. Defaults to the global `update_schedule`, unless this
+    /// gallery has its own `update_interval_ms`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    update_schedule: Option<Cron>,
+
+    /// Whether to pin this gallery the first time it is used, see `Request::PinGallery`.
+    #[serde(default)]
+    pinned: bool,
+
+    /// Whether this gallery may be shown while `Request::SafeMode` is enabled.
+    #[serde(default = "default_safe")]
+    safe: bool,
+
+    /// Time in which images of this gallery are not repeated.
+    /// Defaults to the global `repeat_window_ms`.
+    #[serde(
+        default,
+        with = "duration::optional_millis",
+        skip_serializing_if = "Option::is_none"
+    )]
+    repeat_window_ms: Option<u64>,
+
+    /// Half-life of the penalty for recently shown images of this gallery.
+    /// Defaults to the global `recency_half_life_ms`.
+    #[serde(
+        default,
+        with = "duration::optional_millis",
+        skip_serializing_if = "Option::is_none"
+    )]
+    recency_half_life_ms: Option<u64>,
+
+    /// How images of this gallery are selected.
+    /// Defaults to the global `selection`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    selection: Option<SelectionMode>,
+}
+
+/// A folder of a gallery, either given by its path or as a table with a `weight`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "SourceDefinition", into = "SourceDefinition")]
+struct Source {
+    path: PathBuf,
+
+    /// How likely this folder is selected compared to the other folders of the gallery.
+    /// If no folder of a gallery has a weight, all images of the gallery are equally likely.
+    weight: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SourceDefinition {
+    Path(PathBuf),
+    Weighted { path: PathBuf, weight: f64 },
+}
+
+impl From<SourceDefinition> for Source {
+    fn from(definition: SourceDefinition) -> Self {
+        match definition {
+            SourceDefinition::Path(path) => Source { path, weight: None },
+            SourceDefinition::Weighted { path, weight } => Source {
+                path,
+                weight: Some(weight),
+            },
+        }
+    }
+}
+
+impl From<Source> for SourceDefinition {
+    fn from(source: Source) -> Self {
+        match source.weight {
+            None => SourceDefinition::Path(source.path),
+            Some(weight) => SourceDefinition::Weighted {
+                path: source.path,
+                weight,
+            },
+        }
+    }
+}
+
+fn default_follow_symlinks() -> bool {
+    true
+}
+fn default_include_hidden() -> bool {
+    true
+}
+fn default_safe() -> bool {
+    true
+}
+
+impl Gallery {
+    /// List all files in the folders of this gallery.
+    fn scan(&self) -> Vec<PathBuf> {
+        self.scan_with(&mut image_index::read_folders, &mut HashSet::new())
+    }
+
+    /// Like `scan`, but reusing folder listings cached in `index`.
+    fn scan_indexed(&self, index: &mut ImageIndex) -> Vec<PathBuf> {
+        self.scan_annotated(index, &mut HashSet::new())
+    }
+
+    /// Like `scan_indexed`, additionally adding all files that have a metadata file to
+    /// `with_metadata`, see `image_metadata`.
+    fn scan_annotated(
+        &self,
+        index: &mut ImageIndex,
+        with_metadata: &mut HashSet<PathBuf>,
+    ) -> Vec<PathBuf> {
+        self.scan_with(&mut |folders| index.list_folders(folders), with_metadata)
+    }
+
+    /// Weight of each of `files` for random selection, or None if images should be selected
+    /// uniformly.
+    /// A folder is selected according to its weight, then one of its images uniformly.
+    fn image_weights(&self, files: &[PathBuf]) -> Option<Vec<f64>> {
+        if self.sources.iter().all(|s| s.weight.is_none()) {
+            return None;
+        }
+
+        let folders: Vec<_> = files
+            .iter()
+            .map(|file| self.sources.iter().position(|s| file.starts_with(&s.path)))
+            .collect();
+
+        let mut counts = vec![0usize; self.sources.len()];
+        for &folder in folders.iter().flatten() {
+            counts[folder] += 1;
+        }
+
+        Some(
+            folders
+                .iter()
+                .map(|folder| match *folder {
+                    Some(i) => self.sources[i].weight.unwrap_or(1.0) / counts[i] as f64,
+                    None => 0.0,
+                })
+                .collect(),
+        )
+    }
+
+    /// Scan the folders of this gallery level by level, so that all folders of one level can be
+    /// listed in parallel by `list_folders`.
+    fn scan_with(
+        &self,
+        list_folders: &mut impl FnMut(&[PathBuf]) -> Vec<Option<Vec<FolderEntry>>>,
+        with_metadata: &mut HashSet<PathBuf>,
+    ) -> Vec<PathBuf> {
+        let mut files = vec![];
+        let mut visited = HashSet::new();
+        let mut pending: Vec<_> = self.sources.iter().map(|s| s.path.clone()).collect();
+
+        while !pending.is_empty() {
+            // Resolve symlinks when detecting already visited folders, to protect against cycles
+            pending.retain(|folder| {
+                folder
+                    .canonicalize()
+                    .is_ok_and(|canonical| visited.insert(canonical))
+            });
+
+            let listings = list_folders(&pending);
+            let mut subfolders = vec![];
+            for (folder, entries) in pending.iter().zip(listings) {
+                for entry in entries.into_iter().flatten() {
+                    if !self.include_hidden && entry.name.to_string_lossy().starts_with('.') {
+                        continue;
+                    }
+
+                    if entry.is_symlink && !self.follow_symlinks {
+                        continue;
+                    }
+
+                    let path = folder.join(&entry.name);
+                    if sidecar::is_sidecar(&path) {
+                        with_metadata.extend(image_metadata::image_of(&path));
+                        continue;
+                    }
+
+                    match entry.kind {
+                        EntryKind::File => files.push(path),
+                        EntryKind::Folder if self.recursive => subfolders.push(path),
+                        EntryKind::Folder | EntryKind::Other => {}
+                    }
+                }
+            }
+            pending = subfolders;
+        }
+        files
+    }
 }
 
 struct ApplicationState {
     galleries: HashMap<String, Gallery>,
-    update_interval: PausableInterval,
-    display_command: OsString,
-    display_args: Vec<CmdLinePart>,
+    /// Names of the galleries from the configuration, unlike imported ones
+    configured_galleries: Vec<String>,
+    /// See `Configuration::profile`
+    profile: Option<String>,
+    update_interval: UpdateTimer,
+    /// Interval for galleries without their own `update_interval_ms`.
+    default_update_interval: Duration,
+    /// Schedule for galleries without their own `update_schedule` or `update_interval_ms`.
+    default_update_schedule: Option<Cron>,
+    interval_ramp: Option<IntervalRamp>,
+    /// Whether images only change on request, see `Configuration::one_shot`.
+    one_shot: bool,
+    /// See `Configuration::resume_interval`.
+    resume_interval: bool,
+    display_commands: Vec<CommandLine>,
+    /// See `Configuration::parallel_commands`.
+    parallel_commands: bool,
+    /// Timeout and environment of the commands
+    command_settings: CommandSettings,
+    /// See `Configuration::long_running_command`.
+    long_running_command: bool,
+    /// Display processes started with `long_running_command`
+    long_running: LongRunning,
+    /// See `Configuration::processing`.
+    processing: Option<Processing>,
+    /// See `Configuration::palette`.
+    palette_configuration: Option<PaletteConfiguration>,
+    /// Palette of the current image, from dark to light
+    palette: Vec<Color>,
+    /// Lock screen following the wallpaper, see `Configuration::lockscreen`
+    lockscreen: Option<Lockscreen>,
+    /// See `Configuration::current_link`.
+    current_link: CurrentLink,
+    /// Where the `current_link` is placed, see `current_link::default_path`.
+    current_link_path: PathBuf,
+    /// Log of the shown images, see `Configuration::history`.
+    history: History,
+    /// Clients following the changes, see `Request::Subscribe`.
+    events: Events,
+    /// See `Configuration::notifications`.
+    notifications: bool,
+    /// See `Configuration::dry_run`.
+    dry_run: bool,
+    /// Whether the daemon started in safe mode, which lasts until it is restarted, see
+    /// `Configuration::safe_mode_crash_threshold`.
+    safe_mode: bool,
 
-    message_sources: Vec<MessageSource>,
+    /// The listeners and the configuration each was started with
+    message_sources: Vec<(ListenerConfiguration, MessageSource)>,
     message_queue: Receiver<anyhow::Result<Box<dyn InflightRequest>>>,
     message_input: Sender<anyhow::Result<Box<dyn InflightRequest>>>,
+    /// Set by `Request::Shutdown`, the daemon stops after responding to it
+    shutdown_requested: bool,
 
     /// Task which runs the update subprocess
-    update_task: Option<JoinHandle<io::Result<ExitStatus>>>,
+    update_task: Option<JoinHandle<Result<()>>>,
+
+    /// Command run with each pre-selected image, see `Configuration::prefetch_command`.
+    prefetch_command: Option<CommandLine>,
+    /// Image to show on the next update, selected one update ahead. Its selection only takes
+    /// effect once it is shown, so it can be cleared when the set of selectable images might
+    /// change, e.g. when changing the gallery.
+    next_image: Option<Selection>,
 
     /// In case a new update is requested, while an existing one is still running, this will buffer
     /// the next update, in order to execute it once the first one finishes.
     /// Only one update is buffered, if a third update arrives, while the first is still running,
     /// the second one is discarded in favor for the third.
-    pending_update: Option<Command>,
+    pending_update: Option<Vec<Pipeline>>,
+
+    /// See `Configuration::outputs`.
+    outputs: Vec<Output>,
+    /// See `Configuration::distinct_outputs`.
+    distinct_outputs: bool,
+    /// Detection of connected monitors, see `Configuration::hotplug`.
+    hotplug: Option<Monitor<Outputs>>,
+    /// Names of the connected monitors, if known. Outputs of other monitors aren't updated.
+    connected_outputs: Option<Outputs>,
 
     number_retries: u32,
 
-    /// File where persistent state should be stored
+    /// Size of the recently selected buffer for galleries that don't set their own.
+    recent_image_buffer_size: usize,
+
+    /// Whether selected images are decoded before showing them, see `Configuration`.
+    validate_images: bool,
+
+    /// Directory to move trashed images to, instead of the system trash.
+    trash_directory: Option<PathBuf>,
+
+    /// Default folder for `Request::CollectCurrent`.
+    collect_directory: Option<PathBuf>,
+
+    /// Which sidecar files to write next to shown images.
+    sidecar_format: SidecarFormat,
+
+    /// How images are selected from the `ALL_GALLERIES` pseudo gallery.
+    all_galleries_selection: AllGalleriesSelection,
+    /// Selection mode for galleries without their own, see `Configuration`.
+    selection_mode: SelectionMode,
+    /// Automatic gallery changes, see `Configuration::schedule`.
+    schedule: Schedule,
+    /// Times without automatic updates, see `Configuration::quiet_hours`.
+    quiet_hours: QuietHours,
+    /// Aspect ratio of the display, see `Configuration`.
+    aspect_ratio: Option<AspectRatio>,
+    aspect_tolerance: f64,
+    /// Default half-life of the penalty for recently shown images, see `Configuration`.
+    recency_half_life_ms: Option<u64>,
+    /// Default time in which images are not repeated, see `Configuration`.
+    repeat_window_ms: Option<u64>,
+
+    /// Detection of fullscreen windows, see `Configuration::pause_on_fullscreen`.
+    fullscreen: Option<Monitor>,
+    /// Whether a fullscreen window is focused, which pauses the rotation.
+    fullscreen_active: bool,
+    /// Detection of idle sessions, see `Configuration::idle`.
+    idle: Option<Monitor>,
+    /// Whether the session is idle, which pauses the rotation.
+    idle_active: bool,
+
+    /// Throttling while running on battery, see `Configuration::battery`.
+    power: Option<PowerMonitor>,
+
+    /// See `Configuration::rotations`.
+    rotations: Vec<Rotation>,
+
+    /// Detection of suspends, see `Configuration::on_resume`.
+    suspend: Option<SuspendMonitor>,
+    /// Reloads the configuration file, only set while running as the daemon
+    config_watcher: Option<ConfigWatcher>,
+    /// Notifies systemd, if the daemon runs as its service
+    systemd: Option<Notifier>,
+
+    /// Source of all random decisions, see `Configuration::seed`.
+    rng: StdRng,
+    /// Seed that `rng` starts with, random if None.
+    seed: Option<u64>,
+
+    /// How duplicate files are detected, see `Configuration`.
+    duplicate_detection: DuplicateDetection,
+    /// Cached folder listings and fingerprints for `duplicate_detection`, shared with the
+    /// blocking threads of `with_index`.
+    image_index: Arc<Mutex<ImageIndex>>,
+
+    /// Where persistent state should be stored, see `Configuration::storage`.
     /// If None, then no persistent state is stored
-    storage_file: Option<PathBuf>,
+    storage: Option<Storage>,
+    /// Writes the `storage` in the background
+    state_writer: state_file::Writer,
     /// Part of the state that can be persisted to the disk and loaded on restart
     persistent: PersistentState,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 struct PersistentState {
+    /// Format of the state, older ones are upgraded by `state_migration`.
+    #[serde(default)]
+    pub version: u64,
+
     /// Name of the currently selected gallery, if there is one
     pub current_gallery: Option<String>,
 
-    /// Buffer of recently selected items.
-    /// If a path would be selected by `select_random_image` that's in this buffer, a new item will
-    /// be chosen instead.
+    /// Image that was passed to the display command most recently
+    #[serde(default)]
+    pub current_image: Option<PathBuf>,
+
+    /// Buffers of recently selected items, one per gallery.
+    /// If a path would be selected by `SelectionMode::Random` that's in the buffer of the current
+    /// gallery, a new item will be chosen instead.
     /// Up to `number_retries` attempts will be done at selecting an image.
-    pub recenty_selected: Mutex<CircularQueue<PathBuf>>,
+    #[serde(default)]
+    pub recently_selected: HashMap<String, CircularQueue<PathBuf>>,
+
+    /// Image shown most recently from each gallery, so switching back to a gallery continues
+    /// where it left off. Also see `Request::SelectGallery`.
+    #[serde(default)]
+    pub gallery_images: HashMap<String, PathBuf>,
 
     /// Whether the daemon is currently paused (true) or cycling through images (false).
     /// See `Request::Pause`
     #[serde(default = "default_paused")]
     pub is_paused: bool,
+
+    /// Files which failed to decode as an image.
+    /// These are not selected again until they change, see `Request::Unquarantine`.
+    #[serde(default)]
+    pub quarantined: Quarantine,
+
+    /// Images which are never selected again, see `Request::BanCurrent`.
+    #[serde(default)]
+    pub banned: HashSet<PathBuf>,
+
+    /// How often galleries and images were shown, see `Request::Stats`.
+    #[serde(default)]
+    pub statistics: Statistics,
+
+    /// Snapshots of pinned galleries, by gallery name, see `Request::PinGallery`.
+    #[serde(default)]
+    pub pinned: HashMap<String, PinnedSnapshot>,
+
+    /// Star ratings of images, see `Request::RateCurrent`.
+    #[serde(default)]
+    pub ratings: HashMap<PathBuf, u8>,
+
+    /// Progress through each gallery using `SelectionMode::Shuffle`.
+    #[serde(default)]
+    pub shuffle: HashMap<String, ShuffleState>,
+
+    /// Position in each gallery using `SelectionMode::Alphabetical` or
+    /// `SelectionMode::Chronological`.
+    #[serde(default)]
+    pub sequence_cursors: HashMap<String, Cursor>,
+
+    /// Whether only safe galleries are shown, see `Request::SafeMode`.
+    #[serde(default)]
+    pub safe_only: bool,
+
+    /// Progress through the update interval at shutdown, see `Configuration::resume_interval`.
+    #[serde(default)]
+    pub interval_elapsed_ms: Option<u64>,
+}
+
+/// Image chosen by `ApplicationState::select_random_image`, with the progress of the selection
+/// mode after choosing it. The progress only takes effect with `ApplicationState::commit`, so an
+/// image chosen ahead of time isn't skipped if it's never shown.
+#[derive(Clone)]
+struct Selection {
+    image: PathBuf,
+    progress: Progress,
+}
+
+/// State of a selection mode, see `Selection`.
+#[derive(Clone)]
+enum Progress {
+    /// The selection mode keeps no state
+    None,
+    /// `PersistentState::recently_selected` of a gallery
+    RecentlySelected(String, CircularQueue<PathBuf>),
+    /// `PersistentState::shuffle` of a gallery
+    Shuffle(String, ShuffleState),
+    /// `PersistentState::sequence_cursors` with its key
+    Cursor(String, Cursor),
+}
+
+/// Frozen set of images of a gallery.
+#[derive(Serialize, Deserialize, Clone)]
+struct PinnedSnapshot {
+    /// Time at which the snapshot was taken, in seconds since the UNIX epoch
+    pub created: u64,
+
+    /// Content hashes of the images in the snapshot.
+    /// Using hashes instead of paths means images can be moved or renamed within the gallery.
+    pub hashes: HashSet<String>,
+}
+
+fn default_aspect_tolerance() -> f64 {
+    0.1
 }
 
 fn default_paused() -> bool { false }
 
-fn parse_args<T, S>(args: T) -> impl Iterator<Item = CmdLinePart>
-where
-    T: IntoIterator<Item = S>,
-    S: AsRef<OsStr>,
-{
-    use CmdLinePart::*;
-    args.into_iter().map(|e| match e {
-        text if text.as_ref().to_str() == Some("{image}") => Placeholder,
-        text => Literal(text.as_ref().to_os_string()),
-    })
+impl PersistentState {
+    /// Copy of the buffer of recently selected items of `gallery`, resized to `capacity` if
+    /// necessary.
+    fn recently_selected(&self, gallery: &str, capacity: usize) -> CircularQueue<PathBuf> {
+        let mut buf = CircularQueue::with_capacity(capacity);
+        if let Some(old) = self.recently_selected.get(gallery) {
+            if old.capacity() == capacity {
+                return old.clone();
+            }
+            for item in old.asc_iter() {
+                buf.push(item.to_path_buf());
+            }
+        }
+        buf
+    }
 }
 
 impl ApplicationState {
-    pub fn new<T, S>(update_command: T, update_interval: Duration) -> Result<Self>
-    where
-        T: IntoIterator<Item = S>,
-        S: AsRef<std::ffi::OsStr>,
-    {
-        let mut cmdline = update_command.into_iter();
-
-        let cmd = cmdline
-            .next()
-            .ok_or_else(|| anyhow!("Need a command"))?
-            .as_ref()
-            .to_os_string();
-
+    pub fn new(update_command: &str, update_interval: Duration) -> Result<Self> {
         // arbitrary message limit
         let (sender, receiver) = mpsc::channel(42);
 
         Ok(ApplicationState {
             galleries: HashMap::new(),
-            update_interval: PausableInterval::new(update_interval),
-            display_command: cmd,
-            display_args: parse_args(cmdline).collect(),
+            configured_galleries: Vec::new(),
+            profile: None,
+            update_interval: UpdateTimer::Interval(PausableInterval::new(update_interval)),
+            default_update_interval: update_interval,
+            default_update_schedule: None,
+            interval_ramp: None,
+            one_shot: false,
+            resume_interval: false,
+            display_commands: vec![CommandLine::parse(update_command)?],
+            parallel_commands: false,
+            command_settings: CommandSettings::default(),
+            long_running_command: false,
+            long_running: LongRunning::default(),
+            processing: None,
+            palette_configuration: None,
+            palette: Vec::new(),
+            lockscreen: None,
+            current_link: CurrentLink::default(),
+            current_link_path: current_link::default_path(),
+            history: History::default(),
+            events: Events::default(),
+            notifications: false,
+            dry_run: false,
+            safe_mode: false,
             message_sources: Vec::new(),
             message_queue: receiver,
             message_input: sender,
+            shutdown_requested: false,
             update_task: None,
             pending_update: None,
+            outputs: Vec::new(),
+            distinct_outputs: false,
+            hotplug: None,
+            connected_outputs: None,
+            prefetch_command: None,
+            next_image: None,
             number_retries: default_retries(),
-            storage_file: Some("gallerica.json".into()),
+            recent_image_buffer_size: default_buffer_size(),
+            validate_images: default_validate_images(),
+            sidecar_format: SidecarFormat::default(),
+            trash_directory: None,
+            collect_directory: None,
+            all_galleries_selection: AllGalleriesSelection::default(),
+            selection_mode: SelectionMode::default(),
+            schedule: Schedule::default(),
+            quiet_hours: QuietHours::default(),
+            aspect_ratio: None,
+            aspect_tolerance: default_aspect_tolerance(),
+            recency_half_life_ms: None,
+            repeat_window_ms: None,
+            fullscreen: None,
+            fullscreen_active: false,
+            idle: None,
+            idle_active: false,
+            power: None,
+            suspend: None,
+            config_watcher: None,
+            systemd: None,
+            rotations: Vec::new(),
+            rng: StdRng::from_entropy(),
+            seed: None,
+            duplicate_detection: DuplicateDetection::default(),
+            image_index: Arc::default(),
+            storage: Some(Storage::Json {
+                path: state_dir().join("gallerica.json"),
+            }),
+            state_writer: state_file::Writer::new(),
             persistent: PersistentState {
+                version: state_migration::VERSION,
                 current_gallery: None,
-                recenty_selected: Mutex::new(CircularQueue::with_capacity(default_buffer_size())),
+                current_image: None,
+                recently_selected: HashMap::new(),
+                gallery_images: HashMap::new(),
                 is_paused: false,
+                quarantined: Quarantine::default(),
+                banned: HashSet::new(),
+                statistics: Statistics::default(),
+                pinned: HashMap::new(),
+                ratings: HashMap::new(),
+                shuffle: HashMap::new(),
+                sequence_cursors: HashMap::new(),
+                safe_only: false,
+                interval_elapsed_ms: None,
             },
         })
     }
@@ -163,35 +869,189 @@ impl ApplicationState {
         self.galleries.insert(gallery.name.clone(), gallery);
     }
 
-    pub fn update_persistent_state(&mut self, new_state: PersistentState) -> Result<()> {
-        let target_capacity = self.persistent.recenty_selected.lock().unwrap().capacity();
-        let actual_capacity = new_state.recenty_selected.lock().unwrap().capacity();
+    pub fn update_persistent_state(&mut self, mut new_state: PersistentState) -> Result<()> {
+        // E.g. the gallery was renamed in the config. Only forget the selection, the ratings,
+        // statistics and history are still valid.
+        if let Some(gallery) = &new_state.current_gallery {
+            if !self.is_valid_gallery(gallery) {
+                warn!("State uses unknown gallery '{gallery}', switching to the default gallery");
+                new_state.current_gallery = None;
+            }
+        }
 
-        if actual_capacity != target_capacity {
-            let new: &mut CircularQueue<_> = &mut new_state.recenty_selected.lock().unwrap();
-            let old = std::mem::replace(new, CircularQueue::with_capacity(target_capacity));
+        self.persistent = new_state;
+        self.apply_gallery_interval();
+        self.apply_pause();
+        Ok(())
+    }
 
-            for item in old.asc_iter() {
-                new.push(item.to_path_buf());
+    /// Update interval of the current gallery, or the default one if it has none.
+    /// The interval is longer while saving power, see `Configuration::battery`.
+    fn gallery_interval(&self) -> Duration {
+        let interval = self
+            .persistent
+            .current_gallery
+            .as_ref()
+            .and_then(|name| self.galleries.get(name))
+            .and_then(|gallery| gallery.update_interval_ms)
+            .map_or(self.default_update_interval, Duration::from_millis);
+        match &self.power {
+            Some(power) if power.saving() == PowerSaving::Slowed => {
+                interval.mul_f64(power.interval_factor())
             }
+            _ => interval,
+        }
+    }
+
+    /// Update schedule of the current gallery, or None if it changes images in an interval.
+    fn gallery_schedule(&self) -> Option<Cron> {
+        let gallery = self
+            .persistent
+            .current_gallery
+            .as_ref()
+            .and_then(|name| self.galleries.get(name));
+        match gallery {
+            Some(gallery) if gallery.update_schedule.is_some() => gallery.update_schedule.clone(),
+            Some(gallery) if gallery.update_interval_ms.is_some() => None,
+            _ => self.default_update_schedule.clone(),
         }
+    }
 
-        if let Some(gallery) = &new_state.current_gallery {
-            if !self.galleries.contains_key(gallery) {
-                bail!("State uses invalid gallery '{}'", gallery);
+    /// A fresh timer for the current gallery, which ticks immediately.
+    fn gallery_timer(&self) -> UpdateTimer {
+        if self.one_shot {
+            return UpdateTimer::Once {
+                pending: true,
+                is_paused: false,
+            };
+        }
+        match self.gallery_schedule() {
+            Some(schedule) => UpdateTimer::Cron(Box::new(CronTimer::new(schedule))),
+            None => UpdateTimer::Interval(match self.interval_ramp {
+                Some(ramp) => PausableInterval::ramped(self.gallery_interval(), ramp),
+                None => PausableInterval::new(self.gallery_interval()),
+            }),
+        }
+    }
+
+    /// Switch to the `gallery_timer`, the next update happens one full interval from now, or at
+    /// the next time of the schedule. The timer is only replaced if it actually changes.
+    fn apply_gallery_interval(&mut self) {
+        let interval = self.gallery_interval();
+        let schedule = self.gallery_schedule();
+        let one_shot = self.one_shot;
+        let unchanged = match (&mut self.update_interval, schedule) {
+            (UpdateTimer::Once { .. }, _) => one_shot,
+            _ if one_shot => false,
+            // Keeps the progress of an interval ramp
+            (UpdateTimer::Interval(timer), None) => {
+                if timer.period() != interval {
+                    timer.set_period(interval);
+                    timer.resume_from(Duration::ZERO);
+                }
+                true
             }
+            (UpdateTimer::Cron(timer), Some(schedule)) => *timer.schedule() == schedule,
+            _ => false,
+        };
+        if !unchanged {
+            let was_paused = self.update_interval.is_paused();
+            self.update_interval = self.gallery_timer();
+            self.update_interval.reset();
+            self.update_interval.pause(was_paused);
         }
+    }
 
-        self.persistent = new_state;
-        self.update_interval.pause(self.persistent.is_paused);
+    /// Pause the timer while the user paused it, during quiet hours, while a fullscreen window is
+    /// focused, the session is idle, or to save power.
+    fn apply_pause(&mut self) {
+        let paused = self.persistent.is_paused
+            || self.quiet_hours.is_active()
+            || self.fullscreen_active
+            || self.idle_active
+            || self.power_saving() == PowerSaving::Paused;
+        self.update_interval.pause(paused);
+        for rotation in &mut self.rotations {
+            rotation.timer.pause(paused);
+        }
+    }
+
+    /// The timer if images change in an interval, rather than on a schedule or only once.
+    fn interval(&self) -> Option<&PausableInterval> {
+        match &self.update_interval {
+            UpdateTimer::Interval(interval) => Some(interval),
+            _ => None,
+        }
+    }
+
+    fn power_saving(&self) -> PowerSaving {
+        self.power
+            .as_ref()
+            .map_or(PowerSaving::Off, PowerMonitor::saving)
+    }
+
+    /// Whether `name` is a configured gallery, or the `ALL_GALLERIES` pseudo gallery.
+    fn is_valid_gallery(&self, name: &str) -> bool {
+        name == ALL_GALLERIES || self.galleries.contains_key(name)
+    }
+
+    /// Add the gallery defined in the file at `path`, see `Request::ImportGallery`.
+    fn import_gallery(&mut self, path: &Path, replace: bool) -> Result<()> {
+        let gallery = gallery_file::import(path)?;
+
+        if gallery.name == ALL_GALLERIES {
+            bail!("The gallery name '{ALL_GALLERIES}' is reserved");
+        }
+        if !replace && self.galleries.contains_key(&gallery.name) {
+            bail!("Gallery '{}' already exists", gallery.name);
+        }
+
+        self.add_gallery(gallery);
+        self.next_image = None;
+        Ok(())
+    }
+
+    /// Write the curation data to the file at `path`, see `Request::ExportState`.
+    async fn export_state(&self, path: &Path) -> Result<()> {
+        let mut history = self.history.query(None, usize::MAX).await?;
+        history.reverse();
+        state_export::export(
+            Snapshot {
+                ratings: self.persistent.ratings.clone(),
+                statistics: self.persistent.statistics.clone(),
+                quarantined: self.persistent.quarantined.paths(),
+                banned: self.persistent.banned.clone(),
+                history,
+                ..Snapshot::default()
+            },
+            path,
+        )
+    }
+
+    /// Merge the file at `path` into the state, see `Request::ImportState`.
+    async fn import_state(&mut self, path: &Path) -> Result<()> {
+        let snapshot = state_export::import(path)?;
+        self.history.import(snapshot.history).await?;
+        self.persistent.ratings.extend(snapshot.ratings);
+        self.persistent.statistics.merge(snapshot.statistics);
+        self.persistent.quarantined.extend(snapshot.quarantined);
+        self.persistent.banned.extend(snapshot.banned);
+        self.persist();
         Ok(())
     }
 
     pub fn change_gallery(&mut self, name: &str) -> Result<()> {
-        if !self.galleries.contains_key(name) {
+        if !self.is_valid_gallery(name) {
             bail!("Invalid gallery '{}'", name);
         }
+        if self.persistent.current_gallery.as_deref() != Some(name) {
+            self.events.send(Event::GallerySelected {
+                gallery: name.to_owned(),
+            });
+        }
         self.persistent.current_gallery = Some(name.to_owned());
+        self.next_image = None;
+        self.apply_gallery_interval();
         Ok(())
     }
 
@@ -202,122 +1062,1348 @@ impl ApplicationState {
         let source: Box<dyn MessageReceiver + Send> = match listener {
             ListenerConfiguration::UnixSocket(cfg) => Box::new(UnixSocketReceiver::new(cfg).await?),
             ListenerConfiguration::Mqtt(cfg) => Box::new(MqttReceiver::new(cfg).await?),
+            ListenerConfiguration::Tcp(cfg) => Box::new(TcpReceiver::new(cfg).await?),
+            ListenerConfiguration::NamedPipe(cfg) => named_pipe_listener::receiver(cfg)?,
+            ListenerConfiguration::Stdin => Box::new(StdinReceiver::new()),
         };
 
-        self.message_sources
-            .push(MessageSource::new(source, self.message_input.clone()));
+        self.message_sources.push((
+            listener.clone(),
+            MessageSource::new(source, self.message_input.clone()),
+        ));
         Ok(())
     }
 
-    pub async fn update(&mut self) {
-        let mut cmd = Command::new(&self.display_command);
+    /// Select a new image and pass it to the display command, see `update_image`.
+    pub async fn update(&mut self, trigger: Trigger) -> Response {
+        self.update_image(trigger, self.dry_run).await
+    }
 
-        let replacement = match self.select_random_image().await {
-            Some(path) => path,
-            None => return,
+    /// Select a new image and pass it to the display command, or only log the commands in a
+    /// `dry_run`. Returns `Response::NewImage`, or why no image could be selected.
+    async fn update_image(&mut self, trigger: Trigger, dry_run: bool) -> Response {
+        if dry_run {
+            return self.dry_run(trigger).await;
+        }
+        let Some(selection) = self.next_selection().await else {
+            return self.diagnose_no_images().await;
         };
+        let replacement = self.commit(selection);
 
-        use CmdLinePart::*;
-        cmd.args(self.display_args.iter().map(|ref a| match a {
-            Literal(t) => t.as_ref(),
-            Placeholder => {
-                let repl: &OsStr = replacement.as_ref();
-                repl
-            }
-        }));
+        let changed = match self.persistent.current_gallery.clone() {
+            Some(gallery) => self.record_shown(&gallery, &replacement, None, trigger),
+            None => true,
+        };
+        self.persistent.current_image = Some(replacement.clone());
 
-        self.persist();
+        self.write_sidecar(&replacement);
+        self.update_palette(&replacement).await;
+        let lockscreen = self.lockscreen_pipeline(&replacement, false).await;
+        if let Err(err) = self
+            .current_link
+            .update(&self.current_link_path, &replacement)
+        {
+            warn!(
+                "Failed to update '{}': {err}",
+                self.current_link_path.display()
+            );
+        }
+        if self.notifications && changed {
+            let gallery = self.persistent.current_gallery.as_deref();
+            notification::image_changed(&replacement, gallery.unwrap_or_default());
+        }
 
-        match self.update_task {
-            Some(_) => {
-                if self.pending_update.is_some() {
-                    eprintln!("Discarding pending update");
+        let pipelines = if self.outputs.is_empty() {
+            vec![self.display_pipeline(&replacement, false).await]
+        } else {
+            self.update_outputs(replacement.clone(), trigger, false)
+                .await
+        };
+        if let Some(lockscreen) = lockscreen {
+            tokio::spawn(async move {
+                if let Err(err) = lockscreen.run().await {
+                    warn!("Command of the lock screen failed: {err:#}");
                 }
-                self.pending_update = Some(cmd);
+            });
+        }
+
+        let mut started = Ok(());
+        if self.long_running_command {
+            started = self.long_running.replace_all(pipelines);
+            if let Err(err) = &started {
+                warn!("Display command failed: {err:#}");
             }
-            None => {
-                self.update_task = Some(tokio::spawn(
-                    async move { cmd.spawn().unwrap().wait().await },
-                ));
+        } else {
+            match self.update_task {
+                Some(_) => {
+                    if self.pending_update.is_some() {
+                        info!("Discarding pending update");
+                    }
+                    self.pending_update = Some(pipelines);
+                }
+                None => {
+                    let (task, result) = pipeline::spawn_all(pipelines);
+                    self.update_task = Some(task);
+                    started = result;
+                }
             }
         }
+
+        self.next_image = self.select_valid_image().await;
+        let prefetch = self
+            .next_image
+            .as_ref()
+            .and_then(|next| self.prefetch_pipeline(&next.image));
+        if let Some(prefetch) = prefetch {
+            tokio::spawn(async move {
+                if let Err(err) = prefetch.run().await {
+                    warn!("Prefetch command failed: {err:#}");
+                }
+            });
+        }
+
+        self.persist();
+        match started {
+            Ok(()) => Response::NewImage {
+                image: Some(replacement),
+            },
+            // Also logged once the update task finishes
+            Err(err) => Response::CommandFailed {
+                message: format!("{err:#}"),
+            },
+        }
     }
 
-    /// Iterate all folders of the `current_gallery` and select one file at random.
-    /// Previously selected files will be buffered in `recenty_selected` and are less likely to be
-    /// selected again.
-    async fn select_random_image(&self) -> Option<PathBuf> {
-        let source_folders = &self
-            .galleries
-            .get(self.persistent.current_gallery.as_ref()?)?
-            .sources;
+    /// The image selected ahead of time, unless it can't be shown anymore, or a new selection.
+    async fn next_selection(&mut self) -> Option<Selection> {
+        let next = self.next_image.take().filter(|next| {
+            next.image.is_file()
+                && !self.persistent.quarantined.contains(&next.image)
+                && !self.persistent.banned.contains(&next.image)
+        });
+        match next {
+            Some(selection) => Some(selection),
+            None => self.select_valid_image().await,
+        }
+    }
 
-        let mut rng = rand::thread_rng();
+    /// Log the commands which would show a new image and prefetch the next one, without running
+    /// them or recording anything, see `Configuration::dry_run`.
+    async fn dry_run(&mut self, trigger: Trigger) -> Response {
+        // Images are selected like in a real update, which advances the selection modes, draws
+        // random numbers and quarantines undecodable images. All of it is undone afterwards, so
+        // the next real update shows the images of the dry run.
+        let persistent = self.persistent.clone();
+        let next_image = self.next_image.clone();
+        let rng = self.rng.clone();
+        let response = self.rehearse(trigger).await;
+        self.persistent = persistent;
+        self.next_image = next_image;
+        self.rng = rng;
+        response
+    }
+
+    /// Select the images of a `dry_run` and describe the commands showing them.
+    async fn rehearse(&mut self, trigger: Trigger) -> Response {
+        let Some(selection) = self.next_selection().await else {
+            return self.diagnose_no_images().await;
+        };
+        let replacement = self.commit(selection);
+        if let Some(gallery) = self.persistent.current_gallery.clone() {
+            self.persistent
+                .gallery_images
+                .insert(gallery, replacement.clone());
+        }
+        self.persistent.current_image = Some(replacement.clone());
 
-        let all_files: Vec<_> = source_folders
+        let pipelines = if self.outputs.is_empty() {
+            vec![self.display_pipeline(&replacement, true).await]
+        } else {
+            self.update_outputs(replacement.clone(), trigger, true)
+                .await
+        };
+        let lockscreen = self.lockscreen_pipeline(&replacement, true).await;
+        let next = self.select_valid_image().await;
+        let prefetch = next.and_then(|next| self.prefetch_pipeline(&next.image));
+        let commands: Vec<_> = pipelines
             .iter()
-            .filter_map(|dir| read_dir(dir).ok())
-            .flatten()
-            .filter_map(|entry| entry.ok())
-            .filter(|entry| entry.path().is_file())
-            .map(|entry| entry.path())
+            .chain(&lockscreen)
+            .chain(&prefetch)
+            .flat_map(Pipeline::describe)
             .collect();
+        for command in &commands {
+            info!("Dry run: {command}");
+        }
+        Response::DryRun { commands }
+    }
 
-        let mut tries_left = self.number_retries;
-        loop {
-            let selection = match all_files.choose(&mut rng) {
-                Some(p) => p,
-                None => return None,
+    /// `image` prepared with `processing`, or `image` itself if there is nothing to do or the
+    /// processing failed. In a `dry_run`, only the path of the processed image is determined.
+    async fn processed_image(
+        &self,
+        image: &Path,
+        processing: Option<&Processing>,
+        dry_run: bool,
+    ) -> PathBuf {
+        let Some(processing) = processing else {
+            return image.to_owned();
+        };
+        let cache = project_dirs().cache_dir().join("processed");
+        let processed = match dry_run {
+            true => processing.target(image, &cache),
+            false => processing.apply(image, &cache).await,
+        };
+        match processed {
+            Ok(processed) => processed,
+            Err(err) => {
+                warn!("Failed to process '{}': {err:#}", image.display());
+                image.to_owned()
+            }
+        }
+    }
+
+    /// Commands which show `image` without outputs, see `processed_image` for `dry_run`.
+    async fn display_pipeline(&self, image: &Path, dry_run: bool) -> Pipeline {
+        let image = self
+            .processed_image(image, self.processing.as_ref(), dry_run)
+            .await;
+        let values = Values {
+            image: &image,
+            gallery: self
+                .persistent
+                .current_gallery
+                .as_deref()
+                .unwrap_or_default(),
+            monitor: "",
+            index: 0,
+        };
+        Pipeline::new(
+            &self.display_commands,
+            &values,
+            self.parallel_commands,
+            &self.command_settings,
+        )
+    }
+
+    /// Restart the `long_running_command` of `monitor` with its current image after it exited.
+    async fn restart_display(&mut self, monitor: &str) {
+        let pipeline = if monitor.is_empty() {
+            let Some(image) = self.persistent.current_image.clone() else {
+                return;
+            };
+            self.display_pipeline(&image, false).await
+        } else {
+            let Some(index) = self
+                .outputs
+                .iter()
+                .position(|output| output.name == monitor)
+            else {
+                return;
             };
+            let Some(image) = self.outputs[index].current_image.clone() else {
+                return;
+            };
+            self.output_pipeline(index, image).await
+        };
+        if let Err(err) = self.long_running.replace(pipeline) {
+            warn!("Display command failed: {err:#}");
+        }
+    }
+
+    /// Show the next image of the rotation at `index`.
+    async fn update_rotation(&mut self, index: usize) {
+        let rotation = &self.rotations[index];
+        let gallery = rotation.gallery.clone();
+        let current = rotation.current_image.clone();
+        let Some(selection) = self
+            .select_valid_image_from(&gallery, current.as_deref(), &HashSet::new())
+            .await
+        else {
+            warn!(
+                "Gallery '{gallery}' of rotation '{}' has no images to show",
+                self.rotations[index].name
+            );
+            return;
+        };
+        let image = self.commit(selection);
+        let name = self.rotations[index].name.clone();
+        self.record_shown(&gallery, &image, Some(&name), Trigger::Rotation);
+        let processing = self.rotations[index].processing.clone();
+        let processed = self
+            .processed_image(&image, processing.as_ref(), false)
+            .await;
+        self.rotations[index].show(&image, &processed);
+        self.persist();
+    }
+
+    /// Select an image for each output, and build the commands which show them.
+    /// The first output without its own gallery shows `image` of the current gallery.
+    /// In a `dry_run`, the images are neither recorded nor become the current ones, and the caller
+    /// restores the selection modes.
+    async fn update_outputs(
+        &mut self,
+        image: PathBuf,
+        trigger: Trigger,
+        dry_run: bool,
+    ) -> Vec<Pipeline> {
+        let mut shown = HashSet::from([image.clone()]);
+        let mut main_image = Some(image);
+        let mut pipelines = vec![];
+        for index in 0..self.outputs.len() {
+            let output = &self.outputs[index];
+            let disconnected = self
+                .connected_outputs
+                .as_ref()
+                .is_some_and(|connected| !connected.contains(&output.name));
+            if disconnected {
+                continue;
+            }
+            let image = if output.gallery.is_none() && main_image.is_some() {
+                main_image.take()
+            } else {
+                self.select_output_image(index, &shown, trigger, !dry_run)
+                    .await
+            };
+            let Some(image) = image else {
+                continue;
+            };
+            shown.insert(image.clone());
+            pipelines.push(if dry_run {
+                self.output_commands(index, &image, true).await
+            } else {
+                self.output_pipeline(index, image).await
+            });
+        }
+        pipelines
+    }
+
+    /// Select an image for the output at `index` from its gallery, or the current gallery, and
+    /// `record` it as shown. Images already `shown` on other outputs are avoided if
+    /// `distinct_outputs` is set. The selection mode continues after the image either way.
+    async fn select_output_image(
+        &mut self,
+        index: usize,
+        shown: &HashSet<PathBuf>,
+        trigger: Trigger,
+        record: bool,
+    ) -> Option<PathBuf> {
+        let output = &self.outputs[index];
+        let gallery = output
+            .gallery
+            .clone()
+            .or_else(|| self.persistent.current_gallery.clone())?;
+        let current = output.current_image.clone();
+        let avoid = if self.distinct_outputs {
+            shown
+        } else {
+            &HashSet::new()
+        };
+        let image = self
+            .select_valid_image_from(&gallery, current.as_deref(), avoid)
+            .await
+            .map(|selection| self.commit(selection));
+        let name = self.outputs[index].name.clone();
+        match &image {
+            Some(image) if record => {
+                self.record_shown(&gallery, image, Some(&name), trigger);
+            }
+            Some(_) => {}
+            None => warn!("No image to show on output '{name}'"),
+        }
+        image
+    }
+
+    /// Commands which show `image` on the output at `index`, which becomes its current image.
+    async fn output_pipeline(&mut self, index: usize, image: PathBuf) -> Pipeline {
+        let pipeline = self.output_commands(index, &image, false).await;
+        self.outputs[index].current_image = Some(image);
+        pipeline
+    }
+
+    /// Commands which show `image` on the output at `index`, see `processed_image` for `dry_run`.
+    async fn output_commands(&self, index: usize, image: &Path, dry_run: bool) -> Pipeline {
+        let output = &self.outputs[index];
+        let processing = output.processing.as_ref().or(self.processing.as_ref());
+        let processed = self.processed_image(image, processing, dry_run).await;
+        let gallery = output
+            .gallery
+            .as_deref()
+            .or(self.persistent.current_gallery.as_deref());
+        let commands = output.commands.as_ref().unwrap_or(&self.display_commands);
+        let values = Values {
+            image: &processed,
+            gallery: gallery.unwrap_or_default(),
+            monitor: &output.name,
+            index,
+        };
+        Pipeline::new(
+            commands,
+            &values,
+            self.parallel_commands,
+            &self.command_settings,
+        )
+    }
+
+    /// Remember the `connected` monitors, and show an image on the outputs which were just
+    /// connected, even while paused.
+    async fn outputs_changed(&mut self, connected: Outputs) {
+        // The first report is no change, the outputs were updated at startup
+        let previous = self.connected_outputs.replace(connected);
+        let Some(previous) = previous else {
+            return;
+        };
+        let mut shown: HashSet<_> = self
+            .outputs
+            .iter()
+            .filter_map(|output| output.current_image.clone())
+            .collect();
+        let mut pipelines = vec![];
+        for index in 0..self.outputs.len() {
+            let name = &self.outputs[index].name;
+            let is_new = !previous.contains(name)
+                && self
+                    .connected_outputs
+                    .as_ref()
+                    .is_some_and(|connected| connected.contains(name));
+            if !is_new {
+                continue;
+            }
+            info!("Monitor '{name}' connected");
+            if let Some(image) = self
+                .select_output_image(index, &shown, Trigger::Monitor, true)
+                .await
+            {
+                shown.insert(image.clone());
+                pipelines.push(self.output_pipeline(index, image).await);
+            }
+        }
+        if pipelines.is_empty() {
+            return;
+        }
+        self.persist();
+        if self.long_running_command {
+            if let Err(err) = self.long_running.replace_all(pipelines) {
+                warn!("Display command failed: {err:#}");
+            }
+            return;
+        }
+        let (update, _) = pipeline::spawn_all(pipelines);
+        tokio::spawn(async move {
+            if let Ok(Err(err)) = update.await {
+                warn!("Display command failed: {err:#}");
+            }
+        });
+    }
+
+    /// Count `image` of `gallery` in the statistics, add it to the history, and announce it to
+    /// subscribers. `output` is the output or rotation showing it, None for the main image.
+    /// Nothing is recorded if `output` already shows `image`, e.g. for a gallery with a single
+    /// image, returns whether it changed.
+    fn record_shown(
+        &mut self,
+        gallery: &str,
+        image: &Path,
+        output: Option<&str>,
+        trigger: Trigger,
+    ) -> bool {
+        if !self.events.image_changed(output, image, gallery, trigger) {
+            return false;
+        }
+        self.persistent.statistics.record(gallery, image);
+        self.persistent
+            .gallery_images
+            .insert(gallery.to_owned(), image.to_owned());
+        self.history.record(image, gallery, trigger);
+        true
+    }
+
+    /// Select an image of the `current_gallery`, see `select_valid_image_from`. The image to
+    /// replace is the one last shown from this gallery, e.g. for the position of a shuffle.
+    async fn select_valid_image(&mut self) -> Option<Selection> {
+        let gallery = self.persistent.current_gallery.clone()?;
+        let current = self
+            .persistent
+            .gallery_images
+            .get(&gallery)
+            .or(self.persistent.current_image.as_ref())
+            .cloned();
+        self.select_valid_image_from(&gallery, current.as_deref(), &HashSet::new())
+            .await
+    }
+
+    /// Select an image of `gallery` to replace `current`, preferring images not in `avoid`.
+    /// Images that fail to decode are quarantined if `validate_images` is set.
+    async fn select_valid_image_from(
+        &mut self,
+        gallery: &str,
+        current: Option<&Path>,
+        avoid: &HashSet<PathBuf>,
+    ) -> Option<Selection> {
+        loop {
+            let selection = self.select_random_image(gallery, current, avoid).await?;
+
+            if !self.validate_images || !is_corrupt_image(selection.image.clone()).await {
+                return Some(selection);
+            }
+
+            info!(
+                "Quarantining undecodable image '{}'",
+                selection.image.display()
+            );
+            self.persistent.quarantined.insert(selection.image);
+        }
+    }
+
+    /// Let the selection mode continue after `selection`, and return its image.
+    fn commit(&mut self, selection: Selection) -> PathBuf {
+        match selection.progress {
+            Progress::None => {}
+            Progress::RecentlySelected(gallery, buffer) => {
+                self.persistent.recently_selected.insert(gallery, buffer);
+            }
+            Progress::Shuffle(gallery, state) => {
+                self.persistent.shuffle.insert(gallery, state);
+            }
+            Progress::Cursor(key, cursor) => {
+                self.persistent.sequence_cursors.insert(key, cursor);
+            }
+        }
+        selection.image
+    }
+
+    /// The `prefetch_command` for `image`, if configured.
+    fn prefetch_pipeline(&self, image: &Path) -> Option<Pipeline> {
+        let command = self.prefetch_command.as_ref()?;
+        let values = Values {
+            image,
+            gallery: self
+                .persistent
+                .current_gallery
+                .as_deref()
+                .unwrap_or_default(),
+            monitor: "",
+            index: 0,
+        };
+        Some(Pipeline::new(
+            std::slice::from_ref(command),
+            &values,
+            false,
+            &self.command_settings,
+        ))
+    }
+
+    /// Write the sidecar of `image`, if enabled.
+    fn write_sidecar(&self, image: &Path) {
+        if let Some(stats) = self.persistent.statistics.image(image) {
+            let rating = self.persistent.ratings.get(image).copied();
+            if let Err(err) = sidecar::write(self.sidecar_format, &stats, rating) {
+                warn!("Failed to write sidecar: {err}");
+            }
+        }
+    }
+
+    /// Show `image` on the lock screen, if it follows the wallpaper. See `processed_image` for
+    /// `dry_run`.
+    async fn lockscreen_pipeline(&self, image: &Path, dry_run: bool) -> Option<Pipeline> {
+        let lockscreen = self.lockscreen.as_ref()?;
+        let processed = self
+            .processed_image(image, lockscreen.processing.as_ref(), dry_run)
+            .await;
+        let values = Values {
+            image: &processed,
+            gallery: self
+                .persistent
+                .current_gallery
+                .as_deref()
+                .unwrap_or_default(),
+            monitor: "",
+            index: 0,
+        };
+        Some(Pipeline::new(
+            &lockscreen.commands,
+            &values,
+            lockscreen.parallel_commands,
+            &self.command_settings,
+        ))
+    }
 
-            if tries_left == 0 {
-                return Some(selection.to_path_buf());
+    /// Extract the palette of `image` and export it, if enabled.
+    async fn update_palette(&mut self, image: &Path) {
+        let Some(config) = &self.palette_configuration else {
+            return;
+        };
+        self.palette = match palette::extract(image, config.colors).await {
+            Ok(palette) => palette,
+            Err(err) => {
+                warn!("Failed to extract the palette: {err:#}");
+                return;
             }
+        };
+        for export in &config.exports {
+            if let Err(err) = export.write(&self.palette, image) {
+                warn!("Failed to export the palette: {err:#}");
+            }
+        }
+    }
+
+    /// Log why the current gallery has no images, e.g. because of misconfigured folders.
+    async fn diagnose_no_images(&mut self) -> Response {
+        let Some(name) = self.persistent.current_gallery.clone() else {
+            return Response::InvalidGallery;
+        };
+
+        let galleries: Vec<_> = if name == ALL_GALLERIES {
+            self.galleries.values().cloned().collect()
+        } else {
+            self.galleries.get(&name).cloned().into_iter().collect()
+        };
 
-            let mut buf = self.persistent.recenty_selected.lock().unwrap();
+        let diagnoses = self
+            .with_index(move |index| {
+                let mut diagnoses = vec![];
+                for gallery in galleries {
+                    for source in &gallery.sources {
+                        let folder = &source.path;
+                        let single_folder = Gallery {
+                            sources: vec![source.clone()],
+                            ..gallery.clone()
+                        };
+                        let problem = match read_dir(folder) {
+                            Err(err) => format!("can't be read: {err}"),
+                            Ok(_) => match single_folder.scan_indexed(index).len() {
+                                0 => "contains no files".to_owned(),
+                                count => format!(
+                                    "all {count} file(s) are excluded, e.g. quarantined, banned or not pinned"
+                                ),
+                            },
+                        };
+                        diagnoses.push((folder.clone(), problem));
+                    }
+                }
+                diagnoses
+            })
+            .await
+            .unwrap_or_default();
+
+        warn!("Gallery '{name}' has no images to show");
+        for (folder, problem) in &diagnoses {
+            warn!(gallery = %name, folder = %folder.display(), "{problem}");
+        }
+
+        Response::NoImages {
+            gallery: name,
+            folders_checked: diagnoses.into_iter().map(|(folder, _)| folder).collect(),
+        }
+    }
 
-            if !buf.iter().any(|e| e == selection) {
-                buf.push(selection.to_path_buf());
-                return Some(selection.to_path_buf());
+    /// Iterate all folders of `gallery_name` and select one file to replace `current` according
+    /// to the `SelectionMode` of the gallery. Images in `avoid` are only selected if there are
+    /// no others, e.g. to show different images on each output.
+    /// While `safe_only` is set, unsafe galleries are replaced by all safe galleries.
+    /// Duplicate files count as a single image, see `duplicate_detection`.
+    async fn select_random_image(
+        &mut self,
+        gallery_name: &str,
+        current: Option<&Path>,
+        avoid: &HashSet<PathBuf>,
+    ) -> Option<Selection> {
+        let mut gallery_name = gallery_name.to_owned();
+
+        let safe_only = self.persistent.safe_only;
+        if safe_only && self.galleries.get(&gallery_name).is_some_and(|g| !g.safe) {
+            gallery_name = ALL_GALLERIES.to_owned();
+        }
+
+        let (galleries, buffer_size) = if gallery_name == ALL_GALLERIES {
+            let galleries: Vec<_> = self
+                .galleries
+                .values()
+                .filter(|gallery| gallery.safe || !safe_only)
+                .cloned()
+                .collect();
+            (galleries, self.recent_image_buffer_size)
+        } else {
+            let gallery = self.galleries.get(&gallery_name)?;
+            let buffer_size = gallery
+                .recent_image_buffer_size
+                .unwrap_or(self.recent_image_buffer_size);
+            (vec![gallery.clone()], buffer_size)
+        };
+
+        let pinned = if gallery_name != ALL_GALLERIES {
+            if !self.persistent.pinned.contains_key(&gallery_name)
+                && self.galleries.get(&gallery_name)?.pinned
+            {
+                self.pin_gallery(&gallery_name).await.ok()?;
             }
+            self.persistent.pinned.get(&gallery_name).cloned()
+        } else {
+            None
+        };
+
+        let selection = self.all_galleries_selection;
+        let quarantined = self.persistent.quarantined.clone();
+        let banned = self.persistent.banned.clone();
+        let mode = self.duplicate_detection;
+        let aspect_ratio = self.aspect_ratio;
+        let aspect_tolerance = self.aspect_tolerance;
+        let mut rng = StdRng::seed_from_u64(self.rng.gen());
+        let (mut all_files, metadata) = self
+            .with_index(move |index| {
+                let mut with_metadata = HashSet::new();
+                let files = match selection {
+                    AllGalleriesSelection::PerImage => {
+                        let mut files: Vec<_> = galleries
+                            .iter()
+                            .flat_map(|gallery| gallery.scan_annotated(index, &mut with_metadata))
+                            .collect();
+                        // Galleries may overlap, don't make shared images more likely
+                        files.sort();
+                        files.dedup();
+                        files
+                    }
+                    AllGalleriesSelection::PerGallery => {
+                        let galleries: Vec<_> = galleries
+                            .iter()
+                            .map(|gallery| gallery.scan_annotated(index, &mut with_metadata))
+                            .filter(|files| !files.is_empty())
+                            .collect();
+                        let mut files = galleries.choose(&mut rng).cloned().unwrap_or_default();
+                        // Folder listings are in no particular order, keep the selection
+                        // reproducible with a fixed `seed`
+                        files.sort();
+                        files
+                    }
+                };
+
+                let files = files
+                    .into_iter()
+                    .filter(|path| !quarantined.contains(path) && !banned.contains(path))
+                    .filter(|file| match &pinned {
+                        Some(pinned) => index
+                            .content_hash(file)
+                            .is_some_and(|hash| pinned.hashes.contains(&hash)),
+                        None => true,
+                    })
+                    .collect();
+                let files = index.deduplicate(files, mode);
+                let files = match aspect_ratio {
+                    Some(target) => index.batch(|index| {
+                        prefer_aspect_ratio(files, target, aspect_tolerance, |file| {
+                            index.dimensions(file)
+                        })
+                    }),
+                    None => files,
+                };
+
+                let metadata: HashMap<PathBuf, ImageMetadata> = index.batch(|index| {
+                    files
+                        .iter()
+                        .filter(|file| with_metadata.contains(*file))
+                        .filter_map(|file| Some((file.clone(), index.metadata(file)?)))
+                        .collect()
+                });
+                (files, metadata)
+            })
+            .await?;
+
+        let location = self.schedule.location();
+        let local_time = chrono::Local::now();
+        all_files.retain(|file| {
+            metadata
+                .get(file)
+                .is_none_or(|metadata| metadata.is_allowed_at(&local_time, location))
+        });
 
-            tries_left -= 1;
+        if all_files.iter().any(|file| avoid.contains(file)) {
+            let others: Vec<_> = all_files
+                .iter()
+                .filter(|file| !avoid.contains(*file))
+                .cloned()
+                .collect();
+            if !others.is_empty() {
+                all_files = others;
+            }
+        }
+
+        let gallery = self.galleries.get(&gallery_name);
+        let mode = gallery
+            .and_then(|gallery| gallery.selection)
+            .unwrap_or(self.selection_mode);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let random = matches!(mode, SelectionMode::Random | SelectionMode::Rated);
+
+        let repeat_window = gallery
+            .and_then(|gallery| gallery.repeat_window_ms)
+            .or(self.repeat_window_ms);
+        let has_cooldown = metadata.values().any(|m| m.cooldown_ms.is_some());
+        if random && (repeat_window.is_some() || has_cooldown) {
+            let statistics = &self.persistent.statistics;
+            let fresh: Vec<_> = all_files
+                .iter()
+                .filter(|file| {
+                    let window = metadata
+                        .get(*file)
+                        .and_then(|metadata| metadata.cooldown_ms)
+                        .or(repeat_window);
+                    let Some(window) = window else {
+                        return true;
+                    };
+                    let window = Duration::from_millis(window).as_secs();
+                    statistics
+                        .last_shown(file)
+                        .is_none_or(|shown| now.saturating_sub(shown) >= window)
+                })
+                .cloned()
+                .collect();
+            // Repeating an image is better than showing nothing
+            if !fresh.is_empty() {
+                all_files = fresh;
+            }
+        }
+
+        // The strategies work on copies of their state, see `Selection`
+        let cursor_key = format!("{gallery_name}:{mode:?}");
+        let mut recently_selected = None;
+        let mut shuffle = None;
+        let mut cursor = None;
+        match mode {
+            SelectionMode::Random | SelectionMode::Rated if buffer_size > 0 => {
+                recently_selected = Some(
+                    self.persistent
+                        .recently_selected(&gallery_name, buffer_size),
+                );
+            }
+            SelectionMode::Shuffle => {
+                shuffle = self.persistent.shuffle.get(&gallery_name).cloned();
+            }
+            SelectionMode::Alphabetical | SelectionMode::Chronological => {
+                // Keep the cursor of the other ordering, it's meaningless for this one
+                cursor = self.persistent.sequence_cursors.get(&cursor_key).cloned();
+            }
+            _ => {}
         }
+
+        let mut strategy: Box<dyn SelectionStrategy> = match mode {
+            SelectionMode::Random | SelectionMode::Rated => {
+                let mut weights = gallery.and_then(|gallery| gallery.image_weights(&all_files));
+                let mut scale = |factors: Vec<f64>| {
+                    weights = Some(match weights.take() {
+                        Some(weights) => weights.iter().zip(factors).map(|(w, f)| w * f).collect(),
+                        None => factors,
+                    });
+                };
+
+                if metadata.values().any(|m| m.weight.is_some()) {
+                    scale(
+                        all_files
+                            .iter()
+                            .map(|file| {
+                                metadata
+                                    .get(file)
+                                    .and_then(|metadata| metadata.weight)
+                                    .unwrap_or(1.0)
+                            })
+                            .collect(),
+                    );
+                }
+
+                if mode == SelectionMode::Rated {
+                    scale(
+                        all_files
+                            .iter()
+                            .map(|file| {
+                                let stars = self.persistent.ratings.get(file).copied();
+                                f64::from(stars.unwrap_or(UNRATED_STARS))
+                            })
+                            .collect(),
+                    );
+                }
+
+                let half_life = gallery
+                    .and_then(|gallery| gallery.recency_half_life_ms)
+                    .or(self.recency_half_life_ms);
+                if let Some(half_life) = half_life {
+                    let half_life = Duration::from_millis(half_life);
+                    let statistics = &self.persistent.statistics;
+                    scale(
+                        all_files
+                            .iter()
+                            .map(|file| recency_weight(statistics.last_shown(file), now, half_life))
+                            .collect(),
+                    );
+                }
+
+                // All weights being zero means that no image should be shown
+                let weights = match weights {
+                    Some(weights) => Some(WeightedIndex::new(weights).ok()?),
+                    None => None,
+                };
+
+                Box::new(Random {
+                    recently_selected: recently_selected.as_mut(),
+                    retries: self.number_retries,
+                    weights,
+                })
+            }
+            SelectionMode::Shuffle => Box::new(Shuffle {
+                state: shuffle.get_or_insert_with(ShuffleState::default),
+                current,
+            }),
+            SelectionMode::LeastRecentlyShown => Box::new(LeastRecentlyShown {
+                statistics: &self.persistent.statistics,
+            }),
+            SelectionMode::Alphabetical | SelectionMode::Chronological => Box::new(Sequential {
+                cursor: &mut cursor,
+                by_modification_time: mode == SelectionMode::Chronological,
+            }),
+        };
+
+        let image = strategy.select(&all_files, &mut self.rng)?;
+        drop(strategy);
+        let progress = if let Some(buffer) = recently_selected {
+            Progress::RecentlySelected(gallery_name, buffer)
+        } else if let Some(state) = shuffle {
+            Progress::Shuffle(gallery_name, state)
+        } else if let Some(cursor) = cursor {
+            Progress::Cursor(cursor_key, cursor)
+        } else {
+            Progress::None
+        };
+        Some(Selection { image, progress })
+    }
+
+    /// Run `task` with the `image_index` on a blocking thread.
+    /// Hashing may need to read every file, this way other tasks are not blocked while doing so.
+    /// The index stays in place if `task` panics, which returns None.
+    async fn with_index<R: Send + 'static>(
+        &self,
+        task: impl FnOnce(&mut ImageIndex) -> R + Send + 'static,
+    ) -> Option<R> {
+        let index = self.image_index.clone();
+        tokio::task::spawn_blocking(move || {
+            // The database rolls back what a panicking task left unfinished, so it is still usable
+            let mut index = index.lock().unwrap_or_else(PoisonError::into_inner);
+            task(&mut index)
+        })
+        .await
+        .ok()
+    }
+
+    /// Restart the random number generator with `seed`, or the configured seed if None.
+    fn reseed(&mut self, seed: Option<u64>) {
+        self.next_image = None;
+        self.rng = match seed.or(self.seed) {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+    }
+
+    /// Snapshot the current images of gallery `name`, see `Request::PinGallery`.
+    async fn pin_gallery(&mut self, name: &str) -> Result<()> {
+        let gallery = self
+            .galleries
+            .get(name)
+            .ok_or_else(|| anyhow!("Invalid gallery '{name}'"))?
+            .clone();
+
+        let hashes = self
+            .with_index(move |index| {
+                gallery
+                    .scan_indexed(index)
+                    .iter()
+                    .filter_map(|file| index.content_hash(file))
+                    .collect()
+            })
+            .await
+            .ok_or_else(|| anyhow!("Failed to hash images"))?;
+
+        self.persistent.pinned.insert(
+            name.to_owned(),
+            PinnedSnapshot {
+                created: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+                hashes,
+            },
+        );
+        self.persist();
+        Ok(())
     }
 
     async fn handle_message(&mut self, msg: Box<dyn InflightRequest>) {
         use Request::*;
 
+        let msg = if let Ok(Subscribe) = msg.request() {
+            match msg.into_event_writer() {
+                Ok(writer) => return self.events.subscribe(writer),
+                Err(msg) => msg,
+            }
+        } else {
+            msg
+        };
+
         let response = match msg.request() {
-            Ok(NextImage) => {
-                self.update().await;
-                self.update_interval.reset();
-                Response::NewImage
+            Ok(NextImage { dry_run }) => {
+                let response = self
+                    .update_image(Trigger::Request, *dry_run || self.dry_run)
+                    .await;
+                self.update_interval.restart_ramp();
+                response
+            }
+            Ok(TrashCurrent { image: Some(image) })
+                if self.persistent.current_image.as_ref() != Some(image) =>
+            {
+                Response::Error {
+                    message: format!("'{}' is no longer the current image", image.display()),
+                }
+            }
+            Ok(TrashCurrent { .. }) => match self.persistent.current_image.take() {
+                Some(image) => match trash::trash(&image, self.trash_directory.as_deref()) {
+                    Ok(target) => {
+                        info!("Moved '{}' to '{}'", image.display(), target.display());
+                        let response = self.update(Trigger::Request).await;
+                        self.update_interval.reset();
+                        response
+                    }
+                    Err(err) => {
+                        self.persistent.current_image = Some(image);
+                        Response::Error {
+                            message: format!("{err:#}"),
+                        }
+                    }
+                },
+                None => Response::Error {
+                    message: "No image is currently shown".to_owned(),
+                },
+            },
+            Ok(BanCurrent) => match self.persistent.current_image.clone() {
+                Some(image) => {
+                    info!("Banned '{}'", image.display());
+                    self.persistent.banned.insert(image);
+                    self.persist();
+                    let response = self.update(Trigger::Request).await;
+                    self.update_interval.reset();
+                    response
+                }
+                None => Response::Error {
+                    message: "No image is currently shown".to_owned(),
+                },
+            },
+            Ok(Unban { image }) => {
+                if self.persistent.banned.remove(image) {
+                    self.persist();
+                    Response::Ok
+                } else {
+                    Response::Error {
+                        message: format!("'{}' is not banned", image.display()),
+                    }
+                }
+            }
+            Ok(CollectCurrent { destination }) => {
+                match (
+                    &self.persistent.current_image,
+                    destination.as_ref().or(self.collect_directory.as_ref()),
+                ) {
+                    (None, _) => Response::Error {
+                        message: "No image is currently shown".to_owned(),
+                    },
+                    (Some(_), None) => Response::BadRequest {
+                        message: "No destination given and no `collect_directory` configured"
+                            .to_owned(),
+                    },
+                    (Some(image), Some(dir)) => match trash::collect(image, dir) {
+                        Ok(target) => {
+                            info!("Copied '{}' to '{}'", image.display(), target.display());
+                            Response::Ok
+                        }
+                        Err(err) => Response::Error {
+                            message: format!("{err:#}"),
+                        },
+                    },
+                }
             }
+            Ok(RateCurrent { stars }) => match self.persistent.current_image.clone() {
+                None => Response::Error {
+                    message: "No image is currently shown".to_owned(),
+                },
+                Some(_) if *stars > MAX_STARS => Response::BadRequest {
+                    message: format!("Ratings range from 0 to {MAX_STARS} stars"),
+                },
+                Some(image) => {
+                    self.persistent.ratings.insert(image.clone(), *stars);
+                    self.write_sidecar(&image);
+                    self.persist();
+                    Response::Ok
+                }
+            },
             Ok(UpdateInterval { millis }) => {
-                let was_paused = self.update_interval.is_paused();
-                self.update_interval = PausableInterval::new(Duration::from_millis(*millis));
-                self.update_interval.pause(was_paused);
-                Response::NewImage
+                let period = Duration::from_millis(*millis);
+                if let UpdateTimer::Interval(interval) = &mut self.update_interval {
+                    interval.set_period(period);
+                } else {
+                    let was_paused = self.update_interval.is_paused();
+                    self.update_interval = UpdateTimer::Interval(PausableInterval::new(period));
+                    self.update_interval.pause(was_paused);
+                }
+                Response::NewImage { image: None }
             }
-            Ok(SelectGallery { name, refresh }) => {
+            Ok(SelectGallery {
+                name,
+                refresh,
+                resume,
+            }) => {
                 if let Err(err) = self.change_gallery(name) {
-                    eprintln!("Failed to change gallery to '{name}': {err}");
+                    warn!("Failed to change gallery to '{name}': {err}");
                     Response::InvalidGallery
+                } else if *resume {
+                    self.next_image =
+                        self.persistent
+                            .gallery_images
+                            .get(name)
+                            .map(|image| Selection {
+                                image: image.clone(),
+                                progress: Progress::None,
+                            });
+                    self.update(Trigger::Request).await
+                } else if *refresh {
+                    self.update(Trigger::Request).await
                 } else {
-                    if *refresh {
-                        self.update().await;
-                    }
-
-                    Response::NewImage
+                    Response::NewImage { image: None }
                 }
             }
             Ok(s @ Pause | s @ Resume) => {
-                self.update_interval.pause(matches!(s, Pause));
-                self.persistent.is_paused = self.update_interval.is_paused();
+                let paused = matches!(s, Pause);
+                if self.persistent.is_paused != paused {
+                    self.events.send(Event::PauseChanged { paused });
+                }
+                self.persistent.is_paused = paused;
+                self.apply_pause();
+                self.persist();
+                Response::Ok
+            }
+            Ok(ExportGallery { name, path }) => match self.galleries.get(name) {
+                Some(gallery) => match gallery_file::export(gallery, path) {
+                    Ok(()) => Response::Ok,
+                    Err(err) => Response::Error {
+                        message: format!("{err:#}"),
+                    },
+                },
+                None => Response::InvalidGallery,
+            },
+            Ok(ImportGallery { path, replace }) => match self.import_gallery(path, *replace) {
+                Ok(()) => Response::Ok,
+                Err(err) => Response::Error {
+                    message: format!("{err:#}"),
+                },
+            },
+            Ok(ExportState { path }) => match self.export_state(path).await {
+                Ok(()) => Response::Ok,
+                Err(err) => Response::Error {
+                    message: format!("{err:#}"),
+                },
+            },
+            Ok(ImportState { path }) => match self.import_state(path).await {
+                Ok(()) => Response::Ok,
+                Err(err) => Response::Error {
+                    message: format!("{err:#}"),
+                },
+            },
+            Ok(PinGallery { name }) => {
+                if !self.galleries.contains_key(name) {
+                    Response::InvalidGallery
+                } else if let Err(err) = self.pin_gallery(name).await {
+                    Response::Error {
+                        message: format!("{err:#}"),
+                    }
+                } else {
+                    Response::Ok
+                }
+            }
+            Ok(Unquarantine { image: Some(image) }) => {
+                if self.persistent.quarantined.remove(image) {
+                    self.next_image = None;
+                    self.persist();
+                    Response::Ok
+                } else {
+                    Response::Error {
+                        message: format!("'{}' is not quarantined", image.display()),
+                    }
+                }
+            }
+            Ok(Unquarantine { image: None }) => {
+                let count = self.persistent.quarantined.clear();
+                info!("Released {count} image(s) from the quarantine");
+                self.next_image = None;
                 self.persist();
                 Response::Ok
             }
+            Ok(UnpinGallery { name }) => {
+                if self.galleries.contains_key(name) {
+                    self.persistent.pinned.remove(name);
+                    self.next_image = None;
+                    self.persist();
+                    Response::Ok
+                } else {
+                    Response::InvalidGallery
+                }
+            }
+            Ok(SelectProfile { name }) => match self.select_profile(name).await {
+                Ok(()) => self.update(Trigger::Request).await,
+                Err(err) => Response::Error {
+                    message: format!("{err:#}"),
+                },
+            },
+            Ok(SafeMode { enabled }) => {
+                let changed = self.persistent.safe_only != *enabled;
+                self.persistent.safe_only = *enabled;
+                self.next_image = None;
+                if changed && *enabled {
+                    // Replace the current image, it might be from an unsafe gallery
+                    let response = self.update(Trigger::Request).await;
+                    self.update_interval.reset();
+                    response
+                } else {
+                    self.persist();
+                    Response::Ok
+                }
+            }
+            Ok(GetStatus) => Response::Status {
+                gallery: self.persistent.current_gallery.clone(),
+                current_image: self.persistent.current_image.clone(),
+                next_image: self.next_image.as_ref().map(|next| next.image.clone()),
+                paused: self.persistent.is_paused,
+                safe_only: self.persistent.safe_only,
+                power_saving: self.power_saving(),
+                elapsed_ms: self
+                    .interval()
+                    .map(|interval| interval.elapsed().as_millis() as u64),
+                remaining_ms: self
+                    .interval()
+                    .map(|interval| interval.remaining().as_millis() as u64),
+                palette: self.palette.iter().map(Color::to_string).collect(),
+                profile: self.profile.clone(),
+                safe_mode: self.safe_mode,
+            },
+            Ok(Reseed { seed }) => {
+                self.reseed(*seed);
+                Response::Ok
+            }
+            Ok(ListGalleries) => {
+                let mut galleries: Vec<_> = self.galleries.values().cloned().collect();
+                galleries.sort_by(|a, b| a.name.cmp(&b.name));
+
+                let counts = self
+                    .with_index(move |index| {
+                        galleries
+                            .into_iter()
+                            .map(|g| {
+                                let count = g.scan_indexed(index).len();
+                                (g, count)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .await;
+
+                match counts {
+                    Some(counts) => Response::Galleries {
+                        galleries: counts
+                            .into_iter()
+                            .map(|(g, number_images)| GalleryInfo {
+                                active: self.persistent.current_gallery.as_ref() == Some(&g.name),
+                                safe: g.safe,
+                                pinned: self.persistent.pinned.contains_key(&g.name),
+                                number_images,
+                                name: g.name,
+                            })
+                            .collect(),
+                    },
+                    None => Response::Error {
+                        message: "Failed to scan galleries".to_owned(),
+                    },
+                }
+            }
+            Ok(Stats { gallery, limit }) => {
+                let galleries: Option<Vec<_>> = match gallery {
+                    Some(name) => self
+                        .galleries
+                        .get(name)
+                        .map(|gallery| vec![gallery.clone()]),
+                    None => Some(self.galleries.values().cloned().collect()),
+                };
+
+                match galleries {
+                    Some(mut galleries) => {
+                        galleries.sort_by(|a, b| a.name.cmp(&b.name));
+
+                        let scans = self
+                            .with_index(move |index| {
+                                galleries
+                                    .into_iter()
+                                    .map(|g| {
+                                        let files = g.scan_indexed(index);
+                                        (g.name, files)
+                                    })
+                                    .collect::<Vec<_>>()
+                            })
+                            .await;
+
+                        let statistics = &self.persistent.statistics;
+                        match scans {
+                            Some(scans) => Response::Stats {
+                                galleries: scans
+                                    .iter()
+                                    .map(|(name, files)| statistics.report(name, files, *limit))
+                                    .collect(),
+                            },
+                            None => Response::Error {
+                                message: "Failed to scan galleries".to_owned(),
+                            },
+                        }
+                    }
+                    None => Response::InvalidGallery,
+                }
+            }
+            Ok(Subscribe) => Response::BadRequest {
+                message: "Events can only be received on Unix sockets and TCP".to_owned(),
+            },
+            Ok(History { gallery, limit }) => {
+                match self.history.query(gallery.as_deref(), *limit).await {
+                    Ok(entries) => Response::History { entries },
+                    Err(err) => Response::Error {
+                        message: format!("Failed to read the history: {err:#}"),
+                    },
+                }
+            }
+            Ok(Diagnose) => {
+                let mut galleries: Vec<_> = self.galleries.values().cloned().collect();
+                galleries.sort_by(|a, b| a.name.cmp(&b.name));
+                let commands = self.display_commands.clone();
+                let storage = self.storage.clone();
+                let checks = tokio::task::spawn_blocking(move || {
+                    doctor::diagnose(&galleries, &commands, storage.as_ref())
+                })
+                .await;
+                match checks {
+                    Ok(checks) => Response::Diagnosis { checks },
+                    Err(err) => Response::Error {
+                        message: format!("Failed to diagnose: {err}"),
+                    },
+                }
+            }
+            Ok(RecentEvents { limit }) => Response::RecentEvents {
+                events: events::recent(*limit),
+            },
+            Ok(Shutdown) => {
+                info!("Shutting down on request");
+                self.shutdown_requested = true;
+                Response::Ok
+            }
             Err(err) => Response::BadRequest {
                 message: err.to_string(),
             },
@@ -326,7 +2412,7 @@ impl ApplicationState {
         let result = msg.respond(response).await;
 
         if let Err(err) = result {
-            eprintln!("Error responding to request: {err}");
+            warn!("Error responding to request: {err}");
         }
     }
 
@@ -337,159 +2423,852 @@ impl ApplicationState {
         loop {
             select! {
                 TickResult::Completed = self.update_interval.tick() => {
-                    self.update().await;
+                    self.update(Trigger::Timer).await;
+                },
+
+                index = rotation::next_due(&mut self.rotations) => {
+                    self.update_rotation(index).await;
+                },
+
+                Some(monitor) = self.long_running.exited() => {
+                    self.restart_display(&monitor).await;
                 },
 
                 // If an update finished, then reset the update task back to none
-                _ = async {self.update_task.as_mut().unwrap().await}, if self.update_task.is_some() => {
-                    self.update_task = self.pending_update.take().map(|mut cmd| {
-                        tokio::spawn(
-                            async move { cmd.spawn().unwrap().wait().await },
-                        )
-                    });
+                result = async {self.update_task.as_mut().unwrap().await}, if self.update_task.is_some() => {
+                    if let Ok(Err(err)) = result {
+                        warn!("Display command failed: {err:#}");
+                    }
+                    self.update_task = self
+                        .pending_update
+                        .take()
+                        .map(|pipelines| pipeline::spawn_all(pipelines).0);
                 },
 
                 Some(message) = self.message_queue.recv() => {
                     match message {
                         Ok(message) => self.handle_message(message).await,
-                        Err(err) => { eprintln!("Error while receiving messages!: {err}"); return; },
+                        Err(err) => { error!("Error while receiving messages!: {err}"); break; },
+                    }
+                    if self.shutdown_requested {
+                        break;
+                    }
+                },
+
+                fullscreen = async { self.fullscreen.as_mut().unwrap().changed().await }, if self.fullscreen.is_some() => {
+                    if fullscreen {
+                        info!("Fullscreen window focused, pausing rotation");
+                    } else {
+                        info!("Fullscreen window left, resuming rotation");
+                    }
+                    self.fullscreen_active = fullscreen;
+                    self.apply_pause();
+                },
+
+                idle = async { self.idle.as_mut().unwrap().changed().await }, if self.idle.is_some() => {
+                    if idle {
+                        info!("Session is idle, pausing rotation");
+                    } else {
+                        info!("Session is active again, resuming rotation");
+                    }
+                    self.idle_active = idle;
+                    self.apply_pause();
+                },
+
+                saving = async { self.power.as_mut().unwrap().changed().await }, if self.power.is_some() => {
+                    info!("Power saving changed to {saving:?}");
+                    self.apply_gallery_interval();
+                    self.apply_pause();
+                },
+
+                suspended = async { self.suspend.as_mut().unwrap().resumed().await }, if self.suspend.is_some() => {
+                    info!("Resumed after being suspended for {}s", suspended.as_secs());
+                    let behavior = self.suspend.as_ref().map(SuspendMonitor::behavior);
+                    if behavior == Some(ResumeBehavior::Update) && !self.update_interval.is_paused() {
+                        self.update(Trigger::Resume).await;
                     }
+                    self.update_interval.reset();
+                },
+
+                outputs = async { self.hotplug.as_mut().unwrap().changed().await }, if self.hotplug.is_some() => {
+                    self.outputs_changed(outputs).await;
+                },
+
+                quiet = self.quiet_hours.changed() => {
+                    if quiet {
+                        info!("Quiet hours started, pausing rotation");
+                    } else {
+                        info!("Quiet hours ended, resuming rotation");
+                    }
+                    self.apply_pause();
+                },
+
+                name = self.schedule.changed() => {
+                    info!("Schedule selects gallery '{name}'");
+                    if let Err(err) = self.change_gallery(&name) {
+                        warn!("Failed to change gallery to '{name}': {err}");
+                    } else {
+                        self.update(Trigger::Schedule).await;
+                    }
+                },
+
+                () = async { self.config_watcher.as_mut().unwrap().changed().await }, if self.config_watcher.is_some() => {
+                    self.reload_configuration().await;
+                },
+
+                // Only pinged from here, so a stuck loop is noticed
+                () = async { self.systemd.as_mut().unwrap().watchdog_due().await }, if self.systemd.is_some() => {
+                    self.systemd.as_ref().unwrap().ping_watchdog();
                 },
 
                 _ = &mut shutdown_task => break,
             }
         }
-    }
 
-    pub async fn update_configuration(&mut self, config: &Configuration) -> Result<()> {
-        for mut gallery in config.galleries.iter().cloned() {
-            for folder in gallery.sources.iter_mut() {
-                if let Cow::Owned(path) = expand_tilde(folder)? {
-                    *folder = path;
-                }
-            }
+        self.shut_down().await;
+    }
+
+    /// Stop accepting requests, let the running update finish for up to `SHUTDOWN_TIMEOUT`, and
+    /// save the state.
+    async fn shut_down(&mut self) {
+        if let Some(systemd) = &self.systemd {
+            systemd.stopping();
+        }
+
+        // Removes the sockets, so clients don't wait for responses which never come
+        for (_, source) in std::mem::take(&mut self.message_sources) {
+            source.stop().await;
+        }
+        self.message_queue.close();
+
+        if self.pending_update.take().is_some() {
+            info!("Discarding pending update");
+        }
+        if let Some(mut task) = self.update_task.take() {
+            match tokio::time::timeout(SHUTDOWN_TIMEOUT, &mut task).await {
+                Ok(Ok(Err(err))) => warn!("Display command failed: {err:#}"),
+                Ok(_) => {}
+                Err(_) => {
+                    warn!(
+                        "Display command still running after {}s, stopping it",
+                        SHUTDOWN_TIMEOUT.as_secs()
+                    );
+                    // Kills the command
+                    task.abort();
+                    let _ = task.await;
+                }
+            }
+        }
+
+        if self.resume_interval {
+            self.persistent.interval_elapsed_ms = self
+                .interval()
+                .map(|interval| interval.elapsed().as_millis() as u64);
+            self.persist();
+        }
+        self.state_writer.flush().await;
+    }
+
+    pub async fn update_configuration(&mut self, config: &Configuration) -> Result<()> {
+        self.apply_configuration(config, false).await
+    }
+
+    /// Read the configuration file again and apply it, see `Configuration::reload_on_change`.
+    /// If it can't be read or is invalid, the previous configuration stays in effect.
+    async fn reload_configuration(&mut self) {
+        let Some(watcher) = &mut self.config_watcher else {
+            return;
+        };
+        let path = watcher.path().to_owned();
+        let result = match read_configuration(watcher.source()) {
+            Ok(mut config) => {
+                watcher.poll = config.reload_on_change;
+                if self.safe_mode {
+                    config.enter_safe_mode();
+                }
+                self.apply_configuration(&config, true).await
+            }
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => info!("Reloaded the configuration"),
+            Err(err) => warn!("Failed to reload '{}': {err:#}", path.display()),
+        }
+    }
+
+    /// Apply the configuration with the profile `name` and switch to its default gallery, see
+    /// `Request::SelectProfile`. The profile stays selected when the configuration is reloaded.
+    async fn select_profile(&mut self, name: &str) -> Result<()> {
+        let Some(watcher) = &self.config_watcher else {
+            bail!("Profiles can only be selected while the daemon is running");
+        };
+        let mut source = watcher.source().clone();
+        source.profile = Some(name.to_owned());
+        let mut config = read_configuration(&source)?;
+        if self.safe_mode {
+            config.enter_safe_mode();
+        }
+        self.apply_configuration(&config, true).await?;
+        self.change_gallery(&config.default_gallery)?;
+        if let Some(watcher) = &mut self.config_watcher {
+            *watcher.source_mut() = source;
+        }
+        Ok(())
+    }
+
+    /// Apply `config`, either at startup or when `reloading` it while running. A reload keeps the
+    /// current gallery, the persistent state, the progress of the timer, and the listeners,
+    /// rotations, outputs and random numbers which didn't change. Nothing is applied if `config`
+    /// is invalid.
+    async fn apply_configuration(&mut self, config: &Configuration, reloading: bool) -> Result<()> {
+        // Everything which can fail is prepared before anything is replaced, so a rejected reload
+        // keeps the previous configuration in effect
+        let galleries = config.galleries()?;
+        let gallery_names: HashSet<String> = self
+            .galleries
+            .keys()
+            .filter(|name| !self.configured_galleries.contains(name))
+            .chain(galleries.iter().map(|gallery| &gallery.name))
+            .cloned()
+            .collect();
+        let is_valid_gallery = |name: &str| name == ALL_GALLERIES || gallery_names.contains(name);
+        if !is_valid_gallery(&config.default_gallery) {
+            bail!("Invalid gallery '{}'", config.default_gallery);
+        }
+
+        let display_commands = config
+            .command_lines()?
+            .parse()
+            .context("Invalid `command_line`")?;
+        if config.max_running_commands == 0 {
+            bail!("`max_running_commands` must be at least 1");
+        }
+        let command_settings = CommandSettings {
+            timeout: config.command_timeout_ms.map(Duration::from_millis),
+            environment: config.environment.clone(),
+            limit: Some(Arc::new(Semaphore::new(config.max_running_commands))),
+            stdout_to_stderr: config.listeners.contains(&ListenerConfiguration::Stdin),
+        };
+        let storage = config.storage()?;
+        let mut palette_configuration = config.palette.clone();
+        if let Some(palette) = &mut palette_configuration {
+            for export in &mut palette.exports {
+                export.path = expand::path(&export.path)?.into_owned();
+                if let Some(template) = &mut export.template {
+                    *template = expand::path(template)?.into_owned();
+                }
+            }
+        }
+        let prefetch_command = config
+            .prefetch_command
+            .as_deref()
+            .map(CommandLine::parse)
+            .transpose()
+            .context("Invalid `prefetch_command`")?;
+        if config.interval_ramp.is_some_and(|ramp| ramp.factor < 1.0) {
+            bail!("The factor of `interval_ramp` must be at least 1");
+        }
+        let default_update_interval = Duration::from_millis(config.update_interval_ms);
+        let trash_directory = config
+            .trash_directory
+            .as_deref()
+            .map(|dir| expand::path(dir).map(Cow::into_owned))
+            .transpose()?;
+        let collect_directory = config
+            .collect_directory
+            .as_deref()
+            .map(|dir| expand::path(dir).map(Cow::into_owned))
+            .transpose()?;
+        let image_index = match &config.index_file {
+            Some(filename) => {
+                let state_dir = state_dir();
+                std::fs::create_dir_all(&state_dir)?;
+                Some(ImageIndex::open(&state_dir.join(expand::path(filename)?))?)
+            }
+            None => None,
+        };
+
+        let schedule = Schedule::new(
+            config.schedule.clone(),
+            config.location,
+            config.default_gallery.clone(),
+        )?;
+        if let Some(name) = schedule.galleries().find(|g| !is_valid_gallery(g)) {
+            bail!("Schedule refers to unknown gallery '{name}'");
+        }
+        let quiet_hours = QuietHours::new(config.quiet_hours.clone(), config.location)?;
+
+        let lockscreen_rotation = config
+            .lockscreen
+            .as_ref()
+            .and_then(|lockscreen| lockscreen.rotation(&config.default_gallery));
+        let lockscreen = match &config.lockscreen {
+            Some(lockscreen) if lockscreen_rotation.is_none() => Some(Lockscreen::new(lockscreen)?),
+            _ => None,
+        };
+        let mut rotations: Vec<Rotation> = config
+            .rotations
+            .iter()
+            .chain(&lockscreen_rotation)
+            .map(|rotation| Rotation::new(rotation, default_update_interval, &command_settings))
+            .collect::<Result<_>>()?;
+        for (index, rotation) in rotations.iter().enumerate() {
+            if !is_valid_gallery(&rotation.gallery) {
+                bail!(
+                    "Rotation '{}' refers to unknown gallery '{}'",
+                    rotation.name,
+                    rotation.gallery
+                );
+            }
+            if rotations[..index]
+                .iter()
+                .any(|other| other.name == rotation.name)
+            {
+                bail!("Duplicate rotation '{}'", rotation.name);
+            }
+        }
+
+        let mut outputs: Vec<Output> = config
+            .outputs
+            .iter()
+            .map(Output::new)
+            .collect::<Result<_>>()?;
+        for output in &outputs {
+            if let Some(gallery) = output.gallery.as_ref().filter(|g| !is_valid_gallery(g)) {
+                bail!(
+                    "Output '{}' refers to unknown gallery '{gallery}'",
+                    output.name
+                );
+            }
+        }
+
+        // Last, as it can't be undone completely
+        self.replace_listeners(&config.listeners).await?;
+
+        self.profile = config.profile.clone();
+        for name in std::mem::take(&mut self.configured_galleries) {
+            self.galleries.remove(&name);
+        }
+        for gallery in galleries {
+            self.configured_galleries.push(gallery.name.clone());
             self.add_gallery(gallery);
         }
 
-        self.change_gallery(&config.default_gallery)?;
-
-        let mut cmdline = config.command_line.split(' ');
+        let current = self
+            .persistent
+            .current_gallery
+            .clone()
+            .filter(|name| reloading && self.is_valid_gallery(name));
+        match current {
+            Some(name) => self.change_gallery(&name)?,
+            None => self.change_gallery(&config.default_gallery)?,
+        }
 
-        let cmd = AsRef::<OsStr>::as_ref(&cmdline.next().ok_or_else(|| anyhow!("Need a command"))?)
-            .to_os_string();
+        self.display_commands = display_commands;
+        self.parallel_commands = config.parallel_commands;
+        self.command_settings = command_settings;
+        self.long_running_command = config.long_running_command;
+        self.processing = config.processing.clone();
+        self.current_link = config.current_link;
+        self.storage = storage;
+        let database = match &self.storage {
+            Some(Storage::Sqlite { path }) => Some(path.clone()),
+            _ => None,
+        };
+        self.history = History::new(&config.history, state_dir(), database);
+        self.notifications = config.notifications;
+        self.dry_run = config.dry_run;
+        self.safe_mode = config.safe_mode;
+        self.palette_configuration = palette_configuration;
+        if !self.long_running_command {
+            self.long_running.clear();
+        }
+        self.prefetch_command = prefetch_command;
 
-        self.display_command = cmd;
-        self.display_args = parse_args(cmdline).collect();
+        self.default_update_interval = default_update_interval;
+        self.default_update_schedule = config.update_schedule.clone();
+        self.interval_ramp = config.interval_ramp;
+        self.one_shot = config.one_shot;
+        self.resume_interval = config.resume_interval;
 
-        self.update_interval =
-            PausableInterval::new(Duration::from_millis(config.update_interval_ms));
+        self.number_retries = config.number_retries;
+        self.validate_images = config.validate_images;
+        self.duplicate_detection = config.duplicate_detection;
+        self.all_galleries_selection = config.all_galleries_selection;
+        self.selection_mode = config.selection;
+        self.recency_half_life_ms = config.recency_half_life_ms;
+        self.repeat_window_ms = config.repeat_window_ms;
+        let seed_changed = self.seed != config.seed;
+        self.seed = config.seed;
+        self.power = config.battery.clone().map(PowerMonitor::new);
+        self.suspend = (config.on_resume != ResumeBehavior::Ignore)
+            .then(|| SuspendMonitor::new(config.on_resume));
+        // The new monitors report the current state again, a stale one could pause forever
+        self.idle_active = false;
+        self.idle = config
+            .idle
+            .map(idle::watch)
+            .transpose()
+            .unwrap_or_else(|err| {
+                warn!("Failed to start detecting idle sessions: {err:#}");
+                None
+            })
+            .flatten();
+        self.fullscreen_active = false;
+        self.fullscreen = fullscreen::watch(config.pause_on_fullscreen).unwrap_or_else(|err| {
+            warn!("Failed to start detecting fullscreen windows: {err:#}");
+            None
+        });
+        // Restarting the generator on each reload would repeat the same images
+        if !reloading || seed_changed {
+            self.reseed(config.seed);
+        }
+        self.aspect_ratio = config.aspect_ratio;
+        self.aspect_tolerance = config.aspect_tolerance;
+        self.sidecar_format = config.sidecar;
+        self.trash_directory = trash_directory;
+        self.collect_directory = collect_directory;
+        self.recent_image_buffer_size = config.recent_image_buffer_size;
+        events::keep_recent(config.recent_events);
 
-        for listener in &config.listeners {
-            self.connect_listener(listener).await?;
+        if let Some(image_index) = image_index {
+            self.image_index = Arc::new(Mutex::new(image_index));
         }
 
-        self.number_retries = config.number_retries;
-        {
-            let mut buf = self.persistent.recenty_selected.lock().unwrap();
-            if config.recent_image_buffer_size != buf.capacity() {
-                *buf = CircularQueue::with_capacity(config.recent_image_buffer_size);
+        // Only at startup, where failing ends the daemon anyway
+        if let Some(storage) = self.storage.clone().filter(|_| !reloading) {
+            if let Some(dir) = storage.path().parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            let apply = |state| self.update_persistent_state(state);
+            match &storage {
+                Storage::Json { path } => {
+                    state_file::load::<PersistentState>(path, apply);
+                }
+                Storage::Sqlite { path } => {
+                    state_file::load_database::<PersistentState>(path, apply)?;
+                }
+            }
+            if self.persistent.current_gallery.is_none() {
+                self.change_gallery(&config.default_gallery)?;
             }
         }
 
-        self.storage_file = config.storage_file.clone();
+        self.schedule = schedule;
+        self.quiet_hours = quiet_hours;
 
-        if let Some(filename) = &self.storage_file {
-            let dirs = project_dirs();
-            let state_dir = dirs.state_dir().unwrap_or_else(|| dirs.cache_dir());
-            std::fs::create_dir_all(state_dir)?;
-            let state_file = state_dir.join(filename);
-            if state_file.exists() {
-                let make_ctx = || anyhow!("Failed to open state file '{}'", state_file.display());
-                let mut state = std::fs::File::open(&state_file).with_context(make_ctx)?;
-
-                let mut text = String::new();
-                state.read_to_string(&mut text).with_context(make_ctx)?;
+        if config.safe_mode {
+            self.change_gallery(&config.default_gallery)?;
+        } else if let Some(name) = self.schedule.current() {
+            self.change_gallery(&name)?;
+        }
 
-                let state: PersistentState =
-                    serde_json::from_str(&text).context("Failed to parse persisted state")?;
-                if self.update_persistent_state(state).is_err() {
-                    std::fs::remove_file(&state_file).with_context(|| {
-                        anyhow!(
-                            "Failed to delete corrupt state file '{}'",
-                            state_file.display()
-                        )
-                    })?;
-                }
+        self.lockscreen = lockscreen;
+        let mut previous_rotations = std::mem::take(&mut self.rotations);
+        // A fresh timer would show the next image right away
+        for rotation in &mut rotations {
+            if let Some(index) = previous_rotations
+                .iter()
+                .position(|previous| previous.name == rotation.name)
+            {
+                rotation.continue_from(previous_rotations.swap_remove(index));
             }
         }
+        self.rotations = rotations;
+
+        let previous_outputs = std::mem::take(&mut self.outputs);
+        for output in &mut outputs {
+            output.current_image = previous_outputs
+                .iter()
+                .find(|previous| previous.name == output.name && previous.gallery == output.gallery)
+                .and_then(|previous| previous.current_image.clone());
+        }
+        self.outputs = outputs;
+        self.distinct_outputs = config.distinct_outputs;
+        self.connected_outputs = None;
+        self.hotplug = hotplug::watch(config.hotplug).unwrap_or_else(|err| {
+            warn!("Failed to start detecting connected monitors: {err:#}");
+            None
+        });
+
+        if reloading {
+            self.apply_gallery_interval();
+            self.apply_pause();
+            return Ok(());
+        }
+
+        // A fresh timer ticks immediately, which is consumed below if no update is wanted
+        self.update_interval = self.gallery_timer();
+        self.apply_pause();
 
-        if !config.update_immediately {
+        // Taken even if unused, so a crash doesn't resume an outdated interval
+        let elapsed = self.persistent.interval_elapsed_ms.take();
+        if let (true, Some(elapsed), UpdateTimer::Interval(interval)) =
+            (self.resume_interval, elapsed, &mut self.update_interval)
+        {
+            interval.resume_from(Duration::from_millis(elapsed));
+        } else if !config.update_immediately {
             self.update_interval.tick().await;
+            for rotation in &mut self.rotations {
+                rotation.timer.reset();
+            }
         }
 
         Ok(())
     }
 
+    /// Start the `listeners` which aren't running yet, and stop the ones no longer configured.
+    /// If a listener fails to start, the previous listeners are started again.
+    async fn replace_listeners(&mut self, listeners: &[ListenerConfiguration]) -> Result<()> {
+        // Restarting a listener would drop its connections, and could race with the old one
+        // removing its socket
+        let (kept, removed): (Vec<_>, Vec<_>) = std::mem::take(&mut self.message_sources)
+            .into_iter()
+            .partition(|(listener, _)| listeners.contains(listener));
+        // Stopped first, a changed listener may use the same address
+        let removed: Vec<_> = removed.into_iter().map(|(listener, _)| listener).collect();
+        self.message_sources = kept;
+        let mut added = vec![];
+        for listener in listeners {
+            if self
+                .message_sources
+                .iter()
+                .any(|(running, _)| running == listener)
+            {
+                continue;
+            }
+            if let Err(err) = self.connect_listener(listener).await {
+                self.message_sources
+                    .retain(|(running, _)| !added.contains(running));
+                for previous in &removed {
+                    if let Err(err) = self.connect_listener(previous).await {
+                        warn!("Failed to restart the listener {previous:?}: {err:#}");
+                    }
+                }
+                return Err(err);
+            }
+            added.push(listener.clone());
+        }
+        Ok(())
+    }
+
     /// Store persistent state to disk if configured as such.
     /// If state persistence is turned off, then this is as no-op.
     fn persist(&self) {
-        if let Some(filename) = &self.storage_file {
-            let dirs = project_dirs();
-            let state_file = dirs
-                .state_dir()
-                .unwrap_or_else(|| dirs.cache_dir())
-                .join(filename);
-            let result: Result<()> = (|| {
-                let state_file = std::fs::File::create(state_file)?;
-                serde_json::to_writer(state_file, &self.persistent)?;
-                Ok(())
-            })();
-            if let Err(e) = result {
-                eprintln!("Error persisting state: '{e}'");
+        if let Some(storage) = &self.storage {
+            if let Err(e) = self.state_writer.save(storage.clone(), &self.persistent) {
+                error!("Error persisting state: '{e}'");
             }
         }
 
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(tag = "type")]
 enum ListenerConfiguration {
     UnixSocket(UnixListenerConfig),
     #[serde(rename = "MQTT")]
     Mqtt(MqttListenerConfig),
+    #[serde(rename = "TCP")]
+    Tcp(TcpListenerConfig),
+    /// A named pipe, only available on Windows.
+    NamedPipe(NamedPipeListenerConfig),
+    /// Newline delimited JSON requests on stdin, responses are written to stdout.
+    Stdin,
 }
 
 fn default_listeners() -> Vec<ListenerConfiguration> {
     vec![ListenerConfiguration::UnixSocket(Default::default())]
 }
 
+fn default_update_interval_ms() -> u64 {
+    600_000
+}
+
 fn default_buffer_size() -> usize {
     3
 }
 fn default_retries() -> u32 {
     3
 }
+fn default_max_running_commands() -> usize {
+    8
+}
 fn default_update_immediately() -> bool {
     true
 }
+fn default_reload_on_change() -> bool {
+    true
+}
+fn default_recent_events() -> usize {
+    100
+}
+fn default_validate_images() -> bool {
+    true
+}
+fn default_startup_timeout_ms() -> u64 {
+    60 * 1000
+}
+fn default_safe_mode_crash_threshold() -> usize {
+    3
+}
+fn default_safe_mode_window_ms() -> u64 {
+    10 * 60 * 1000
+}
+
+/// How images are selected from the `ALL_GALLERIES` pseudo gallery.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+enum AllGalleriesSelection {
+    /// Every image is equally likely, so larger galleries are shown more often.
+    #[default]
+    PerImage,
+    /// Pick a gallery first, then an image from it, so every gallery is shown equally often.
+    PerGallery,
+}
 
 #[derive(Deserialize, Debug)]
 struct Configuration {
-    pub command_line: String,
+    /// Command which shows the image. Words containing spaces are quoted like in a shell, e.g.
+    /// `swaybg -o "DP 1" -i {image}`. Its words may contain placeholders, which are replaced on
+    /// each update: `{image}` is the path of the image, `{image_dir}` its folder and `{basename}`
+    /// its file name, `{gallery}` the gallery it was selected from, and `{monitor}` and `{index}`
+    /// the name and position of the output, see `outputs`. Write `{{` and `}}` for literal braces.
+    /// A list of commands runs them one after another, e.g. to set the wallpaper and then update
+    /// the lock screen. A failing command is reported, and stops the ones after it.
+    /// Either this or `backend` must be set.
+    #[serde(default)]
+    pub command_line: Option<CommandLines>,
+
+    /// Built-in commands for a wallpaper tool, instead of `command_line`, e.g.
+    /// `{ type = "swww", transition = "fade" }`. One of "swww", "hyprpaper", "feh" or
+    /// "xwallpaper". Targets the monitors of `outputs`, except for feh.
+    #[serde(default)]
+    pub backend: Option<Backend>,
+
+    /// Run the commands of `command_line` all at once instead of one after another.
+    #[serde(default)]
+    pub parallel_commands: bool,
+
+    /// Kill the commands of an update which are still running after this time, e.g. "30s", and
+    /// report the failure. Without a timeout, a hung command delays all following updates.
+    #[serde(default, with = "duration::optional_millis")]
+    pub command_timeout_ms: Option<u64>,
+
+    /// How many display commands and hooks may run at once, further ones wait until others
+    /// finished. Keeps quickly requested images from piling up setters, e.g. for the lock screen.
+    /// Commands of a `long_running_command` aren't counted.
+    #[serde(default = "default_max_running_commands")]
+    pub max_running_commands: usize,
+
+    /// Environment variables of the display commands and hooks, e.g.
+    /// `{ SWWW_TRANSITION_FPS = "60" }`. The values of the placeholders are passed as well, like
+    /// `GALLERICA_IMAGE` for `{image}`.
+    #[serde(default)]
+    pub environment: BTreeMap<String, String>,
+
+    /// The display commands keep running while their image is shown, like `swaybg -i {image}`.
+    /// On each update the new commands are started first, then the previous ones are stopped,
+    /// and commands which exit on their own are restarted. They are stopped when gallerica exits.
+    #[serde(default)]
+    pub long_running_command: bool,
+
+    /// Command run with the next image as soon as it is selected, one update before it is passed
+    /// to `command_line`, e.g. to warm a cache or to prepare a blurred variant for a lock screen.
+    /// Uses the same placeholders as `command_line`.
+    pub prefetch_command: Option<String>,
+
+    /// Time between two images, in milliseconds or as a duration like "15m" or "2h".
+    #[serde(
+        default = "default_update_interval_ms",
+        deserialize_with = "duration::millis::deserialize"
+    )]
     pub update_interval_ms: u64,
+
+    /// Cron expression for the times at which images change, replacing `update_interval_ms`.
+    /// E.g. "0 */2 * * *" changes the image every other full hour. Unlike an interval, the
+    /// schedule doesn't drift when images are skipped or the rotation is paused, and it isn't
+    /// slowed down while saving power.
+    #[serde(default)]
+    pub update_schedule: Option<Cron>,
+
+    /// Show images more often after the start, e.g. `{ start_ms = 10000, factor = 1.5 }`.
+    /// The time between two images starts at `start_ms` and grows by `factor` after each image,
+    /// up to the update interval. Showing the next image manually starts over.
+    #[serde(default)]
+    pub interval_ramp: Option<IntervalRamp>,
+
+    /// Only show an image at startup and on explicit requests like `NextImage`, without
+    /// changing it regularly, e.g. for a new random wallpaper on each login.
+    /// Replaces `update_interval_ms` and `update_schedule`.
+    #[serde(default)]
+    pub one_shot: bool,
+
+    /// Continue the update interval where it was at shutdown after a restart, instead of
+    /// showing a new image right away and starting the interval over.
+    /// Needs a `storage`, and doesn't apply to `update_schedule`.
+    #[serde(default)]
+    pub resume_interval: bool,
+
     pub default_gallery: String,
     pub galleries: Vec<Gallery>,
 
+    /// Further rotations running next to the main one, each with its own command, gallery and
+    /// timer, see `RotationConfiguration`. They pause together with the main rotation.
+    #[serde(default)]
+    pub rotations: Vec<RotationConfiguration>,
+
+    /// Command which sets the lock screen image, e.g. blurred with
+    /// `{ command_line = "ln -sf {image} /tmp/lock.jpg", processing = { blur = 10 } }`.
+    /// Shows the wallpaper, unless it has its own `update_interval_ms` or `update_schedule`, then
+    /// it's a rotation named "lockscreen".
+    #[serde(default)]
+    pub lockscreen: Option<LockscreenConfiguration>,
+
+    /// Monitors which each show their own image, e.g. `{ name = "DP-1", gallery = "wide" }`.
+    /// On each update, the command runs once per output, with the `{monitor}` placeholder
+    /// replaced by its name. Without outputs the command runs once, with an empty `{monitor}`.
+    #[serde(default)]
+    pub outputs: Vec<OutputConfiguration>,
+
+    /// Avoid showing the same image on several outputs at once.
+    #[serde(default)]
+    pub distinct_outputs: bool,
+
+    /// Detect which monitors are connected, using "x11", "wayland" or "auto". Outputs are only
+    /// updated while their monitor is connected, and show an image as soon as it's plugged in.
+    #[serde(default)]
+    pub hotplug: DisplayServer,
+
+    /// Prepare images before they are passed to the display command, e.g.
+    /// `{ resolution = [1920, 1080], blur = 10, dim = 0.3 }`. `{image}` is then the processed
+    /// copy in the cache directory. Scaling large images down in advance helps slow devices like
+    /// photo frames. Outputs can have their own `processing`.
+    #[serde(default)]
+    pub processing: Option<Processing>,
+
+    /// Extract the dominant colors of each image and write them to files, e.g.
+    /// `{ colors = 16, exports = [{ path = "~/.cache/colors.json" }] }`. Exports use the "json",
+    /// "xresources" or "css" `format`, or fill in a `template` file. The colors are also part of
+    /// `GetStatus`.
+    #[serde(default)]
+    pub palette: Option<PaletteConfiguration>,
+
+    /// Keep `$XDG_STATE_HOME/gallerica/current` pointing to the current image, as a "symlink" or
+    /// a "copy", or "off".
+    #[serde(default)]
+    pub current_link: CurrentLink,
+
+    /// Record each shown image with the time and why it was shown, see `Request::History`.
+    /// E.g. `{ max_entries = 1000, max_age_ms = "30d" }`, or `{ enabled = false }`.
+    #[serde(default)]
+    pub history: HistoryConfiguration,
+
+    /// Show a desktop notification with the name, gallery and a thumbnail of each new image.
+    #[serde(default)]
+    pub notifications: bool,
+
+    /// Select images as usual, but only log the commands which would show them, e.g. to try out a
+    /// new `command_line`. Nothing is run, and neither the state, the history, sidecar files, the
+    /// palette nor the `current_link` are updated.
+    #[serde(default)]
+    pub dry_run: bool,
+
+    /// How images are selected when the pseudo gallery "*" (all galleries) is active.
+    /// Either "per-image" or "per-gallery".
+    #[serde(default)]
+    pub all_galleries_selection: AllGalleriesSelection,
+
+    /// How images are selected from galleries without their own `selection`.
+    /// One of "random", "rated", "shuffle", "least-recently-shown", "alphabetical" or
+    /// "chronological".
+    #[serde(default)]
+    pub selection: SelectionMode,
+
+    /// Aspect ratio of the display, e.g. "16:9" or 2.33.
+    /// If set, images with a similar aspect ratio are preferred, then images with the same
+    /// orientation. Reading the dimensions of images for the first time is slow for large
+    /// galleries, consider setting `index_file`.
+    pub aspect_ratio: Option<AspectRatio>,
+
+    /// How much the aspect ratio of an image may differ from `aspect_ratio`, relative to it.
+    #[serde(default = "default_aspect_tolerance")]
+    pub aspect_tolerance: f64,
+
+    /// Galleries to select automatically during certain times of each day or year, e.g.
+    /// `{ gallery = "night", from = "sunset", to = "07:00" }`,
+    /// `{ gallery = "christmas", from_date = "12-01", to_date = "12-26" }` or
+    /// `{ gallery = "autumn", months = ["october", "november"] }`.
+    /// Times are either "HH:MM" in local time, "sunrise" or "sunset". Conditions can be combined,
+    /// a rule matches if all its conditions are met. The first matching rule wins, while no rule
+    /// matches the `default_gallery` is selected.
+    /// Galleries selected manually stay active until the schedule selects a different gallery.
+    #[serde(default)]
+    pub schedule: Vec<ScheduleRule>,
+
+    /// Times of each day during which no images are shown automatically, e.g.
+    /// `{ from = "23:00", to = "07:00" }`. Requests like `NextImage` still show images.
+    #[serde(default)]
+    pub quiet_hours: Vec<TimeRange>,
+
+    /// Location used to calculate sunrise and sunset for the `schedule` and `quiet_hours`.
+    pub location: Option<Location>,
+
+    /// Time to wait after starting, before doing anything else.
+    #[serde(default)]
+    pub startup_delay_ms: u64,
+
+    /// Paths that need to exist before the daemon starts, e.g. network mounts or the socket of
+    /// the compositor.
+    #[serde(default)]
+    pub wait_for_paths: Vec<PathBuf>,
+
+    /// Whether to wait until the network is up before starting, e.g. for an MQTT listener.
+    #[serde(default)]
+    pub wait_for_network: bool,
+
+    /// Maximum time to wait for `wait_for_paths` and `wait_for_network`.
+    /// Once it expires the daemon starts anyway.
+    #[serde(default = "default_startup_timeout_ms")]
+    pub startup_timeout_ms: u64,
+
     /// Whether a new image should be selected immediately on startup (true) or only after the first
     /// time interval has passed (false).
     #[serde(default = "default_update_immediately")]
     pub update_immediately: bool,
 
+    /// Reload this file when it changes, applying the new galleries, commands, intervals and
+    /// listeners without a restart. The current gallery and the progress of the interval are
+    /// kept, and so are the connections of unchanged listeners. Sending SIGHUP to the daemon
+    /// reloads it as well.
+    #[serde(default = "default_reload_on_change")]
+    pub reload_on_change: bool,
+
+    /// Named sets of options, e.g. a `[profiles.work]` with its own `default_gallery`,
+    /// `update_interval_ms` and `command_line`. The options of the selected `profile` replace
+    /// the ones of the file, environment variables and `--set` still take precedence.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, toml::Value>,
+
+    /// The profile whose options are used, see `profiles`. Also selected with `--profile`, or
+    /// while running with `Request::SelectProfile`.
+    #[serde(default)]
+    pub profile: Option<String>,
+
+    /// Which messages the daemon logs, and how, e.g. `{ level = "debug", format = "json" }`.
+    #[serde(default)]
+    pub log: LogConfiguration,
+
+    /// Number of events kept in memory for `Request::RecentEvents`: shown images, selected
+    /// galleries, pauses, and logged warnings and errors.
+    #[serde(default = "default_recent_events")]
+    pub recent_events: usize,
+
     #[serde(default = "default_listeners")]
     pub listeners: Vec<ListenerConfiguration>,
 
-    /// Number of image paths the daemon will remember.
+    /// Number of image paths the daemon will remember per gallery.
     /// Each time an image is selected, the path to that image will be cached.
     /// If selecting a new random image would result an image in this cache,
     /// then the daemon will reroll and select a new one.
@@ -497,32 +3276,290 @@ struct Configuration {
     #[serde(default = "default_buffer_size")]
     pub recent_image_buffer_size: usize,
 
+    /// Don't show images again within this time in the "random" and "rated" selection modes,
+    /// e.g. "2h", unless all images of the gallery were shown within it.
+    /// Single images can use their own time with `cooldown_ms` in their metadata file, see
+    /// `image_metadata`.
+    #[serde(default, with = "duration::optional_millis")]
+    pub repeat_window_ms: Option<u64>,
+
+    /// Pause the rotation while the session is idle, so no images are shown that nobody sees.
+    #[serde(default)]
+    pub idle: Option<IdleConfiguration>,
+
+    /// What to do when the machine resumes from suspend, which the update interval doesn't count.
+    /// Either "ignore" to continue the interval, "update" to show the next image right away, or
+    /// "reset" to start the interval over. Schedules set with `update_schedule` always follow the
+    /// wall clock.
+    #[serde(default)]
+    pub on_resume: ResumeBehavior,
+
+    /// Show images less often, or not at all, while running on battery.
+    /// Without this section images are shown as usual.
+    #[serde(default)]
+    pub battery: Option<BatteryConfiguration>,
+
+    /// Pause the rotation while a fullscreen window is focused, e.g. a movie or game.
+    /// Can be "off", "auto", "x11" or "wayland", see `DisplayServer`.
+    #[serde(default)]
+    pub pause_on_fullscreen: DisplayServer,
+
+    /// Seed for all random decisions, like selecting images and shuffling galleries.
+    /// With the same seed, configuration, images and state file, the same sequence of images is
+    /// selected, e.g. for tests or to show the same images on several machines.
+    /// A random seed is used by default, see also `Request::Reseed`.
+    pub seed: Option<u64>,
+
+    /// Make recently shown images less likely in the "random" and "rated" selection modes.
+    /// An image shown this long ago is half as likely as one that was never shown, one shown
+    /// twice as long ago three quarters as likely, and so on.
+    /// This works well together with a small or disabled `recent_image_buffer_size`.
+    #[serde(default, with = "duration::optional_millis")]
+    pub recency_half_life_ms: Option<u64>,
+
     /// Number of tries when avoiding recent images.
     /// Set to zero to disable this feature.
     #[serde(default = "default_retries")]
     pub number_retries: u32,
 
-    /// File where persistent state should be stored.
+    /// Whether to fully decode each selected file before showing it.
+    /// Files that fail to decode (e.g. truncated downloads) are put into quarantine and not
+    /// selected again until they change. Formats that can't be decoded by gallerica, like HEIC or
+    /// SVG, are shown without a check.
+    #[serde(default = "default_validate_images")]
+    pub validate_images: bool,
+
+    /// How to detect files that show the same image, e.g. because source folders overlap.
+    /// Duplicates are treated as a single image for selection and `recent_image_buffer_size`.
+    /// One of "off", "content" (identical files) or "perceptual" (visually identical images).
+    #[serde(default)]
+    pub duplicate_detection: DuplicateDetection,
+
+    /// Number of consecutive unclean shutdowns within `safe_mode_window_ms`, after which the
+    /// daemon starts in safe mode.
+    /// In safe mode the persisted gallery is ignored in favor of `default_gallery`, images are
+    /// only printed instead of being passed to `command_line`, and only the default Unix socket
+    /// listener is started. Rotations, the lock screen, the commands of `outputs`,
+    /// `long_running_command`, `processing` and `palette` are turned off. Safe mode lasts until
+    /// the daemon is restarted, also across reloads of the configuration.
+    /// Set to zero to never start in safe mode.
+    #[serde(default = "default_safe_mode_crash_threshold")]
+    pub safe_mode_crash_threshold: usize,
+
+    /// Time window for counting consecutive crashes, see `safe_mode_crash_threshold`.
+    #[serde(default = "default_safe_mode_window_ms")]
+    pub safe_mode_window_ms: u64,
+
+    /// Whether the daemon runs in safe mode, see `safe_mode_crash_threshold`.
+    #[serde(skip)]
+    pub safe_mode: bool,
+
+    /// Sidecar files to write next to each shown image, recording when it was shown.
+    /// One of "off", "json" (`<image>.gallerica.json`) or "xmp" (`<image>.xmp`).
+    /// Sidecar files are never shown as images.
+    #[serde(default)]
+    pub sidecar: SidecarFormat,
+
+    /// Directory to which `Request::TrashCurrent` moves images.
+    /// If omitted, images are moved to the trash of the desktop environment.
+    pub trash_directory: Option<PathBuf>,
+
+    /// Directory to which `Request::CollectCurrent` copies images, if the request names none.
+    pub collect_directory: Option<PathBuf>,
+
+    /// File where persistent state should be stored, which may start with `~` and contain
+    /// environment variables like `${XDG_DATA_HOME}`, like all paths in the configuration.
     /// Relative paths are interpreted relative to the state directory,
     /// or the cache directory if the state directory is not available.
-    /// If this option is omitted, no state is persisted.
+    /// If this option and `storage` are omitted, no state is persisted.
     pub storage_file: Option<PathBuf>,
+
+    /// Where persistent state is stored, instead of the JSON `storage_file`, e.g.
+    /// `{ type = "sqlite", path = "gallerica.sqlite" }`. A database only writes what changed, and
+    /// also holds the `history`. Relative paths are interpreted like `storage_file`.
+    #[serde(default)]
+    pub storage: Option<Storage>,
+
+    /// Database for caching folder listings and image fingerprints across restarts, which avoids
+    /// rescanning large collections on slow (e.g. network mounted) file systems.
+    /// Relative paths are interpreted like `storage_file`.
+    /// If this option is omitted, the index is only kept in memory.
+    pub index_file: Option<PathBuf>,
+}
+
+impl Configuration {
+    /// The `storage`, or the `storage_file`, with an absolute path.
+    fn storage(&self) -> Result<Option<Storage>> {
+        let storage = match (&self.storage, &self.storage_file) {
+            (Some(_), Some(_)) => bail!("Set either `storage` or `storage_file`, not both"),
+            (Some(storage), None) => storage.clone(),
+            (None, Some(path)) => Storage::Json { path: path.clone() },
+            (None, None) => return Ok(None),
+        };
+        storage.relative_to(&state_dir()).map(Some)
+    }
+
+    /// The `command_line`, or the commands of the `backend`.
+    fn command_lines(&self) -> Result<CommandLines> {
+        match (&self.command_line, &self.backend) {
+            (Some(lines), None) => Ok(lines.clone()),
+            (None, Some(backend)) => Ok(backend.command_lines(!self.outputs.is_empty())),
+            (Some(_), Some(_)) => bail!("Set either `command_line` or `backend`, not both"),
+            (None, None) => bail!("Set `command_line` or `backend` to show images"),
+        }
+    }
+
+    /// The configured galleries, with `~` and variables in their folders expanded.
+    fn galleries(&self) -> Result<Vec<Gallery>> {
+        let mut galleries = self.galleries.clone();
+        for gallery in galleries.iter_mut() {
+            if gallery.name == ALL_GALLERIES {
+                bail!("The gallery name '{ALL_GALLERIES}' is reserved");
+            }
+
+            for source in gallery.sources.iter_mut() {
+                if let Cow::Owned(path) = expand::path(&source.path)? {
+                    source.path = path;
+                }
+            }
+        }
+        Ok(galleries)
+    }
+
+    fn startup_conditions(&self) -> Result<StartupConditions> {
+        Ok(StartupConditions {
+            delay: Duration::from_millis(self.startup_delay_ms),
+            paths: self
+                .wait_for_paths
+                .iter()
+                .map(|path| Ok(expand::path(path)?.into_owned()))
+                .collect::<Result<_>>()?,
+            network: self.wait_for_network,
+            timeout: Duration::from_millis(self.startup_timeout_ms),
+        })
+    }
+
+    /// Replace all settings that might prevent the daemon from starting with known good ones, and
+    /// turn off everything else which runs commands or decodes images.
+    fn enter_safe_mode(&mut self) {
+        self.safe_mode = true;
+        self.command_line = Some(CommandLines::One(BUILTIN_COMMAND_LINE.to_owned()));
+        self.backend = None;
+        self.prefetch_command = None;
+        self.listeners = default_listeners();
+        self.long_running_command = false;
+        self.rotations.clear();
+        self.lockscreen = None;
+        for output in &mut self.outputs {
+            output.command_line = None;
+            output.processing = None;
+        }
+        self.processing = None;
+        self.palette = None;
+    }
+
+    /// Drop the settings which only matter while the daemon keeps running, see `CliCommand::Once`.
+    fn prepare_once(&mut self) {
+        self.listeners.clear();
+        self.long_running_command = false;
+        self.prefetch_command = None;
+        self.rotations.clear();
+        self.hotplug = DisplayServer::Off;
+        self.idle = None;
+        self.on_resume = ResumeBehavior::Ignore;
+        self.battery = None;
+        self.pause_on_fullscreen = DisplayServer::Off;
+    }
+}
+
+fn read_configuration(source: &ConfigSource) -> Result<Configuration> {
+    let migrated = source.load()?;
+    let config: Configuration =
+        toml::from_str(&migrated.document.to_string()).context("Failed to parse configuration")?;
+
+    // Before the warnings, so they are logged as configured
+    logging::configure(&config.log);
+    for warning in &migrated.warnings {
+        warn!("{warning}. Run `gallerica migrate-config` to update the config file.");
+    }
+    validation::check(&config)?;
+    Ok(config)
 }
 
-async fn read_configuration(app: &mut ApplicationState, config_file: &Path) -> Result<()> {
-    let make_ctx = || anyhow!("Failed to open config file '{}'", config_file.display());
+/// Apply all config migrations to `config_file` and write the result back.
+fn migrate_configuration(config_file: &Path) -> Result<()> {
+    let text = std::fs::read_to_string(config_file)
+        .with_context(|| anyhow!("Failed to open config file '{}'", config_file.display()))?;
+
+    let format = Format::of(config_file)?;
+    let migrated = config_migration::migrate_document(format.parse(&text)?);
+    if migrated.warnings.is_empty() {
+        println!("Config file is up to date");
+        return Ok(());
+    }
+
+    for warning in &migrated.warnings {
+        println!("{warning}");
+    }
+    if format != Format::Toml {
+        bail!("Only TOML files can be rewritten, update the options above by hand");
+    }
 
-    let mut cfg = std::fs::File::open(config_file).context(make_ctx())?;
+    let mut backup = config_file.as_os_str().to_owned();
+    backup.push(".bak");
+    std::fs::copy(config_file, &backup).context("Failed to back up config file")?;
+    std::fs::write(config_file, migrated.document.to_string())
+        .context("Failed to write migrated config file")?;
+
+    println!(
+        "Migrated '{}', the original is kept at '{}'",
+        config_file.display(),
+        Path::new(&backup).display()
+    );
+    Ok(())
+}
 
-    let mut text = String::new();
-    cfg.read_to_string(&mut text).context(make_ctx())?;
+/// Check whether the file at `path` fails to decode as an image. Formats which the `image` crate
+/// doesn't support, like HEIC or SVG, aren't checked, the display command may still show them.
+/// Decoding happens on a blocking thread, as it may take a while for large images.
+async fn is_corrupt_image(path: PathBuf) -> bool {
+    tokio::task::spawn_blocking(move || {
+        let decoded = image::ImageReader::open(&path)
+            .and_then(|reader| reader.with_guessed_format())
+            .map_err(image::ImageError::from)
+            .and_then(|reader| reader.decode());
+        matches!(decoded, Err(err) if !matches!(err, image::ImageError::Unsupported(_)))
+    })
+    .await
+    .unwrap_or(true)
+}
 
-    let cfg: Configuration = toml::from_str(&text).context("Failed to parse configuration")?;
+/// Show one image of `gallery` with the configuration of `source` and wait for the display
+/// command, see `CliCommand::Once`.
+async fn show_once(source: &ConfigSource, gallery: Option<&str>, replace: bool) -> Result<()> {
+    let _lock = InstanceLock::acquire(&source.path, replace).await?;
+    let mut config = read_configuration(source)?;
+    config.prepare_once();
+    config.startup_conditions()?.wait().await;
 
-    app.update_configuration(&cfg)
+    let mut state = ApplicationState::new(BUILTIN_COMMAND_LINE, Duration::from_millis(10000))?;
+    state
+        .update_configuration(&config)
         .await
         .context("Failed to apply configuration")?;
-
+    if let Some(name) = gallery {
+        state.change_gallery(name)?;
+    }
+    match state.update(Trigger::Request).await {
+        Response::NewImage { .. } | Response::DryRun { .. } => {}
+        Response::CommandFailed { message } => bail!("Display command failed: {message}"),
+        Response::NoImages { gallery, .. } => bail!("Gallery '{gallery}' has no images"),
+        response => bail!("Failed to show an image: {response:?}"),
+    }
+    if let Some(task) = state.update_task.take() {
+        task.await?.context("Display command failed")?;
+    }
+    state.state_writer.flush().await;
     Ok(())
 }
 
@@ -537,39 +3574,558 @@ async fn shutdown_signal_received() {
     }
 }
 
-/// Given a path, expand a leading `~` to the user home directory, if any.
-/// Returns Err if the user does not have a home.
-fn expand_tilde(path: &Path) -> Result<Cow<Path>> {
-    let mut components = path.components();
-
-    if let Some(Component::Normal(start)) = components.next() {
-        if let Some("~") = start.to_str() {
-            return Ok(Cow::Owned(
-                UserDirs::new()
-                    .ok_or_else(|| anyhow!("User has no home directory!"))?
-                    .home_dir()
-                    .join(components),
-            ));
-        }
+/// `config.toml` in the config directory, or `config.json` if only that exists.
+fn default_config_path() -> PathBuf {
+    let dir = gallerica::project_dirs().config_dir().to_owned();
+    let json = dir.join("config.json");
+    let toml = dir.join("config.toml");
+    if !toml.exists() && json.exists() {
+        json
+    } else {
+        toml
     }
-
-    Ok(Cow::Borrowed(path))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let mut state = ApplicationState::new("echo {image}".split(' '), Duration::from_millis(10000))?;
+/// Name this binary was started as, so aliases like `gallerica-cli` complete themselves.
+fn bin_name() -> String {
+    std::env::args_os()
+        .next()
+        .as_deref()
+        .map(Path::new)
+        .and_then(Path::file_name)
+        .map_or("gallerica".to_owned(), |name| {
+            name.to_string_lossy().into_owned()
+        })
+}
 
+fn main() -> Result<ExitCode> {
     let cli = Cli::parse();
+    logging::init();
 
-    let config_path = if let Some(ref path) = cli.config_file {
-        path.clone()
-    } else {
-        gallerica::project_dirs().config_dir().join("config.toml")
+    let config_path = match &cli.config_file {
+        Some(path) => path.clone(),
+        None => default_config_path(),
     };
+    let mut source = ConfigSource::new(config_path.clone(), cli.set);
+    source.profile = cli.profile;
+    let starts = matches!(
+        cli.command,
+        None | Some(CliCommand::Daemon | CliCommand::Once { .. })
+    );
+    if starts && cli.config_file.is_none() {
+        source.create_default()?;
+    }
+
+    let result =
+        match cli.command {
+            // The client blocks on its requests, so it runs outside of the runtime
+            Some(CliCommand::Client(command)) => return Ok(client::main(cli.client, command)),
+            Some(CliCommand::Completions { shell }) => {
+                print!(
+                    "{}",
+                    completions::generate(shell, Cli::command(), &bin_name())
+                );
+                Ok(())
+            }
+            Some(CliCommand::MigrateConfig) => migrate_configuration(&config_path),
+            Some(CliCommand::Doctor) => doctor::run(&source),
+            Some(CliCommand::CheckConfig) => doctor::check_config(&source),
+            Some(CliCommand::Once { gallery }) => tokio::runtime::Runtime::new()?
+                .block_on(show_once(&source, gallery.as_deref(), cli.replace)),
+            None | Some(CliCommand::Daemon) => {
+                tokio::runtime::Runtime::new()?.block_on(run_daemon(source, cli.replace))
+            }
+        };
+    result.map(|()| ExitCode::SUCCESS)
+}
+
+/// Run the daemon with the configuration of `source` until it is shut down.
+async fn run_daemon(source: ConfigSource, replace: bool) -> Result<()> {
+    let mut state = ApplicationState::new(BUILTIN_COMMAND_LINE, Duration::from_millis(10000))?;
+
+    let lock = InstanceLock::acquire(&source.path, replace).await?;
+
+    let mut config = read_configuration(&source)?;
 
-    read_configuration(&mut state, &config_path).await?;
+    config.startup_conditions()?.wait().await;
+
+    let (marker, crashes) = RunningMarker::create(
+        crash_marker::path(&source.path),
+        Duration::from_millis(config.safe_mode_window_ms),
+    )?;
+    if config.safe_mode_crash_threshold > 0 && crashes >= config.safe_mode_crash_threshold {
+        warn!(
+            "Gallerica did not shut down cleanly {crashes} times in a row, starting in safe mode"
+        );
+        config.enter_safe_mode();
+    }
+
+    state
+        .update_configuration(&config)
+        .await
+        .context("Failed to apply configuration")?;
+    state.config_watcher = Some(ConfigWatcher::new(source, config.reload_on_change)?);
+    state.systemd = Notifier::from_environment();
+    if let Some(systemd) = &state.systemd {
+        systemd.ready();
+    }
 
     state.run().await;
+
+    marker.remove();
+    drop(lock);
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{message_api::EventWriter, test_util::TempDir};
+
+    /// A daemon which writes the `current_link` into `state_dir`, not the user's state directory.
+    fn test_state(state_dir: &Path) -> ApplicationState {
+        let mut state = ApplicationState::new("true", Duration::from_secs(60)).unwrap();
+        state.current_link_path = state_dir.join("current");
+        state
+    }
+
+    #[test]
+    fn test_command_parsing() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_folders_are_selected_by_weight() {
+        let gallery: Gallery = toml::from_str(
+            r#"
+            name = "test"
+            folders = ["/archive", { path = "/favorites", weight = 3.0 }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(gallery.sources[0].weight, None);
+
+        let files: Vec<_> = ["/archive/a", "/archive/b", "/favorites/c", "/elsewhere/d"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(
+            gallery.image_weights(&files),
+            Some(vec![0.5, 0.5, 3.0, 0.0])
+        );
+    }
+
+    #[test]
+    fn test_recency_times_are_durations() {
+        let config: Configuration = toml::from_str(
+            r#"
+            command_line = "true"
+            default_gallery = "wallpapers"
+            repeat_window_ms = "2h"
+            recency_half_life_ms = 86400000
+
+            [[galleries]]
+            name = "wallpapers"
+            folders = ["/wallpapers"]
+            repeat_window_ms = "30m"
+            recency_half_life_ms = "1d"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.repeat_window_ms, Some(2 * 60 * 60 * 1000));
+        assert_eq!(config.recency_half_life_ms, Some(24 * 60 * 60 * 1000));
+        let gallery = &config.galleries[0];
+        assert_eq!(gallery.repeat_window_ms, Some(30 * 60 * 1000));
+        assert_eq!(gallery.recency_half_life_ms, Some(24 * 60 * 60 * 1000));
+    }
+
+    #[tokio::test]
+    async fn test_only_corrupt_images_are_quarantined() {
+        let dir = TempDir::new("corrupt");
+        let truncated = dir.join("truncated.png");
+        std::fs::write(&truncated, b"\x89PNG\r\n\x1a\n").unwrap();
+        let unsupported = dir.join("drawing.svg");
+        std::fs::write(&unsupported, "<svg></svg>").unwrap();
+        let valid = dir.join("valid.png");
+        image::RgbImage::new(2, 2).save(&valid).unwrap();
+
+        assert!(is_corrupt_image(truncated).await);
+        assert!(!is_corrupt_image(unsupported).await);
+        assert!(!is_corrupt_image(valid).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reload_keeps_rotations() {
+        let dir = TempDir::new("reload");
+        let state_dir = TempDir::new("reload-state");
+        let config: Configuration = toml::from_str(&format!(
+            r#"
+            command_line = "true"
+            default_gallery = "wallpapers"
+            listeners = []
+            history = {{ enabled = false }}
+            seed = 1
+
+            [[galleries]]
+            name = "wallpapers"
+            folders = ["{}"]
+
+            [[rotations]]
+            name = "lockscreen"
+            command_line = "true"
+            gallery = "wallpapers"
+            update_interval_ms = 60000
+            "#,
+            dir.display()
+        ))
+        .unwrap();
+        let mut state = test_state(&state_dir);
+        state.update_configuration(&config).await.unwrap();
+        // The first image of the rotation after starting
+        rotation::next_due(&mut state.rotations).await;
+        let random = state.rng.clone();
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        state.apply_configuration(&config, true).await.unwrap();
+
+        let next = rotation::next_due(&mut state.rotations);
+        assert!(tokio::time::timeout(Duration::from_secs(29), next)
+            .await
+            .is_err());
+        assert_eq!(state.rng.gen::<u64>(), random.clone().gen::<u64>());
+    }
+
+    #[tokio::test]
+    async fn test_safe_mode_turns_off_commands() {
+        let dir = TempDir::new("safe-mode");
+        let state_dir = TempDir::new("safe-mode-state");
+        let mut config: Configuration = toml::from_str(&format!(
+            r#"
+            command_line = "feh {{image}}"
+            default_gallery = "wallpapers"
+            listeners = []
+            history = {{ enabled = false }}
+            long_running_command = true
+            processing = {{ blur = 1.0 }}
+            lockscreen = {{ command_line = "swaylock -i {{image}}" }}
+
+            [[galleries]]
+            name = "wallpapers"
+            folders = ["{}"]
+
+            [[rotations]]
+            name = "bar"
+            command_line = "true"
+            gallery = "wallpapers"
+
+            [[outputs]]
+            name = "DP-1"
+            command_line = "swww img -o {{monitor}} {{image}}"
+            "#,
+            dir.display()
+        ))
+        .unwrap();
+        config.enter_safe_mode();
+        config.listeners.clear();
+        let mut state = test_state(&state_dir);
+        state.update_configuration(&config).await.unwrap();
+
+        assert!(state.rotations.is_empty());
+        assert!(state.lockscreen.is_none());
+        assert!(state.outputs[0].commands.is_none());
+        assert!(!state.long_running_command);
+        assert!(state.processing.is_none());
+        assert!(state.safe_mode);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_reload_changes_nothing() {
+        let dir = TempDir::new("rejected-reload");
+        let state_dir = TempDir::new("rejected-reload-state");
+        let config = |gallery: &str, rotation_gallery: &str| -> Configuration {
+            toml::from_str(&format!(
+                r#"
+                command_line = "feh {{image}}"
+                default_gallery = "{gallery}"
+                listeners = []
+                history = {{ enabled = false }}
+
+                [[galleries]]
+                name = "{gallery}"
+                folders = ["{}"]
+
+                [[rotations]]
+                name = "lockscreen"
+                command_line = "true"
+                gallery = "{rotation_gallery}"
+                "#,
+                dir.display()
+            ))
+            .unwrap()
+        };
+        let mut state = test_state(&state_dir);
+        state
+            .update_configuration(&config("wallpapers", "wallpapers"))
+            .await
+            .unwrap();
+        state.fullscreen_active = true;
+
+        let invalid = config("backgrounds", "wallpapers");
+        assert!(state.apply_configuration(&invalid, true).await.is_err());
+
+        assert!(state.galleries.contains_key("wallpapers"));
+        assert!(!state.galleries.contains_key("backgrounds"));
+        assert_eq!(state.configured_galleries, ["wallpapers"]);
+        assert_eq!(
+            state.persistent.current_gallery.as_deref(),
+            Some("wallpapers")
+        );
+        assert!(state.fullscreen_active);
+
+        // The monitors are restarted by a successful reload, and report fullscreen windows again
+        let valid = config("backgrounds", "backgrounds");
+        state.apply_configuration(&valid, true).await.unwrap();
+        assert!(!state.galleries.contains_key("wallpapers"));
+        assert!(!state.fullscreen_active);
+    }
+
+    #[tokio::test]
+    async fn test_discarded_next_image_is_not_skipped() {
+        let dir = TempDir::new("next-image");
+        let state_dir = TempDir::new("next-image-state");
+        for name in ["a.png", "b.png", "c.png"] {
+            image::RgbImage::new(2, 2).save(dir.join(name)).unwrap();
+        }
+        for mode in ["alphabetical", "shuffle"] {
+            let config: Configuration = toml::from_str(&format!(
+                r#"
+                command_line = "true"
+                default_gallery = "wallpapers"
+                listeners = []
+                history = {{ enabled = false }}
+                selection = "{mode}"
+
+                [[galleries]]
+                name = "wallpapers"
+                folders = ["{}"]
+                "#,
+                dir.display()
+            ))
+            .unwrap();
+            let mut state = test_state(&state_dir);
+            state.update_configuration(&config).await.unwrap();
+
+            let mut shown = vec![];
+            for _ in 0..3 {
+                state.update_image(Trigger::Request, false).await;
+                assert!(state.next_image.is_some());
+                shown.push(state.persistent.current_image.clone().unwrap());
+                // Discards the pre-selected image
+                state.change_gallery("wallpapers").unwrap();
+            }
+
+            if mode == "shuffle" {
+                shown.sort();
+            }
+            let expected: Vec<_> = ["a.png", "b.png", "c.png"]
+                .iter()
+                .map(|name| dir.join(name))
+                .collect();
+            assert_eq!(shown, expected, "{mode}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_has_no_side_effects() {
+        let dir = TempDir::new("dry-run");
+        let state_dir = TempDir::new("dry-run-state");
+        for name in ["a.png", "b.png", "c.png"] {
+            image::RgbImage::new(2, 2).save(dir.join(name)).unwrap();
+        }
+        std::fs::write(dir.join("truncated.png"), b"\x89PNG\r\n\x1a\n").unwrap();
+        let config: Configuration = toml::from_str(&format!(
+            r#"
+            command_line = "feh --bg-fill {{image}}"
+            default_gallery = "wallpapers"
+            listeners = []
+            history = {{ enabled = false }}
+            sidecar = "json"
+            selection = "shuffle"
+            processing = {{ blur = 1.0 }}
+
+            [[galleries]]
+            name = "wallpapers"
+            folders = ["{}"]
+            "#,
+            dir.display()
+        ))
+        .unwrap();
+        let mut state = test_state(&state_dir);
+        state.update_configuration(&config).await.unwrap();
+        // Real updates would write the processed images to the cache of the user
+        let processing = state.processing.take();
+        state.update_image(Trigger::Request, false).await;
+        state.processing = processing.clone();
+        let persistent = serde_json::to_string(&state.persistent).unwrap();
+        let rng = state.rng.clone();
+        let files = std::fs::read_dir(&*dir).unwrap().count();
+
+        let response = state.update_image(Trigger::Request, true).await;
+
+        let Response::DryRun { commands } = response else {
+            panic!("{response:?}");
+        };
+        assert_eq!(
+            serde_json::to_string(&state.persistent).unwrap(),
+            persistent
+        );
+        assert_eq!(state.rng, rng);
+        assert_eq!(std::fs::read_dir(&*dir).unwrap().count(), files);
+        // Only the path of the processed image is known, nothing was written to the cache
+        let processed = commands[0].strip_prefix("feh --bg-fill ").unwrap();
+        assert!(!Path::new(processed).exists());
+
+        // The dry run predicted the next update
+        state.processing = None;
+        let Response::NewImage { image: Some(image) } =
+            state.update_image(Trigger::Request, false).await
+        else {
+            panic!("no image shown");
+        };
+        let cache = project_dirs().cache_dir().join("processed");
+        let target = processing.unwrap().target(&image, &cache).unwrap();
+        assert_eq!(target, Path::new(processed));
+    }
+
+    /// Request which ignores its response.
+    struct TestRequest(Request);
+
+    #[async_trait::async_trait]
+    impl InflightRequest for TestRequest {
+        fn request(&self) -> anyhow::Result<&Request> {
+            Ok(&self.0)
+        }
+
+        async fn respond(self: Box<Self>, _: Response) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn into_event_writer(self: Box<Self>) -> Result<EventWriter, Box<dyn InflightRequest>> {
+            Err(self)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_banned_images_are_never_selected() {
+        let dir = TempDir::new("ban");
+        let state_dir = TempDir::new("ban-state");
+        for name in ["a.png", "b.png", "c.png"] {
+            image::RgbImage::new(2, 2).save(dir.join(name)).unwrap();
+        }
+        for mode in ["random", "shuffle", "rated", "alphabetical"] {
+            let config: Configuration = toml::from_str(&format!(
+                r#"
+                command_line = "true"
+                default_gallery = "wallpapers"
+                listeners = []
+                history = {{ enabled = false }}
+                selection = "{mode}"
+
+                [[galleries]]
+                name = "wallpapers"
+                folders = ["{}"]
+                "#,
+                dir.display()
+            ))
+            .unwrap();
+            let mut state = test_state(&state_dir);
+            state.update_configuration(&config).await.unwrap();
+            state.update_image(Trigger::Request, false).await;
+            let banned = state.persistent.current_image.clone().unwrap();
+            let link = std::fs::read_link(state_dir.join("current")).unwrap();
+            assert_eq!(link, banned);
+
+            state
+                .handle_message(Box::new(TestRequest(Request::BanCurrent)))
+                .await;
+
+            assert!(state.persistent.banned.contains(&banned));
+            for _ in 0..6 {
+                assert_ne!(state.persistent.current_image.as_ref(), Some(&banned));
+                state.update_image(Trigger::Request, false).await;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_state_with_removed_gallery_is_kept() {
+        let dir = TempDir::new("removed-gallery");
+        let state_dir = TempDir::new("removed-gallery-state");
+        let storage = dir.join("state.json");
+        std::fs::write(
+            &storage,
+            format!(
+                r#"{{"version": {}, "current_gallery": "removed", "ratings": {{"/a.png": 4}}}}"#,
+                state_migration::VERSION
+            ),
+        )
+        .unwrap();
+        let config: Configuration = toml::from_str(&format!(
+            r#"
+            command_line = "true"
+            default_gallery = "wallpapers"
+            listeners = []
+            history = {{ enabled = false }}
+            storage = {{ type = "json", path = "{}" }}
+
+            [[galleries]]
+            name = "wallpapers"
+            folders = ["{}"]
+            "#,
+            storage.display(),
+            dir.display()
+        ))
+        .unwrap();
+        let mut state = test_state(&state_dir);
+        state.update_configuration(&config).await.unwrap();
+
+        assert_eq!(
+            state.persistent.current_gallery.as_deref(),
+            Some("wallpapers")
+        );
+        assert_eq!(state.persistent.ratings.get(Path::new("/a.png")), Some(&4));
+        assert!(!dir.join("state.json.corrupt").exists());
+    }
+
+    #[tokio::test]
+    async fn test_reapplied_images_are_recorded_once() {
+        let mut state = ApplicationState::new("true", Duration::from_secs(60)).unwrap();
+        let image = PathBuf::from(format!("/reapplied-{}.png", std::process::id()));
+        assert!(state.record_shown("gallery", &image, None, Trigger::Timer));
+        assert!(!state.record_shown("gallery", &image, None, Trigger::Request));
+
+        let stats = state.persistent.statistics.image(&image).unwrap();
+        assert_eq!(stats.times_shown, 1);
+        let announced = events::recent(usize::MAX)
+            .into_iter()
+            .filter(|recent| {
+                matches!(&recent.event, Event::ImageShown { image: shown, .. } if *shown == image)
+            })
+            .count();
+        assert_eq!(announced, 1);
+    }
+
+    #[tokio::test]
+    async fn test_index_survives_panicking_tasks() {
+        let state_dir = TempDir::new("index-panic-state");
+        let state = test_state(&state_dir);
+        let index = state.image_index.clone();
+
+        assert!(state
+            .with_index(|_| panic!("Failed to scan"))
+            .await
+            .is_none());
+        assert!(Arc::ptr_eq(&index, &state.image_index));
+        assert_eq!(state.with_index(|_| 1).await, Some(1));
+    }
+}
@@ -1,13 +1,54 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
 use async_trait::async_trait;
 use clap::Subcommand;
 use serde::{Deserialize, Serialize};
-use tokio::task::JoinHandle;
+use tokio::{io::AsyncWrite, task::JoinHandle};
 
 #[derive(Debug, Serialize, Deserialize, Subcommand)]
 #[serde(tag = "method")]
 pub enum Request {
     /// Immediately show the next image, no matter the update rate.
-    NextImage,
+    NextImage {
+        /// Select the image, but only report the commands which would show it
+        #[clap(long)]
+        #[serde(default)]
+        dry_run: bool,
+    },
+
+    /// Move the currently shown image to the trash, and immediately show the next image.
+    TrashCurrent {
+        /// Only trash the image if it is still the current one, e.g. after asking for confirmation
+        #[clap(long)]
+        #[serde(default)]
+        image: Option<PathBuf>,
+    },
+
+    /// Never select the currently shown image again, in any selection mode, and immediately show
+    /// the next image.
+    BanCurrent,
+
+    /// Undo `BanCurrent`, selecting the image again
+    Unban {
+        /// Image to select again
+        image: PathBuf,
+    },
+
+    /// Copy the currently shown image into a folder, e.g. to collect favorite images
+    CollectCurrent {
+        /// Folder to copy the image to. Defaults to `collect_directory` of the configuration.
+        #[serde(default)]
+        destination: Option<PathBuf>,
+    },
+
+    /// Rate the currently shown image, see the "rated" selection mode
+    RateCurrent {
+        /// Number of stars from 0 (never show again when using "rated") to 5
+        stars: u8,
+    },
 
     /// Stop selecting new images until a `Resume` message is sent.
     /// Any currently running and pending updates will be completed.
@@ -17,36 +58,520 @@ pub enum Request {
     /// See `Pause` for more information.
     Resume,
 
-    /// Change the time between two images
+    /// Change the time between two images. The time since the last image counts towards the
+    /// new interval.
+    /// Selecting a gallery applies the interval configured for that gallery again.
     UpdateInterval {
-        /// Number of milliseconds to wait before showing the next image
+        /// Time to wait before showing the next image, in milliseconds or as a duration like
+        /// "90s", "15m" or "2h"
+        #[serde(with = "crate::duration::millis")]
+        #[clap(value_parser = crate::duration::parse_millis)]
         millis: u64,
     },
 
     /// Choose a new gallery from which images are selected
     SelectGallery {
-        /// Name of the new gallery to use
+        /// Name of the new gallery to use, or "*" to use the images of all galleries
+        #[clap(value_name = "GALLERY")]
         name: String,
 
         /// Whether to immediately refresh the display or wait till the next scheduled update
         #[clap(long, action=clap::ArgAction::Set, value_parser, default_value = "true")]
         refresh: bool,
+
+        /// Show the image last shown from this gallery again, instead of a new one
+        #[clap(long)]
+        #[serde(default)]
+        resume: bool,
+    },
+
+    /// Write the definition of a gallery to a file, e.g. to share it with others.
+    /// The format is JSON if the file name ends in ".json", otherwise TOML.
+    ExportGallery {
+        /// Name of the gallery to export
+        #[clap(value_name = "GALLERY")]
+        name: String,
+
+        /// File to write the gallery definition to
+        path: PathBuf,
+    },
+
+    /// Add a gallery from a file created by `ExportGallery`.
+    /// The gallery is available until the daemon restarts.
+    ImportGallery {
+        /// File to read the gallery definition from
+        path: PathBuf,
+
+        /// Replace an existing gallery with the same name
+        #[clap(long)]
+        #[serde(default)]
+        replace: bool,
+    },
+
+    /// Write the ratings, statistics, quarantined images and history to a JSON file, e.g. to move
+    /// them to another machine.
+    ExportState {
+        /// File to write the state to
+        path: PathBuf,
+    },
+
+    /// Merge a file created by `ExportState` into the state. Ratings in the file replace
+    /// existing ones, the statistics keep the larger count of each image.
+    ImportState {
+        /// File to read the state from
+        path: PathBuf,
+    },
+
+    /// Freeze the current images of a gallery.
+    /// Images that are added to the gallery folders afterwards, or whose content changes, are not
+    /// shown until the gallery is pinned again.
+    PinGallery {
+        /// Name of the gallery to pin
+        #[clap(value_name = "GALLERY")]
+        name: String,
+    },
+
+    /// Select images again which were quarantined because they failed to decode, e.g. after
+    /// fixing them with another program.
+    Unquarantine {
+        /// Only release this image, instead of all quarantined ones
+        #[clap(long)]
+        #[serde(default)]
+        image: Option<PathBuf>,
+    },
+
+    /// Undo `PinGallery`, showing all images in the gallery folders again
+    UnpinGallery {
+        /// Name of the gallery to unpin
+        #[clap(value_name = "GALLERY")]
+        name: String,
     },
+
+    /// Switch to another profile of the configuration, applying its options and showing an image
+    /// of its `default_gallery`. It stays selected until the daemon restarts.
+    SelectProfile {
+        /// Name of the profile, see `profiles` of the configuration
+        #[clap(value_name = "PROFILE")]
+        name: String,
+    },
+
+    /// Only show galleries marked as `safe`, e.g. before sharing the screen.
+    /// Enabling it immediately replaces the current image. Unrelated to the safe mode entered
+    /// after repeated crashes.
+    SafeMode {
+        #[clap(action=clap::ArgAction::Set, value_parser)]
+        enabled: bool,
+    },
+
+    /// Report the current gallery and image, and the image that will be shown next
+    GetStatus,
+
+    /// List the configured galleries with their number of images
+    ListGalleries,
+
+    /// Keep the connection open and receive an `Event` for each change, e.g. to follow the
+    /// shown images in a status bar. Only supported on Unix sockets and TCP.
+    Subscribe,
+
+    /// Restart the random number generator, e.g. to synchronize the images shown on several
+    /// machines.
+    Reseed {
+        /// New seed, defaults to the `seed` of the configuration, or a random one if there is none
+        #[serde(default)]
+        seed: Option<u64>,
+    },
+
+    /// Report how often galleries and images were shown
+    Stats {
+        /// Only report this gallery instead of all of them
+        #[clap(long, value_name = "GALLERY")]
+        gallery: Option<String>,
+
+        /// Maximum number of images in each list of the report
+        #[clap(long, default_value = "10")]
+        #[serde(default = "default_stats_limit")]
+        limit: usize,
+    },
+
+    /// Stop the daemon, after the running update finished and the state was saved, like on
+    /// SIGTERM.
+    Shutdown,
+
+    /// Report the most recent events, newest first: shown images, selected galleries, pauses,
+    /// and the warnings and errors of the daemon, e.g. to find out why the image didn't change.
+    RecentEvents {
+        /// Maximum number of reported events
+        #[clap(long, default_value = "20")]
+        #[serde(default = "default_recent_events_limit")]
+        limit: usize,
+    },
+
+    /// Check the environment of the running daemon: whether the folders of its galleries can be
+    /// read and contain images, its display commands are installed and its state can be saved.
+    Diagnose,
+
+    /// Report the most recently shown images, newest first
+    History {
+        /// Only report images of this gallery
+        #[clap(long, value_name = "GALLERY")]
+        gallery: Option<String>,
+
+        /// Maximum number of reported images
+        #[clap(long, default_value = "20")]
+        #[serde(default = "default_history_limit")]
+        limit: usize,
+    },
+}
+
+fn default_stats_limit() -> usize {
+    10
+}
+
+fn default_history_limit() -> usize {
+    20
+}
+
+fn default_recent_events_limit() -> usize {
+    20
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Response {
     Ok,
-    NewImage,
+    /// The request was carried out, see `image` for whether it showed a new image.
+    NewImage {
+        /// The image which is shown now, None if the request didn't change it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        image: Option<PathBuf>,
+    },
     InvalidGallery,
-    BadRequest { message: String },
+    /// The selected gallery has no images which could be shown.
+    NoImages {
+        gallery: String,
+        folders_checked: Vec<PathBuf>,
+    },
+    BadRequest {
+        message: String,
+    },
+    Error {
+        message: String,
+    },
+    /// The image was selected, but the commands were only reported instead of running them.
+    DryRun {
+        commands: Vec<String>,
+    },
+    /// The image was changed, but the display command couldn't be started.
+    CommandFailed {
+        message: String,
+    },
+    Stats {
+        galleries: Vec<GalleryStats>,
+    },
+    Galleries {
+        galleries: Vec<GalleryInfo>,
+    },
+    History {
+        entries: Vec<HistoryEntry>,
+    },
+    Diagnosis {
+        checks: Vec<Diagnostic>,
+    },
+    RecentEvents {
+        events: Vec<RecentEvent>,
+    },
+    Status {
+        gallery: Option<String>,
+        current_image: Option<PathBuf>,
+        /// Image selected for the next update, if one could be selected
+        next_image: Option<PathBuf>,
+        paused: bool,
+        /// Whether only safe galleries are shown, see `Request::SafeMode`
+        safe_only: bool,
+        power_saving: PowerSaving,
+        /// Time since the last update, not counting pauses. None for `update_schedule`
+        elapsed_ms: Option<u64>,
+        /// Time until the next update, not counting pauses. None for `update_schedule`
+        remaining_ms: Option<u64>,
+        /// Colors of the current image as "#rrggbb", from dark to light, see
+        /// `Configuration::palette`
+        #[serde(default)]
+        palette: Vec<String>,
+        /// Selected profile of the configuration, see `Request::SelectProfile`
+        #[serde(default)]
+        profile: Option<String>,
+        /// Whether the daemon started in safe mode after crashing repeatedly, see
+        /// `Configuration::safe_mode_crash_threshold`
+        #[serde(default)]
+        safe_mode: bool,
+    },
+}
+
+/// How the rotation is throttled to save power, see `Configuration::battery`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PowerSaving {
+    /// Running on mains power, or no power saving configured
+    #[default]
+    Off,
+    /// The update interval is longer than usual
+    Slowed,
+    /// The rotation is paused
+    Paused,
+}
+
+impl Request {
+    /// Fail if this request must not be accepted from other machines, because it reads or writes
+    /// files at paths chosen by the client.
+    pub fn check_remote(&self) -> anyhow::Result<()> {
+        use Request::*;
+        let accesses_files = match self {
+            ExportGallery { .. }
+            | ImportGallery { .. }
+            | ExportState { .. }
+            | ImportState { .. }
+            | CollectCurrent {
+                destination: Some(_),
+            } => true,
+            // These only refer to images the daemon already knows about
+            TrashCurrent { .. }
+            | Unquarantine { .. }
+            | Unban { .. }
+            | NextImage { .. }
+            | BanCurrent
+            | CollectCurrent { destination: None }
+            | RateCurrent { .. }
+            | Pause
+            | Resume
+            | UpdateInterval { .. }
+            | SelectGallery { .. }
+            | SelectProfile { .. }
+            | PinGallery { .. }
+            | UnpinGallery { .. }
+            | SafeMode { .. }
+            | GetStatus
+            | ListGalleries
+            | Subscribe
+            | Reseed { .. }
+            | Stats { .. }
+            | Shutdown
+            | Diagnose
+            | RecentEvents { .. }
+            | History { .. } => false,
+        };
+        if accesses_files {
+            anyhow::bail!("Requests with file paths are only accepted from the local machine");
+        }
+        Ok(())
+    }
+
+    /// Make all relative paths in this request absolute, by interpreting them relative to `base`.
+    /// Clients should call this before sending requests, as the daemon may run in a different
+    /// working directory.
+    pub fn make_paths_absolute(&mut self, base: &Path) {
+        use Request::*;
+        match self {
+            ExportGallery { path, .. }
+            | ImportGallery { path, .. }
+            | ExportState { path }
+            | ImportState { path }
+            | Unban { image: path } => *path = base.join(&*path),
+            CollectCurrent {
+                destination: Some(path),
+            }
+            | TrashCurrent { image: Some(path) }
+            | Unquarantine { image: Some(path) } => *path = base.join(&*path),
+            NextImage { .. }
+            | TrashCurrent { image: None }
+            | BanCurrent
+            | Unquarantine { image: None }
+            | CollectCurrent { destination: None }
+            | RateCurrent { .. }
+            | Pause
+            | Resume
+            | UpdateInterval { .. }
+            | SelectGallery { .. }
+            | SelectProfile { .. }
+            | PinGallery { .. }
+            | UnpinGallery { .. }
+            | SafeMode { .. }
+            | GetStatus
+            | ListGalleries
+            | Subscribe
+            | Reseed { .. }
+            | Stats { .. }
+            | Shutdown
+            | Diagnose
+            | RecentEvents { .. }
+            | History { .. } => {}
+        }
+    }
 }
 
+/// A configured gallery, see `Request::ListGalleries`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GalleryInfo {
+    pub name: String,
+
+    /// Number of images currently in the gallery folders
+    pub number_images: usize,
+
+    /// Whether this is the current gallery
+    pub active: bool,
+
+    /// Whether the gallery is shown while `Request::SafeMode` is enabled
+    pub safe: bool,
+
+    /// Whether the gallery is pinned, see `Request::PinGallery`
+    pub pinned: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GalleryStats {
+    pub name: String,
+
+    /// Number of times an image was shown from this gallery
+    pub times_shown: u64,
+
+    /// Number of images currently in this gallery
+    pub number_images: usize,
+
+    /// Images that were shown most often, in descending order
+    pub most_shown: Vec<ImageStats>,
+
+    /// Images that were never shown
+    pub never_shown: Vec<PathBuf>,
+
+    /// Image that was shown most recently
+    pub last_shown: Option<ImageStats>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageStats {
+    pub path: PathBuf,
+    pub times_shown: u64,
+
+    /// Time when the image was last shown, in seconds since the UNIX epoch
+    pub last_shown: u64,
+}
+
+/// An image which was shown, see `Request::History`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub path: PathBuf,
+    pub gallery: String,
+
+    /// Time when the image was shown, in seconds since the UNIX epoch
+    pub shown: u64,
+
+    pub trigger: Trigger,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DiagnosticStatus {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// The result of one check, see `Request::Diagnose`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    /// What was checked, like "gallery 'landscapes'"
+    pub check: String,
+    pub status: DiagnosticStatus,
+    /// What was found, e.g. the problem
+    pub detail: String,
+    /// How to fix the problem
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fix: Option<String>,
+}
+
+impl fmt::Display for Diagnostic {
+    /// Like `[ warn ] check: detail`, with the fix on the next line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let marker = match self.status {
+            DiagnosticStatus::Ok => "  ok  ",
+            DiagnosticStatus::Warning => " warn ",
+            DiagnosticStatus::Error => " FAIL ",
+        };
+        let detail = self.detail.trim_end().replace('\n', "\n         ");
+        write!(f, "[{marker}] {}: {detail}", self.check)?;
+        if let Some(fix) = &self.fix {
+            write!(f, "\n         fix: {fix}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A change sent to clients after `Request::Subscribe`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    /// A new image is shown
+    ImageShown {
+        image: PathBuf,
+        gallery: String,
+        /// Output or rotation which shows the image, None for the main image
+        output: Option<String>,
+        trigger: Trigger,
+    },
+    GallerySelected {
+        gallery: String,
+    },
+    PauseChanged {
+        paused: bool,
+    },
+    /// A message logged by the daemon, like a failed display command. Only reported by
+    /// `Request::RecentEvents`, not sent to subscribers.
+    Logged {
+        /// "error", "warn" or "info"
+        level: String,
+        /// Module which logged the message, like "gallerica::mqtt_listener"
+        target: String,
+        message: String,
+    },
+}
+
+/// An event reported by `Request::RecentEvents`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecentEvent {
+    /// Time of the event, in milliseconds since the UNIX epoch
+    pub time_ms: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Why an image was shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    /// The update interval or schedule elapsed, including the first image after starting
+    Timer,
+    /// A request like `NextImage`
+    Request,
+    /// The schedule selected another gallery
+    Schedule,
+    /// The system resumed from suspend
+    Resume,
+    /// A monitor was connected
+    Monitor,
+    /// The timer of a rotation elapsed
+    Rotation,
+}
+
+/// Connection which `Event`s are written to, see `Request::Subscribe`.
+pub type EventWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
 #[async_trait]
 pub trait InflightRequest: Send {
     fn request(&self) -> anyhow::Result<&Request>;
     async fn respond(self: Box<Self>, response: Response) -> anyhow::Result<()>;
+
+    /// Keep the connection open to write events to it. Returns the request again if the
+    /// listener can't do this.
+    fn into_event_writer(self: Box<Self>) -> Result<EventWriter, Box<dyn InflightRequest>>;
 }
 
 #[async_trait]
@@ -71,6 +596,12 @@ impl MessageSource {
 
         Self(task)
     }
+
+    /// Stop receiving messages, and wait until the receiver is dropped, e.g. its socket removed.
+    pub async fn stop(mut self) {
+        self.0.abort();
+        let _ = (&mut self.0).await;
+    }
 }
 
 impl Drop for MessageSource {
@@ -4,7 +4,7 @@ use async_trait::async_trait;
 use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct MqttListenerConfig {
     pub client_id: String,
     pub host: String,
@@ -62,6 +62,10 @@ impl InflightRequest for MqttRequest {
 
         Ok(())
     }
+
+    fn into_event_writer(self: Box<Self>) -> Result<EventWriter, Box<dyn InflightRequest>> {
+        Err(self)
+    }
 }
 
 pub struct MqttReceiver {
@@ -104,8 +108,11 @@ impl MessageReceiver for MqttReceiver {
 
         loop {
             if let Incoming(Publish(publish)) = self.connection.poll().await? {
+                let data = serde_json::from_slice::<RequestData>(&publish.payload)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|data| data.request.check_remote().map(|()| data));
                 return Ok(Box::new(MqttRequest {
-                    data: serde_json::from_slice(&publish.payload).map_err(|e| e.into()),
+                    data,
                     client: self.client.clone(),
                 }));
             }
@@ -0,0 +1,188 @@
+use crate::message_api::*;
+use gallerica::transport::DEFAULT_PIPE_NAME;
+
+fn default_name() -> String {
+    DEFAULT_PIPE_NAME.to_owned()
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NamedPipeListenerConfig {
+    /// Name of the pipe, e.g. "\\.\pipe\gallerica". Only available on Windows.
+    #[serde(default = "default_name")]
+    pub name: String,
+}
+
+impl Default for NamedPipeListenerConfig {
+    fn default() -> Self {
+        Self {
+            name: default_name(),
+        }
+    }
+}
+
+/// Receiver for the requests sent to the pipe of `config`.
+pub fn receiver(
+    config: &NamedPipeListenerConfig,
+) -> anyhow::Result<Box<dyn MessageReceiver + Send>> {
+    #[cfg(windows)]
+    return Ok(Box::new(windows::NamedPipeReceiver::new(config)?));
+
+    #[cfg(not(windows))]
+    anyhow::bail!(
+        "Can't listen on the pipe '{}', named pipes are only available on Windows",
+        config.name
+    );
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::time::Duration;
+
+    use super::*;
+    use anyhow::{bail, Context};
+    use async_trait::async_trait;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::windows::named_pipe::{NamedPipeServer, ServerOptions},
+        sync::mpsc,
+        task::JoinHandle,
+    };
+    use tracing::warn;
+
+    /// Clients have this long to send their request, so idle clients don't occupy the pipe.
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Requests are small, anything larger than this is cut off and fails to parse.
+    const MAX_REQUEST_SIZE: u64 = 1 << 20;
+
+    /// Time to wait after failing to create or connect an instance of the pipe.
+    const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+    struct PipeRequest {
+        pub request: anyhow::Result<Request>,
+        pub pipe: NamedPipeServer,
+    }
+
+    #[async_trait]
+    impl InflightRequest for PipeRequest {
+        fn request(&self) -> anyhow::Result<&Request> {
+            self.request
+                .as_ref()
+                .map_err(|e| anyhow::format_err!(e.to_string()))
+        }
+
+        async fn respond(mut self: Box<Self>, response: Response) -> anyhow::Result<()> {
+            self.pipe.write_all(&serde_json::to_vec(&response)?).await?;
+            // Closing our end lets the client read to the end of the response
+            self.pipe.shutdown().await?;
+            Ok(())
+        }
+
+        fn into_event_writer(self: Box<Self>) -> Result<EventWriter, Box<dyn InflightRequest>> {
+            Ok(Box::new(self.pipe))
+        }
+    }
+
+    /// Creates a new instance of the pipe for each client in the background, reading their
+    /// requests in separate tasks like the TCP listener. Clients on other machines are rejected.
+    pub struct NamedPipeReceiver {
+        requests: mpsc::Receiver<PipeRequest>,
+        accept_task: JoinHandle<()>,
+    }
+
+    impl NamedPipeReceiver {
+        pub fn new(config: &NamedPipeListenerConfig) -> anyhow::Result<Self> {
+            let name = config.name.clone();
+            // Fails if the pipe already exists, e.g. because another daemon is running
+            let mut server = ServerOptions::new()
+                .first_pipe_instance(true)
+                .reject_remote_clients(true)
+                .create(&name)
+                .with_context(|| format!("Failed to create the named pipe '{name}'"))?;
+
+            let (sender, requests) = mpsc::channel(16);
+            let accept_task = tokio::spawn(async move {
+                loop {
+                    if let Err(err) = server.connect().await {
+                        warn!("Failed to accept a client of the named pipe: {err}");
+                        tokio::time::sleep(ACCEPT_RETRY_INTERVAL).await;
+                        continue;
+                    }
+                    // The next client needs a new instance, create it before handing this one off
+                    let next = loop {
+                        match ServerOptions::new()
+                            .reject_remote_clients(true)
+                            .create(&name)
+                        {
+                            Ok(next) => break next,
+                            Err(err) => {
+                                warn!("Failed to create an instance of the named pipe: {err}");
+                                tokio::time::sleep(ACCEPT_RETRY_INTERVAL).await;
+                            }
+                        }
+                    };
+                    let pipe = std::mem::replace(&mut server, next);
+                    let sender = sender.clone();
+                    tokio::spawn(async move {
+                        match read_request(pipe).await {
+                            Ok(request) => {
+                                let _ = sender.send(request).await;
+                            }
+                            Err(err) => warn!("Failed to read a named pipe request: {err:#}"),
+                        }
+                    });
+                }
+            });
+
+            Ok(Self {
+                requests,
+                accept_task,
+            })
+        }
+    }
+
+    impl Drop for NamedPipeReceiver {
+        fn drop(&mut self) {
+            self.accept_task.abort();
+        }
+    }
+
+    /// Read the single line request sent on `pipe`.
+    async fn read_request(mut pipe: NamedPipeServer) -> anyhow::Result<PipeRequest> {
+        let mut buf = vec![];
+        tokio::time::timeout(
+            REQUEST_TIMEOUT,
+            BufReader::new(&mut pipe)
+                .take(MAX_REQUEST_SIZE)
+                .read_until(b'\n', &mut buf),
+        )
+        .await
+        .context("The client sent no request in time")??;
+        Ok(PipeRequest {
+            request: serde_json::from_slice(&buf).map_err(|e| e.into()),
+            pipe,
+        })
+    }
+
+    #[async_trait]
+    impl MessageReceiver for NamedPipeReceiver {
+        async fn receive_message(&mut self) -> anyhow::Result<Box<dyn InflightRequest>> {
+            match self.requests.recv().await {
+                Some(request) => Ok(Box::new(request)),
+                None => bail!("The named pipe listener stopped accepting clients"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_pipes_are_rejected_on_other_platforms() {
+        let err = receiver(&NamedPipeListenerConfig::default()).err().unwrap();
+        assert!(err.to_string().contains("only available on Windows"));
+    }
+}
@@ -0,0 +1,34 @@
+//! Desktop notifications about the shown image, e.g. to know which file is on the screen while
+//! curating a gallery.
+
+use std::path::Path;
+
+use notify_rust::{Notification, Timeout};
+use tracing::warn;
+
+/// How long notifications are shown.
+const TIMEOUT_MS: u32 = 5000;
+
+/// Show a notification with the name and a thumbnail of `image` from `gallery`.
+/// Failures, e.g. without a notification daemon, are logged.
+pub fn image_changed(image: &Path, gallery: &str) {
+    let name = image
+        .file_name()
+        .unwrap_or(image.as_os_str())
+        .to_string_lossy()
+        .into_owned();
+    let image = image.to_owned();
+    let gallery = gallery.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let result = Notification::new()
+            .appname("gallerica")
+            .summary(&name)
+            .body(&format!("Gallery '{gallery}'"))
+            .image_path(&image.to_string_lossy())
+            .timeout(Timeout::Milliseconds(TIMEOUT_MS))
+            .show();
+        if let Err(err) = result {
+            warn!("Failed to send notification: {err}");
+        }
+    });
+}
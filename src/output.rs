@@ -0,0 +1,60 @@
+//! Monitors which show their own image on each update, e.g. a wallpaper per screen.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    processing::Processing,
+    template::{CommandLine, CommandLines},
+};
+
+/// See `Configuration::outputs`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct OutputConfiguration {
+    /// Name of the monitor as known to the display server, e.g. "DP-1". Replaces the `{monitor}`
+    /// placeholder of the command.
+    pub name: String,
+
+    /// Gallery the images of this monitor are selected from.
+    /// Defaults to the current gallery, which follows `SelectGallery` and the schedule.
+    #[serde(default)]
+    pub gallery: Option<String>,
+
+    /// Commands which show the image on this monitor. Defaults to the global `command_line`.
+    #[serde(default)]
+    pub command_line: Option<CommandLines>,
+
+    /// How images are prepared for this monitor, e.g. for its resolution. Defaults to the global
+    /// `processing`.
+    #[serde(default)]
+    pub processing: Option<Processing>,
+}
+
+pub struct Output {
+    pub name: String,
+    pub gallery: Option<String>,
+    /// None to use the global commands
+    pub commands: Option<Vec<CommandLine>>,
+    pub processing: Option<Processing>,
+    pub current_image: Option<PathBuf>,
+}
+
+impl Output {
+    pub fn new(config: &OutputConfiguration) -> Result<Self> {
+        let commands = config
+            .command_line
+            .as_ref()
+            .map(CommandLines::parse)
+            .transpose()
+            .with_context(|| format!("Invalid command of output '{}'", config.name))?;
+        Ok(Self {
+            name: config.name.clone(),
+            gallery: config.gallery.clone(),
+            commands,
+            processing: config.processing.clone(),
+            current_image: None,
+        })
+    }
+}
@@ -0,0 +1,206 @@
+//! Color palettes extracted from the shown image, e.g. to theme the terminal and status bar
+//! after the wallpaper like pywal does.
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+/// See `Configuration::palette`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PaletteConfiguration {
+    /// Number of colors to extract.
+    #[serde(default = "default_colors")]
+    pub colors: usize,
+
+    /// Files the palette is written to after each update.
+    #[serde(default)]
+    pub exports: Vec<PaletteExport>,
+}
+
+fn default_colors() -> usize {
+    16
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PaletteExport {
+    pub path: PathBuf,
+
+    #[serde(default)]
+    pub format: PaletteFormat,
+
+    /// File whose `{color0}`, `{color1}`, …, `{background}`, `{foreground}` and `{wallpaper}`
+    /// placeholders are replaced, instead of using a `format`.
+    #[serde(default)]
+    pub template: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PaletteFormat {
+    #[default]
+    Json,
+    /// `*.color0: #rrggbb` lines, for `xrdb -merge`
+    Xresources,
+    /// Custom properties like `--color0` on `:root`
+    Css,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color([u8; 3]);
+
+impl Color {
+    fn luminance(self) -> u32 {
+        let [r, g, b] = self.0.map(u32::from);
+        2126 * r + 7152 * g + 722 * b
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [r, g, b] = self.0;
+        write!(f, "#{r:02x}{g:02x}{b:02x}")
+    }
+}
+
+/// The `count` dominant colors of the image at `path`, from dark to light.
+pub async fn extract(path: &Path, count: usize) -> Result<Vec<Color>> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let image = image::open(&path)
+            .with_context(|| format!("Failed to open '{}'", path.display()))?
+            .thumbnail(64, 64)
+            .into_rgb8();
+        let pixels = image.pixels().map(|pixel| pixel.0).collect();
+        Ok(median_cut(pixels, count))
+    })
+    .await?
+}
+
+/// Split the colors into `count` boxes, halving the box with the widest range of a channel each
+/// time, and return the average color of each box from dark to light.
+fn median_cut(pixels: Vec<[u8; 3]>, count: usize) -> Vec<Color> {
+    let widest_channel = |pixels: &[[u8; 3]]| {
+        (0..3)
+            .map(|channel| {
+                let values = pixels.iter().map(|pixel| pixel[channel]);
+                let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+                (range, channel)
+            })
+            .max()
+            .unwrap_or((0, 0))
+    };
+
+    let mut boxes = vec![pixels];
+    while boxes.len() < count {
+        let Some((index, (range, channel))) = boxes
+            .iter()
+            .map(|pixels| widest_channel(pixels))
+            .enumerate()
+            .max_by_key(|(_, widest)| *widest)
+        else {
+            break;
+        };
+        if range == 0 {
+            break;
+        }
+        let mut lower = boxes.swap_remove(index);
+        lower.sort_unstable_by_key(|pixel| pixel[channel]);
+        let upper = lower.split_off(lower.len() / 2);
+        boxes.extend([lower, upper]);
+    }
+
+    let mut colors: Vec<_> = boxes
+        .iter()
+        .filter(|pixels| !pixels.is_empty())
+        .map(|pixels| {
+            let mut sum = [0usize; 3];
+            for pixel in pixels {
+                for channel in 0..3 {
+                    sum[channel] += usize::from(pixel[channel]);
+                }
+            }
+            Color(sum.map(|sum| (sum / pixels.len()) as u8))
+        })
+        .collect();
+    colors.sort_by_key(|color| color.luminance());
+    // Images with few colors still fill all slots, so templates can rely on them
+    while let Some(&last) = colors.last().filter(|_| colors.len() < count) {
+        colors.push(last);
+    }
+    colors
+}
+
+impl PaletteExport {
+    /// Write the `colors` of the `wallpaper`.
+    pub fn write(&self, colors: &[Color], wallpaper: &Path) -> Result<()> {
+        let (Some(background), Some(foreground)) = (colors.first(), colors.last()) else {
+            return Ok(());
+        };
+        let text = match (&self.template, self.format) {
+            (Some(template), _) => {
+                let mut text = fs::read_to_string(template)
+                    .with_context(|| format!("Failed to read '{}'", template.display()))?;
+                for (index, color) in colors.iter().enumerate() {
+                    text = text.replace(&format!("{{color{index}}}"), &color.to_string());
+                }
+                text.replace("{background}", &background.to_string())
+                    .replace("{foreground}", &foreground.to_string())
+                    .replace("{wallpaper}", &wallpaper.to_string_lossy())
+            }
+            (None, PaletteFormat::Json) => {
+                let colors: Vec<_> = colors.iter().map(Color::to_string).collect();
+                let json = serde_json::json!({
+                    "wallpaper": wallpaper,
+                    "background": background.to_string(),
+                    "foreground": foreground.to_string(),
+                    "colors": colors,
+                });
+                serde_json::to_string_pretty(&json)? + "\n"
+            }
+            (None, PaletteFormat::Xresources) => {
+                let mut text = format!("*.background: {background}\n*.foreground: {foreground}\n");
+                for (index, color) in colors.iter().enumerate() {
+                    text += &format!("*.color{index}: {color}\n");
+                }
+                text
+            }
+            (None, PaletteFormat::Css) => {
+                let mut text = format!(
+                    ":root {{\n  --background: {background};\n  --foreground: {foreground};\n"
+                );
+                for (index, color) in colors.iter().enumerate() {
+                    text += &format!("  --color{index}: {color};\n");
+                }
+                text + "}\n"
+            }
+        };
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, text)
+            .with_context(|| format!("Failed to write '{}'", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_palette_is_sorted_and_filled() {
+        let pixels = [[250, 250, 250], [10, 0, 0], [12, 0, 0], [0, 0, 200]].repeat(4);
+        let colors = median_cut(pixels, 4);
+        assert_eq!(colors.len(), 4);
+        assert_eq!(colors[0].to_string(), "#0a0000");
+        assert_eq!(colors.last().unwrap().to_string(), "#fafafa");
+        assert!(colors
+            .windows(2)
+            .all(|pair| pair[0].luminance() <= pair[1].luminance()));
+
+        assert_eq!(median_cut(vec![[1, 2, 3]; 3], 4), vec![Color([1, 2, 3]); 4]);
+    }
+}
@@ -0,0 +1,340 @@
+//! The commands which show one image, e.g. setting the wallpaper, then updating the lock screen
+//! and regenerating a color scheme.
+
+use std::{collections::BTreeMap, os::fd::AsFd, process::Stdio, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use tokio::{
+    process::{Child, Command},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+
+use crate::template::{CommandLine, Values};
+
+/// Settings of all display commands and hooks.
+#[derive(Debug, Clone, Default)]
+pub struct CommandSettings {
+    /// See `Configuration::command_timeout_ms`
+    pub timeout: Option<Duration>,
+    /// See `Configuration::environment`
+    pub environment: BTreeMap<String, String>,
+    /// Permits for `Configuration::max_running_commands`, shared by all pipelines
+    pub limit: Option<Arc<Semaphore>>,
+    /// Whether the output of the commands goes to stderr, because stdout carries the responses
+    /// of the stdin listener
+    pub stdout_to_stderr: bool,
+}
+
+struct Running {
+    program: String,
+    child: Child,
+    /// Released once the child was reaped
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+pub struct Pipeline {
+    /// Commands which weren't started yet, or are queued for a permit of the `limit`
+    pending: Vec<Command>,
+    running: Vec<Running>,
+    failures: Vec<String>,
+    /// See `Values::monitor`
+    monitor: String,
+    parallel: bool,
+    timeout: Option<Duration>,
+    limit: Option<Arc<Semaphore>>,
+}
+
+impl Pipeline {
+    /// The `commands` with their placeholders replaced by `values`. Commands still running after
+    /// the timeout of the `settings` are killed, the timeout includes the time they are queued.
+    /// They don't read stdin of the daemon, which may be a listener.
+    pub fn new(
+        commands: &[CommandLine],
+        values: &Values,
+        parallel: bool,
+        settings: &CommandSettings,
+    ) -> Self {
+        Self {
+            pending: commands
+                .iter()
+                .map(|command| {
+                    let mut command = command.command(values);
+                    command
+                        .envs(&settings.environment)
+                        .stdin(Stdio::null())
+                        .kill_on_drop(true);
+                    if settings.stdout_to_stderr {
+                        command.stdout(stderr());
+                    }
+                    command
+                })
+                .collect(),
+            running: vec![],
+            failures: vec![],
+            monitor: values.monitor.to_owned(),
+            parallel,
+            timeout: settings.timeout,
+            limit: settings.limit.clone(),
+        }
+    }
+
+    /// Number of commands to start next.
+    fn next_count(&self) -> usize {
+        if self.parallel {
+            self.pending.len()
+        } else {
+            self.pending.len().min(1)
+        }
+    }
+
+    /// Start the first command, or all of them if `parallel` is set, without waiting for them.
+    /// Commands beyond the `limit` stay queued until `run` gets a permit for them.
+    /// Fails if a program can't be started, e.g. because of a typo. The failure is also reported
+    /// by `run`.
+    pub fn start(&mut self) -> Result<()> {
+        let mut failures = vec![];
+        for _ in 0..self.next_count() {
+            let permit = match &self.limit {
+                Some(limit) => match limit.clone().try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => break,
+                },
+                None => None,
+            };
+            failures.extend(self.spawn_next(permit).err());
+        }
+        if failures.is_empty() {
+            return Ok(());
+        }
+        bail!("{}", failures.join(", "))
+    }
+
+    /// Like `start`, but wait for permits of the `limit` instead of leaving commands queued.
+    async fn start_queued(&mut self) {
+        for _ in 0..self.next_count() {
+            let permit = match &self.limit {
+                // The semaphore is never closed
+                Some(limit) => limit.clone().acquire_owned().await.ok(),
+                None => None,
+            };
+            // Reported by `wait`
+            let _ = self.spawn_next(permit);
+        }
+    }
+
+    /// Spawn the first pending command. A failure is recorded, and stops the commands after it
+    /// unless they run in parallel.
+    fn spawn_next(&mut self, permit: Option<OwnedSemaphorePermit>) -> Result<(), String> {
+        let mut command = self.pending.remove(0);
+        let program = program(&command);
+        match command.spawn() {
+            Ok(child) => {
+                self.running.push(Running {
+                    program,
+                    child,
+                    _permit: permit,
+                });
+                Ok(())
+            }
+            Err(err) => {
+                let failure = format!("Failed to run '{program}': {err}");
+                if !self.parallel {
+                    self.pending.clear();
+                }
+                self.failures.push(failure.clone());
+                Err(failure)
+            }
+        }
+    }
+
+    /// The commands which weren't started yet, as they would be typed in a shell.
+    pub fn describe(&self) -> Vec<String> {
+        self.pending
+            .iter()
+            .map(|command| {
+                let command = command.as_std();
+                let words = std::iter::once(command.get_program())
+                    .chain(command.get_args())
+                    .map(|word| word.to_string_lossy());
+                shell_words::join(words)
+            })
+            .collect()
+    }
+
+    /// Start all commands, without waiting for them, and return them with the name of their
+    /// monitor. None of them are left running if any fails to start. They don't count towards
+    /// the `limit`, as they never exit on their own.
+    pub fn start_detached(mut self) -> Result<(String, Vec<(String, Child)>)> {
+        self.parallel = true;
+        self.limit = None;
+        self.start()?;
+        let children = self
+            .running
+            .into_iter()
+            .map(|running| (running.program, running.child))
+            .collect();
+        Ok((self.monitor, children))
+    }
+
+    /// Run the commands one after another, or all at once if `parallel` is set.
+    /// A failing command stops the ones after it, unless they run in parallel.
+    pub async fn run(mut self) -> Result<()> {
+        if self.running.is_empty() && self.failures.is_empty() {
+            // Reported below
+            let _ = self.start();
+        }
+        let Some(timeout) = self.timeout else {
+            return self.wait().await;
+        };
+        match tokio::time::timeout(timeout, self.wait()).await {
+            Ok(result) => result,
+            Err(_) => {
+                for running in &mut self.running {
+                    // Also reaps the child
+                    let _ = running.child.kill().await;
+                }
+                bail!("Timed out after {timeout:?}, the commands were killed")
+            }
+        }
+    }
+
+    /// Wait for the running commands, then start the next ones. Children are only removed once
+    /// they were reaped, so `run` can still kill them on a timeout.
+    async fn wait(&mut self) -> Result<()> {
+        loop {
+            while let Some(running) = self.running.first_mut() {
+                let result = running.child.wait().await;
+                let program = self.running.remove(0).program;
+                match result {
+                    Ok(status) if !status.success() => {
+                        self.failures.push(format!("'{program}' failed: {status}"))
+                    }
+                    Ok(_) => {}
+                    Err(err) => self
+                        .failures
+                        .push(format!("Failed to wait for '{program}': {err}")),
+                }
+            }
+            if self.pending.is_empty() || !self.failures.is_empty() {
+                break;
+            }
+            self.start_queued().await;
+        }
+        if !self.failures.is_empty() {
+            bail!("{}", self.failures.join(", "));
+        }
+        Ok(())
+    }
+}
+
+/// Start all `pipelines` at once, e.g. one per output, and wait in the background until they are
+/// done. The second result tells whether all of them could be started, see `Pipeline::start`.
+pub fn spawn_all(mut pipelines: Vec<Pipeline>) -> (JoinHandle<Result<()>>, Result<()>) {
+    let failures: Vec<_> = pipelines
+        .iter_mut()
+        .filter_map(|pipeline| pipeline.start().err())
+        .map(|err| format!("{err:#}"))
+        .collect();
+    let started = if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("{}", failures.join(", ")))
+    };
+    let task = tokio::spawn(async move {
+        let tasks: Vec<_> = pipelines
+            .into_iter()
+            .map(|pipeline| tokio::spawn(pipeline.run()))
+            .collect();
+        let mut failures = vec![];
+        for task in tasks {
+            match task.await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => failures.push(format!("{err:#}")),
+                Err(err) => failures.push(err.to_string()),
+            }
+        }
+        if !failures.is_empty() {
+            bail!("{}", failures.join(", "));
+        }
+        Ok(())
+    });
+    (task, started)
+}
+
+/// A handle of the daemon's stderr for the stdout of a child, discarding its output if stderr
+/// can't be duplicated.
+fn stderr() -> Stdio {
+    std::io::stderr()
+        .as_fd()
+        .try_clone_to_owned()
+        .map_or_else(|_| Stdio::null(), Stdio::from)
+}
+
+fn program(command: &Command) -> String {
+    command
+        .as_std()
+        .get_program()
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_commands_beyond_limit_are_queued() {
+        let limit = Arc::new(Semaphore::new(1));
+        let settings = CommandSettings {
+            limit: Some(limit.clone()),
+            ..Default::default()
+        };
+        let values = Values {
+            image: Path::new("image.png"),
+            gallery: "",
+            monitor: "",
+            index: 0,
+        };
+        let pipeline = |command| {
+            let commands = [CommandLine::parse(command).unwrap()];
+            Pipeline::new(&commands, &values, false, &settings)
+        };
+
+        let mut first = pipeline("sleep 0.1");
+        let mut second = pipeline("true");
+        first.start().unwrap();
+        second.start().unwrap();
+        assert_eq!(first.running.len(), 1);
+        assert!(second.running.is_empty());
+        assert_eq!(limit.available_permits(), 0);
+
+        let (first, second) = tokio::join!(first.run(), second.run());
+        first.unwrap();
+        second.unwrap();
+        assert_eq!(limit.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_commands_do_not_read_stdin() {
+        let settings = CommandSettings {
+            stdout_to_stderr: true,
+            ..Default::default()
+        };
+        let values = Values {
+            image: Path::new("image.png"),
+            gallery: "",
+            monitor: "",
+            index: 0,
+        };
+        // Waits for the end of stdin, unless it is closed
+        let commands = [CommandLine::parse("cat").unwrap()];
+        let pipeline = Pipeline::new(&commands, &values, false, &settings);
+        tokio::time::timeout(Duration::from_secs(5), pipeline.run())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+//! Saving power while running on battery, by showing images less often.
+
+use std::{
+    fs::read_to_string,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::time::{Interval, MissedTickBehavior};
+
+use crate::message_api::PowerSaving;
+
+/// How often the power supplies are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+/// See `Configuration::battery`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct BatteryConfiguration {
+    /// Factor for the update interval while running on battery.
+    #[serde(default = "default_interval_factor")]
+    pub interval_factor: f64,
+
+    /// Pause the rotation while running on battery.
+    #[serde(default)]
+    pub pause_on_battery: bool,
+
+    /// Pause the rotation while running on battery with a charge below this percentage.
+    #[serde(default)]
+    pub pause_below_percent: Option<u8>,
+}
+
+fn default_interval_factor() -> f64 {
+    2.0
+}
+
+/// Power supply of the machine, as reported by the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PowerState {
+    pub on_battery: bool,
+    /// Charge of the batteries in percent, if there are any
+    pub charge: Option<u8>,
+}
+
+impl PowerState {
+    /// Read the state of all power supplies in `dir`, usually `/sys/class/power_supply`.
+    /// Machines without a battery, or whose power supplies can't be read, run on mains power.
+    pub fn read(dir: &Path) -> Self {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Self::default();
+        };
+
+        let read = |supply: &PathBuf, name| {
+            read_to_string(supply.join(name)).map(|value| value.trim().to_owned())
+        };
+        let mut mains_online = false;
+        let mut discharging = false;
+        let mut charges = vec![];
+        for supply in entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            match read(&supply, "type").as_deref() {
+                Ok("Mains" | "USB") => {
+                    mains_online |= read(&supply, "online").is_ok_and(|v| v == "1")
+                }
+                Ok("Battery") => {
+                    // Batteries of peripherals like mice don't power the machine
+                    if read(&supply, "scope").is_ok_and(|scope| scope == "Device") {
+                        continue;
+                    }
+                    discharging |= read(&supply, "status").is_ok_and(|s| s == "Discharging");
+                    charges.extend(
+                        read(&supply, "capacity")
+                            .ok()
+                            .and_then(|c| c.parse::<u8>().ok()),
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        Self {
+            on_battery: discharging && !mains_online,
+            charge: charges.iter().min().copied(),
+        }
+    }
+}
+
+/// Watches the power supply, and decides how much power to save.
+pub struct PowerMonitor {
+    config: BatteryConfiguration,
+    interval: Option<Interval>,
+    saving: PowerSaving,
+}
+
+impl PowerMonitor {
+    pub fn new(config: BatteryConfiguration) -> Self {
+        let mut monitor = Self {
+            config,
+            interval: None,
+            saving: PowerSaving::Off,
+        };
+        monitor.saving = monitor.saving_for(PowerState::read(Path::new(POWER_SUPPLY_DIR)));
+        monitor
+    }
+
+    /// How much power is currently saved.
+    pub fn saving(&self) -> PowerSaving {
+        self.saving
+    }
+
+    /// Factor for the update interval while slowed down.
+    pub fn interval_factor(&self) -> f64 {
+        self.config.interval_factor
+    }
+
+    fn saving_for(&self, state: PowerState) -> PowerSaving {
+        if !state.on_battery {
+            return PowerSaving::Off;
+        }
+        let low = self
+            .config
+            .pause_below_percent
+            .zip(state.charge)
+            .is_some_and(|(threshold, charge)| charge < threshold);
+        if self.config.pause_on_battery || low {
+            PowerSaving::Paused
+        } else {
+            PowerSaving::Slowed
+        }
+    }
+
+    /// Wait until the power saving changes, and return the new one.
+    pub async fn changed(&mut self) -> PowerSaving {
+        loop {
+            self.interval
+                .get_or_insert_with(|| {
+                    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+                    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                    interval
+                })
+                .tick()
+                .await;
+            let saving = self.saving_for(PowerState::read(Path::new(POWER_SUPPLY_DIR)));
+            if saving != self.saving {
+                self.saving = saving;
+                return saving;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_power_supplies_are_read() {
+        let dir = TempDir::new("power");
+        let supply = |name: &str, files: &[(&str, &str)]| {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+            for (file, content) in files {
+                std::fs::write(dir.join(name).join(file), format!("{content}\n")).unwrap();
+            }
+        };
+        supply("AC", &[("type", "Mains"), ("online", "0")]);
+        supply(
+            "BAT0",
+            &[
+                ("type", "Battery"),
+                ("status", "Discharging"),
+                ("capacity", "42"),
+            ],
+        );
+        supply(
+            "hidpp_battery_0",
+            &[("type", "Battery"), ("scope", "Device"), ("capacity", "5")],
+        );
+        let on_battery = PowerState::read(&dir);
+
+        supply("AC", &[("online", "1")]);
+        let on_mains = PowerState::read(&dir);
+
+        assert_eq!(
+            on_battery,
+            PowerState {
+                on_battery: true,
+                charge: Some(42)
+            }
+        );
+        assert!(!on_mains.on_battery);
+    }
+}
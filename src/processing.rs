@@ -0,0 +1,111 @@
+//! Preparing images before they are shown, e.g. scaling them down to the resolution of the
+//! monitor, or blurring and dimming them for a lock screen. Processed images are cached.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use image::{imageops::FilterType, DynamicImage};
+use serde::Deserialize;
+
+/// Number of processed images kept in the cache, the oldest ones are removed.
+const CACHE_SIZE: usize = 32;
+
+/// See `Configuration::processing`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Processing {
+    /// Resolution of the monitor. Larger images are scaled down to cover it, cropping the edges.
+    #[serde(default)]
+    pub resolution: Option<(u32, u32)>,
+
+    /// Scale images down to fit into `resolution` instead, without cropping them.
+    #[serde(default)]
+    pub fit: bool,
+
+    /// Strength of the blur, e.g. 10. No blur by default.
+    #[serde(default)]
+    pub blur: f32,
+
+    /// How much darker the image gets, from 0 to 1, e.g. 0.3.
+    #[serde(default)]
+    pub dim: f32,
+}
+
+impl Processing {
+    /// Process `image` into the `cache` directory, and return the path of the result. Images are
+    /// only processed again if they or the settings changed.
+    pub async fn apply(&self, image: &Path, cache: &Path) -> Result<PathBuf> {
+        let settings = self.clone();
+        let image = image.to_owned();
+        let cache = cache.to_owned();
+        tokio::task::spawn_blocking(move || settings.apply_blocking(&image, &cache)).await?
+    }
+
+    /// Path in the `cache` directory which `apply` writes the processed `path` to.
+    pub fn target(&self, path: &Path, cache: &Path) -> Result<PathBuf> {
+        let modified = fs::metadata(path)?.modified()?;
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(path.as_os_str().as_encoded_bytes());
+        hasher.update(format!("{modified:?} {self:?}").as_bytes());
+        Ok(cache.join(format!("{}.jpg", hasher.finalize().to_hex())))
+    }
+
+    fn apply_blocking(&self, path: &Path, cache: &Path) -> Result<PathBuf> {
+        let target = self.target(path, cache)?;
+        if target.exists() {
+            return Ok(target);
+        }
+
+        let mut image = image::open(path)
+            .with_context(|| format!("Failed to open '{}' for processing", path.display()))?;
+        if let Some((width, height)) = self.resolution {
+            if image.width() > width || image.height() > height {
+                image = if self.fit {
+                    image.resize(width, height, FilterType::Lanczos3)
+                } else {
+                    image.resize_to_fill(width, height, FilterType::Lanczos3)
+                };
+            }
+        }
+        if self.blur > 0.0 {
+            image = image.fast_blur(self.blur);
+        }
+        let mut image = image.into_rgb8();
+        if self.dim > 0.0 {
+            let factor = 1.0 - self.dim.clamp(0.0, 1.0);
+            for channel in image.iter_mut() {
+                *channel = (*channel as f32 * factor) as u8;
+            }
+        }
+
+        fs::create_dir_all(cache)?;
+        DynamicImage::from(image)
+            .save(&target)
+            .with_context(|| format!("Failed to save '{}'", target.display()))?;
+        prune(cache);
+        Ok(target)
+    }
+}
+
+/// Remove the oldest images from the `cache`, keeping `CACHE_SIZE` of them.
+fn prune(cache: &Path) {
+    let Ok(entries) = fs::read_dir(cache) else {
+        return;
+    };
+    let mut files: Vec<_> = entries
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let modified = entry.metadata().ok()?.modified().ok()?;
+            Some((modified, entry.path()))
+        })
+        .collect();
+    if files.len() <= CACHE_SIZE {
+        return;
+    }
+    files.sort();
+    for (_, path) in &files[..files.len() - CACHE_SIZE] {
+        let _ = fs::remove_file(path);
+    }
+}
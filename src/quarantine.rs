@@ -0,0 +1,106 @@
+//! Files which failed to decode as an image, see `Configuration::validate_images`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Size and modification time of a file, which change when the file is fixed or replaced.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileVersion {
+    pub len: u64,
+    /// In milliseconds since the UNIX epoch, None if the file system doesn't report it
+    pub modified_ms: Option<u64>,
+}
+
+impl FileVersion {
+    /// The current version of the file at `path`, None if it can't be read.
+    pub fn of(path: &Path) -> Option<Self> {
+        let metadata = path.metadata().ok()?;
+        let modified_ms = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|modified| modified.as_millis() as u64);
+        Some(Self {
+            len: metadata.len(),
+            modified_ms,
+        })
+    }
+}
+
+/// Quarantined files, with the version of each that failed to decode. A file is selected again
+/// once it changes, e.g. when a truncated download is completed.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(transparent)]
+pub struct Quarantine(HashMap<PathBuf, FileVersion>);
+
+impl Quarantine {
+    /// Quarantine the current version of the file at `path`, unless it can't be read anymore.
+    pub fn insert(&mut self, path: PathBuf) {
+        if let Some(version) = FileVersion::of(&path) {
+            self.0.insert(path, version);
+        }
+    }
+
+    /// Whether `path` is quarantined and didn't change since.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.0
+            .get(path)
+            .is_some_and(|version| FileVersion::of(path).as_ref() == Some(version))
+    }
+
+    /// Release `path` from the quarantine. Returns whether it was quarantined.
+    pub fn remove(&mut self, path: &Path) -> bool {
+        self.0.remove(path).is_some()
+    }
+
+    /// Release all files, returns their number.
+    pub fn clear(&mut self) -> usize {
+        let count = self.0.len();
+        self.0.clear();
+        count
+    }
+
+    /// The quarantined files, see `Request::ExportState`.
+    pub fn paths(&self) -> HashSet<PathBuf> {
+        self.0.keys().cloned().collect()
+    }
+
+    /// Quarantine the current versions of `paths`, e.g. imported from another machine.
+    pub fn extend(&mut self, paths: impl IntoIterator<Item = PathBuf>) {
+        for path in paths {
+            self.insert(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_changed_files_are_released() {
+        let dir = TempDir::new("quarantine");
+        let broken = dir.join("broken.png");
+        std::fs::write(&broken, b"truncated").unwrap();
+
+        let mut quarantine = Quarantine::default();
+        quarantine.insert(broken.clone());
+        quarantine.insert(dir.join("missing.png"));
+        assert!(quarantine.contains(&broken));
+        assert_eq!(quarantine.paths(), HashSet::from([broken.clone()]));
+
+        std::fs::write(&broken, b"the complete download").unwrap();
+        assert!(!quarantine.contains(&broken));
+
+        quarantine.insert(broken.clone());
+        assert!(quarantine.remove(&broken));
+        assert!(!quarantine.contains(&broken));
+        assert_eq!(quarantine.clear(), 0);
+    }
+}
@@ -0,0 +1,161 @@
+//! Rotations next to the main one, each showing images of its own gallery with its own command
+//! and timer, e.g. the desktop wallpaper every 30 minutes and the lock screen every 6 hours.
+
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::Poll,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use croner::Cron;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    pipeline::{CommandSettings, Pipeline},
+    processing::Processing,
+    template::{CommandLine, CommandLines, Values},
+    timer::{CronTimer, PausableInterval, TickResult, UpdateTimer},
+};
+
+/// See `Configuration::rotations`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RotationConfiguration {
+    pub name: String,
+
+    /// Commands which show the image, like the global `command_line`.
+    pub command_line: CommandLines,
+
+    /// Run the commands of `command_line` all at once, like the global `parallel_commands`.
+    #[serde(default)]
+    pub parallel_commands: bool,
+
+    /// Gallery the images are selected from. Unlike the main rotation, it isn't changed by the
+    /// schedule or `SelectGallery`.
+    pub gallery: String,
+
+    /// Time between two images. Defaults to the global `update_interval_ms`.
+    #[serde(default, with = "crate::duration::optional_millis")]
+    pub update_interval_ms: Option<u64>,
+
+    /// Cron expression for the times at which images change, replacing `update_interval_ms`.
+    #[serde(default)]
+    pub update_schedule: Option<Cron>,
+
+    /// How images are prepared for this rotation, see the global `processing`, which isn't used
+    /// for rotations.
+    #[serde(default)]
+    pub processing: Option<Processing>,
+}
+
+pub struct Rotation {
+    pub name: String,
+    pub gallery: String,
+    commands: Vec<CommandLine>,
+    parallel_commands: bool,
+    command_settings: CommandSettings,
+    pub processing: Option<Processing>,
+    pub timer: UpdateTimer,
+    pub current_image: Option<PathBuf>,
+}
+
+impl Rotation {
+    /// A rotation whose timer ticks immediately, see `UpdateTimer`.
+    pub fn new(
+        config: &RotationConfiguration,
+        default_interval: Duration,
+        command_settings: &CommandSettings,
+    ) -> Result<Self> {
+        let commands = config
+            .command_line
+            .parse()
+            .with_context(|| format!("Invalid command of rotation '{}'", config.name))?;
+        let timer = match &config.update_schedule {
+            Some(schedule) => UpdateTimer::Cron(Box::new(CronTimer::new(schedule.clone()))),
+            None => UpdateTimer::Interval(PausableInterval::new(
+                config
+                    .update_interval_ms
+                    .map_or(default_interval, Duration::from_millis),
+            )),
+        };
+        Ok(Self {
+            name: config.name.clone(),
+            gallery: config.gallery.clone(),
+            commands,
+            parallel_commands: config.parallel_commands,
+            command_settings: command_settings.clone(),
+            processing: config.processing.clone(),
+            timer,
+            current_image: None,
+        })
+    }
+
+    /// Continue the timer and the current image of `previous`, this rotation before the
+    /// configuration was reloaded, unless its interval, schedule or gallery changed.
+    pub fn continue_from(&mut self, previous: Rotation) {
+        let same_timing = match (&self.timer, &previous.timer) {
+            (UpdateTimer::Interval(timer), UpdateTimer::Interval(previous)) => {
+                timer.period() == previous.period()
+            }
+            (UpdateTimer::Cron(timer), UpdateTimer::Cron(previous)) => {
+                timer.schedule() == previous.schedule()
+            }
+            _ => false,
+        };
+        if same_timing && self.gallery == previous.gallery {
+            self.timer = previous.timer;
+            self.current_image = previous.current_image;
+        }
+    }
+
+    /// Run the commands of this rotation in the background, showing `image` as `processed`.
+    pub fn show(&mut self, image: &Path, processed: &Path) {
+        self.current_image = Some(image.to_owned());
+        let values = Values {
+            image: processed,
+            gallery: &self.gallery,
+            monitor: "",
+            index: 0,
+        };
+        let pipeline = Pipeline::new(
+            &self.commands,
+            &values,
+            self.parallel_commands,
+            &self.command_settings,
+        );
+        let name = self.name.clone();
+        tokio::spawn(async move {
+            if let Err(err) = pipeline.run().await {
+                warn!("Command of rotation '{name}' failed: {err:#}");
+            }
+        });
+    }
+}
+
+/// Wait until the timer of any rotation ticks, and return its index.
+/// Never returns if there are no rotations, or all of them are paused.
+pub async fn next_due(rotations: &mut [Rotation]) -> usize {
+    type Tick<'a> = Pin<Box<dyn Future<Output = TickResult> + Send + 'a>>;
+    let mut ticks: Vec<Option<Tick>> = rotations
+        .iter_mut()
+        .map(|rotation| Some(Box::pin(rotation.timer.tick()) as Tick))
+        .collect();
+    std::future::poll_fn(|context| {
+        for (index, slot) in ticks.iter_mut().enumerate() {
+            let Some(tick) = slot else {
+                continue;
+            };
+            match tick.as_mut().poll(context) {
+                Poll::Ready(TickResult::Completed) => return Poll::Ready(index),
+                // Paused timers stay paused until the next call
+                Poll::Ready(TickResult::Paused) => *slot = None,
+                Poll::Pending => {}
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
@@ -0,0 +1,490 @@
+//! Switching galleries automatically, depending on the time of day and the date.
+
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Datelike, Local, Month, NaiveDate, NaiveTime, TimeZone};
+use serde::Deserialize;
+use sunrise::{Coordinates, SolarDay, SolarEvent};
+use tokio::time::{Interval, MissedTickBehavior};
+
+/// How often the schedule is evaluated, e.g. also right after midnight for date based rules.
+/// Checking regularly instead of sleeping until the next change handles suspend and clock changes.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Position on earth, used to calculate sunrise and sunset.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// A point in time during each day.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum TimeOfDay {
+    Clock(NaiveTime),
+    Sunrise,
+    Sunset,
+}
+
+impl FromStr for TimeOfDay {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sunrise" => Ok(Self::Sunrise),
+            "sunset" => Ok(Self::Sunset),
+            _ => NaiveTime::parse_from_str(s, "%H:%M")
+                .map(Self::Clock)
+                .map_err(|_| {
+                    anyhow!("Invalid time '{s}', expected 'HH:MM', 'sunrise' or 'sunset'")
+                }),
+        }
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// A day of each year, written as "MM-DD".
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String")]
+pub struct DayOfYear {
+    month: u32,
+    day: u32,
+}
+
+impl FromStr for DayOfYear {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid date '{s}', expected 'MM-DD'");
+        let (month, day) = s.split_once('-').ok_or_else(invalid)?;
+        let (month, day) = (
+            month.parse().map_err(|_| invalid())?,
+            day.parse().map_err(|_| invalid())?,
+        );
+        // Validate against a leap year, so February 29 is allowed
+        NaiveDate::from_ymd_opt(2000, month, day).ok_or_else(invalid)?;
+        Ok(Self { month, day })
+    }
+}
+
+impl TryFrom<String> for DayOfYear {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+/// A month, either as number from 1 to 12, or by its english name.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "MonthDefinition")]
+pub struct MonthOfYear(u32);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MonthDefinition {
+    Number(u32),
+    Name(String),
+}
+
+impl TryFrom<MonthDefinition> for MonthOfYear {
+    type Error = anyhow::Error;
+
+    fn try_from(definition: MonthDefinition) -> Result<Self> {
+        match definition {
+            MonthDefinition::Number(n @ 1..=12) => Ok(Self(n)),
+            MonthDefinition::Number(n) => bail!("Invalid month {n}"),
+            MonthDefinition::Name(name) => name
+                .parse::<Month>()
+                .map(|month| Self(month.number_from_month()))
+                .map_err(|_| anyhow!("Invalid month '{name}'")),
+        }
+    }
+}
+
+/// Select `gallery` while all conditions of the rule are met.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ScheduleRule {
+    pub gallery: String,
+
+    /// Time range of each day. If `to` is before `from`, the range extends over midnight.
+    /// Both or neither need to be given.
+    #[serde(default)]
+    pub from: Option<TimeOfDay>,
+    #[serde(default)]
+    pub to: Option<TimeOfDay>,
+
+    /// Range of days of each year, both inclusive. If `to_date` is before `from_date`, the
+    /// range extends over new year.
+    /// Both or neither need to be given.
+    #[serde(default)]
+    pub from_date: Option<DayOfYear>,
+    #[serde(default)]
+    pub to_date: Option<DayOfYear>,
+
+    /// Months in which the rule is active.
+    #[serde(default)]
+    pub months: Vec<MonthOfYear>,
+}
+
+impl ScheduleRule {
+    fn uses_sun(&self) -> bool {
+        [self.from, self.to]
+            .iter()
+            .flatten()
+            .any(|time| !matches!(time, TimeOfDay::Clock(_)))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.from.is_some() != self.to.is_some() {
+            bail!(
+                "Schedule for gallery '{}' needs both `from` and `to`",
+                self.gallery
+            );
+        }
+        if self.from_date.is_some() != self.to_date.is_some() {
+            bail!(
+                "Schedule for gallery '{}' needs both `from_date` and `to_date`",
+                self.gallery
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether this rule is active at `now`.
+    fn is_active<Tz: TimeZone>(&self, now: &DateTime<Tz>, location: Option<Location>) -> bool {
+        self.is_active_on(now.date_naive()) && self.is_active_at(now, location)
+    }
+
+    fn is_active_on(&self, date: NaiveDate) -> bool {
+        if !self.months.is_empty() && !self.months.contains(&MonthOfYear(date.month())) {
+            return false;
+        }
+
+        let (Some(from), Some(to)) = (self.from_date, self.to_date) else {
+            return true;
+        };
+        let day = DayOfYear {
+            month: date.month(),
+            day: date.day(),
+        };
+        if from <= to {
+            from <= day && day <= to
+        } else {
+            from <= day || day <= to
+        }
+    }
+
+    /// Whether the time range of this rule contains `now`.
+    fn is_active_at<Tz: TimeZone>(&self, now: &DateTime<Tz>, location: Option<Location>) -> bool {
+        match (self.from, self.to) {
+            (Some(from), Some(to)) => in_time_range(from, to, now, location),
+            _ => true,
+        }
+    }
+}
+
+/// Whether the daily time range from `from` to `to` contains `now`. If `to` is before `from`, the
+/// range extends over midnight.
+/// Ranges using the sun never contain `now` on days without sunrise or sunset, or without a
+/// `location`.
+pub fn in_time_range<Tz: TimeZone>(
+    from: TimeOfDay,
+    to: TimeOfDay,
+    now: &DateTime<Tz>,
+    location: Option<Location>,
+) -> bool {
+    let resolve = |time: TimeOfDay| -> Option<NaiveTime> {
+        let event = match time {
+            TimeOfDay::Clock(time) => return Some(time),
+            TimeOfDay::Sunrise => SolarEvent::Sunrise,
+            TimeOfDay::Sunset => SolarEvent::Sunset,
+        };
+        let location = location?;
+        let coordinates = Coordinates::new(location.latitude, location.longitude)?;
+        let event_time = SolarDay::new(coordinates, now.date_naive()).event_time(event)?;
+        Some(event_time.with_timezone(&now.timezone()).time())
+    };
+
+    let (Some(from), Some(to)) = (resolve(from), resolve(to)) else {
+        return false;
+    };
+    let time = now.time();
+    if from <= to {
+        from <= time && time < to
+    } else {
+        from <= time || time < to
+    }
+}
+
+/// A time range of each day, like the time range of a `ScheduleRule`.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct TimeRange {
+    pub from: TimeOfDay,
+    pub to: TimeOfDay,
+}
+
+/// Polling timer for `CHECK_INTERVAL`, created on first use.
+fn check_interval(interval: &mut Option<Interval>) -> &mut Interval {
+    interval.get_or_insert_with(|| {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        interval
+    })
+}
+
+/// Times during which no images are shown automatically, see `Configuration::quiet_hours`.
+#[derive(Default)]
+pub struct QuietHours {
+    ranges: Vec<TimeRange>,
+    location: Option<Location>,
+
+    interval: Option<Interval>,
+    active: bool,
+}
+
+impl QuietHours {
+    pub fn new(ranges: Vec<TimeRange>, location: Option<Location>) -> Result<Self> {
+        let uses_sun = ranges
+            .iter()
+            .flat_map(|range| [range.from, range.to])
+            .any(|time| !matches!(time, TimeOfDay::Clock(_)));
+        if uses_sun && location.is_none() {
+            bail!("Quiet hours use sunrise or sunset, which requires a `location`");
+        }
+
+        let mut quiet_hours = Self {
+            ranges,
+            location,
+            interval: None,
+            active: false,
+        };
+        quiet_hours.active = quiet_hours.is_active_at(&Local::now());
+        Ok(quiet_hours)
+    }
+
+    /// Whether it's currently quiet, as of the last check.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    fn is_active_at<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> bool {
+        self.ranges
+            .iter()
+            .any(|range| in_time_range(range.from, range.to, now, self.location))
+    }
+
+    /// Wait until quiet hours start or end, and return whether it's quiet now.
+    /// Never returns if there are no quiet hours.
+    pub async fn changed(&mut self) -> bool {
+        if self.ranges.is_empty() {
+            return std::future::pending().await;
+        }
+
+        loop {
+            check_interval(&mut self.interval).tick().await;
+            let active = self.is_active_at(&Local::now());
+            if active != self.active {
+                self.active = active;
+                return active;
+            }
+        }
+    }
+}
+
+/// Decides which gallery should be shown, see `Configuration::schedule`.
+#[derive(Default)]
+pub struct Schedule {
+    rules: Vec<ScheduleRule>,
+    location: Option<Location>,
+    /// Gallery to show while no rule is active
+    fallback: String,
+
+    interval: Option<Interval>,
+    /// Gallery selected by the last evaluation
+    current: Option<String>,
+}
+
+impl Schedule {
+    pub fn new(
+        rules: Vec<ScheduleRule>,
+        location: Option<Location>,
+        fallback: String,
+    ) -> Result<Self> {
+        for rule in &rules {
+            rule.validate()?;
+        }
+        if location.is_none() {
+            if let Some(rule) = rules.iter().find(|rule| rule.uses_sun()) {
+                bail!(
+                    "Schedule for gallery '{}' uses sunrise or sunset, which requires a `location`",
+                    rule.gallery
+                );
+            }
+        }
+        if let Some(location) = location {
+            if Coordinates::new(location.latitude, location.longitude).is_none() {
+                bail!("Invalid location {location:?}");
+            }
+        }
+
+        Ok(Self {
+            rules,
+            location,
+            fallback,
+            interval: None,
+            current: None,
+        })
+    }
+
+    pub fn location(&self) -> Option<Location> {
+        self.location
+    }
+
+    /// Galleries that the schedule may select.
+    pub fn galleries(&self) -> impl Iterator<Item = &str> {
+        self.rules.iter().map(|rule| rule.gallery.as_str())
+    }
+
+    /// The gallery selected at `now`, or None if there is no schedule.
+    /// The first active rule wins.
+    fn gallery_at<Tz: TimeZone>(&self, now: &DateTime<Tz>) -> Option<&str> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let active = self
+            .rules
+            .iter()
+            .find(|rule| rule.is_active(now, self.location));
+        Some(active.map_or(self.fallback.as_str(), |rule| rule.gallery.as_str()))
+    }
+
+    /// The gallery selected right now, or None if there is no schedule.
+    /// Later calls to `changed` only report galleries different from this one.
+    pub fn current(&mut self) -> Option<String> {
+        self.current = self.gallery_at(&Local::now()).map(str::to_owned);
+        self.current.clone()
+    }
+
+    /// Wait until the schedule selects a different gallery, and return its name.
+    /// Never returns if there is no schedule.
+    pub async fn changed(&mut self) -> String {
+        if self.rules.is_empty() {
+            return std::future::pending().await;
+        }
+
+        loop {
+            check_interval(&mut self.interval).tick().await;
+            let now = Local::now();
+            let Some(gallery) = self.gallery_at(&now) else {
+                continue;
+            };
+            if self.current.as_deref() != Some(gallery) {
+                let gallery = gallery.to_owned();
+                self.current = Some(gallery.clone());
+                return gallery;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+
+    fn rule(gallery: &str, from: &str, to: &str) -> ScheduleRule {
+        ScheduleRule {
+            gallery: gallery.to_owned(),
+            from: Some(from.parse().unwrap()),
+            to: Some(to.parse().unwrap()),
+            from_date: None,
+            to_date: None,
+            months: vec![],
+        }
+    }
+
+    #[test]
+    fn test_rules_are_matched_by_time_of_day() {
+        let schedule = Schedule::new(
+            vec![
+                rule("night", "20:00", "06:00"),
+                rule("lunch", "12:00", "13:00"),
+            ],
+            None,
+            "day".to_owned(),
+        )
+        .unwrap();
+
+        let at = |time: &str| {
+            let time = format!("2024-03-01T{time}:00Z");
+            let now = DateTime::parse_from_rfc3339(&time).unwrap();
+            schedule
+                .gallery_at(&now.with_timezone(&Utc))
+                .unwrap()
+                .to_owned()
+        };
+        assert_eq!(at("23:30"), "night");
+        assert_eq!(at("05:59"), "night");
+        assert_eq!(at("06:00"), "day");
+        assert_eq!(at("12:30"), "lunch");
+        assert_eq!(at("20:00"), "night");
+    }
+
+    #[test]
+    fn test_quiet_hours_extend_over_midnight() {
+        let ranges = vec![TimeRange {
+            from: "23:00".parse().unwrap(),
+            to: "07:00".parse().unwrap(),
+        }];
+        let quiet_hours = QuietHours::new(ranges, None).unwrap();
+
+        let at = |time: &str| {
+            let time = format!("2024-03-01T{time}:00Z");
+            quiet_hours.is_active_at(&DateTime::parse_from_rfc3339(&time).unwrap())
+        };
+        assert!(at("23:30"));
+        assert!(at("06:59"));
+        assert!(!at("07:00"));
+        assert!(!at("12:00"));
+    }
+
+    #[test]
+    fn test_sun_needs_location() {
+        let rules = vec![rule("day", "sunrise", "sunset")];
+        assert!(Schedule::new(rules, None, "night".to_owned()).is_err());
+    }
+
+    #[test]
+    fn test_rules_are_matched_by_date() {
+        let rules: Vec<ScheduleRule> = serde_json::from_str(
+            r#"[
+                { "gallery": "christmas", "from_date": "12-20", "to_date": "01-06" },
+                { "gallery": "autumn", "months": ["october", 11] }
+            ]"#,
+        )
+        .unwrap();
+        let schedule = Schedule::new(rules, None, "default".to_owned()).unwrap();
+
+        let on = |date: &str| {
+            let time = format!("{date}T12:00:00Z");
+            let now = DateTime::parse_from_rfc3339(&time).unwrap();
+            schedule.gallery_at(&now).unwrap().to_owned()
+        };
+        assert_eq!(on("2024-10-01"), "autumn");
+        assert_eq!(on("2024-11-30"), "autumn");
+        assert_eq!(on("2024-12-19"), "default");
+        assert_eq!(on("2024-12-20"), "christmas");
+        assert_eq!(on("2025-01-06"), "christmas");
+        assert_eq!(on("2025-01-07"), "default");
+    }
+}
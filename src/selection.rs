@@ -0,0 +1,438 @@
+//! Strategies for choosing the next image out of the images of a gallery.
+
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use circular_queue::CircularQueue;
+use rand::{distributions::WeightedIndex, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::statistics::Statistics;
+
+/// How the next image of a gallery is selected.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SelectionMode {
+    /// Select a random image, trying to avoid the images in the recently selected buffer.
+    /// Folder weights are only used by this mode and "rated".
+    #[default]
+    Random,
+    /// Show all images in random order, before showing any image again.
+    Shuffle,
+    /// Like "random", but the probability of an image is proportional to its rating, see
+    /// `Request::RateCurrent`. Unrated images count as `UNRATED_STARS` stars.
+    Rated,
+    /// Show the image which was not shown for the longest time.
+    LeastRecentlyShown,
+    /// Show all images ordered by path, with numbers compared by value so "2.jpg" comes before
+    /// "10.jpg", then start over.
+    Alphabetical,
+    /// Show all images ordered by modification time, oldest first, then start over.
+    Chronological,
+}
+
+/// Highest rating an image can get.
+pub const MAX_STARS: u8 = 5;
+
+/// Rating assumed for images that were not rated yet.
+pub const UNRATED_STARS: u8 = 3;
+
+/// Images shown just now are still this likely, so galleries with a single image keep working.
+const MIN_RECENCY_WEIGHT: f64 = 0.001;
+
+/// Relative probability of an image last shown at `last_shown` (in seconds since the UNIX epoch),
+/// approaching one exponentially as the time since then grows.
+pub fn recency_weight(last_shown: Option<u64>, now: u64, half_life: Duration) -> f64 {
+    let Some(last_shown) = last_shown else {
+        return 1.0;
+    };
+    let age = now.saturating_sub(last_shown) as f64;
+    let half_lives = age / half_life.as_secs_f64().max(f64::MIN_POSITIVE);
+    (1.0 - 0.5f64.powf(half_lives)).max(MIN_RECENCY_WEIGHT)
+}
+
+/// Ratio of width to height of an image or display, written as a number or as "W:H".
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "AspectRatioDefinition")]
+pub struct AspectRatio(f64);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AspectRatioDefinition {
+    Number(f64),
+    Text(String),
+}
+
+impl TryFrom<AspectRatioDefinition> for AspectRatio {
+    type Error = anyhow::Error;
+
+    fn try_from(definition: AspectRatioDefinition) -> anyhow::Result<Self> {
+        let ratio = match definition {
+            AspectRatioDefinition::Number(ratio) => ratio,
+            AspectRatioDefinition::Text(text) => {
+                let invalid = || anyhow::anyhow!("Invalid aspect ratio '{text}', expected 'W:H'");
+                let (width, height) = text.split_once(':').ok_or_else(invalid)?;
+                let width: f64 = width.trim().parse().map_err(|_| invalid())?;
+                let height: f64 = height.trim().parse().map_err(|_| invalid())?;
+                width / height
+            }
+        };
+        if !ratio.is_finite() || ratio <= 0.0 {
+            anyhow::bail!("Aspect ratio must be positive");
+        }
+        Ok(Self(ratio))
+    }
+}
+
+/// Reduce `files` to the images that fit best on a display with aspect ratio `target`.
+/// These are the images whose aspect ratio differs by at most `tolerance` (relative to `target`),
+/// or if there are none, the images with the same orientation (landscape or portrait), or if
+/// there are none either, all files.
+pub fn prefer_aspect_ratio(
+    files: Vec<PathBuf>,
+    target: AspectRatio,
+    tolerance: f64,
+    dimensions: impl Fn(&Path) -> Option<(u32, u32)>,
+) -> Vec<PathBuf> {
+    let ratios: Vec<_> = files
+        .iter()
+        .map(|file| dimensions(file).map(|(w, h)| f64::from(w) / f64::from(h)))
+        .collect();
+
+    let matching = |accept: &dyn Fn(f64) -> bool| -> Vec<PathBuf> {
+        files
+            .iter()
+            .zip(&ratios)
+            .filter(|(_, ratio)| ratio.is_some_and(accept))
+            .map(|(file, _)| file.clone())
+            .collect()
+    };
+
+    let AspectRatio(target) = target;
+    let close = matching(&|ratio| (ratio - target).abs() / target <= tolerance);
+    if !close.is_empty() {
+        return close;
+    }
+    let same_orientation = matching(&|ratio| (ratio >= 1.0) == (target >= 1.0));
+    if !same_orientation.is_empty() {
+        return same_orientation;
+    }
+    files
+}
+
+pub trait SelectionStrategy {
+    /// Choose one of `files`, or None if no file should be shown.
+    fn select(&mut self, files: &[PathBuf], rng: &mut dyn RngCore) -> Option<PathBuf>;
+}
+
+/// See `SelectionMode::Random` and `SelectionMode::Rated`.
+pub struct Random<'a> {
+    /// Images that should not be selected again, if there is no buffer then every selection
+    /// is accepted.
+    pub recently_selected: Option<&'a mut CircularQueue<PathBuf>>,
+    /// Number of new attempts if a recently selected image was chosen.
+    pub retries: u32,
+    /// Probability of each file, if they are not uniform.
+    pub weights: Option<WeightedIndex<f64>>,
+}
+
+impl SelectionStrategy for Random<'_> {
+    fn select(&mut self, files: &[PathBuf], rng: &mut dyn RngCore) -> Option<PathBuf> {
+        let mut tries_left = self.retries;
+        loop {
+            let selection = match &self.weights {
+                Some(weights) => files.get(weights.sample(rng)),
+                None => files.choose(rng),
+            }?;
+
+            let buffer = match &mut self.recently_selected {
+                Some(buffer) if tries_left > 0 => buffer,
+                _ => return Some(selection.clone()),
+            };
+
+            if !buffer.iter().any(|e| e == selection) {
+                buffer.push(selection.clone());
+                return Some(selection.clone());
+            }
+
+            tries_left -= 1;
+        }
+    }
+}
+
+/// Progress of `SelectionMode::Shuffle` through a gallery.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct ShuffleState {
+    /// Images not yet shown in this round, the next image is at the end
+    remaining: Vec<PathBuf>,
+    /// Images already shown in this round
+    shown: HashSet<PathBuf>,
+}
+
+/// See `SelectionMode::Shuffle`.
+pub struct Shuffle<'a> {
+    pub state: &'a mut ShuffleState,
+    /// Image that is currently shown, which should not be the first image of the next round
+    pub current: Option<&'a Path>,
+}
+
+impl SelectionStrategy for Shuffle<'_> {
+    fn select(&mut self, files: &[PathBuf], rng: &mut dyn RngCore) -> Option<PathBuf> {
+        let state = &mut *self.state;
+        let available: HashSet<_> = files.iter().collect();
+        state.remaining.retain(|file| available.contains(file));
+
+        if state.remaining.is_empty() {
+            state.shown.clear();
+            state.remaining = files.to_vec();
+            state.remaining.shuffle(rng);
+
+            let len = state.remaining.len();
+            if len > 1 && state.remaining.last().map(PathBuf::as_path) == self.current {
+                state.remaining.swap(0, len - 1);
+            }
+        } else {
+            // Images added during a round are shown in the same round
+            let remaining: HashSet<_> = state.remaining.iter().cloned().collect();
+            for file in files {
+                if !remaining.contains(file) && !state.shown.contains(file) {
+                    let position = rng.gen_range(0..=state.remaining.len());
+                    state.remaining.insert(position, file.clone());
+                }
+            }
+        }
+
+        let selection = state.remaining.pop()?;
+        state.shown.insert(selection.clone());
+        Some(selection)
+    }
+}
+
+/// See `SelectionMode::LeastRecentlyShown`.
+pub struct LeastRecentlyShown<'a> {
+    pub statistics: &'a Statistics,
+}
+
+impl SelectionStrategy for LeastRecentlyShown<'_> {
+    fn select(&mut self, files: &[PathBuf], rng: &mut dyn RngCore) -> Option<PathBuf> {
+        // Images that were never shown come first, as None is less than any time
+        let oldest = files
+            .iter()
+            .map(|file| self.statistics.last_shown(file))
+            .min()?;
+        files
+            .iter()
+            .filter(|file| self.statistics.last_shown(file) == oldest)
+            .choose(rng)
+            .cloned()
+    }
+}
+
+/// Position of `Sequential` in a gallery, i.e. the most recently shown image.
+/// The sort key is stored instead of just the path, so the position stays valid if the image is
+/// removed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    modified: Option<SystemTime>,
+    path: PathBuf,
+}
+
+impl Cursor {
+    fn of(path: &Path, by_modification_time: bool) -> Self {
+        let modified = by_modification_time
+            .then(|| path.metadata().and_then(|m| m.modified()).ok())
+            .flatten();
+        Self {
+            modified,
+            path: path.to_path_buf(),
+        }
+    }
+
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.modified
+            .cmp(&other.modified)
+            .then_with(|| natural_cmp(&self.path, &other.path))
+    }
+}
+
+/// Compare paths, treating runs of digits as numbers.
+fn natural_cmp(a: &Path, b: &Path) -> Ordering {
+    fn chunks(text: &str) -> impl Iterator<Item = &str> {
+        let mut rest = text;
+        std::iter::from_fn(move || {
+            let first = rest.chars().next()?;
+            let is_digit = first.is_ascii_digit();
+            let end = rest
+                .find(|c: char| c.is_ascii_digit() != is_digit)
+                .unwrap_or(rest.len());
+            let (chunk, tail) = rest.split_at(end);
+            rest = tail;
+            Some(chunk)
+        })
+    }
+
+    let (a, b) = (a.to_string_lossy(), b.to_string_lossy());
+    for (x, y) in chunks(&a).zip(chunks(&b)) {
+        let ordering = match (x.parse::<u128>(), y.parse::<u128>()) {
+            (Ok(m), Ok(n)) => m.cmp(&n).then_with(|| x.cmp(y)),
+            _ => x.cmp(y),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    chunks(&a).count().cmp(&chunks(&b).count())
+}
+
+/// See `SelectionMode::Alphabetical` and `SelectionMode::Chronological`.
+pub struct Sequential<'a> {
+    /// Position in the gallery, updated to the selected image
+    pub cursor: &'a mut Option<Cursor>,
+    pub by_modification_time: bool,
+}
+
+impl SelectionStrategy for Sequential<'_> {
+    fn select(&mut self, files: &[PathBuf], _rng: &mut dyn RngCore) -> Option<PathBuf> {
+        let candidates: Vec<_> = files
+            .iter()
+            .map(|file| Cursor::of(file, self.by_modification_time))
+            .collect();
+
+        let cursor = self.cursor.as_ref();
+        let next = candidates
+            .iter()
+            .filter(|candidate| {
+                cursor.is_none_or(|cursor| candidate.cmp(cursor) == Ordering::Greater)
+            })
+            .min_by(|a, b| a.cmp(b))
+            // Start over after the last image
+            .or_else(|| candidates.iter().min_by(|a, b| a.cmp(b)))?;
+
+        *self.cursor = Some(next.clone());
+        Some(next.path.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shuffle_shows_every_image_once_per_round() {
+        let files: Vec<PathBuf> = ["a", "b", "c", "d"].iter().map(PathBuf::from).collect();
+        let mut state = ShuffleState::default();
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..3 {
+            let mut round: Vec<_> = (0..files.len())
+                .map(|_| {
+                    Shuffle {
+                        state: &mut state,
+                        current: None,
+                    }
+                    .select(&files, &mut rng)
+                    .unwrap()
+                })
+                .collect();
+            round.sort();
+            assert_eq!(round, files);
+        }
+    }
+
+    #[test]
+    fn test_same_seed_selects_same_sequence() {
+        let files: Vec<PathBuf> = (0..100).map(|i| PathBuf::from(i.to_string())).collect();
+        let sequence = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut recent = CircularQueue::with_capacity(10);
+            (0..20)
+                .map(|_| {
+                    Random {
+                        recently_selected: Some(&mut recent),
+                        retries: 10,
+                        weights: None,
+                    }
+                    .select(&files, &mut rng)
+                    .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sequence(42), sequence(42));
+        assert_ne!(sequence(42), sequence(43));
+    }
+
+    #[test]
+    fn test_alphabetical_order_compares_numbers() {
+        let files: Vec<PathBuf> = ["img10.jpg", "img2.jpg", "img1.jpg", "a/img3.jpg"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let mut cursor = None;
+        let mut rng = rand::thread_rng();
+
+        let order: Vec<_> = (0..5)
+            .map(|_| {
+                Sequential {
+                    cursor: &mut cursor,
+                    by_modification_time: false,
+                }
+                .select(&files, &mut rng)
+                .unwrap()
+            })
+            .collect();
+
+        let expected: Vec<PathBuf> = [
+            "a/img3.jpg",
+            "img1.jpg",
+            "img2.jpg",
+            "img10.jpg",
+            "a/img3.jpg",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn test_recency_weight_decays() {
+        let hour = Duration::from_secs(3600);
+        assert_eq!(recency_weight(None, 10_000, hour), 1.0);
+        assert_eq!(
+            recency_weight(Some(10_000), 10_000, hour),
+            MIN_RECENCY_WEIGHT
+        );
+        assert_eq!(recency_weight(Some(10_000), 13_600, hour), 0.5);
+        assert_eq!(recency_weight(Some(10_000), 17_200, hour), 0.75);
+    }
+
+    #[test]
+    fn test_aspect_ratio_falls_back_to_orientation() {
+        let files: Vec<PathBuf> = ["wide", "hd", "portrait"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let dimensions = |file: &Path| match file.to_str() {
+            Some("wide") => Some((2100, 900)),
+            Some("hd") => Some((1920, 1080)),
+            Some("portrait") => Some((1080, 1920)),
+            _ => None,
+        };
+        let ratio = |text: &str| {
+            AspectRatio::try_from(AspectRatioDefinition::Text(text.to_owned())).unwrap()
+        };
+
+        let hd = prefer_aspect_ratio(files.clone(), ratio("16:9"), 0.1, dimensions);
+        assert_eq!(hd, vec![PathBuf::from("hd")]);
+
+        let ultrawide = prefer_aspect_ratio(files.clone(), ratio("32:9"), 0.1, dimensions);
+        assert_eq!(ultrawide, vec![PathBuf::from("wide"), PathBuf::from("hd")]);
+
+        let square = prefer_aspect_ratio(files.clone(), AspectRatio(0.5), 0.01, |_| None);
+        assert_eq!(square, files);
+    }
+}
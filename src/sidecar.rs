@@ -0,0 +1,110 @@
+//! Sidecar files next to images, recording what gallerica knows about them for other tools.
+
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::message_api::ImageStats;
+
+/// Toolkit name written to XMP sidecars, used to recognize sidecars created by gallerica.
+const XMP_TOOLKIT: &str = "gallerica";
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SidecarFormat {
+    /// Don't write sidecar files.
+    #[default]
+    Off,
+    /// Write `<image>.gallerica.json`.
+    Json,
+    /// Write an XMP sidecar `<image>.xmp`, as understood by most photo management tools.
+    /// Existing XMP sidecars that were not created by gallerica are left untouched.
+    Xmp,
+}
+
+#[derive(Serialize)]
+struct JsonSidecar {
+    /// RFC 3339 timestamp
+    last_shown: String,
+    times_shown: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rating: Option<u8>,
+}
+
+/// Suffixes of the files which belong to an image, the sidecars and the metadata file.
+pub const SUFFIXES: [&str; 3] = [".gallerica.json", ".xmp", crate::image_metadata::EXTENSION];
+
+/// Path of the sidecar file for `image`, i.e. the image path with `extension` appended.
+pub fn sidecar_path(image: &Path, extension: &str) -> PathBuf {
+    let mut path = OsString::from(image);
+    path.push(extension);
+    path.into()
+}
+
+/// Whether `path` is a sidecar file, which should not be shown as an image.
+pub fn is_sidecar(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+}
+
+fn timestamp(seconds: u64) -> String {
+    DateTime::<Utc>::from_timestamp(seconds as i64, 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Write the sidecar of `format` for the image described by `stats` and its `rating`.
+pub fn write(format: SidecarFormat, stats: &ImageStats, rating: Option<u8>) -> Result<()> {
+    match format {
+        SidecarFormat::Off => Ok(()),
+        SidecarFormat::Json => {
+            let content = JsonSidecar {
+                last_shown: timestamp(stats.last_shown),
+                times_shown: stats.times_shown,
+                rating,
+            };
+            let path = sidecar_path(&stats.path, ".gallerica.json");
+            std::fs::write(path, serde_json::to_vec_pretty(&content)?)?;
+            Ok(())
+        }
+        SidecarFormat::Xmp => {
+            let path = sidecar_path(&stats.path, ".xmp");
+            if let Ok(existing) = std::fs::read_to_string(&path) {
+                if !existing.contains(&format!("x:xmptk=\"{XMP_TOOLKIT}\"")) {
+                    bail!(
+                        "Not overwriting XMP sidecar '{}' created by another tool",
+                        path.display()
+                    );
+                }
+            }
+
+            let last_shown = timestamp(stats.last_shown);
+            let rating = rating
+                .map(|stars| format!("\n    xmp:Rating=\"{stars}\""))
+                .unwrap_or_default();
+            let content = format!(
+                r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/" x:xmptk="{XMP_TOOLKIT}">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:gallerica="https://github.com/texel-sensei/gallerica/ns/1.0/"
+    xmp:MetadataDate="{last_shown}"{rating}
+    gallerica:LastShown="{last_shown}"
+    gallerica:TimesShown="{times_shown}"/>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>
+"#,
+                times_shown = stats.times_shown,
+            );
+            std::fs::write(path, content)?;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::path::PathBuf;
+
+use tokio::time::{sleep, Duration, Instant};
+use tracing::warn;
+
+/// How often readiness conditions are checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Conditions that have to be fulfilled before the daemon starts, see `Configuration`.
+pub struct StartupConditions {
+    pub delay: Duration,
+    pub paths: Vec<PathBuf>,
+    pub network: bool,
+
+    /// Give up waiting for `paths` and `network` after this time.
+    pub timeout: Duration,
+}
+
+impl StartupConditions {
+    /// Wait until all conditions are fulfilled, or the timeout expires.
+    pub async fn wait(&self) {
+        sleep(self.delay).await;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let missing_paths: Vec<_> = self.paths.iter().filter(|p| !p.exists()).collect();
+            let network_missing = self.network && !network_online();
+
+            if missing_paths.is_empty() && !network_missing {
+                return;
+            }
+
+            if Instant::now() >= deadline {
+                for path in missing_paths {
+                    warn!("Path '{}' still missing, starting anyway", path.display());
+                }
+                if network_missing {
+                    warn!("Network still offline, starting anyway");
+                }
+                return;
+            }
+
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// Whether the system has a default route, which is a good indication that the network is up.
+#[cfg(target_os = "linux")]
+fn network_online() -> bool {
+    let has_default_route = |file, destination_column: usize, default: &str| {
+        std::fs::read_to_string(file).is_ok_and(|routes| {
+            routes.lines().any(|line| {
+                line.split_whitespace().nth(destination_column) == Some(default)
+                    // ignore routes via the loopback interface
+                    && !line.split_whitespace().any(|column| column == "lo")
+            })
+        })
+    };
+
+    has_default_route("/proc/net/route", 1, "00000000")
+        || has_default_route(
+            "/proc/net/ipv6_route",
+            0,
+            "00000000000000000000000000000000",
+        )
+}
+
+/// Without a portable way to check for connectivity, assume the network is always online.
+#[cfg(not(target_os = "linux"))]
+fn network_online() -> bool {
+    true
+}
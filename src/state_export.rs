@@ -0,0 +1,103 @@
+//! Portable snapshots of the curation data, see `Request::ExportState` and
+//! `Request::ImportState`, e.g. to move it to another machine.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    expand, gallery_file::contract_tilde, message_api::HistoryEntry, statistics::Statistics,
+};
+
+/// Format of the snapshot, see `Snapshot::version`.
+const VERSION: u64 = 1;
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Snapshot {
+    /// Format of the snapshot, newer ones are rejected
+    pub version: u64,
+
+    #[serde(default)]
+    pub ratings: HashMap<PathBuf, u8>,
+
+    #[serde(default)]
+    pub statistics: Statistics,
+
+    #[serde(default)]
+    pub quarantined: HashSet<PathBuf>,
+
+    #[serde(default)]
+    pub banned: HashSet<PathBuf>,
+
+    /// Shown images, oldest first
+    #[serde(default)]
+    pub history: Vec<HistoryEntry>,
+}
+
+impl Snapshot {
+    /// Replace every image path of the snapshot by `map(path)`.
+    fn map_paths(self, map: &impl Fn(PathBuf) -> Result<PathBuf>) -> Result<Self> {
+        Ok(Self {
+            version: self.version,
+            ratings: self
+                .ratings
+                .into_iter()
+                .map(|(path, rating)| Ok((map(path)?, rating)))
+                .collect::<Result<_>>()?,
+            statistics: self.statistics.map_paths(map)?,
+            quarantined: self
+                .quarantined
+                .into_iter()
+                .map(map)
+                .collect::<Result<_>>()?,
+            banned: self.banned.into_iter().map(map).collect::<Result<_>>()?,
+            history: self
+                .history
+                .into_iter()
+                .map(|entry| {
+                    Ok(HistoryEntry {
+                        path: map(entry.path)?,
+                        ..entry
+                    })
+                })
+                .collect::<Result<_>>()?,
+        })
+    }
+}
+
+/// Write `snapshot` to the file at `path`. Images inside the home directory are written relative
+/// to `~`, so they are found again if the user name differs on the other machine.
+pub fn export(snapshot: Snapshot, path: &Path) -> Result<()> {
+    let snapshot = Snapshot {
+        version: VERSION,
+        ..snapshot
+    }
+    .map_paths(&|path| Ok(contract_tilde(&path).into_owned()))?;
+    std::fs::write(path, serde_json::to_string_pretty(&snapshot)?)
+        .with_context(|| format!("Failed to write state snapshot '{}'", path.display()))
+}
+
+/// Read a snapshot from the file at `path`.
+pub fn import(path: &Path) -> Result<Snapshot> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read state snapshot '{}'", path.display()))?;
+    let snapshot: Snapshot = serde_json::from_str(&text)
+        .with_context(|| format!("Failed to parse state snapshot '{}'", path.display()))?;
+    if snapshot.version > VERSION {
+        bail!(
+            "State snapshot '{}' was written by a newer gallerica",
+            path.display()
+        );
+    }
+    snapshot.map_paths(&|path| {
+        Ok(match expand::path(&path)? {
+            Cow::Owned(expanded) => expanded,
+            Cow::Borrowed(_) => path,
+        })
+    })
+}
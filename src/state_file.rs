@@ -0,0 +1,254 @@
+//! Saving the persistent state without risking it, e.g. when gallerica crashes while writing it.
+//! The previous state is kept as a `.bak` file to fall back to. Writes happen in the background,
+//! so a slow disk doesn't stall the event loop. Also see `storage` for the SQLite backend.
+
+use std::{
+    ffi::OsStr,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::{
+    pin, select,
+    sync::{mpsc, oneshot},
+    time::{sleep, Instant},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    state_migration,
+    storage::{Database, Storage},
+};
+
+/// How long changes are collected before they are written, a burst of them results in one write.
+const DEBOUNCE: Duration = Duration::from_secs(1);
+
+fn with_suffix(path: &Path, suffix: impl AsRef<OsStr>) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Write `state` to a temporary file, then rename it to `path`. The previous file becomes the
+/// backup, so there always is a complete state to read.
+fn write(path: &Path, state: &[u8]) -> Result<()> {
+    let temporary = with_suffix(path, ".tmp");
+    let mut file = fs::File::create(&temporary)?;
+    file.write_all(state)?;
+    file.sync_all()?;
+    if path.exists() {
+        fs::rename(path, with_suffix(path, ".bak"))?;
+    }
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+enum Message {
+    Save(Storage, Value),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Saves the state in a background task, see `write` and `Database::save`.
+pub struct Writer {
+    sender: mpsc::UnboundedSender<Message>,
+}
+
+impl Writer {
+    /// Start the background task, must be called inside the runtime.
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(write_in_background(receiver));
+        Self { sender }
+    }
+
+    /// Save `state` to `storage` once changes stop for a moment, at most `DEBOUNCE` later.
+    pub fn save<T: Serialize>(&self, storage: Storage, state: &T) -> Result<()> {
+        let state = serde_json::to_value(state)?;
+        // The task only stops with the runtime
+        let _ = self.sender.send(Message::Save(storage, state));
+        Ok(())
+    }
+
+    /// Wait until the latest state was written, e.g. before exiting.
+    pub async fn flush(&self) {
+        let (done, written) = oneshot::channel();
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            let _ = written.await;
+        }
+    }
+}
+
+async fn write_in_background(mut receiver: mpsc::UnboundedReceiver<Message>) {
+    let mut pending = None;
+    let mut database = None;
+    let debounce = sleep(Duration::ZERO);
+    pin!(debounce);
+    loop {
+        select! {
+            message = receiver.recv() => match message {
+                Some(Message::Save(storage, state)) => {
+                    if pending.is_none() {
+                        debounce.as_mut().reset(Instant::now() + DEBOUNCE);
+                    }
+                    pending = Some((storage, state));
+                }
+                Some(Message::Flush(done)) => {
+                    write_pending(pending.take(), &mut database).await;
+                    let _ = done.send(());
+                }
+                None => {
+                    write_pending(pending.take(), &mut database).await;
+                    break;
+                }
+            },
+            () = &mut debounce, if pending.is_some() => {
+                write_pending(pending.take(), &mut database).await;
+            }
+        }
+    }
+}
+
+/// Write the `pending` state, keeping the `database` open for the next write.
+async fn write_pending(
+    pending: Option<(Storage, Value)>,
+    database: &mut Option<(PathBuf, Database)>,
+) {
+    let Some((storage, state)) = pending else {
+        return;
+    };
+    let mut open = database.take();
+    let result = tokio::task::spawn_blocking(move || {
+        let result = save(&storage, &state, &mut open);
+        (result, open)
+    })
+    .await;
+    match result {
+        Ok((Ok(()), open)) => *database = open,
+        Ok((Err(err), _)) => error!("Error persisting state: '{err:#}'"),
+        Err(err) => error!("Error persisting state: '{err}'"),
+    }
+}
+
+fn save(
+    storage: &Storage,
+    state: &Value,
+    database: &mut Option<(PathBuf, Database)>,
+) -> Result<()> {
+    match storage {
+        Storage::Json { path } => write(path, &serde_json::to_vec(state)?),
+        Storage::Sqlite { path } => {
+            let (_, database) = match database {
+                Some(open) if open.0 == *path => open,
+                _ => database.insert((path.clone(), Database::open(path)?)),
+            };
+            database.save(state)
+        }
+    }
+}
+
+/// Upgrade the `state` with `state_migration`, and deserialize it.
+fn parse<T: DeserializeOwned>(mut state: Value) -> Result<T> {
+    let version = state_migration::migrate(&mut state)?;
+    if version < state_migration::VERSION {
+        info!(
+            "Upgrading state from version {version} to {}",
+            state_migration::VERSION
+        );
+    }
+    Ok(serde_json::from_value(state)?)
+}
+
+fn read<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(err)
+                .with_context(|| format!("Failed to open state file '{}'", path.display()))
+        }
+    };
+    serde_json::from_str(&text)
+        .map_err(anyhow::Error::from)
+        .and_then(parse)
+        .map(Some)
+        .with_context(|| format!("Failed to parse state file '{}'", path.display()))
+}
+
+/// Read the state at `path`, or its backup if it is missing, and pass it to `apply`. A file
+/// which can't be parsed or is rejected by `apply` is moved to `.corrupt` for inspection, and
+/// the backup is used instead. Returns whether any state was applied.
+pub fn load<T: DeserializeOwned>(path: &Path, mut apply: impl FnMut(T) -> Result<()>) -> bool {
+    for (candidate, is_backup) in [(path.to_owned(), false), (with_suffix(path, ".bak"), true)] {
+        let result = read(&candidate).and_then(|state| {
+            state
+                .map(&mut apply)
+                .transpose()
+                .with_context(|| format!("Invalid state file '{}'", candidate.display()))
+        });
+        match result {
+            Ok(Some(())) => {
+                if is_backup {
+                    info!("Restored the state from its backup");
+                }
+                return true;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                warn!("Ignoring state: {err:#}");
+                if !is_backup {
+                    let _ = fs::rename(&candidate, with_suffix(path, ".corrupt"));
+                }
+            }
+        }
+    }
+    false
+}
+
+/// Read the state from the SQLite database at `path` and pass it to `apply`, see `load`.
+/// Unlike files, a database is never left half written, so there is no backup to fall back to.
+/// Returns whether any state was applied.
+pub fn load_database<T: DeserializeOwned>(
+    path: &Path,
+    apply: impl FnOnce(T) -> Result<()>,
+) -> Result<bool> {
+    let Some(state) = Database::open(path)?.load()? else {
+        return Ok(false);
+    };
+    parse(state)
+        .and_then(apply)
+        .with_context(|| format!("Invalid state in '{}'", path.display()))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_corrupt_state_falls_back_to_backup() {
+        let dir = TempDir::new("state");
+        let path = dir.join("state.json");
+
+        write(&path, br#"{"version": 1, "n": 1}"#).unwrap();
+        write(&path, br#"{"version": 1, "n": 2}"#).unwrap();
+        let mut loaded = vec![];
+        let mut apply = |state: serde_json::Value| {
+            loaded.push(state["n"].as_u64().unwrap());
+            Ok(())
+        };
+        assert!(load(&path, &mut apply));
+
+        fs::write(&path, "{\"truncat").unwrap();
+        assert!(load(&path, &mut apply));
+        let corrupt = fs::read_to_string(dir.join("state.json.corrupt")).unwrap();
+
+        assert_eq!(loaded, vec![2, 1]);
+        assert_eq!(corrupt, "{\"truncat");
+    }
+}
@@ -0,0 +1,65 @@
+//! Upgrades state files written by older versions, like `config_migration` does for the
+//! configuration.
+//!
+//! Migrations are applied to the JSON document before it is deserialized, so a changed field can
+//! be converted instead of discarding the whole state with the history and ratings in it.
+
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Value};
+
+/// Version of the state written by this build, see `PersistentState::version`.
+pub const VERSION: u64 = 2;
+
+type Migration = fn(&mut Map<String, Value>);
+
+/// The migration at index `n` upgrades a state from version `n` to `n + 1`.
+const MIGRATIONS: &[Migration] = &[
+    // States without a version, the format is otherwise the same
+    |_| {},
+    // The quarantine was a list of paths, which also contained images that are merely of an
+    // unsupported format. Its files are checked again.
+    |state| {
+        state.remove("quarantined");
+    },
+];
+
+const _: () = assert!(MIGRATIONS.len() as u64 == VERSION);
+
+/// Upgrade the `state` to the current `VERSION`, and return the version it had before.
+pub fn migrate(state: &mut Value) -> Result<u64> {
+    let state = state.as_object_mut().context("State is not an object")?;
+    let version = match state.get("version") {
+        None => 0,
+        Some(version) => version.as_u64().context("Invalid state version")?,
+    };
+    if version > VERSION {
+        bail!("State version {version} was written by a newer gallerica, expected {VERSION}");
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(state);
+    }
+    state.insert("version".to_owned(), VERSION.into());
+    Ok(version)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_states_are_upgraded_to_the_current_version() {
+        let mut state = serde_json::json!({ "current_gallery": "wallpapers" });
+        assert_eq!(migrate(&mut state).unwrap(), 0);
+        assert_eq!(state["version"], VERSION);
+        assert_eq!(state["current_gallery"], "wallpapers");
+
+        assert_eq!(migrate(&mut state).unwrap(), VERSION);
+
+        let mut quarantined = serde_json::json!({ "version": 1, "quarantined": ["/a.heic"] });
+        migrate(&mut quarantined).unwrap();
+        assert!(quarantined.get("quarantined").is_none());
+
+        let mut newer = serde_json::json!({ "version": VERSION + 1 });
+        assert!(migrate(&mut newer).is_err());
+    }
+}
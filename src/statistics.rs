@@ -0,0 +1,171 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::message_api::{GalleryStats, ImageStats};
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct GalleryCounter {
+    times_shown: u64,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+struct ImageCounter {
+    times_shown: u64,
+    last_shown: u64,
+}
+
+/// Display counts of galleries and images, accumulated across restarts.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct Statistics {
+    galleries: HashMap<String, GalleryCounter>,
+    images: HashMap<PathBuf, ImageCounter>,
+}
+
+impl Statistics {
+    /// Record that `image` was shown from `gallery`.
+    pub fn record(&mut self, gallery: &str, image: &Path) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        self.galleries
+            .entry(gallery.to_owned())
+            .or_default()
+            .times_shown += 1;
+
+        let image = self.images.entry(image.to_path_buf()).or_default();
+        image.times_shown += 1;
+        image.last_shown = now;
+    }
+
+    /// Statistics of a single image, if it was ever shown.
+    pub fn image(&self, path: &Path) -> Option<ImageStats> {
+        self.images.get(path).map(|counter| ImageStats {
+            path: path.to_path_buf(),
+            times_shown: counter.times_shown,
+            last_shown: counter.last_shown,
+        })
+    }
+
+    /// Time when `path` was last shown, in seconds since the UNIX epoch.
+    pub fn last_shown(&self, path: &Path) -> Option<u64> {
+        self.images.get(path).map(|counter| counter.last_shown)
+    }
+
+    /// Combine the counts of `other`, e.g. from another machine, keeping the larger count and
+    /// the later time of each. Merging the same statistics twice doesn't change them.
+    pub fn merge(&mut self, other: Statistics) {
+        for (name, counter) in other.galleries {
+            let merged = self.galleries.entry(name).or_default();
+            merged.times_shown = merged.times_shown.max(counter.times_shown);
+        }
+        for (path, counter) in other.images {
+            let merged = self.images.entry(path).or_default();
+            merged.times_shown = merged.times_shown.max(counter.times_shown);
+            merged.last_shown = merged.last_shown.max(counter.last_shown);
+        }
+    }
+
+    /// The same statistics, with each image path replaced by `map(path)`.
+    pub fn map_paths<E>(self, map: impl Fn(PathBuf) -> Result<PathBuf, E>) -> Result<Self, E> {
+        Ok(Self {
+            galleries: self.galleries,
+            images: self
+                .images
+                .into_iter()
+                .map(|(path, counter)| Ok((map(path)?, counter)))
+                .collect::<Result<_, E>>()?,
+        })
+    }
+
+    /// Build the report for `gallery`, which currently contains `files`.
+    /// Each list in the report contains at most `limit` images.
+    pub fn report(&self, gallery: &str, files: &[PathBuf], limit: usize) -> GalleryStats {
+        let mut shown = vec![];
+        let mut never_shown = vec![];
+        for path in files {
+            match self.image(path) {
+                Some(image) => shown.push(image),
+                None => never_shown.push(path.clone()),
+            }
+        }
+
+        let last_shown = shown.iter().max_by_key(|image| image.last_shown).cloned();
+
+        shown.sort_by(|a, b| {
+            b.times_shown
+                .cmp(&a.times_shown)
+                .then_with(|| a.path.cmp(&b.path))
+        });
+        shown.truncate(limit);
+
+        GalleryStats {
+            name: gallery.to_owned(),
+            times_shown: self
+                .galleries
+                .get(gallery)
+                .map_or(0, |counter| counter.times_shown),
+            number_images: files.len(),
+            most_shown: shown,
+            never_shown: never_shown.into_iter().take(limit).collect(),
+            last_shown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_report_orders_by_count() {
+        let files: Vec<PathBuf> = ["a", "b", "c"].iter().map(PathBuf::from).collect();
+
+        let mut statistics = Statistics::default();
+        statistics.record("gallery", &files[1]);
+        statistics.record("gallery", &files[1]);
+        statistics.record("gallery", &files[0]);
+        statistics.record("other", &files[2]);
+
+        let report = statistics.report("gallery", &files, 2);
+        assert_eq!(report.times_shown, 3);
+        assert_eq!(report.number_images, 3);
+
+        let most_shown: Vec<_> = report
+            .most_shown
+            .iter()
+            .map(|image| (image.path.clone(), image.times_shown))
+            .collect();
+        assert_eq!(
+            most_shown,
+            vec![(files[1].clone(), 2), (files[0].clone(), 1)]
+        );
+        assert!(report.never_shown.is_empty());
+    }
+
+    #[test]
+    fn test_merge_keeps_larger_counts() {
+        let (a, b) = (Path::new("a"), Path::new("b"));
+
+        let mut statistics = Statistics::default();
+        statistics.record("gallery", a);
+        let mut other = Statistics::default();
+        other.record("gallery", a);
+        other.record("gallery", a);
+        other.record("gallery", b);
+
+        statistics.merge(other.clone());
+        statistics.merge(other);
+
+        let times_shown = |path| statistics.image(path).map(|image| image.times_shown);
+        assert_eq!(times_shown(a), Some(2));
+        assert_eq!(times_shown(b), Some(1));
+        assert_eq!(statistics.galleries["gallery"].times_shown, 3);
+    }
+}
@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use crate::message_api::*;
+use async_trait::async_trait;
+use tokio::{
+    io::{stdin, stdout, AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    sync::Mutex,
+};
+
+type Output = Arc<Mutex<Box<dyn AsyncWrite + Send + Unpin>>>;
+
+/// Request read from a single line of stdin.
+/// The response is written as a single line to stdout.
+struct StdinRequest {
+    pub request: anyhow::Result<Request>,
+    output: Output,
+}
+
+#[async_trait]
+impl InflightRequest for StdinRequest {
+    fn request(&self) -> anyhow::Result<&Request> {
+        self.request
+            .as_ref()
+            .map_err(|e| anyhow::format_err!(e.to_string()))
+    }
+
+    async fn respond(self: Box<Self>, response: Response) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(&response)?;
+        line.push(b'\n');
+
+        // Locked for the whole line, so responses to concurrent requests don't interleave
+        let mut out = self.output.lock().await;
+        out.write_all(&line).await?;
+        out.flush().await?;
+        Ok(())
+    }
+
+    fn into_event_writer(self: Box<Self>) -> Result<EventWriter, Box<dyn InflightRequest>> {
+        Err(self)
+    }
+}
+
+/// Receives newline delimited JSON requests on stdin, answering them on stdout.
+/// Once stdin is closed, no further requests are received.
+pub struct StdinReceiver {
+    input: Box<dyn AsyncBufRead + Send + Unpin>,
+    output: Output,
+}
+
+impl StdinReceiver {
+    pub fn new() -> Self {
+        Self::with(BufReader::new(stdin()), stdout())
+    }
+
+    /// Read the requests from `input` and write the responses to `output` instead.
+    fn with(
+        input: impl AsyncBufRead + Send + Unpin + 'static,
+        output: impl AsyncWrite + Send + Unpin + 'static,
+    ) -> Self {
+        Self {
+            input: Box::new(input),
+            output: Arc::new(Mutex::new(Box::new(output))),
+        }
+    }
+}
+
+impl Default for StdinReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl MessageReceiver for StdinReceiver {
+    async fn receive_message(&mut self) -> anyhow::Result<Box<dyn InflightRequest>> {
+        loop {
+            let mut line = vec![];
+            if self.input.read_until(b'\n', &mut line).await? == 0 {
+                // stdin was closed, e.g. because the supervisor went away. Don't treat this as
+                // an error, other listeners may still be active.
+                return std::future::pending().await;
+            }
+
+            let request = match String::from_utf8(line) {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => serde_json::from_str(&line).map_err(|e| e.into()),
+                // Answered like other invalid requests, instead of stopping the listener
+                Err(err) => Err(err.into()),
+            };
+
+            return Ok(Box::new(StdinRequest {
+                request,
+                output: self.output.clone(),
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_requests_are_answered_line_by_line() {
+        let input: &[u8] =
+            b"{\"method\": \"Pause\"}\n\n  \nnot json\n\xff\xfe\n{\"method\": \"Resume\"}\n";
+        let (output, mut responses) = tokio::io::duplex(1024);
+        let mut receiver = StdinReceiver::with(input, output);
+
+        let pause = receiver.receive_message().await.unwrap();
+        assert!(matches!(pause.request(), Ok(Request::Pause)));
+        pause.respond(Response::Ok).await.unwrap();
+
+        // Blank lines are skipped
+        let invalid = receiver.receive_message().await.unwrap();
+        assert!(invalid.request().is_err());
+        invalid
+            .respond(Response::BadRequest {
+                message: "invalid".to_owned(),
+            })
+            .await
+            .unwrap();
+
+        // Invalid UTF-8 doesn't stop the listener
+        let binary = receiver.receive_message().await.unwrap();
+        assert!(binary.request().is_err());
+        let resume = receiver.receive_message().await.unwrap();
+        assert!(matches!(resume.request(), Ok(Request::Resume)));
+        // Unanswered requests keep stdout open
+        drop((binary, resume));
+
+        // A closed stdin isn't an error, there are just no more requests
+        let closed =
+            tokio::time::timeout(Duration::from_millis(50), receiver.receive_message()).await;
+        assert!(closed.is_err());
+
+        drop(receiver);
+        let mut written = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut responses, &mut written)
+            .await
+            .unwrap();
+        assert_eq!(
+            written,
+            "{\"type\":\"Ok\"}\n{\"type\":\"BadRequest\",\"message\":\"invalid\"}\n"
+        );
+    }
+}
@@ -0,0 +1,261 @@
+//! Where the persistent state is stored, see `Configuration::storage`.
+//!
+//! The SQLite backend keeps each entry of the state in its own row, e.g. the statistics of one
+//! image, and only writes the rows which changed. It also contains the history, so one file
+//! holds all data worth keeping around.
+
+use std::{
+    collections::HashMap,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use crate::{expand, message_api::HistoryEntry};
+
+/// See `Configuration::storage`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Storage {
+    /// A JSON file, which is rewritten on every change
+    Json { path: PathBuf },
+    /// An SQLite database, which also stores the history
+    Sqlite { path: PathBuf },
+}
+
+impl Storage {
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::Json { path } | Self::Sqlite { path } => path,
+        }
+    }
+
+    /// The same storage, with `~` and variables in the path expanded, and a relative path
+    /// interpreted relative to `base`.
+    pub fn relative_to(&self, base: &Path) -> Result<Self> {
+        Ok(match self {
+            Self::Json { path } => Self::Json {
+                path: base.join(expand::path(path)?),
+            },
+            Self::Sqlite { path } => Self::Sqlite {
+                path: base.join(expand::path(path)?),
+            },
+        })
+    }
+}
+
+/// Objects are split into rows down to this depth, e.g. `statistics`, `images` and the path of
+/// one image. Deeper values are stored as JSON.
+const MAX_DEPTH: usize = 3;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS state (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS history (
+        id INTEGER PRIMARY KEY,
+        path BLOB NOT NULL,
+        gallery TEXT NOT NULL,
+        shown INTEGER NOT NULL,
+        trigger TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS history_shown ON history (shown);
+";
+
+/// An SQLite database with the state and history.
+pub struct Database {
+    db: Connection,
+    /// Content of the `state` table, to find the rows which changed
+    saved: HashMap<String, String>,
+}
+
+/// Split `value` into rows keyed by the keys leading to them, as a JSON array.
+fn flatten(path: &mut Vec<String>, value: &Value, rows: &mut HashMap<String, String>) {
+    match value {
+        Value::Object(object) if path.len() < MAX_DEPTH && !object.is_empty() => {
+            for (key, value) in object {
+                path.push(key.clone());
+                flatten(path, value, rows);
+                path.pop();
+            }
+        }
+        _ => {
+            rows.insert(Value::from(path.clone()).to_string(), value.to_string());
+        }
+    }
+}
+
+/// Reassemble the rows produced by `flatten`.
+fn unflatten(rows: &HashMap<String, String>) -> Result<Value> {
+    let mut state = Value::Object(Map::new());
+    for (key, value) in rows {
+        let path: Vec<String> = serde_json::from_str(key)
+            .with_context(|| format!("Invalid key '{key}' in the state database"))?;
+        let value: Value = serde_json::from_str(value)
+            .with_context(|| format!("Invalid value of '{key}' in the state database"))?;
+        let Some((last, parents)) = path.split_last() else {
+            continue;
+        };
+        let mut object = &mut state;
+        for parent in parents {
+            object = object
+                .as_object_mut()
+                .context("Conflicting keys in the state database")?
+                .entry(parent.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+        }
+        object
+            .as_object_mut()
+            .context("Conflicting keys in the state database")?
+            .insert(last.clone(), value);
+    }
+    Ok(state)
+}
+
+/// `value` in the range of an SQLite integer.
+fn sql_integer(value: u64) -> i64 {
+    value.try_into().unwrap_or(i64::MAX)
+}
+
+impl Database {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = Connection::open(path)
+            .with_context(|| format!("Failed to open state database '{}'", path.display()))?;
+        db.pragma_update(None, "journal_mode", "WAL")?;
+        db.execute_batch(SCHEMA)
+            .with_context(|| format!("Failed to initialize '{}'", path.display()))?;
+        let saved = db
+            .prepare("SELECT key, value FROM state")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Self { db, saved })
+    }
+
+    /// The stored state, or None if there is none yet.
+    pub fn load(&self) -> Result<Option<Value>> {
+        if self.saved.is_empty() {
+            return Ok(None);
+        }
+        unflatten(&self.saved).map(Some)
+    }
+
+    /// Store `state`, only writing the rows which changed since the last save.
+    pub fn save(&mut self, state: &Value) -> Result<()> {
+        let mut rows = HashMap::new();
+        flatten(&mut vec![], state, &mut rows);
+
+        let transaction = self.db.transaction()?;
+        {
+            let mut upsert = transaction.prepare_cached(
+                "INSERT INTO state (key, value) VALUES (?1, ?2)
+                 ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            )?;
+            let mut delete = transaction.prepare_cached("DELETE FROM state WHERE key = ?1")?;
+            for (key, value) in &rows {
+                if self.saved.get(key) != Some(value) {
+                    upsert.execute(params![key, value])?;
+                }
+            }
+            for key in self.saved.keys() {
+                if !rows.contains_key(key) {
+                    delete.execute(params![key])?;
+                }
+            }
+        }
+        transaction.commit()?;
+        self.saved = rows;
+        Ok(())
+    }
+
+    /// Add `entry` to the history, and remove entries beyond `max_entries` or older than
+    /// `max_age` seconds.
+    pub fn append_history(
+        &self,
+        entry: &HistoryEntry,
+        max_entries: usize,
+        max_age: u64,
+    ) -> Result<()> {
+        self.db.execute(
+            "INSERT INTO history (path, gallery, shown, trigger) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                entry.path.as_os_str().as_bytes(),
+                entry.gallery,
+                sql_integer(entry.shown),
+                serde_json::to_string(&entry.trigger)?,
+            ],
+        )?;
+        self.db.execute(
+            "DELETE FROM history WHERE shown < ?1
+                OR id <= (SELECT MAX(id) FROM history) - ?2",
+            params![
+                sql_integer(entry.shown.saturating_sub(max_age)),
+                sql_integer(max_entries as u64),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The `limit` most recent history entries, optionally only of `gallery`, newest first.
+    pub fn query_history(&self, gallery: Option<&str>, limit: usize) -> Result<Vec<HistoryEntry>> {
+        let mut query = self.db.prepare(
+            "SELECT path, gallery, shown, trigger FROM history
+             WHERE ?1 IS NULL OR gallery = ?1
+             ORDER BY shown DESC, id DESC LIMIT ?2",
+        )?;
+        let rows = query.query_map(params![gallery, sql_integer(limit as u64)], |row| {
+            let path: Vec<u8> = row.get(0)?;
+            let shown: i64 = row.get(2)?;
+            let trigger: String = row.get(3)?;
+            Ok((path, row.get(1)?, shown, trigger))
+        })?;
+        let mut entries = vec![];
+        for row in rows {
+            let (path, gallery, shown, trigger) = row?;
+            entries.push(HistoryEntry {
+                path: PathBuf::from(std::ffi::OsStr::from_bytes(&path)),
+                gallery,
+                shown: shown.try_into().unwrap_or_default(),
+                trigger: serde_json::from_str(&trigger)?,
+            });
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_state_is_stored_in_rows() {
+        let dir = TempDir::new("storage");
+        let path = dir.join("state.sqlite");
+
+        let state = serde_json::json!({
+            "current_gallery": "wallpapers",
+            "ratings": {},
+            "statistics": {
+                "images": { "/a.png": { "times_shown": 2, "last_shown": 1 } },
+            },
+        });
+        let mut database = Database::open(&path).unwrap();
+        assert!(database.load().unwrap().is_none());
+        database.save(&state).unwrap();
+        let mut changed = state.clone();
+        changed["current_gallery"] = Value::Null;
+        database.save(&changed).unwrap();
+        let rows = database.saved.len();
+        drop(database);
+
+        let loaded = Database::open(&path).unwrap().load().unwrap();
+
+        assert_eq!(rows, 3);
+        assert_eq!(loaded, Some(changed));
+    }
+}
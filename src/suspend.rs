@@ -0,0 +1,71 @@
+//! Detecting when the machine resumes from suspend, which the timers don't notice because
+//! their monotonic clock stops while suspended.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Deserialize;
+use tokio::time::{Interval, MissedTickBehavior};
+
+/// How often the clocks are compared.
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Shortest difference between the clocks that counts as a suspend, to ignore small adjustments
+/// of the wall clock.
+const MIN_SUSPEND: Duration = Duration::from_secs(30);
+
+/// See `Configuration::on_resume`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ResumeBehavior {
+    /// Continue the interval where it was before the suspend.
+    #[default]
+    Ignore,
+    /// Show the next image right away, and start a new interval.
+    Update,
+    /// Start a new interval without changing the image.
+    Reset,
+}
+
+/// Notices a suspend by the wall clock advancing further than the monotonic clock.
+pub struct SuspendMonitor {
+    behavior: ResumeBehavior,
+    interval: Option<Interval>,
+    last_check: (Instant, SystemTime),
+}
+
+impl SuspendMonitor {
+    pub fn new(behavior: ResumeBehavior) -> Self {
+        Self {
+            behavior,
+            interval: None,
+            last_check: (Instant::now(), SystemTime::now()),
+        }
+    }
+
+    pub fn behavior(&self) -> ResumeBehavior {
+        self.behavior
+    }
+
+    /// Wait until the machine resumed from a suspend, and return roughly how long it slept.
+    pub async fn resumed(&mut self) -> Duration {
+        loop {
+            self.interval
+                .get_or_insert_with(|| {
+                    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+                    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+                    interval
+                })
+                .tick()
+                .await;
+            let now = (Instant::now(), SystemTime::now());
+            let (monotonic, wall) = std::mem::replace(&mut self.last_check, now);
+            // A wall clock that was set back doesn't count as a suspend
+            let suspended = now.1.duration_since(wall).map_or(Duration::ZERO, |wall| {
+                wall.saturating_sub(now.0 - monotonic)
+            });
+            if suspended >= MIN_SUSPEND {
+                return suspended;
+            }
+        }
+    }
+}
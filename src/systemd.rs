@@ -0,0 +1,116 @@
+//! Notifications of the systemd service manager, for units with `Type=notify`: the daemon reports
+//! when it is ready and when it stops, and pings the watchdog from its main loop, so systemd
+//! notices failed starts and hung daemons.
+
+use std::{
+    os::{linux::net::SocketAddrExt, unix::net::SocketAddr, unix::net::UnixDatagram},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tokio::time::{Interval, MissedTickBehavior};
+use tracing::warn;
+
+pub struct Notifier {
+    socket: UnixDatagram,
+    /// Pings the watchdog, if it is enabled for this process
+    watchdog: Option<Interval>,
+}
+
+/// The address of the `NOTIFY_SOCKET`, which is in the abstract namespace if it starts with `@`.
+fn address(socket: &str) -> Result<SocketAddr> {
+    let address = match socket.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name),
+        None => SocketAddr::from_pathname(socket),
+    };
+    address.with_context(|| format!("Invalid NOTIFY_SOCKET '{socket}'"))
+}
+
+/// Time between pings of the watchdog, half of its timeout `WATCHDOG_USEC`. It only applies to
+/// this process, if `WATCHDOG_PID` is set to another one it's meant for a parent.
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if pid.is_some_and(|pid| pid.parse() != Ok(std::process::id())) {
+        return None;
+    }
+    let usec: u64 = usec?.parse().ok().filter(|usec| *usec > 0)?;
+    Some(Duration::from_micros(usec) / 2)
+}
+
+impl Notifier {
+    /// Connect to the service manager which started the daemon, if any.
+    pub fn from_environment() -> Option<Self> {
+        let socket = std::env::var("NOTIFY_SOCKET").ok()?;
+        let connect = || -> Result<UnixDatagram> {
+            let socket_address = address(&socket)?;
+            let datagram = UnixDatagram::unbound()?;
+            datagram
+                .connect_addr(&socket_address)
+                .with_context(|| format!("Failed to connect to NOTIFY_SOCKET '{socket}'"))?;
+            Ok(datagram)
+        };
+        let socket = connect()
+            .map_err(|err| warn!("Not notifying systemd: {err:#}"))
+            .ok()?;
+
+        let watchdog = watchdog_interval(
+            std::env::var("WATCHDOG_USEC").ok().as_deref(),
+            std::env::var("WATCHDOG_PID").ok().as_deref(),
+        )
+        .map(|period| {
+            let mut interval = tokio::time::interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        Some(Self { socket, watchdog })
+    }
+
+    fn notify(&self, state: &str) {
+        if let Err(err) = self.socket.send(state.as_bytes()) {
+            warn!("Failed to notify systemd of {state}: {err}");
+        }
+    }
+
+    /// The daemon started, and its listeners accept requests.
+    pub fn ready(&self) {
+        self.notify("READY=1");
+    }
+
+    pub fn stopping(&self) {
+        self.notify("STOPPING=1");
+    }
+
+    /// Wait until the watchdog needs the next ping, forever if it isn't enabled.
+    pub async fn watchdog_due(&mut self) {
+        match &mut self.watchdog {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    pub fn ping_watchdog(&self) {
+        self.notify("WATCHDOG=1");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_watchdog_applies_to_this_process() {
+        let own = std::process::id().to_string();
+        assert_eq!(
+            watchdog_interval(Some("10000000"), None),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            watchdog_interval(Some("10000000"), Some(&own)),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(watchdog_interval(Some("10000000"), Some("1")), None);
+        assert_eq!(watchdog_interval(Some("0"), None), None);
+        assert_eq!(watchdog_interval(None, None), None);
+    }
+}
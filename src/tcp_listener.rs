@@ -0,0 +1,216 @@
+use std::time::Duration;
+
+use crate::message_api::*;
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use gallerica::transport::{DEFAULT_TCP_ADDRESS, TOKEN_FIELD};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tracing::warn;
+
+/// Clients have this long to send their request, so idle connections don't pile up.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Requests are small, anything larger than this is cut off and fails to parse.
+const MAX_REQUEST_SIZE: u64 = 1 << 20;
+
+/// Time to wait after failing to accept a connection, e.g. because there are too many open files.
+const ACCEPT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+fn default_address() -> String {
+    DEFAULT_TCP_ADDRESS.to_owned()
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TcpListenerConfig {
+    /// Address to listen on, e.g. "127.0.0.1:7253".
+    #[serde(default = "default_address")]
+    pub address: String,
+
+    /// Secret which clients have to send along with each request, e.g. with the `--token` option
+    /// of gallerica-cli. Required unless the address is a loopback address, as anyone who can
+    /// reach the address could control the daemon otherwise.
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+struct TcpRequest {
+    pub request: anyhow::Result<Request>,
+    pub stream: TcpStream,
+}
+
+#[async_trait]
+impl InflightRequest for TcpRequest {
+    fn request(&self) -> anyhow::Result<&Request> {
+        self.request
+            .as_ref()
+            .map_err(|e| anyhow::format_err!(e.to_string()))
+    }
+
+    async fn respond(mut self: Box<Self>, response: Response) -> anyhow::Result<()> {
+        self.stream.writable().await?;
+        self.stream
+            .write_all(&serde_json::to_vec(&response)?)
+            .await?;
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
+    fn into_event_writer(self: Box<Self>) -> Result<EventWriter, Box<dyn InflightRequest>> {
+        Ok(Box::new(self.stream))
+    }
+}
+
+/// Accepts connections in the background, reading the request of each connection in its own task
+/// so slow clients don't hold up the others.
+pub struct TcpReceiver {
+    requests: mpsc::Receiver<TcpRequest>,
+    accept_task: JoinHandle<()>,
+}
+
+impl TcpReceiver {
+    pub async fn new(config: &TcpListenerConfig) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(&config.address)
+            .await
+            .with_context(|| format!("Failed to listen on TCP address '{}'", config.address))?;
+        if config.token.is_none() && !listener.local_addr()?.ip().is_loopback() {
+            bail!(
+                "The TCP listener on '{}' is reachable from other machines and needs a `token`",
+                config.address
+            );
+        }
+        Ok(Self::listen(listener, config.token.clone()))
+    }
+
+    /// Receive the requests of clients connecting to `listener`.
+    fn listen(listener: TcpListener, token: Option<String>) -> Self {
+        let (sender, requests) = mpsc::channel(16);
+        let accept_task = tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _addr)) => stream,
+                    Err(err) => {
+                        warn!("Failed to accept a TCP connection: {err}");
+                        tokio::time::sleep(ACCEPT_RETRY_INTERVAL).await;
+                        continue;
+                    }
+                };
+                let sender = sender.clone();
+                let token = token.clone();
+                tokio::spawn(async move {
+                    match read_request(stream, token.as_deref()).await {
+                        Ok(request) => {
+                            let _ = sender.send(request).await;
+                        }
+                        Err(err) => warn!("Failed to read a TCP request: {err:#}"),
+                    }
+                });
+            }
+        });
+
+        Self {
+            requests,
+            accept_task,
+        }
+    }
+}
+
+impl Drop for TcpReceiver {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+/// Read the request sent on `stream`, which has to carry `token` if there is one.
+async fn read_request(mut stream: TcpStream, token: Option<&str>) -> anyhow::Result<TcpRequest> {
+    let mut buf = vec![];
+    tokio::time::timeout(
+        REQUEST_TIMEOUT,
+        (&mut stream).take(MAX_REQUEST_SIZE).read_to_end(&mut buf),
+    )
+    .await
+    .context("The client sent no request in time")??;
+    Ok(TcpRequest {
+        request: parse(&buf, token),
+        stream,
+    })
+}
+
+/// The request in `buf`, if it carries `token` and may be sent over the network.
+fn parse(buf: &[u8], token: Option<&str>) -> anyhow::Result<Request> {
+    let mut request: serde_json::Value = serde_json::from_slice(buf)?;
+    let sent = request
+        .as_object_mut()
+        .and_then(|request| request.remove(TOKEN_FIELD));
+    if let Some(token) = token {
+        let sent = sent
+            .as_ref()
+            .and_then(|sent| sent.as_str())
+            .unwrap_or_default();
+        // Hashes compare in constant time, so the token can't be guessed by timing
+        if blake3::hash(sent.as_bytes()) != blake3::hash(token.as_bytes()) {
+            bail!("Missing or wrong token");
+        }
+    }
+    let request: Request = serde_json::from_value(request)?;
+    request.check_remote()?;
+    Ok(request)
+}
+
+#[async_trait]
+impl MessageReceiver for TcpReceiver {
+    async fn receive_message(&mut self) -> anyhow::Result<Box<dyn InflightRequest>> {
+        match self.requests.recv().await {
+            Some(request) => Ok(Box::new(request)),
+            None => bail!("The TCP listener stopped accepting connections"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_requests_need_the_token() {
+        let token = Some("secret");
+        assert!(parse(br#"{"method": "Pause", "token": "secret"}"#, token).is_ok());
+        assert!(parse(br#"{"method": "Pause", "token": "guess"}"#, token).is_err());
+        assert!(parse(br#"{"method": "Pause"}"#, token).is_err());
+        assert!(parse(br#"{"method": "Pause"}"#, None).is_ok());
+
+        let export = br#"{"method": "ExportState", "path": "/tmp/x", "token": "secret"}"#;
+        assert!(parse(export, token).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_idle_connections_dont_block_others() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut receiver = TcpReceiver::listen(listener, None);
+
+        let _idle = TcpStream::connect(address).await.unwrap();
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(br#"{"method": "Pause"}"#).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let request = tokio::time::timeout(Duration::from_secs(5), receiver.receive_message())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(request.request(), Ok(Request::Pause)));
+    }
+
+    #[tokio::test]
+    async fn test_other_machines_need_a_token() {
+        let config = TcpListenerConfig {
+            address: "0.0.0.0:0".to_owned(),
+            token: None,
+        };
+        assert!(TcpReceiver::new(&config).await.is_err());
+    }
+}
@@ -0,0 +1,271 @@
+//! Command lines with placeholders like `{image}`, which are filled in on each update.
+
+use std::{
+    ffi::{OsStr, OsString},
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::expand;
+
+/// Values of the placeholders for one run of a command.
+pub struct Values<'a> {
+    /// `{image}`, with `{image_dir}` and `{basename}` taken from it
+    pub image: &'a Path,
+    /// `{gallery}`, the gallery the image was selected from
+    pub gallery: &'a str,
+    /// `{monitor}`, the name of the output, empty without outputs
+    pub monitor: &'a str,
+    /// `{index}`, the position of the output in `outputs`, starting at 0
+    pub index: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Image,
+    ImageDir,
+    Basename,
+    Gallery,
+    Monitor,
+    Index,
+}
+
+impl Placeholder {
+    const ALL: [Self; 6] = [
+        Self::Image,
+        Self::ImageDir,
+        Self::Basename,
+        Self::Gallery,
+        Self::Monitor,
+        Self::Index,
+    ];
+
+    /// Environment variable with the same value, e.g. `GALLERICA_IMAGE`.
+    fn variable(self) -> &'static str {
+        match self {
+            Self::Image => "GALLERICA_IMAGE",
+            Self::ImageDir => "GALLERICA_IMAGE_DIR",
+            Self::Basename => "GALLERICA_BASENAME",
+            Self::Gallery => "GALLERICA_GALLERY",
+            Self::Monitor => "GALLERICA_MONITOR",
+            Self::Index => "GALLERICA_INDEX",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "image" => Self::Image,
+            "image_dir" => Self::ImageDir,
+            "basename" => Self::Basename,
+            "gallery" => Self::Gallery,
+            "monitor" => Self::Monitor,
+            "index" => Self::Index,
+            _ => return None,
+        })
+    }
+
+    fn value(self, values: &Values) -> OsString {
+        let image = values.image;
+        match self {
+            Self::Image => image.into(),
+            Self::ImageDir => image.parent().unwrap_or(Path::new("")).into(),
+            Self::Basename => image.file_name().unwrap_or_default().into(),
+            Self::Gallery => values.gallery.into(),
+            Self::Monitor => values.monitor.into(),
+            Self::Index => values.index.to_string().into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// A single word of a command line, e.g. `--file={image}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Word {
+    parts: Vec<Part>,
+}
+
+impl Word {
+    /// Parse the placeholders in `text`, and expand a leading `~` and environment variables like
+    /// `${HOME}`, see `expand::variable`. Literal braces are written as `{{` and `}}`.
+    fn parse(text: &str) -> Result<Self> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let text = expand::tilde(text)?;
+        let text = text.as_ref();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '$' if chars.as_str().starts_with('{') && !chars.as_str().starts_with("{{") => {
+                    let rest = &chars.as_str()[1..];
+                    let Some(end) = rest.find('}') else {
+                        bail!("Unclosed '${{' in '{text}', write '${{{{' for a literal '${{'");
+                    };
+                    literal += &expand::variable(&rest[..end])?;
+                    chars = rest[end + 1..].chars();
+                }
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let Some(end) = rest.find('}') else {
+                        bail!("Unclosed '{{' in '{text}', write '{{{{' for a literal brace");
+                    };
+                    let name = &rest[..end];
+                    let Some(placeholder) = Placeholder::parse(name) else {
+                        bail!(
+                            "Unknown placeholder '{{{name}}}' in '{text}', use one of {{image}}, \
+                             {{image_dir}}, {{basename}}, {{gallery}}, {{monitor}} or {{index}}"
+                        );
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder(placeholder));
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => bail!("Unmatched '}}' in '{text}', write '}}}}' for a literal brace"),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Self { parts })
+    }
+
+    fn expand(&self, values: &Values) -> OsString {
+        let mut word = OsString::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => word.push(text),
+                Part::Placeholder(placeholder) => word.push(placeholder.value(values)),
+            }
+        }
+        word
+    }
+}
+
+/// A program with its arguments, like `feh --bg-fill {image}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandLine {
+    program: OsString,
+    args: Vec<Word>,
+}
+
+impl CommandLine {
+    /// Parse a command line whose words are separated by whitespace, and quoted like in a shell,
+    /// e.g. `swaybg -o "DP 1" -i '{image}'`. Placeholders are also replaced within quotes, and
+    /// `~` and variables like `${HOME}` are expanded when parsing.
+    pub fn parse(text: &str) -> Result<Self> {
+        let words = shell_words::split(text).with_context(|| format!("Can't split '{text}'"))?;
+        let mut words = words.iter();
+        let Some(program) = words.next() else {
+            bail!("Need a command");
+        };
+        Ok(Self {
+            program: expand::path(Path::new(program))?.into_owned().into(),
+            args: words.map(|word| Word::parse(word)).collect::<Result<_>>()?,
+        })
+    }
+
+    /// The command with the placeholders replaced by `values`. The values are also passed as
+    /// environment variables like `GALLERICA_IMAGE`, so scripts don't need to parse arguments.
+    pub fn command(&self, values: &Values) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(self.args.iter().map(|arg| arg.expand(values)));
+        for placeholder in Placeholder::ALL {
+            cmd.env(placeholder.variable(), placeholder.value(values));
+        }
+        cmd
+    }
+
+    pub fn program(&self) -> &OsStr {
+        &self.program
+    }
+
+    /// Whether any argument contains the `{image}` placeholder.
+    pub fn uses_image(&self) -> bool {
+        self.args
+            .iter()
+            .any(|arg| arg.parts.contains(&Part::Placeholder(Placeholder::Image)))
+    }
+}
+
+/// One command line, or a list of them which run for each image, see `Configuration::command_line`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum CommandLines {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl CommandLines {
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        let lines = match self {
+            Self::One(line) => std::slice::from_ref(line),
+            Self::Many(lines) => lines.as_slice(),
+        };
+        lines.iter().map(String::as_str)
+    }
+
+    /// Parse each command line, see `CommandLine::parse`.
+    pub fn parse(&self) -> Result<Vec<CommandLine>> {
+        let commands = self
+            .iter()
+            .map(|line| {
+                CommandLine::parse(line).with_context(|| format!("Invalid command '{line}'"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if commands.is_empty() {
+            bail!("Need a command");
+        }
+        Ok(commands)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_placeholders_are_replaced() {
+        let command =
+            CommandLine::parse("show --file={image} {image_dir}/{{x}} {basename}:{index}").unwrap();
+        let values = Values {
+            image: Path::new("/images/cat.jpg"),
+            gallery: "pets",
+            monitor: "DP-1",
+            index: 1,
+        };
+        let args: Vec<_> = command.args.iter().map(|arg| arg.expand(&values)).collect();
+        assert_eq!(args, ["--file=/images/cat.jpg", "/images/{x}", "cat.jpg:1"]);
+
+        let command = CommandLine::parse(r#"show -o "DP 1" 'it''s' a\ b"#).unwrap();
+        let args: Vec<_> = command.args.iter().map(|arg| arg.expand(&values)).collect();
+        assert_eq!(args, ["-o", "DP 1", "its", "a b"]);
+
+        let command = CommandLine::parse("show ${GALLERICA_TEST_UNSET:-x}{index} ${{x}}").unwrap();
+        let args: Vec<_> = command.args.iter().map(|arg| arg.expand(&values)).collect();
+        assert_eq!(args, ["x1", "${x}"]);
+
+        assert!(CommandLine::parse("show {imgae}").is_err());
+        assert!(CommandLine::parse("show 'unclosed").is_err());
+        assert!(CommandLine::parse("show {image").is_err());
+        assert!(CommandLine::parse("show image}").is_err());
+        assert!(CommandLine::parse("show ${GALLERICA_TEST_UNSET}").is_err());
+    }
+}
@@ -0,0 +1,35 @@
+//! Fixtures shared by the tests of several modules.
+
+use std::{
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+/// An empty directory for the files of a test, removed with its content when dropped, also if
+/// the test fails.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// The directory is named after `name` and the process, so neither tests running in parallel
+    /// nor concurrent test runs share files. Leftovers of an earlier run are removed.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("gallerica-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
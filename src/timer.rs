@@ -1,13 +1,42 @@
+use chrono::{DateTime, Local};
+use croner::Cron;
+use serde::Deserialize;
 use tokio::time::{self, sleep, Duration, Instant, Interval};
+use tracing::warn;
+
+/// Longest sleep of a `CronTimer`, so that a changed clock or a suspended machine delays a tick by
+/// at most this long.
+const MAX_CRON_SLEEP: Duration = Duration::from_secs(60);
 
 pub enum TickResult {
     Completed,
     Paused,
 }
 
+/// See `Configuration::interval_ramp`.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct IntervalRamp {
+    /// Time until the first update after the start.
+    pub start_ms: u64,
+
+    /// Factor by which the time between two updates grows after each update.
+    #[serde(default = "default_ramp_factor")]
+    pub factor: f64,
+}
+
+fn default_ramp_factor() -> f64 {
+    1.5
+}
+
 pub struct PausableInterval {
     delay: Interval,
 
+    /// Time between two ticks, once fully ramped up.
+    period: Duration,
+    ramp: Option<IntervalRamp>,
+    /// Time until the tick after the next one while ramping up.
+    ramped: Duration,
+
     is_paused: bool,
 
     already_expired: Option<Duration>,
@@ -21,32 +50,125 @@ impl PausableInterval {
 
         Self {
             delay,
+            period: interval,
+            ramp: None,
+            ramped: interval,
             is_paused: false,
             already_expired: None,
             last_interaction: Instant::now(),
         }
     }
 
+    /// An interval which starts with `ramp.start_ms` between two ticks, growing up to `interval`.
+    /// The first tick completes immediately.
+    pub fn ramped(interval: Duration, ramp: IntervalRamp) -> Self {
+        let first = Duration::from_millis(ramp.start_ms).min(interval);
+        Self {
+            period: interval,
+            ramp: Some(ramp),
+            ..Self::new(first)
+        }
+    }
+
     pub async fn tick(&mut self) -> TickResult {
         if self.is_paused() {
             return TickResult::Paused;
         }
 
-        if let Some(mut expired) = self.already_expired.take() {
-            expired += Instant::now() - self.last_interaction;
+        // Nothing is changed before the sleep completes, in case this is cancelled
+        if let Some(expired) = self.already_expired {
+            let expired = expired + (Instant::now() - self.last_interaction);
 
             let duration = self.delay.period().saturating_sub(expired);
             sleep(duration).await;
+            self.already_expired = None;
             self.delay.reset();
         } else {
             self.delay.tick().await;
         }
 
         self.last_interaction = Instant::now();
+        if self.ramp.is_some() {
+            self.ramp_up();
+        }
 
         TickResult::Completed
     }
 
+    /// Time since the last tick, not counting time while paused.
+    pub fn elapsed(&self) -> Duration {
+        let expired = self.already_expired.unwrap_or_default();
+        match self.is_paused {
+            true => expired,
+            false => expired + (Instant::now() - self.last_interaction),
+        }
+    }
+
+    /// Continue an interval of which `elapsed` already passed, e.g. before a restart.
+    /// This replaces a pending immediate tick of a new interval.
+    pub fn resume_from(&mut self, elapsed: Duration) {
+        self.already_expired = Some(elapsed);
+        self.last_interaction = Instant::now();
+    }
+
+    /// Time between two ticks, once fully ramped up.
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Time until the next tick, not counting time while paused.
+    pub fn remaining(&self) -> Duration {
+        self.delay.period().saturating_sub(self.elapsed())
+    }
+
+    /// Change the period, keeping the time that already elapsed since the last tick.
+    /// If that's longer than the new period, the next tick happens immediately.
+    /// A ramp continues where it is, but doesn't grow beyond the new period.
+    pub fn set_period(&mut self, period: Duration) {
+        let elapsed = self.elapsed();
+        self.period = period;
+        let current = match self.ramp {
+            Some(_) => {
+                self.ramped = self.ramped.min(period);
+                self.delay.period().min(period)
+            }
+            None => period,
+        };
+        self.restart(current);
+        self.resume_from(elapsed);
+    }
+
+    /// Start ramping up from the beginning, the next tick happens after the shortest period.
+    /// Like `reset` for intervals without a ramp.
+    pub fn restart_ramp(&mut self) {
+        match self.ramp {
+            Some(ramp) => {
+                self.ramped = Duration::from_millis(ramp.start_ms).min(self.period);
+                self.ramp_up();
+            }
+            None => self.reset(),
+        }
+    }
+
+    /// Wait the ramped period until the next tick, and lengthen it for the tick after.
+    fn ramp_up(&mut self) {
+        let Some(ramp) = self.ramp else {
+            return;
+        };
+        self.restart(self.ramped);
+        self.ramped = self.ramped.mul_f64(ramp.factor).min(self.period);
+    }
+
+    /// Tick every `period`, starting one period from now.
+    fn restart(&mut self, period: Duration) {
+        let now = Instant::now();
+        self.delay = time::interval_at(now + period, period);
+        self.delay
+            .set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        self.already_expired = None;
+        self.last_interaction = now;
+    }
+
     /// Return whether this intervall is currently paused or not.
     pub fn is_paused(&self) -> bool {
         self.is_paused
@@ -77,6 +199,137 @@ impl PausableInterval {
     }
 }
 
+/// Ticks at the wall clock times matching a cron expression, e.g. "0 */2 * * *" for every other
+/// full hour. Like an `Interval`, a new timer ticks immediately.
+pub struct CronTimer {
+    schedule: Cron,
+
+    is_paused: bool,
+
+    /// Time of the next tick, kept when a pending `tick()` is cancelled.
+    next: Option<DateTime<Local>>,
+}
+
+impl CronTimer {
+    pub fn new(schedule: Cron) -> Self {
+        Self {
+            schedule,
+            is_paused: false,
+            next: Some(Local::now()),
+        }
+    }
+
+    pub async fn tick(&mut self) -> TickResult {
+        if self.is_paused {
+            return TickResult::Paused;
+        }
+
+        let next = match self.next {
+            Some(next) => next,
+            None => match self.schedule.find_next_occurrence(&Local::now(), false) {
+                Ok(next) => *self.next.insert(next),
+                Err(err) => {
+                    warn!("No next time for the schedule \"{}\": {err}", self.schedule);
+                    return std::future::pending().await;
+                }
+            },
+        };
+
+        // Negative durations fail to convert, i.e. the time has come
+        while let Ok(remaining) = (next - Local::now()).to_std() {
+            if remaining.is_zero() {
+                break;
+            }
+            sleep(remaining.min(MAX_CRON_SLEEP)).await;
+        }
+        self.next = None;
+
+        TickResult::Completed
+    }
+
+    pub fn schedule(&self) -> &Cron {
+        &self.schedule
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.is_paused
+    }
+
+    /// Pause or resume the timer. Times which pass while paused are skipped.
+    pub fn pause(&mut self, paused: bool) {
+        if self.is_paused != paused {
+            self.is_paused = paused;
+            self.next = None;
+        }
+    }
+
+    /// Skip a pending immediate tick, the next one happens at the next matching time.
+    pub fn reset(&mut self) {
+        self.next = None;
+    }
+}
+
+/// Timer of the image updates, either a fixed interval or a cron schedule.
+pub enum UpdateTimer {
+    Interval(PausableInterval),
+    Cron(Box<CronTimer>),
+    /// Ticks immediately, then never again.
+    Once {
+        pending: bool,
+        is_paused: bool,
+    },
+}
+
+impl UpdateTimer {
+    pub async fn tick(&mut self) -> TickResult {
+        match self {
+            Self::Interval(interval) => interval.tick().await,
+            Self::Cron(cron) => cron.tick().await,
+            Self::Once {
+                is_paused: true, ..
+            } => TickResult::Paused,
+            Self::Once { pending, .. } => {
+                if !std::mem::take(pending) {
+                    return std::future::pending().await;
+                }
+                TickResult::Completed
+            }
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        match self {
+            Self::Interval(interval) => interval.is_paused(),
+            Self::Cron(cron) => cron.is_paused(),
+            Self::Once { is_paused, .. } => *is_paused,
+        }
+    }
+
+    pub fn pause(&mut self, paused: bool) {
+        match self {
+            Self::Interval(interval) => interval.pause(paused),
+            Self::Cron(cron) => cron.pause(paused),
+            Self::Once { is_paused, .. } => *is_paused = paused,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        match self {
+            Self::Interval(interval) => interval.reset(),
+            Self::Cron(cron) => cron.reset(),
+            Self::Once { pending, .. } => *pending = false,
+        }
+    }
+
+    /// See `PausableInterval::restart_ramp`.
+    pub fn restart_ramp(&mut self) {
+        match self {
+            Self::Interval(interval) => interval.restart_ramp(),
+            timer => timer.reset(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -146,4 +399,62 @@ mod test {
             Duration::from_secs(100 - 60)
         );
     }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_changed_period_keeps_elapsed_time() {
+        let mut interval = PausableInterval::new(Duration::from_secs(100));
+        interval.tick().await;
+
+        sleep(Duration::from_secs(30)).await;
+        interval.pause(true);
+        sleep(Duration::from_secs(30)).await;
+        interval.set_period(Duration::from_secs(50));
+        assert_eq!(interval.remaining(), Duration::from_secs(20));
+
+        interval.pause(false);
+        assert_eq!(measure(interval.tick()).await, Duration::from_secs(20));
+        assert_eq!(measure(interval.tick()).await, Duration::from_secs(50));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_resumed_interval_continues() {
+        let mut interval = PausableInterval::new(Duration::from_secs(100));
+        interval.resume_from(Duration::from_secs(70));
+
+        sleep(Duration::from_secs(10)).await;
+        assert_eq!(interval.elapsed(), Duration::from_secs(80));
+
+        assert_eq!(measure(interval.tick()).await, Duration::from_secs(20));
+        assert_eq!(interval.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ramp_lengthens_up_to_period() {
+        let ramp = IntervalRamp {
+            start_ms: 1000,
+            factor: 2.0,
+        };
+        let mut interval = PausableInterval::ramped(Duration::from_secs(5), ramp);
+
+        assert_eq!(measure(interval.tick()).await, Duration::ZERO);
+        assert_eq!(measure(interval.tick()).await, Duration::from_secs(1));
+        assert_eq!(measure(interval.tick()).await, Duration::from_secs(2));
+        assert_eq!(measure(interval.tick()).await, Duration::from_secs(4));
+        assert_eq!(measure(interval.tick()).await, Duration::from_secs(5));
+        assert_eq!(measure(interval.tick()).await, Duration::from_secs(5));
+
+        interval.restart_ramp();
+        assert_eq!(measure(interval.tick()).await, Duration::from_secs(1));
+        assert_eq!(measure(interval.tick()).await, Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_cron_timer_waits_for_schedule() {
+        let mut timer = CronTimer::new("0 * * * *".parse().unwrap());
+        assert!(matches!(timer.tick().await, TickResult::Completed));
+
+        let next = time::timeout(Duration::from_millis(10), timer.tick()).await;
+        assert!(next.is_err());
+        assert!(timer.next.is_some_and(|next| next > Local::now()));
+    }
 }
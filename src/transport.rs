@@ -0,0 +1,450 @@
+//! Discovery of the endpoints a gallerica daemon listens on, shared between daemon and CLI.
+//!
+//! Requests are sent as a single JSON document, after which the client closes its write half.
+//! The daemon then answers with a single JSON response and closes the connection.
+//! After `Request::Subscribe`, the response and each event are written as a line of JSON instead,
+//! and the connection stays open. Requests to a TCP listener with a token carry it in the
+//! `TOKEN_FIELD` of the request.
+//!
+//! Named pipes can't be closed for writing only, so requests are sent as a single line of JSON
+//! there instead.
+//!
+//! Via MQTT, requests are published to the topic of the daemon together with a reply topic, on
+//! which the daemon publishes its response.
+
+use std::{
+    fmt::Display,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{Shutdown, TcpStream},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context};
+use rumqttc::{Client, MqttOptions, QoS};
+use serde::{Deserialize, Serialize};
+
+use crate::{message_api::Event, project_dirs, Request, Response};
+
+/// Name of the Unix socket used if none is configured.
+pub const DEFAULT_SOCKET_NAME: &str = "gallerica.sock";
+
+/// Address used for TCP if none is configured.
+/// This is also the fallback on platforms without Unix sockets.
+pub const DEFAULT_TCP_ADDRESS: &str = "127.0.0.1:7253";
+
+/// Name of the named pipe used if none is configured, only available on Windows.
+pub const DEFAULT_PIPE_NAME: &str = r"\\.\pipe\gallerica";
+
+/// Field of a request to a TCP listener which holds its token.
+pub const TOKEN_FIELD: &str = "token";
+
+/// Port of the MQTT broker if none is given.
+pub const DEFAULT_MQTT_PORT: u16 = 1883;
+
+/// How long to wait for the response to a request sent via MQTT, as a daemon which isn't
+/// subscribed to the topic never answers.
+const MQTT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time between attempts to connect while waiting for the daemon, see `Timeouts::connect`.
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Directory in which Unix sockets are placed.
+/// This is the system runtime directory (`XDG_RUNTIME_DIR`), or a subdirectory of the temp
+/// directory on systems without one (e.g. macOS).
+pub fn runtime_dir() -> PathBuf {
+    project_dirs()
+        .runtime_dir()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| std::env::temp_dir().join("gallerica"))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// A named pipe like `DEFAULT_PIPE_NAME`
+    #[cfg(windows)]
+    Pipe(String),
+    /// A TCP listener at "host:port", which may need a `token`
+    Tcp {
+        address: String,
+        token: Option<String>,
+    },
+    /// The `topic` of a daemon with an MQTT listener, via the broker at "host:port"
+    Mqtt { broker: String, topic: String },
+}
+
+impl Endpoint {
+    /// Endpoint for the Unix socket `socket`.
+    /// Relative paths are relative to the `runtime_dir`.
+    #[cfg(unix)]
+    pub fn unix(socket: &Path) -> Self {
+        Self::Unix(runtime_dir().join(socket))
+    }
+
+    /// Find the endpoint of a locally running daemon, without the user having to specify one.
+    /// Prefers the default Unix socket or named pipe and falls back to TCP on the default address.
+    pub fn discover() -> Self {
+        #[cfg(unix)]
+        {
+            let socket = Self::unix(Path::new(DEFAULT_SOCKET_NAME));
+            if matches!(&socket, Self::Unix(path) if path.exists()) {
+                return socket;
+            }
+        }
+        #[cfg(windows)]
+        if Path::new(DEFAULT_PIPE_NAME).exists() {
+            return Self::Pipe(DEFAULT_PIPE_NAME.to_owned());
+        }
+
+        Self::Tcp {
+            address: DEFAULT_TCP_ADDRESS.to_owned(),
+            token: None,
+        }
+    }
+
+    /// Like `discover`, but wait up to `timeout` for the default socket or pipe to appear, e.g.
+    /// while the daemon is starting.
+    pub fn discover_within(timeout: Duration) -> Self {
+        #[cfg(any(unix, windows))]
+        {
+            #[cfg(unix)]
+            let socket = runtime_dir().join(DEFAULT_SOCKET_NAME);
+            #[cfg(windows)]
+            let socket = PathBuf::from(DEFAULT_PIPE_NAME);
+            let deadline = Instant::now() + timeout;
+            while !socket.exists() && Instant::now() < deadline {
+                std::thread::sleep(CONNECT_RETRY_INTERVAL);
+            }
+        }
+        #[cfg(not(any(unix, windows)))]
+        let _ = timeout;
+
+        Self::discover()
+    }
+
+    /// List all local endpoints, i.e. all Unix sockets in the `runtime_dir`, or the discovered
+    /// endpoint on other platforms.
+    pub fn discover_all() -> anyhow::Result<Vec<Self>> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+
+            let path = runtime_dir();
+            std::fs::create_dir_all(&path)?;
+
+            let mut endpoints = vec![];
+            for file in path.read_dir()?.filter_map(|e| e.ok()) {
+                if file.file_type()?.is_socket() {
+                    endpoints.push(Self::Unix(file.path()));
+                }
+            }
+            Ok(endpoints)
+        }
+
+        #[cfg(not(unix))]
+        Ok(vec![Self::discover()])
+    }
+
+    /// Send `request` to the daemon listening on this endpoint and wait for its response.
+    pub fn send(&self, request: &Request) -> anyhow::Result<Response> {
+        self.send_with(request, Timeouts::default())
+    }
+
+    /// Like `send`, waiting for the daemon as configured in `timeouts`.
+    pub fn send_with(&self, request: &Request, timeouts: Timeouts) -> anyhow::Result<Response> {
+        (|| {
+            if let Self::Mqtt { broker, topic } = self {
+                return exchange_mqtt(broker, topic, request, timeouts);
+            }
+            let mut stream = Stream::connect(self, timeouts)?;
+            serde_json::to_writer(&mut stream, &self.payload(request)?)?;
+            stream.shutdown_write()?;
+
+            let mut response = vec![];
+            stream
+                .read_to_end(&mut response)
+                .map_err(|err| timed_out(err, timeouts))?;
+            Ok(serde_json::from_slice(&response)?)
+        })()
+        .with_context(|| format!("Failed to send request to {self}"))
+    }
+
+    /// Receive the events of the daemon listening on this endpoint, see `Request::Subscribe`.
+    /// The events end when the daemon shuts down.
+    pub fn subscribe(&self) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Event>>> {
+        self.subscribe_with(Timeouts::default())
+    }
+
+    /// Like `subscribe`, waiting for the daemon as configured in `timeouts`. The response timeout
+    /// only applies to confirming the subscription, as events can be arbitrarily far apart.
+    pub fn subscribe_with(
+        &self,
+        timeouts: Timeouts,
+    ) -> anyhow::Result<impl Iterator<Item = anyhow::Result<Event>>> {
+        let mut lines = (|| {
+            if let Self::Mqtt { .. } = self {
+                bail!("Events can only be received on Unix sockets and TCP");
+            }
+            let mut stream = Stream::connect(self, timeouts)?;
+            serde_json::to_writer(&mut stream, &self.payload(&Request::Subscribe)?)?;
+            stream.shutdown_write()?;
+
+            let mut reader = BufReader::new(stream);
+            let mut response = String::new();
+            reader
+                .read_line(&mut response)
+                .map_err(|err| timed_out(err, timeouts))?;
+            if response.is_empty() {
+                bail!("The daemon closed the connection");
+            }
+            match serde_json::from_str(&response)? {
+                Response::Ok => {}
+                response => bail!("{response:?}"),
+            }
+            reader.get_ref().set_read_timeout(None)?;
+            Ok(reader.lines())
+        })()
+        .with_context(|| format!("Failed to subscribe to {self}"))?;
+
+        Ok(std::iter::from_fn(move || lines.next()).map(|line| Ok(serde_json::from_str(&line?)?)))
+    }
+
+    /// `request` as it is sent to this endpoint, with the token of a TCP listener.
+    fn payload(&self, request: &Request) -> anyhow::Result<serde_json::Value> {
+        let mut payload = serde_json::to_value(request)?;
+        if let Self::Tcp {
+            token: Some(token), ..
+        } = self
+        {
+            payload[TOKEN_FIELD] = token.clone().into();
+        }
+        Ok(payload)
+    }
+}
+
+/// How long to wait for the daemon, see `Endpoint::send_with`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeouts {
+    /// Keep retrying to connect for this long, e.g. while the daemon is still starting.
+    /// With MQTT, only the connection to the broker is retried.
+    pub connect: Duration,
+    /// Give up if the daemon doesn't respond within this time. None waits indefinitely, except
+    /// for MQTT, where unanswered requests are common and time out after a few seconds.
+    pub response: Option<Duration>,
+}
+
+/// A connection to a Unix socket, named pipe or TCP endpoint.
+enum Stream {
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixStream),
+    /// The client end of a named pipe, which is opened like a file
+    #[cfg(windows)]
+    Pipe(std::fs::File),
+    Tcp(TcpStream),
+}
+
+impl Stream {
+    /// Connect to `endpoint`, retrying until the connect timeout passed.
+    fn connect(endpoint: &Endpoint, timeouts: Timeouts) -> std::io::Result<Self> {
+        let deadline = Instant::now() + timeouts.connect;
+        loop {
+            let stream = match endpoint {
+                #[cfg(unix)]
+                Endpoint::Unix(path) => {
+                    std::os::unix::net::UnixStream::connect(path).map(Self::Unix)
+                }
+                // Fails while all instances of the pipe are busy, which is retried like a refused
+                // connection
+                #[cfg(windows)]
+                Endpoint::Pipe(name) => std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(name)
+                    .map(Self::Pipe),
+                Endpoint::Tcp { address, .. } => TcpStream::connect(address).map(Self::Tcp),
+                Endpoint::Mqtt { .. } => unreachable!("MQTT doesn't use streams"),
+            };
+            match stream {
+                Ok(stream) => {
+                    stream.set_read_timeout(timeouts.response)?;
+                    return Ok(stream);
+                }
+                Err(_) if Instant::now() < deadline => std::thread::sleep(CONNECT_RETRY_INTERVAL),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.set_read_timeout(timeout),
+            // Pipes opened as files don't support timeouts, the daemon answers pipes promptly
+            #[cfg(windows)]
+            Self::Pipe(_) => {
+                let _ = timeout;
+                Ok(())
+            }
+            Self::Tcp(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    /// Signal the end of the request to the daemon.
+    fn shutdown_write(&self) -> std::io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.shutdown(Shutdown::Write),
+            #[cfg(windows)]
+            Self::Pipe(pipe) => {
+                let mut pipe: &std::fs::File = pipe;
+                pipe.write_all(b"\n")?;
+                pipe.flush()
+            }
+            Self::Tcp(stream) => stream.shutdown(Shutdown::Write),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.read(buf),
+            #[cfg(windows)]
+            Self::Pipe(pipe) => pipe.read(buf),
+            Self::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.write(buf),
+            #[cfg(windows)]
+            Self::Pipe(pipe) => pipe.write(buf),
+            Self::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.flush(),
+            #[cfg(windows)]
+            Self::Pipe(pipe) => pipe.flush(),
+            Self::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+/// `err` with a readable message if it is caused by the response timeout.
+fn timed_out(err: std::io::Error, timeouts: Timeouts) -> anyhow::Error {
+    match (err.kind(), timeouts.response) {
+        (ErrorKind::WouldBlock | ErrorKind::TimedOut, Some(timeout)) => {
+            anyhow!("No response within {}", crate::duration::format(timeout))
+        }
+        _ => err.into(),
+    }
+}
+
+/// A request with the fields the MQTT listener of the daemon expects besides it.
+#[derive(Serialize)]
+struct MqttRequest<'a> {
+    #[serde(flatten)]
+    request: &'a Request,
+    reply_topic: &'a str,
+    correlation_data: &'a str,
+}
+
+#[derive(Deserialize)]
+struct MqttResponse {
+    #[serde(flatten)]
+    response: Response,
+    correlation_data: Option<String>,
+}
+
+fn exchange_mqtt(
+    broker: &str,
+    topic: &str,
+    request: &Request,
+    timeouts: Timeouts,
+) -> anyhow::Result<Response> {
+    use rumqttc::{
+        Event::Incoming,
+        Packet::{ConnAck, Publish},
+    };
+
+    let (host, port) = match broker.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .with_context(|| format!("Invalid port in '{broker}'"))?,
+        ),
+        None => (broker, DEFAULT_MQTT_PORT),
+    };
+    // Unique per process, so concurrent invocations don't receive each other's responses
+    let client_id = format!("gallerica-cli-{}", std::process::id());
+    let reply_topic = format!("{topic}/reply/{client_id}");
+    let correlation_data = format!("{:x}", rand::random::<u64>());
+
+    let (mut client, mut connection) = Client::new(MqttOptions::new(&client_id, host, port), 10);
+    client.subscribe(&reply_topic, QoS::AtLeastOnce)?;
+    client.publish(
+        topic,
+        QoS::AtLeastOnce,
+        false,
+        serde_json::to_vec(&MqttRequest {
+            request,
+            reply_topic: &reply_topic,
+            correlation_data: &correlation_data,
+        })?,
+    )?;
+
+    let timeout = timeouts.response.unwrap_or(MQTT_TIMEOUT);
+    let connect_deadline = Instant::now() + timeouts.connect;
+    let mut deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let Ok(event) = connection.recv_timeout(remaining) else {
+            bail!("No response within {}", crate::duration::format(timeout));
+        };
+        let event = match event {
+            Ok(event) => event,
+            // The next poll connects again, the queued requests are sent once connected
+            Err(_) if Instant::now() < connect_deadline => {
+                std::thread::sleep(CONNECT_RETRY_INTERVAL);
+                deadline = Instant::now() + timeout;
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        match event {
+            Incoming(ConnAck(_)) => deadline = Instant::now() + timeout,
+            Incoming(Publish(publish)) if publish.topic == reply_topic => {
+                let reply: MqttResponse = serde_json::from_slice(&publish.payload)?;
+                if reply.correlation_data.as_deref() == Some(&correlation_data) {
+                    let _ = client.disconnect();
+                    return Ok(reply.response);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "Unix socket '{}'", path.display()),
+            #[cfg(windows)]
+            Self::Pipe(name) => write!(f, "named pipe '{name}'"),
+            Self::Tcp { address, .. } => write!(f, "TCP address '{address}'"),
+            Self::Mqtt { broker, topic } => write!(f, "MQTT topic '{topic}' on '{broker}'"),
+        }
+    }
+}
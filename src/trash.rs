@@ -0,0 +1,236 @@
+//! Moving images to the trash, following the freedesktop.org trash specification, or copying
+//! them into other folders.
+
+use std::{
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use directories::BaseDirs;
+use tracing::warn;
+
+use crate::sidecar;
+
+/// Where `trash` moves files to.
+enum Bin<'a> {
+    /// A plain folder
+    Folder(&'a Path),
+    /// A trash directory with the `files` and their `info` files
+    Trash(PathBuf),
+}
+
+/// Move `file` to the trash, or into `quarantine` if that is given. Its sidecar and metadata
+/// files are moved along with it.
+/// Returns the new location of the file.
+pub fn trash(file: &Path, quarantine: Option<&Path>) -> Result<PathBuf> {
+    let bin = match quarantine {
+        Some(dir) => Bin::Folder(dir),
+        None => Bin::Trash(
+            BaseDirs::new()
+                .ok_or_else(|| anyhow!("User has no home directory!"))?
+                .data_dir()
+                .join("Trash"),
+        ),
+    };
+    trash_into(file, &bin)
+}
+
+fn trash_into(file: &Path, bin: &Bin) -> Result<PathBuf> {
+    let file = file
+        .canonicalize()
+        .with_context(|| format!("Failed to find '{}'", file.display()))?;
+
+    let target = bin.put(&file, &file)?;
+    for suffix in sidecar::SUFFIXES {
+        let companion = sidecar::sidecar_path(&file, suffix);
+        if !companion.exists() {
+            continue;
+        }
+        // Named after the new name of the image, so they still belong together
+        if let Err(err) = bin.put(&companion, &sidecar::sidecar_path(&target, suffix)) {
+            warn!("Failed to trash '{}': {err:#}", companion.display());
+        }
+    }
+    Ok(target)
+}
+
+impl Bin<'_> {
+    /// Move `file` into the bin, named like `name` unless that's taken.
+    /// Returns the new location of the file.
+    fn put(&self, file: &Path, name: &Path) -> Result<PathBuf> {
+        match self {
+            Self::Folder(dir) => {
+                std::fs::create_dir_all(dir)?;
+                let target = unique_target(dir, name, |_| Ok(()))?;
+                move_file(file, &target)?;
+                Ok(target)
+            }
+            Self::Trash(trash) => {
+                let files = trash.join("files");
+                let info = trash.join("info");
+                std::fs::create_dir_all(&files)?;
+                std::fs::create_dir_all(&info)?;
+
+                // The info file is created first, its name reserves the name in `files`
+                let target = unique_target(&files, name, |name| {
+                    let mut info_file = OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .open(info_path(&info, name))?;
+                    write!(
+                        info_file,
+                        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+                        percent_encode(file),
+                        chrono::Local::now().format("%Y-%m-%dT%H:%M:%S")
+                    )?;
+                    Ok(())
+                })?;
+
+                if let Err(err) = move_file(file, &target) {
+                    let _ = std::fs::remove_file(info_path(
+                        &info,
+                        target.file_name().unwrap_or_default(),
+                    ));
+                    return Err(err);
+                }
+                Ok(target)
+            }
+        }
+    }
+}
+
+/// Info file in `info` of the trashed file called `name`.
+fn info_path(info: &Path, name: impl AsRef<Path>) -> PathBuf {
+    let mut info_name = name.as_ref().as_os_str().to_owned();
+    info_name.push(".trashinfo");
+    info.join(info_name)
+}
+
+/// Copy `file` into `dir`, without overwriting existing files.
+/// Returns the path of the copy.
+pub fn collect(file: &Path, dir: &Path) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create '{}'", dir.display()))?;
+    let target = unique_target(dir, file, |_| Ok(()))?;
+    std::fs::copy(file, &target).with_context(|| {
+        format!(
+            "Failed to copy '{}' to '{}'",
+            file.display(),
+            target.display()
+        )
+    })?;
+    Ok(target)
+}
+
+/// Find a file name in `dir` based on the name of `file` that's not yet taken.
+/// `reserve` is called with each candidate name, and may fail with `AlreadyExists` to try the
+/// next one.
+fn unique_target(
+    dir: &Path,
+    file: &Path,
+    mut reserve: impl FnMut(&Path) -> std::io::Result<()>,
+) -> Result<PathBuf> {
+    let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = file
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+
+    for counter in 0.. {
+        let name = match counter {
+            0 => PathBuf::from(file.file_name().unwrap_or_default()),
+            n => PathBuf::from(format!("{stem}.{n}{extension}")),
+        };
+        let target = dir.join(&name);
+        if target.exists() {
+            continue;
+        }
+
+        match reserve(&name) {
+            Ok(()) => return Ok(target),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    unreachable!()
+}
+
+/// Rename `from` to `to`, copying the file if they are on different file systems.
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    let make_ctx = || format!("Failed to move '{}' to '{}'", from.display(), to.display());
+    if std::fs::rename(from, to).is_err() {
+        std::fs::copy(from, to).with_context(make_ctx)?;
+        if let Err(err) = std::fs::remove_file(from) {
+            // Don't leave a second copy behind
+            let _ = std::fs::remove_file(to);
+            return Err(err).with_context(make_ctx);
+        }
+    }
+    Ok(())
+}
+
+/// Percent encode a path as required for the `Path` key of trash info files.
+fn percent_encode(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    #[test]
+    fn test_sidecars_are_trashed_with_the_image() {
+        let dir = TempDir::new("trash");
+        let trash = dir.join("Trash");
+        let image = dir.join("a.png");
+        for file in [&dir.join("a.png"), &dir.join("a.png.xmp")] {
+            fs::write(file, "old").unwrap();
+        }
+        let target = trash_into(&image, &Bin::Trash(trash.clone())).unwrap();
+        assert_eq!(target, trash.join("files/a.png"));
+
+        // Another image of the same name gets a new name, and so do its sidecars
+        fs::write(&image, "new").unwrap();
+        fs::write(dir.join("a.png.gallerica.json"), "{}").unwrap();
+        let target = trash_into(&image, &Bin::Trash(trash.clone())).unwrap();
+        assert_eq!(target, trash.join("files/a.1.png"));
+
+        let mut trashed: Vec<_> = fs::read_dir(trash.join("files"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        trashed.sort();
+        assert_eq!(
+            trashed,
+            ["a.1.png", "a.1.png.gallerica.json", "a.png", "a.png.xmp"]
+        );
+        assert!(trash.join("info/a.1.png.gallerica.json.trashinfo").exists());
+        assert_eq!(fs::read_dir(&*dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_failed_move_removes_the_info_file() {
+        let dir = TempDir::new("trash-failure");
+        let trash = Bin::Trash(dir.join("Trash"));
+
+        let gone = dir.join("gone.png");
+        assert!(trash.put(&gone, &gone).is_err());
+
+        assert_eq!(fs::read_dir(dir.join("Trash/info")).unwrap().count(), 0);
+    }
+}
@@ -1,27 +1,34 @@
 use std::{
     fs::{create_dir_all, remove_file},
-    path::{Path, PathBuf},
+    path::PathBuf,
 };
 
 use crate::message_api::*;
-use crate::project_dirs;
 use anyhow::Context;
 use async_trait::async_trait;
+use gallerica::transport::{runtime_dir, DEFAULT_SOCKET_NAME};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{UnixListener, UnixStream},
 };
 
 fn default_path() -> PathBuf {
-    "gallerica.sock".into()
+    DEFAULT_SOCKET_NAME.into()
 }
 
-#[derive(serde::Deserialize, Debug)]
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct UnixListenerConfig {
     #[serde(default = "default_path")]
     pub path_to_socket: PathBuf,
 }
 
+impl UnixListenerConfig {
+    /// The absolute path of the socket, relative paths are relative to the `runtime_dir`.
+    pub fn socket_path(&self) -> anyhow::Result<PathBuf> {
+        Ok(runtime_dir().join(crate::expand::path(&self.path_to_socket)?))
+    }
+}
+
 impl Default for UnixListenerConfig {
     fn default() -> Self {
         Self {
@@ -51,6 +58,10 @@ impl InflightRequest for UnixRequest {
         self.stream.shutdown().await?;
         Ok(())
     }
+
+    fn into_event_writer(self: Box<Self>) -> Result<EventWriter, Box<dyn InflightRequest>> {
+        Ok(Box::new(self.stream))
+    }
 }
 
 pub struct UnixSocketReceiver {
@@ -60,12 +71,11 @@ pub struct UnixSocketReceiver {
 
 impl UnixSocketReceiver {
     pub async fn new(config: &UnixListenerConfig) -> anyhow::Result<Self> {
-        let dirs = project_dirs();
-        let path = dirs.runtime_dir().unwrap_or_else(|| Path::new("/tmp"));
-        let file = path.join(&config.path_to_socket);
+        let path = runtime_dir();
+        let file = config.socket_path()?;
 
         (|| {
-            create_dir_all(path)?;
+            create_dir_all(&path)?;
 
             let listener = match UnixListener::bind(&file) {
                 Ok(l) => l,
@@ -0,0 +1,288 @@
+//! Keys in the configuration which no option reads, e.g. misspelled ones, which serde otherwise
+//! silently ignores. They are found by deserializing the configuration a second time, comparing
+//! the keys of each table with the fields of the struct it becomes.
+
+use std::{cell::RefCell, fmt};
+
+use serde::de::{
+    self, value::StrDeserializer, DeserializeOwned, DeserializeSeed, EnumAccess, MapAccess,
+    SeqAccess, VariantAccess, Visitor,
+};
+use toml::Value;
+
+/// A step from a table or array to one of its values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// `path` written like `galleries[1].folders`.
+pub fn display(path: &[Segment]) -> String {
+    let mut text = String::new();
+    for segment in path {
+        match segment {
+            Segment::Key(key) if text.is_empty() => text += key,
+            Segment::Key(key) => text += &format!(".{key}"),
+            Segment::Index(index) => text += &format!("[{index}]"),
+        }
+    }
+    text
+}
+
+/// The paths of the keys in `value` which aren't fields of the struct `T` deserializes them into.
+/// Keys in tagged or untagged enums aren't checked, as serde buffers them before it knows the type.
+pub fn find<T: DeserializeOwned>(value: &Value) -> Vec<Vec<Segment>> {
+    let unknown = RefCell::new(vec![]);
+    // Errors are reported by the regular parse, the keys up to the error are still collected
+    let _ = T::deserialize(Tracked {
+        value,
+        path: vec![],
+        unknown: &unknown,
+    });
+    unknown.into_inner()
+}
+
+#[derive(Debug)]
+struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+/// Deserializes `value`, recording unknown keys in `unknown`.
+struct Tracked<'a> {
+    value: &'a Value,
+    path: Vec<Segment>,
+    unknown: &'a RefCell<Vec<Vec<Segment>>>,
+}
+
+impl<'a> Tracked<'a> {
+    fn child(&self, segment: Segment, value: &'a Value) -> Self {
+        let mut path = self.path.clone();
+        path.push(segment);
+        Self {
+            value,
+            path,
+            unknown: self.unknown,
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Tracked<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::String(text) => visitor.visit_str(text),
+            Value::Integer(number) => visitor.visit_i64(*number),
+            Value::Float(number) => visitor.visit_f64(*number),
+            Value::Boolean(value) => visitor.visit_bool(*value),
+            Value::Datetime(datetime) => visitor.visit_string(datetime.to_string()),
+            Value::Array(values) => visitor.visit_seq(Seq {
+                values: values.iter().enumerate(),
+                parent: &self,
+            }),
+            Value::Table(table) => visitor.visit_map(Map {
+                entries: table.iter(),
+                value: None,
+                parent: &self,
+            }),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        if let Value::Table(table) = self.value {
+            let mut unknown = self.unknown.borrow_mut();
+            for key in table.keys().filter(|key| !fields.contains(&key.as_str())) {
+                let mut path = self.path.clone();
+                path.push(Segment::Key(key.clone()));
+                unknown.push(path);
+            }
+        }
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.value {
+            Value::String(variant) => visitor.visit_enum(StrDeserializer::<Error>::new(variant)),
+            // Externally tagged, like `{ variant = { ... } }`
+            Value::Table(table) if table.len() == 1 => {
+                let (variant, value) = table.iter().next().expect("checked length");
+                let value = self.child(Segment::Key(variant.clone()), value);
+                visitor.visit_enum(Enum { variant, value })
+            }
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map identifier ignored_any
+    }
+}
+
+struct Seq<'a, 'p> {
+    values: std::iter::Enumerate<std::slice::Iter<'a, Value>>,
+    parent: &'p Tracked<'a>,
+}
+
+impl<'de> SeqAccess<'de> for Seq<'_, '_> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.values.next() {
+            Some((index, value)) => seed
+                .deserialize(self.parent.child(Segment::Index(index), value))
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+struct Map<'a, 'p> {
+    entries: toml::map::Iter<'a>,
+    /// The value of the key returned last
+    value: Option<(&'a String, &'a Value)>,
+    parent: &'p Tracked<'a>,
+}
+
+impl<'de> MapAccess<'de> for Map<'_, '_> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let Some((key, value)) = self.entries.next() else {
+            return Ok(None);
+        };
+        self.value = Some((key, value));
+        seed.deserialize(StrDeserializer::<Error>::new(key))
+            .map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let (key, value) = self
+            .value
+            .take()
+            .ok_or_else(|| de::Error::custom("value requested before its key"))?;
+        seed.deserialize(self.parent.child(Segment::Key(key.clone()), value))
+    }
+}
+
+struct Enum<'a> {
+    variant: &'a str,
+    value: Tracked<'a>,
+}
+
+impl<'de, 'a> EnumAccess<'de> for Enum<'a> {
+    type Error = Error;
+    type Variant = Tracked<'a>;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Tracked<'a>), Error> {
+        let variant = seed.deserialize(StrDeserializer::<Error>::new(self.variant))?;
+        Ok((variant, self.value))
+    }
+}
+
+impl<'de> VariantAccess<'de> for Tracked<'_> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_any(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_struct(self, "", fields, visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde::Deserialize;
+
+    #[allow(dead_code)]
+    #[derive(Deserialize)]
+    struct Gallery {
+        name: String,
+        #[serde(default)]
+        weight: Option<f64>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Deserialize)]
+    struct Config {
+        interval: u64,
+        galleries: Vec<Gallery>,
+    }
+
+    #[test]
+    fn test_misspelled_keys_are_found() {
+        let value: Value = toml::from_str(
+            r#"
+            interval = 10
+            intervall = 20
+            [[galleries]]
+            name = "a"
+            [[galleries]]
+            name = "b"
+            wieght = 2.0
+            "#,
+        )
+        .unwrap();
+        let unknown: Vec<_> = find::<Config>(&value).iter().map(|p| display(p)).collect();
+        assert_eq!(unknown, vec!["intervall", "galleries[1].wieght"]);
+    }
+}
@@ -0,0 +1,276 @@
+//! Checks of a parsed configuration which its types can't express, like a `default_gallery` which
+//! isn't defined. All problems are found at once, each with the path of its option, so a config
+//! file can be fixed in one go.
+
+use std::{collections::HashMap, fmt};
+
+use anyhow::{bail, Result};
+use tracing::warn;
+
+use crate::{
+    template::{CommandLine, CommandLines},
+    Configuration, ListenerConfiguration, ALL_GALLERIES,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The daemon can't start with this configuration
+    Error,
+    /// Probably a mistake, but the daemon works
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    pub severity: Severity,
+    /// Path of the option, like `galleries[1].folders`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}`: {}", self.path, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Problems(Vec<Problem>);
+
+impl Problems {
+    fn error(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.0.push(Problem {
+            severity: Severity::Error,
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+
+    fn warning(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.0.push(Problem {
+            severity: Severity::Warning,
+            path: path.into(),
+            message: message.into(),
+        });
+    }
+
+    /// Check `lines`, the commands at `path`, which should pass `{image}` if they `display` it.
+    fn commands(&mut self, path: &str, lines: &CommandLines, display: bool) {
+        let lines: Vec<_> = lines.iter().collect();
+        if lines.is_empty() {
+            self.error(path, "needs a command");
+        }
+        for (index, line) in lines.iter().enumerate() {
+            let path = match lines.len() {
+                1 => path.to_owned(),
+                _ => format!("{path}[{index}]"),
+            };
+            match CommandLine::parse(line) {
+                Err(err) => self.error(path, format!("{err:#}")),
+                Ok(command) if display && !command.uses_image() => self.warning(
+                    path,
+                    "contains no `{image}` placeholder, the image is only passed as the \
+                     GALLERICA_IMAGE environment variable",
+                ),
+                Ok(_) => {}
+            }
+        }
+    }
+}
+
+/// All problems of `config`.
+pub fn validate(config: &Configuration) -> Vec<Problem> {
+    let mut problems = Problems::default();
+
+    match config.command_lines() {
+        Ok(lines) => problems.commands("command_line", &lines, true),
+        Err(err) => problems.error("command_line", format!("{err:#}")),
+    }
+    if let Some(command) = &config.prefetch_command {
+        problems.commands(
+            "prefetch_command",
+            &CommandLines::One(command.clone()),
+            false,
+        );
+    }
+    if config.max_running_commands == 0 {
+        problems.error("max_running_commands", "must be at least 1");
+    }
+    if config.update_interval_ms == 0 && config.update_schedule.is_none() && !config.one_shot {
+        problems.error(
+            "update_interval_ms",
+            "must be greater than 0, use `one_shot` to never change the image on its own",
+        );
+    }
+
+    let mut names = HashMap::new();
+    if config.galleries.is_empty() {
+        problems.error("galleries", "needs at least one gallery");
+    }
+    for (index, gallery) in config.galleries.iter().enumerate() {
+        let path = format!("galleries[{index}]");
+        if gallery.name == ALL_GALLERIES {
+            problems.error(
+                format!("{path}.name"),
+                format!("'{ALL_GALLERIES}' is reserved for all galleries"),
+            );
+        } else if let Some(first) = names.insert(gallery.name.as_str(), index) {
+            problems.error(
+                format!("{path}.name"),
+                format!(
+                    "'{}' is already the name of `galleries[{first}]`",
+                    gallery.name
+                ),
+            );
+        }
+        if gallery.sources.is_empty() {
+            problems.error(format!("{path}.folders"), "needs at least one folder");
+        }
+        if gallery.update_interval_ms == Some(0) {
+            problems.error(
+                format!("{path}.update_interval_ms"),
+                "must be greater than 0",
+            );
+        }
+    }
+
+    let mut gallery_exists = |path: String, name: &str| {
+        if name != ALL_GALLERIES && !names.contains_key(name) && !config.galleries.is_empty() {
+            let mut known: Vec<_> = names.keys().map(|name| format!("'{name}'")).collect();
+            known.sort();
+            problems.error(
+                path,
+                format!(
+                    "'{name}' is not one of the galleries {}, or \"{ALL_GALLERIES}\"",
+                    known.join(", ")
+                ),
+            );
+        }
+    };
+    gallery_exists("default_gallery".to_owned(), &config.default_gallery);
+    for (index, rule) in config.schedule.iter().enumerate() {
+        gallery_exists(format!("schedule[{index}].gallery"), &rule.gallery);
+    }
+    for (index, rotation) in config.rotations.iter().enumerate() {
+        gallery_exists(format!("rotations[{index}].gallery"), &rotation.gallery);
+    }
+    if let Some(gallery) = config
+        .lockscreen
+        .as_ref()
+        .and_then(|lockscreen| lockscreen.gallery.as_ref())
+    {
+        gallery_exists("lockscreen.gallery".to_owned(), gallery);
+    }
+
+    for (index, rotation) in config.rotations.iter().enumerate() {
+        problems.commands(
+            &format!("rotations[{index}].command_line"),
+            &rotation.command_line,
+            true,
+        );
+    }
+    if let Some(lockscreen) = &config.lockscreen {
+        problems.commands("lockscreen.command_line", &lockscreen.command_line, true);
+    }
+
+    for (name, profile) in &config.profiles {
+        if !profile.is_table() {
+            problems.error(format!("profiles.{name}"), "must be a table of options");
+        }
+    }
+
+    check_listeners(&config.listeners, &mut problems);
+    problems.0
+}
+
+/// Print the warnings of `validate`, and return its errors as one.
+pub fn check(config: &Configuration) -> Result<()> {
+    let (errors, warnings): (Vec<_>, Vec<_>) = validate(config)
+        .into_iter()
+        .partition(|problem| problem.severity == Severity::Error);
+    for warning in warnings {
+        warn!("{warning}");
+    }
+    if !errors.is_empty() {
+        let errors: Vec<_> = errors.iter().map(Problem::to_string).collect();
+        bail!("Invalid configuration:\n  {}", errors.join("\n  "));
+    }
+    Ok(())
+}
+
+/// Listeners can't share a socket, an address or an MQTT client id.
+fn check_listeners(listeners: &[ListenerConfiguration], problems: &mut Problems) {
+    let mut used = HashMap::new();
+    for (index, listener) in listeners.iter().enumerate() {
+        let path = format!("listeners[{index}]");
+        let resource = match listener {
+            ListenerConfiguration::UnixSocket(config) => match config.socket_path() {
+                Ok(socket) => format!("the socket '{}'", socket.display()),
+                Err(err) => {
+                    problems.error(format!("{path}.path_to_socket"), format!("{err:#}"));
+                    continue;
+                }
+            },
+            ListenerConfiguration::Tcp(config) => format!("the address '{}'", config.address),
+            ListenerConfiguration::NamedPipe(config) => format!("the pipe '{}'", config.name),
+            ListenerConfiguration::Mqtt(config) => format!(
+                "the MQTT client id '{}' at {}:{}",
+                config.client_id, config.host, config.port
+            ),
+            ListenerConfiguration::Stdin => "stdin".to_owned(),
+        };
+        if let Some(first) = used.get(&resource) {
+            problems.error(path, format!("uses {resource} like `listeners[{first}]`"));
+        } else {
+            used.insert(resource, index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_all_problems_are_reported() {
+        let config: Configuration = toml::from_str(
+            r#"
+            command_line = "feh --bg-fill"
+            update_interval_ms = 0
+            default_gallery = "missing"
+
+            [[listeners]]
+            type = "TCP"
+            address = "127.0.0.1:7253"
+            [[listeners]]
+            type = "TCP"
+            address = "127.0.0.1:7253"
+
+            [[galleries]]
+            name = "a"
+            folders = []
+            [[galleries]]
+            name = "a"
+            folders = ["/images"]
+            "#,
+        )
+        .unwrap();
+
+        let problems: Vec<_> = validate(&config)
+            .iter()
+            .map(|problem| (problem.severity, problem.path.clone()))
+            .collect();
+
+        assert_eq!(
+            problems,
+            [
+                (Severity::Warning, "command_line".to_owned()),
+                (Severity::Error, "update_interval_ms".to_owned()),
+                (Severity::Error, "galleries[0].folders".to_owned()),
+                (Severity::Error, "galleries[1].name".to_owned()),
+                (Severity::Error, "default_gallery".to_owned()),
+                (Severity::Error, "listeners[1]".to_owned()),
+            ]
+        );
+    }
+}